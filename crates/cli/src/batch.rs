@@ -0,0 +1,225 @@
+//! `lie batch`: run every prompt of a JSONL file and write one response per line.
+
+use anyhow::Context;
+use lie_core::{Engine, EngineResponse, error::ErrorCode};
+use lie_server::CompletionRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+
+/// An input line: a `/v1/completion` request body plus the caller's id for it.
+#[derive(Deserialize)]
+struct BatchItem {
+    id: serde_json::Value,
+    #[serde(flatten)]
+    request: CompletionRequest,
+}
+
+/// An output line: the full response, tagged with the input's id.
+#[derive(Serialize, Deserialize)]
+struct BatchResult {
+    id: serde_json::Value,
+    #[serde(flatten)]
+    response: EngineResponse,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub processed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub total_tokens: u64,
+    /// Sum of the per-request durations reported by the runtime.
+    pub inference_ms: u64,
+}
+
+pub struct BatchOptions {
+    /// Requests in flight at once.
+    pub concurrency: usize,
+    /// Leave out ids that already have a line in the output file, and append to it.
+    pub skip_existing: bool,
+}
+
+/// Run every request in `input`, writing results to `output` as they finish. A request
+/// that fails is recorded as an error line; it doesn't stop the run.
+pub async fn run(engine: Arc<Engine>, input: &Path, output: &Path, options: &BatchOptions) -> anyhow::Result<Summary> {
+    let content = std::fs::read_to_string(input)
+        .with_context(|| format!("Failed to read {}", input.display()))?;
+    let done = if options.skip_existing { existing_ids(output)? } else { HashSet::new() };
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(options.skip_existing)
+        .truncate(!options.skip_existing)
+        .open(output)
+        .with_context(|| format!("Failed to open {}", output.display()))?;
+
+    let mut summary = Summary::default();
+    let mut in_flight = JoinSet::new();
+
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let item: BatchItem = match serde_json::from_str(line) {
+            Ok(item) => item,
+            Err(e) => {
+                let message = format!("Validation Error: line {}: {}", index + 1, e);
+                let id = line_id(line);
+                write_result(&mut file, &mut summary, BatchResult { id, response: EngineResponse::error(ErrorCode::ValidationError, message) })?;
+                continue;
+            }
+        };
+        if done.contains(&item.id.to_string()) {
+            summary.skipped += 1;
+            continue;
+        }
+
+        while in_flight.len() >= options.concurrency.max(1) {
+            let result = in_flight.join_next().await.expect("set is not empty")?;
+            write_result(&mut file, &mut summary, result)?;
+        }
+
+        let engine = engine.clone();
+        in_flight.spawn(async move {
+            let response = match item.request.to_options() {
+                Ok(inference_options) => engine.process_request(&item.request.prompt, inference_options, None).await
+                    .unwrap_or_else(|e| EngineResponse::from_error(&e)),
+                Err(message) => EngineResponse::error(ErrorCode::ValidationError, message),
+            };
+            BatchResult { id: item.id, response }
+        });
+    }
+
+    while let Some(result) = in_flight.join_next().await {
+        write_result(&mut file, &mut summary, result?)?;
+    }
+    Ok(summary)
+}
+
+fn write_result(file: &mut std::fs::File, summary: &mut Summary, result: BatchResult) -> anyhow::Result<()> {
+    summary.processed += 1;
+    if result.response.status == "error" {
+        summary.failed += 1;
+    }
+    summary.total_tokens += result.response.usage.total_tokens as u64;
+    summary.inference_ms += result.response.usage.duration_ms;
+
+    writeln!(file, "{}", serde_json::to_string(&result)?).context("Failed to write result")?;
+    file.flush()?;
+    Ok(())
+}
+
+/// The id of a line that isn't a valid request, if it has one at all.
+fn line_id(line: &str) -> serde_json::Value {
+    serde_json::from_str::<serde_json::Value>(line).ok()
+        .and_then(|value| value.get("id").cloned())
+        .unwrap_or(serde_json::Value::Null)
+}
+
+/// Ids (as JSON text) already in the output file; none if it doesn't exist yet.
+fn existing_ids(output: &Path) -> anyhow::Result<HashSet<String>> {
+    let content = match std::fs::read_to_string(output) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", output.display())),
+    };
+    Ok(content.lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter_map(|value| value.get("id").filter(|id| !id.is_null()).map(|id| id.to_string()))
+        .collect())
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} processed ({} failed), {} skipped, {} tokens, {} ms of inference",
+            self.processed, self.failed, self.skipped, self.total_tokens, self.inference_ms
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use lie_core::config::EngineConfig;
+    use lie_core::error::EngineError;
+    use lie_core::runtime::{FinishReason, InferenceOptions, InferenceResult, LoadReport, ModelLoadConfig, ModelRuntime, Usage};
+
+    /// Echoes the prompt, or fails on prompts starting with "fail".
+    struct EchoRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for EchoRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, _options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            if prompt.starts_with("fail") {
+                return Err(EngineError::Runtime("Decode failed".to_string()));
+            }
+            Ok(InferenceResult {
+                text: prompt.to_uppercase(),
+                usage: Usage { total_tokens: 10, duration_ms: 5, ..Usage::default() },
+                finish_reason: FinishReason::Eos,
+                seed: None,
+                logprobs: None,
+            })
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    fn results(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_batch_records_failures_and_resumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("prompts.jsonl");
+        let output = dir.path().join("results.jsonl");
+        std::fs::write(&input, concat!(
+            "{\"id\": 1, \"prompt\": \"hello\"}\n",
+            "{\"id\": \"two\", \"prompt\": \"fail please\"}\n",
+            "\n",
+            "{\"id\": 3, \"prompt\": \"hi\", \"limits\": {\"max_tokens\": 0}}\n",
+            "not json\n",
+        )).unwrap();
+
+        let engine = Arc::new(Engine::new(EngineConfig::default(), Box::new(EchoRuntime)));
+        let options = BatchOptions { concurrency: 2, skip_existing: false };
+        let summary = run(engine.clone(), &input, &output, &options).await.unwrap();
+        assert_eq!(summary, Summary { processed: 4, failed: 3, skipped: 0, total_tokens: 10, inference_ms: 5 });
+
+        let lines = results(&output);
+        let by_id = |id: serde_json::Value| lines.iter().find(|line| line["id"] == id).unwrap().clone();
+        assert_eq!(by_id(1.into())["output"]["text"], "HELLO");
+        assert_eq!(by_id("two".into())["error_info"]["code"], "runtime_error");
+        assert_eq!(by_id(3.into())["error_info"]["code"], "validation_error");
+        assert!(by_id(serde_json::Value::Null)["error"].as_str().unwrap().contains("line 5"));
+
+        // Resuming skips every id already written and appends the rest
+        std::fs::write(&input, "{\"id\": 1, \"prompt\": \"hello\"}\n{\"id\": 4, \"prompt\": \"new\"}\n").unwrap();
+        let options = BatchOptions { concurrency: 1, skip_existing: true };
+        let summary = run(engine, &input, &output, &options).await.unwrap();
+        assert_eq!((summary.processed, summary.skipped), (1, 1));
+
+        let lines = results(&output);
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[4]["output"]["text"], "NEW");
+    }
+}
@@ -0,0 +1,186 @@
+//! `lie bench`: measure prompt evaluation and generation speed of the loaded model.
+
+use lie_core::{Engine, runtime::{InferenceOptions, TokenChunk}};
+use serde::Serialize;
+use std::fmt;
+use std::time::Instant;
+
+/// Repeated until the prompt has enough tokens.
+const FILLER: &str = "The quick brown fox jumps over the lazy dog while the river runs past the old mill. ";
+
+pub struct BenchOptions {
+    /// Prompt length in tokens.
+    pub prompt_tokens: usize,
+    /// Tokens to generate per iteration; generation may end earlier at end of sequence.
+    pub gen_tokens: u32,
+    pub iterations: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Stats {
+    pub mean: f64,
+    pub median: f64,
+    pub p95: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub model: String,
+    pub iterations: usize,
+    /// Mean prompt and output lengths actually measured.
+    pub prompt_tokens: f64,
+    pub generated_tokens: f64,
+    pub prompt_tokens_per_second: Stats,
+    pub generation_tokens_per_second: Stats,
+    pub time_to_first_token_ms: Stats,
+    /// Peak resident set size of the process; `None` where it can't be read.
+    pub peak_rss_bytes: Option<u64>,
+}
+
+/// One measured inference.
+struct Sample {
+    prompt_tokens: u32,
+    generated_tokens: u32,
+    prompt_tokens_per_second: f64,
+    generation_tokens_per_second: f64,
+    time_to_first_token_ms: f64,
+}
+
+/// Run `options.iterations` inferences on a synthetic prompt. Each one starts from an
+/// empty context, so the whole prompt is evaluated every time.
+pub async fn run(engine: &Engine, options: &BenchOptions) -> anyhow::Result<Report> {
+    let prompt = synthetic_prompt(engine, options.prompt_tokens).await?;
+
+    let mut samples = Vec::with_capacity(options.iterations);
+    for _ in 0..options.iterations {
+        samples.push(measure(engine, &prompt, options.gen_tokens).await?);
+    }
+
+    let stats_of = |value: fn(&Sample) -> f64| stats(&samples.iter().map(value).collect::<Vec<_>>());
+    Ok(Report {
+        model: engine.model_path().display().to_string(),
+        iterations: samples.len(),
+        prompt_tokens: stats_of(|s| s.prompt_tokens as f64).mean,
+        generated_tokens: stats_of(|s| s.generated_tokens as f64).mean,
+        prompt_tokens_per_second: stats_of(|s| s.prompt_tokens_per_second),
+        generation_tokens_per_second: stats_of(|s| s.generation_tokens_per_second),
+        time_to_first_token_ms: stats_of(|s| s.time_to_first_token_ms),
+        peak_rss_bytes: peak_rss_bytes(),
+    })
+}
+
+/// Text that tokenizes to `tokens` tokens, built from the model's own pieces of `FILLER`.
+async fn synthetic_prompt(engine: &Engine, tokens: usize) -> anyhow::Result<String> {
+    let mut text = FILLER.to_string();
+    loop {
+        let (ids, pieces) = engine.tokenize(&text, false).await?;
+        if ids.is_empty() {
+            anyhow::bail!("The tokenizer produced no tokens for the benchmark prompt");
+        }
+        if ids.len() >= tokens {
+            return Ok(pieces[..tokens].concat());
+        }
+        text = text.repeat(tokens / ids.len() + 1);
+    }
+}
+
+async fn measure(engine: &Engine, prompt: &str, gen_tokens: u32) -> anyhow::Result<Sample> {
+    let options = InferenceOptions {
+        max_tokens: Some(gen_tokens),
+        max_time_ms: None,
+        reset_context: true,
+        ..InferenceOptions::default()
+    };
+
+    let started = Instant::now();
+    let mut first_token = None;
+    let mut chunks = engine.process_request_stream(prompt, options).await?;
+    while let Some(chunk) = chunks.recv().await {
+        match chunk {
+            TokenChunk::Token { .. } => {
+                first_token.get_or_insert_with(|| started.elapsed());
+            }
+            TokenChunk::Done { usage, .. } => {
+                let evaluated = usage.input_tokens.saturating_sub(usage.cached_tokens);
+                return Ok(Sample {
+                    prompt_tokens: usage.input_tokens,
+                    generated_tokens: usage.output_tokens,
+                    prompt_tokens_per_second: per_second(evaluated, usage.prompt_eval_ms),
+                    generation_tokens_per_second: usage.tokens_per_second,
+                    time_to_first_token_ms: first_token.unwrap_or_else(|| started.elapsed()).as_secs_f64() * 1000.0,
+                });
+            }
+            TokenChunk::Error { message } => anyhow::bail!("Benchmark inference failed: {}", message),
+        }
+    }
+    anyhow::bail!("Benchmark inference ended without a result")
+}
+
+fn per_second(tokens: u32, ms: u64) -> f64 {
+    if ms == 0 { 0.0 } else { tokens as f64 * 1000.0 / ms as f64 }
+}
+
+/// Mean, median and 95th percentile (nearest rank) of `values`, which must not be empty.
+fn stats(values: &[f64]) -> Stats {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let n = sorted.len();
+    let median = if n.is_multiple_of(2) { (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0 } else { sorted[n / 2] };
+    let rank = ((n as f64 * 0.95).ceil() as usize).max(1);
+    Stats {
+        mean: sorted.iter().sum::<f64>() / n as f64,
+        median,
+        p95: sorted[rank - 1],
+    }
+}
+
+/// The process's peak resident set size, from `VmHWM` in /proc (Linux only).
+fn peak_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kib: u64 = line.trim_start_matches("VmHWM:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Model: {}", self.model)?;
+        writeln!(f, "{} iterations, {:.0} prompt tokens, {:.1} generated tokens on average", self.iterations, self.prompt_tokens, self.generated_tokens)?;
+        writeln!(f)?;
+        writeln!(f, "{:<26} {:>10} {:>10} {:>10}", "", "mean", "median", "p95")?;
+        for (name, stats) in [
+            ("prompt eval (tokens/s)", &self.prompt_tokens_per_second),
+            ("generation (tokens/s)", &self.generation_tokens_per_second),
+            ("time to first token (ms)", &self.time_to_first_token_ms),
+        ] {
+            writeln!(f, "{:<26} {:>10.1} {:>10.1} {:>10.1}", name, stats.mean, stats.median, stats.p95)?;
+        }
+        match self.peak_rss_bytes {
+            Some(bytes) => write!(f, "\nPeak RSS: {:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+            None => write!(f, "\nPeak RSS: unavailable"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let stats = stats(&[30.0, 10.0, 20.0, 40.0]);
+        assert_eq!(stats, Stats { mean: 25.0, median: 25.0, p95: 40.0 });
+
+        let single = super::stats(&[7.0]);
+        assert_eq!(single, Stats { mean: 7.0, median: 7.0, p95: 7.0 });
+
+        let values: Vec<f64> = (1..=20).map(f64::from).collect();
+        assert_eq!(super::stats(&values).p95, 19.0);
+    }
+
+    #[test]
+    fn test_per_second() {
+        assert_eq!(per_second(512, 250), 2048.0);
+        assert_eq!(per_second(10, 0), 0.0);
+    }
+}
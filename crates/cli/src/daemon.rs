@@ -0,0 +1,297 @@
+//! `lie serve --daemon`, `lie status` and `lie stop`.
+//!
+//! The runtime is already multi-threaded by the time a command runs, and forking a process
+//! with threads leaves the child with only the forking one. So rather than fork itself,
+//! `--daemon` starts `lie serve` again as a new session with stdin closed and stdout and
+//! stderr going to the log file, and waits for it to write `server.pid_file`. That happens
+//! once the server is listening, so a port that's taken is reported by `lie serve --daemon`
+//! itself. The server removes the pidfile when it exits; one left behind by a crash is
+//! noticed and removed by the next `status`, `stop` or `serve --daemon`.
+
+use anyhow::Context;
+use lie_core::config::{EngineConfig, ListenMode, ServerConfig};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Passed to the background `lie serve`, which then writes the pidfile.
+pub const CHILD_FLAG: &str = "--daemon-child";
+
+/// How long `serve --daemon` waits for the server to start listening. Loading the model
+/// happens after that, so this only covers startup.
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// On top of `shutdown_grace_secs`, how long `stop` gives the server to unload the model
+/// and exit.
+const STOP_MARGIN: Duration = Duration::from_secs(15);
+
+/// The pid in `path`, if the file exists.
+pub fn read_pid(path: &Path) -> anyhow::Result<Option<u32>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => content.trim().parse().map(Some)
+            .map_err(|_| anyhow::anyhow!("{} doesn't hold a process id: '{}'", path.display(), content.trim())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// The pid of the server `path` points to, removing the file if that process is gone.
+pub fn running_pid(path: &Path) -> anyhow::Result<Option<u32>> {
+    let pid = match read_pid(path) {
+        Ok(Some(pid)) => pid,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            tracing::warn!("{}; removing it", e);
+            remove(path);
+            return Ok(None);
+        }
+    };
+    if is_alive(pid) {
+        return Ok(Some(pid));
+    }
+    eprintln!("Removed the stale pidfile {} (process {} is gone)", path.display(), pid);
+    remove(path);
+    Ok(None)
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            tracing::warn!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Holds `server.pid_file` for as long as the server runs.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed to write pidfile {}", path.display()))?;
+        Ok(Self { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Leave it alone if another server has taken it over since
+        if matches!(read_pid(&self.path), Ok(Some(pid)) if pid == std::process::id()) {
+            remove(&self.path);
+        }
+    }
+}
+
+/// The log file of a background server: `logging.file`, or `lie-server.log` next to the
+/// pidfile.
+pub fn log_file(config: &EngineConfig) -> PathBuf {
+    match &config.logging.file {
+        Some(path) => path.clone(),
+        None => config.server.pid_file.with_file_name("lie-server.log"),
+    }
+}
+
+/// Start `lie serve` with the same arguments in the background and wait until it listens.
+#[cfg(unix)]
+pub fn start(config: &EngineConfig) -> anyhow::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let pid_file = &config.server.pid_file;
+    if let Some(pid) = running_pid(pid_file)? {
+        anyhow::bail!("A server is already running (pid {}, {}); stop it with lie stop", pid, pid_file.display());
+    }
+    let log = log_file(config);
+    if let Some(dir) = log.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let output = std::fs::OpenOptions::new().create(true).append(true).open(&log)
+        .with_context(|| format!("Failed to open log file {}", log.display()))?;
+
+    let mut args: Vec<_> = std::env::args_os().skip(1).filter(|arg| arg != "--daemon").collect();
+    args.push(CHILD_FLAG.into());
+    let mut command = std::process::Command::new(std::env::current_exe().context("Failed to find the lie executable")?);
+    command.args(args)
+        .stdin(std::process::Stdio::null())
+        .stdout(output.try_clone()?)
+        .stderr(output);
+    if config.logging.file.is_none() {
+        command.env("CELA_LOGGING_FILE", &log);
+    }
+    // SAFETY: setsid is async-signal-safe and touches nothing of the parent's
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().context("Failed to start the server in the background")?;
+
+    let started = std::time::Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            anyhow::bail!("The server exited during startup ({}); see {}", status, log.display());
+        }
+        if matches!(read_pid(pid_file), Ok(Some(pid)) if pid == child.id()) {
+            println!("Server running in the background (pid {}); logs go to {}", child.id(), log.display());
+            return Ok(());
+        }
+        if started.elapsed() > START_TIMEOUT {
+            anyhow::bail!(
+                "The server (pid {}) didn't start listening within {} s; see {}",
+                child.id(), START_TIMEOUT.as_secs(), log.display()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(not(unix))]
+pub fn start(_config: &EngineConfig) -> anyhow::Result<()> {
+    anyhow::bail!("--daemon is only supported on unix; run lie serve under a service manager instead")
+}
+
+/// Print whether the server in the pidfile runs and what its `/v1/health` says. Returns
+/// whether it runs and answers.
+pub async fn status(config: &EngineConfig) -> anyhow::Result<bool> {
+    let pid_file = &config.server.pid_file;
+    let Some(pid) = running_pid(pid_file)? else {
+        println!("Not running (no server in {})", pid_file.display());
+        return Ok(false);
+    };
+    let server = &config.server;
+    if server.listen == ListenMode::Unix {
+        println!("Running (pid {}) on the unix socket {}", pid, server.socket_path.display());
+        return Ok(true);
+    }
+    match health(server).await {
+        Ok(health) => {
+            println!("Running (pid {}) on {}:{}", pid, server.host, server.port);
+            println!("{}", health);
+            Ok(true)
+        }
+        Err(e) => {
+            println!("Running (pid {}), but /v1/health doesn't answer: {:#}", pid, e);
+            Ok(false)
+        }
+    }
+}
+
+async fn health(server: &ServerConfig) -> anyhow::Result<String> {
+    // A server listening on every address is reached on loopback
+    let host = match server.host.as_str() {
+        "0.0.0.0" => "127.0.0.1",
+        "::" => "[::1]",
+        host => host,
+    };
+    let scheme = if server.tls.is_some() { "https" } else { "http" };
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        // Only the local server is asked, and its certificate may well be self-signed
+        .danger_accept_invalid_certs(true)
+        .build()?;
+    let response = client.get(format!("{}://{}:{}/v1/health", scheme, host, server.port)).send().await?;
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        anyhow::bail!("HTTP {}: {}", status, body);
+    }
+    Ok(body)
+}
+
+/// Send SIGTERM to the server in the pidfile and wait for it to exit. Nothing running is
+/// not an error, so scripts can always call it.
+#[cfg(unix)]
+pub async fn stop(config: &EngineConfig) -> anyhow::Result<()> {
+    let pid_file = &config.server.pid_file;
+    let Some(pid) = running_pid(pid_file)? else {
+        println!("Not running (no server in {})", pid_file.display());
+        return Ok(());
+    };
+    let target = libc::pid_t::try_from(pid).with_context(|| format!("{} holds an invalid pid {}", pid_file.display(), pid))?;
+    // SAFETY: kill has no memory safety requirements
+    if unsafe { libc::kill(target, libc::SIGTERM) } == -1 {
+        return Err(std::io::Error::last_os_error()).with_context(|| format!("Failed to signal process {}", pid));
+    }
+    let timeout = Duration::from_secs(config.server.shutdown_grace_secs) + STOP_MARGIN;
+    let started = std::time::Instant::now();
+    while is_alive(pid) {
+        if started.elapsed() > timeout {
+            anyhow::bail!("The server (pid {}) is still running after {} s; kill -9 {} ends it", pid, timeout.as_secs(), pid);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    // The server removes it itself unless it was killed
+    remove(pid_file);
+    println!("Stopped the server (pid {})", pid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn stop(_config: &EngineConfig) -> anyhow::Result<()> {
+    anyhow::bail!("lie stop is only supported on unix")
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process exists and may be signalled
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        // An exited child of this process stays a zombie until reaped; that's gone too
+        return !is_zombie(pid);
+    }
+    // It exists but belongs to someone else
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(unix)]
+fn is_zombie(pid: libc::pid_t) -> bool {
+    std::fs::read_to_string(format!("/proc/{}/stat", pid))
+        .ok()
+        .and_then(|stat| stat.rsplit_once(')').map(|(_, rest)| rest.trim_start().starts_with('Z')))
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pidfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run").join("lie.pid");
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(running_pid(&path).unwrap(), Some(std::process::id()));
+        drop(pid_file);
+        assert!(!path.exists());
+
+        std::fs::write(&path, "not a pid").unwrap();
+        assert!(read_pid(&path).is_err());
+        assert_eq!(running_pid(&path).unwrap(), None);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stale_pidfile_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lie.pid");
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        std::fs::write(&path, format!("{}\n", child.id())).unwrap();
+        assert_eq!(running_pid(&path).unwrap(), None);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,247 @@
+//! `lie doctor`: check the setup for the usual reasons the engine won't start, and print
+//! the results as a table or JSON for bug reports.
+
+use lie_core::config::{EngineConfig, ListenMode, MemoryBackend};
+use lie_core::error::EngineError;
+use lie_core::gguf::GgufFile;
+use lie_core::memory::store::{JsonStore, MemoryStore, SqliteStore};
+use lie_core::runtime::{InferenceOptions, KvCacheType, ModelRuntime};
+use lie_core::Engine;
+use serde::Serialize;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Self { name, status, detail: detail.into() }
+    }
+}
+
+/// Whether the config was found and is usable. `resolved` is the outcome of resolving it.
+pub fn config(explicit: Option<&Path>, resolved: Result<&EngineConfig, &EngineError>) -> Check {
+    let source = match EngineConfig::file_path(explicit) {
+        Some(path) => path.display().to_string(),
+        None => "no config file, using the defaults".to_string(),
+    };
+    match resolved {
+        Ok(config) => match config.validate() {
+            Ok(()) => Check::new("config", Status::Pass, source),
+            Err(e) => Check::new("config", Status::Fail, format!("{}: {}", source, e)),
+        },
+        // The error names the file already
+        Err(e) => Check::new("config", Status::Fail, format!("{}; the other checks use the defaults", e)),
+    }
+}
+
+/// The checks that need no model loaded.
+pub fn run(config: &EngineConfig) -> Vec<Check> {
+    let model = GgufFile::read(&config.model.default_path);
+    vec![
+        model_file(config, &model),
+        memory_required(config, &model),
+        memory_file(config),
+        server_port(config),
+        gpu(config),
+    ]
+}
+
+fn model_file(config: &EngineConfig, model: &Result<GgufFile, EngineError>) -> Check {
+    let path = &config.model.default_path;
+    if config.model.runtime == "candle" {
+        return match lie_runtime_candle::check_model_dir(path) {
+            Ok(()) => Check::new("model", Status::Pass, format!("{}: model directory", path.display())),
+            Err(e) => Check::new("model", Status::Fail, e.to_string()),
+        };
+    }
+    if config.model.runtime != "llamacpp" {
+        return Check::new("model", Status::Pass, format!("not used by the {} runtime", config.model.runtime));
+    }
+    match model {
+        Ok(file) => match file.problems().into_iter().next() {
+            Some(problem) => Check::new("model", Status::Fail, format!("{}: {}", path.display(), problem)),
+            None => Check::new("model", Status::Pass, format!(
+                "{}: {}, {}, {:.1}B params",
+                path.display(), file.architecture().unwrap_or("unknown"), file.quantization(), file.parameter_count() as f64 / 1e9
+            )),
+        },
+        Err(e) => Check::new("model", Status::Fail, e.to_string()),
+    }
+}
+
+/// Bytes per cached value of each KV cache type; the quantized ones are stored in blocks
+/// of 32 values with a 2-byte scale.
+fn kv_bytes_per_value(kind: KvCacheType) -> f64 {
+    match kind {
+        KvCacheType::F16 => 2.0,
+        KvCacheType::Q8 => 34.0 / 32.0,
+        KvCacheType::Q4 => 18.0 / 32.0,
+    }
+}
+
+/// The weights plus the KV cache for the configured context, against the RAM available.
+fn memory_required(config: &EngineConfig, model: &Result<GgufFile, EngineError>) -> Check {
+    if config.model.runtime != "llamacpp" {
+        return Check::new("ram", Status::Pass, format!("not used by the {} runtime", config.model.runtime));
+    }
+    let Ok(file) = model else {
+        return Check::new("ram", Status::Warn, "can't estimate without a readable model file");
+    };
+    let context = config.model.default_context_size as u64;
+    let kv = file.kv_cache_bytes(context, kv_bytes_per_value(config.model.kv_cache_type)).unwrap_or(0);
+    let required = file.file_size + kv;
+    let estimate = format!("about {:.1} GB needed ({:.1} GB weights, {:.1} GB KV cache for {} tokens)",
+        required as f64 / 1e9, file.file_size as f64 / 1e9, kv as f64 / 1e9, context);
+    match available_memory() {
+        // Offloaded layers live in GPU memory instead, so this is only a hint then
+        Some(available) if required > available => Check::new(
+            "ram",
+            if config.model.default_gpu_layers > 0 { Status::Warn } else { Status::Fail },
+            format!("{}, {:.1} GB available", estimate, available as f64 / 1e9),
+        ),
+        Some(available) => Check::new("ram", Status::Pass, format!("{}, {:.1} GB available", estimate, available as f64 / 1e9)),
+        None => Check::new("ram", Status::Warn, format!("{}; can't tell how much is available", estimate)),
+    }
+}
+
+/// `MemAvailable` from /proc/meminfo, in bytes.
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// The memory store can be read as it is and written to. `lie serve` always turns memory
+/// on, so this is checked whether or not `memory.enabled` is set.
+fn memory_file(config: &EngineConfig) -> Check {
+    let path = &config.memory.persistence_path;
+    if path.exists() {
+        let mut store: Box<dyn MemoryStore> = match config.memory.backend {
+            MemoryBackend::Json => Box::new(JsonStore::new(path, Duration::ZERO, 1)),
+            MemoryBackend::Sqlite => Box::new(SqliteStore::new(path)),
+        };
+        if let Err(e) = store.load() {
+            return Check::new("memory", Status::Fail, e.to_string());
+        }
+    }
+    if let Err(e) = lie_core::builder::check_writable(path) {
+        return Check::new("memory", Status::Fail, e.to_string());
+    }
+    let state = if path.exists() { "readable and writable" } else { "will be created on the first write" };
+    Check::new("memory", Status::Pass, format!("{}: {}", path.display(), state))
+}
+
+fn server_port(config: &EngineConfig) -> Check {
+    let server = &config.server;
+    if server.listen == ListenMode::Unix {
+        return Check::new("port", Status::Pass, format!("listens on the unix socket {}", server.socket_path.display()));
+    }
+    match std::net::TcpListener::bind((server.host.as_str(), server.port)) {
+        Ok(_) => Check::new("port", Status::Pass, format!("{}:{} is free", server.host, server.port)),
+        Err(e) => Check::new("port", Status::Fail, format!("can't listen on {}:{}: {}", server.host, server.port, e)),
+    }
+}
+
+fn gpu(config: &EngineConfig) -> Check {
+    if config.model.runtime != "llamacpp" {
+        return Check::new("gpu", Status::Pass, format!("not used by the {} runtime", config.model.runtime));
+    }
+    let features = lie_runtime_llamacpp::build_features();
+    let devices = lie_runtime_llamacpp::gpu_devices();
+    let built = if features.is_empty() { "CPU-only build".to_string() } else { format!("built with {}", features.join(", ")) };
+    let gpu_layers = config.model.default_gpu_layers;
+    match (devices.is_empty(), gpu_layers > 0) {
+        (false, _) => Check::new("gpu", Status::Pass, format!("{}; {}", built, devices.join("; "))),
+        (true, true) => Check::new("gpu", Status::Warn, format!(
+            "{}, no GPU found; model.default_gpu_layers = {} will run on the CPU", built, gpu_layers
+        )),
+        (true, false) if !features.is_empty() => Check::new("gpu", Status::Warn, format!("{}, but no GPU found; check the drivers", built)),
+        (true, false) => Check::new("gpu", Status::Pass, built),
+    }
+}
+
+/// Load the model and generate a single token with it.
+pub async fn smoke(config: &EngineConfig, runtime: Result<Box<dyn ModelRuntime>, EngineError>) -> Check {
+    let started = Instant::now();
+    let result = async {
+        let engine = Engine::builder().config(config.clone()).runtime(runtime?).build()?;
+        engine.init().await?;
+        let options = InferenceOptions { max_tokens: Some(1), ..InferenceOptions::default() };
+        let response = engine.process_request("Hello", options, None).await?;
+        match response.error {
+            Some(error) => Err(EngineError::Runtime(error)),
+            None => Ok(()),
+        }
+    }.await;
+    match result {
+        Ok(()) => Check::new("inference", Status::Pass, format!("loaded the model and generated a token in {:.1} s", started.elapsed().as_secs_f64())),
+        Err(e) => Check::new("inference", Status::Fail, e.to_string()),
+    }
+}
+
+pub fn failed(checks: &[Check]) -> bool {
+    checks.iter().any(|check| check.status == Status::Fail)
+}
+
+pub fn print(checks: &[Check], json: bool) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(checks)?);
+        return Ok(());
+    }
+    let width = checks.iter().map(|check| check.name.len()).max().unwrap_or(0);
+    for check in checks {
+        let status = match check.status {
+            Status::Pass => "pass",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        println!("{}  {:width$}  {}", status, check.name, check.detail, width = width);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = EngineConfig::default();
+        config.memory.persistence_path = dir.path().join("memory.json");
+        let check = memory_file(&config);
+        assert_eq!(check.status, Status::Pass, "{}", check.detail);
+        assert!(check.detail.ends_with("will be created on the first write"), "{}", check.detail);
+
+        std::fs::write(&config.memory.persistence_path, r#"{"version": 99, "namespaces": {}}"#).unwrap();
+        let check = memory_file(&config);
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.detail.contains("version 99"), "{}", check.detail);
+    }
+
+    #[test]
+    fn test_server_port() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = EngineConfig::default();
+        config.server.host = "127.0.0.1".to_string();
+        config.server.port = taken.local_addr().unwrap().port();
+        assert_eq!(server_port(&config).status, Status::Fail);
+        drop(taken);
+        assert_eq!(server_port(&config).status, Status::Pass);
+    }
+}
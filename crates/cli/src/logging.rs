@@ -0,0 +1,104 @@
+//! Sets up `tracing` output as the `[logging]` config section says.
+
+use anyhow::Context;
+use lie_core::config::{LogFormat, LogRotation, LoggingConfig};
+use std::io::IsTerminal;
+use std::path::Path;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+/// Changes the levels of the subscriber `init_logging` installed.
+pub struct LogLevels(reload::Handle<EnvFilter, Registry>);
+
+impl LogLevels {
+    /// Switch to the levels of `config`, with `CELA_LOG` applied over them as before.
+    pub fn reload(&self, config: &LoggingConfig) -> anyhow::Result<()> {
+        let filter = filter(config, env_directives().as_deref())?;
+        self.0.reload(filter).map_err(|e| anyhow::anyhow!("Failed to change the log levels: {}", e))
+    }
+}
+
+fn env_directives() -> Option<String> {
+    std::env::var("CELA_LOG").or_else(|_| std::env::var("RUST_LOG")).ok()
+}
+
+/// Install the global subscriber for `config`. Directives in `CELA_LOG` (or `RUST_LOG`),
+/// such as `lie_core=debug,lie_server=warn`, are applied over the configured levels.
+pub fn init_logging(config: &LoggingConfig) -> anyhow::Result<LogLevels> {
+    let (filter, levels) = reload::Layer::new(filter(config, env_directives().as_deref())?);
+    let (writer, ansi) = match &config.file {
+        Some(path) => (BoxMakeWriter::new(file_appender(path, config.rotation)?), false),
+        None => (BoxMakeWriter::new(std::io::stderr), std::io::stderr().is_terminal()),
+    };
+
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi);
+    let layer = match config.format {
+        LogFormat::Full => layer.boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        // Lists the spans of each event, which gives it the request id of `inference`
+        LogFormat::Json => layer.json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .try_init()
+        .map_err(|e| anyhow::anyhow!("Failed to set up logging: {}", e))?;
+    Ok(LogLevels(levels))
+}
+
+/// `logging.level`, then `logging.modules`, then the directives in `env`; a later directive
+/// for the same module replaces an earlier one.
+fn filter(config: &LoggingConfig, env: Option<&str>) -> anyhow::Result<EnvFilter> {
+    let mut filter = EnvFilter::try_new(&config.level)
+        .with_context(|| format!("Invalid logging.level '{}'", config.level))?;
+    for (module, level) in &config.modules {
+        let directive = format!("{}={}", module, level);
+        filter = filter.add_directive(directive.parse().with_context(|| format!("Invalid logging.modules entry '{}'", directive))?);
+    }
+    for directive in env.unwrap_or("").split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        filter = filter.add_directive(directive.parse().with_context(|| format!("Invalid CELA_LOG directive '{}'", directive))?);
+    }
+    Ok(filter)
+}
+
+/// Writes to `path`, or with rotation to `path` with the date or hour appended.
+fn file_appender(path: &Path, rotation: LogRotation) -> anyhow::Result<RollingFileAppender> {
+    let name = path.file_name()
+        .with_context(|| format!("logging.file {} is not a file name", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let rotation = match rotation {
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+    };
+    RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(name.to_string_lossy())
+        .build(dir)
+        .with_context(|| format!("Failed to open log file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let mut config = LoggingConfig::default();
+        config.modules.insert("lie_server".to_string(), "warn".to_string());
+        let directives = filter(&config, Some("lie_core=debug, lie_server=trace")).unwrap().to_string();
+        assert!(directives.contains("lie_core=debug"), "{}", directives);
+        assert!(directives.contains("lie_server=trace") && !directives.contains("lie_server=warn"), "{}", directives);
+        assert!(directives.contains("info"), "{}", directives);
+
+        let err = filter(&config, Some("lie_core=loud")).unwrap_err().to_string();
+        assert_eq!(err, "Invalid CELA_LOG directive 'lie_core=loud'");
+    }
+}
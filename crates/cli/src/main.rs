@@ -1,37 +1,374 @@
-use clap::{Parser, Subcommand};
-use lie_core::{Engine, config::EngineConfig, runtime::InferenceOptions};
-use lie_runtime_llamacpp::LlamaCppRuntime;
+use anyhow::Context;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum, ValueHint};
+use indicatif::{ProgressBar, ProgressStyle};
+use lie_core::{Engine, EngineResponse, build_info::BuildInfo, chat::{ChatMessage, Role}, config::EngineConfig, error::EngineError, gguf, memory::DEFAULT_NAMESPACE, runtime::{registry::RuntimeFactory, InferenceOptions, LoadReport, ResponseFormat}};
+use lie_runtime_llamacpp::{check_model_file, LlamaCppRuntime};
+use lie_runtime_openai::OpenAiRuntime;
+use lie_runtime_candle::CandleRuntime;
 use lie_server::Server;
+use std::io::{IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::Arc;
 
+mod batch;
+mod bench;
+mod daemon;
+mod doctor;
+mod logging;
+mod pull;
+mod reload;
+mod repl;
+
 #[derive(Parser)]
 #[command(name = "lie")]
 #[command(about = "Local AI Engine CLI", long_about = None)]
 struct Cli {
+    /// Path to a TOML config file (default: ./cela.toml, then ~/.config/cela/config.toml)
+    #[arg(long, global = true, value_hint = ValueHint::FilePath)]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    model: ModelArgs,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+/// Model settings that override the config file and environment.
+#[derive(Args, Clone)]
+struct ModelArgs {
+    /// Backend to run the model with: llamacpp, candle, openai, or mock to try things out without a model (overrides model.runtime)
+    #[arg(long, global = true)]
+    runtime: Option<String>,
+
+    /// GGUF model to load, or model directory with the candle runtime (overrides model.default_path)
+    #[arg(long, global = true, value_hint = ValueHint::FilePath)]
+    model: Option<PathBuf>,
+
+    /// Context size in tokens (overrides model.default_context_size)
+    #[arg(long, global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    ctx_size: Option<u32>,
+
+    /// Layers to offload to the GPU (overrides model.default_gpu_layers)
+    #[arg(long, global = true)]
+    gpu_layers: Option<usize>,
+
+    /// CPU threads to run the model with (overrides model.n_threads)
+    #[arg(long, global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    threads: Option<u64>,
+}
+
+impl ModelArgs {
+    fn apply(&self, config: &mut EngineConfig) {
+        if let Some(runtime) = &self.runtime {
+            config.model.runtime = runtime.clone();
+        }
+        if let Some(path) = &self.model {
+            config.model.default_path = path.clone();
+        }
+        if let Some(ctx_size) = self.ctx_size {
+            config.model.default_context_size = ctx_size as usize;
+        }
+        if let Some(gpu_layers) = self.gpu_layers {
+            config.model.default_gpu_layers = gpu_layers;
+        }
+        if let Some(threads) = self.threads {
+            config.model.n_threads = Some(threads as usize);
+        }
+    }
+}
+
+/// What `lie run` prints to stdout.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Only `output.text`; errors and notes go to stderr
+    Text,
+    /// The response JSON, indented
+    Json,
+    /// The response JSON on one line
+    JsonCompact,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Start the engine in server mode
-    Serve,
+    Serve {
+        /// Run in the background, writing server.pid_file and logging to logging.file (unix only)
+        #[arg(long)]
+        daemon: bool,
+        /// Set on the background process `--daemon` starts
+        #[arg(long = "daemon-child", hide = true)]
+        daemon_child: bool,
+    },
+    /// Show whether the background server runs, and its /v1/health
+    Status,
+    /// Stop the background server and wait for it to shut down
+    Stop,
     /// Run a single inference (CLI mode)
     Run {
-        #[arg(short, long)]
-        prompt: String,
+        /// The prompt; `-` reads it from stdin, as does leaving it out when stdin is piped
+        #[arg(short, long, conflicts_with = "prompt_file")]
+        prompt: Option<String>,
+
+        /// Read the prompt from this file
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        prompt_file: Option<PathBuf>,
         
         #[arg(long)]
         max_tokens: Option<u32>,
+
+        /// Sampling temperature; 0 is greedy
+        #[arg(long)]
+        temperature: Option<f32>,
+
+        /// Sample only from the K most likely tokens
+        #[arg(long)]
+        top_k: Option<u32>,
+
+        /// Sample only from the most likely tokens covering this probability mass
+        #[arg(long)]
+        top_p: Option<f32>,
+
+        /// Only produce JSON matching the JSON Schema in this file
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        json_schema: Option<PathBuf>,
+
+        /// Seed for sampling; reuse the `seed` from a previous response to reproduce it
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// Truncated output always exits with status 2; accepted for compatibility
+        #[arg(long, hide = true)]
+        fail_on_truncation: bool,
         
         #[arg(long, default_value = "false")]
         enable_memory: bool,
+
+        /// What to print to stdout: the response JSON, the same on one line, or only the output text
+        #[arg(long, value_enum, default_value_t = OutputFormat::Json)]
+        output: OutputFormat,
+
+        /// Print only the result: no progress bar, notes or log messages below warnings
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Print a one-line usage summary to stderr after the result
+        #[arg(long)]
+        usage: bool,
+
+        /// Start from this prepared context (see `lie session create`)
+        #[arg(long)]
+        session: Option<String>,
+
+        /// System prompt to put before the prompt (overrides model.system_prompt)
+        #[arg(long)]
+        system: Option<String>,
+
+        /// Add the composed prompt and the options used to the response as `debug`
+        #[arg(long)]
+        debug: bool,
+
+        /// Compose the prompt but don't run it (implies --debug)
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Chat using the configured chat template: interactively, or a single turn with --prompt
+    Chat {
+        /// Send this one message and print the response JSON instead of starting a session
+        #[arg(short, long)]
+        prompt: Option<String>,
+
+        /// Optional system message
+        #[arg(long)]
+        system: Option<String>,
+
+        #[arg(long)]
+        max_tokens: Option<u32>,
+
+        /// Exit with status 2 if the output was cut short by a token or time limit
+        #[arg(long)]
+        fail_on_truncation: bool,
+
+        #[arg(long, default_value = "false")]
+        enable_memory: bool,
+    },
+    /// Run every request in a JSONL file ({"id", "prompt", "limits"} per line), writing one
+    /// response per line
+    Batch {
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        input: PathBuf,
+
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: PathBuf,
+
+        /// Requests in flight at once
+        #[arg(long, default_value = "1")]
+        concurrency: usize,
+
+        /// Skip ids already in the output file and append to it, to resume an interrupted run
+        #[arg(long)]
+        skip_existing: bool,
+
+        #[arg(long, default_value = "false")]
+        enable_memory: bool,
+    },
+    /// Measure prompt evaluation and generation speed on a synthetic prompt
+    Bench {
+        /// Prompt length in tokens
+        #[arg(long, default_value = "512", value_parser = clap::value_parser!(u64).range(1..))]
+        prompt_tokens: u64,
+
+        /// Tokens to generate per iteration
+        #[arg(long, default_value = "128", value_parser = clap::value_parser!(u32).range(1..))]
+        gen_tokens: u32,
+
+        #[arg(long, default_value = "5", value_parser = clap::value_parser!(u64).range(1..))]
+        iterations: u64,
+
+        /// Print the results as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the token ids, count and token texts of TEXT
+    Tokenize {
+        text: String,
+
+        /// Don't prepend the beginning-of-sequence token
+        #[arg(long)]
+        no_bos: bool,
     },
     /// Manage Memory
     Memory {
+        /// Memory namespace to work on
+        #[arg(long, global = true, default_value = DEFAULT_NAMESPACE)]
+        namespace: String,
         #[command(subcommand)]
         action: MemoryAction,
-    }
+    },
+    /// Inspect models
+    Models {
+        #[command(subcommand)]
+        action: ModelsAction,
+    },
+    /// Manage prepared contexts: texts evaluated once and saved, for `run --session` to start from
+    Session {
+        #[command(subcommand)]
+        action: SessionAction,
+    },
+    /// Print the shell completion script for SHELL
+    Completions {
+        shell: clap_complete::Shell,
+    },
+    /// Write, show or check the config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Check the config, model file, memory file, port and GPU, for when something doesn't work
+    Doctor {
+        /// Print the results as JSON
+        #[arg(long)]
+        json: bool,
+        /// Also load the model and generate one token
+        #[arg(long)]
+        smoke: bool,
+    },
+    /// Print the man page in roff format
+    Manpage,
+}
+
+/// The whole command tree, for generating completions and the man page.
+fn command() -> clap::Command {
+    Cli::command()
+}
+
+/// Write the completion script for `shell` to `out`.
+fn write_completions(shell: clap_complete::Shell, out: &mut dyn std::io::Write) {
+    let mut command = command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, out);
+}
+
+#[derive(Subcommand)]
+enum SessionAction {
+    /// Evaluate a text with the configured model and save the result as NAME
+    Create {
+        name: String,
+
+        /// The text; left out, it is read from --file or stdin
+        #[arg(long, conflicts_with = "file")]
+        text: Option<String>,
+
+        /// Read the text from this file
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        file: Option<PathBuf>,
+    },
+    /// List the prepared contexts
+    List,
+    /// Delete a prepared context
+    Delete {
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ModelsAction {
+    /// Load the configured model and print its metadata
+    Info,
+    /// List the GGUF files in model.models_dir, read from their headers
+    List,
+    /// Print the full metadata and tensor list of a GGUF file as JSON, without loading it
+    Inspect {
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    /// Check that a GGUF file is complete and undamaged
+    Verify {
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// Also check the file against this SHA-256 checksum (hex)
+        #[arg(long)]
+        sha256: Option<String>,
+    },    /// Download a GGUF file into model.models_dir, resuming an interrupted download
+    Pull {
+        /// An http(s) URL, or a Hugging Face <owner>/<repo>/<file> (uses HF_TOKEN if set)
+        source: String,
+        /// Check the download against this SHA-256 checksum (hex); by default the one at
+        /// <url>.sha256 is used if the server has it
+        #[arg(long)]
+        sha256: Option<String>,
+        /// File name to save as; the last part of the URL by default
+        #[arg(long)]
+        name: Option<String>,
+        /// Download again over an existing file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Write a commented config file holding the defaults (to --config, or else
+    /// ~/.config/cela/config.toml)
+    Init {
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: Option<PathBuf>,
+        /// Replace an existing file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the effective config: defaults, then the file, environment and flags, with API keys redacted
+    Show {
+        /// Print it as JSON instead of TOML
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check a config file (the one in use by default) and list every problem in it
+    Validate {
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: Option<PathBuf>,
+    },
 }
 
 #[derive(Subcommand)]
@@ -40,78 +377,593 @@ enum MemoryAction {
         key: String,
         value: String,
     },
+    /// Print a single fact
+    Get {
+        key: String,
+    },
+    /// List all facts and the summary
+    List,
+    /// Delete a fact
+    Delete {
+        key: String,
+    },
+    /// Remove all facts and the summary
+    Clear,
     Summary {
         text: String,
     }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+/// Load the model, drawing a progress bar on stderr (when it's a terminal) meanwhile.
+async fn load_model(engine: &Engine) -> anyhow::Result<LoadReport> {
+    let bar = if std::io::stderr().is_terminal() { ProgressBar::new(100) } else { ProgressBar::hidden() };
+    bar.set_style(ProgressStyle::with_template("{spinner} Loading model [{bar:30}] {pos}% ({elapsed})")?.progress_chars("=> "));
+    bar.enable_steady_tick(std::time::Duration::from_millis(100));
+
+    let progress = bar.clone();
+    let result = engine.init_with_progress(Arc::new(move |fraction| progress.set_position((fraction * 100.0) as u64))).await;
+    bar.finish_and_clear();
+    Ok(result?)
+}
+
+/// Stop the running generation on Ctrl-C; the partial output is still printed.
+/// This build, with the features and backend of the runtime named `runtime`.
+fn build_info(runtime: &str) -> BuildInfo {
+    let mut info = BuildInfo::current();
+    info.features = match runtime {
+        "candle" => lie_runtime_candle::build_features(),
+        _ => lie_runtime_llamacpp::build_features(),
+    };
+    info.backend = Some(match runtime {
+        "llamacpp" => lie_runtime_llamacpp::backend_description(),
+        "candle" => lie_runtime_candle::backend_description(),
+        other => other.to_string(),
+    });
+    info
+}
+
+fn cancel_on_ctrl_c(engine: &Arc<Engine>) {
+    let engine = engine.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            engine.cancel_all();
+        }
+    });
+}
+
+/// Exit status for a finished request: 1 for an error response (failures that produce no
+/// response at all also exit with 1), 2 for truncated output when `fail_on_truncation`
+/// is set, otherwise 0. `lie run` always sets it.
+fn exit_code(response: &EngineResponse, fail_on_truncation: bool) -> u8 {
+    match response.status.as_str() {
+        "error" => 1,
+        "truncated" if fail_on_truncation => 2,
+        _ => 0,
+    }
+}
+
+/// Print `run`'s response to stdout as `output` says. With `OutputFormat::Text`, an error
+/// goes to stderr instead, as does a note when the output was cut short unless `quiet`; a
+/// dry run prints the prompt it composed.
+fn print_response(response: &EngineResponse, output: OutputFormat, quiet: bool) -> anyhow::Result<()> {
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(response)?),
+        OutputFormat::JsonCompact => println!("{}", serde_json::to_string(response)?),
+        OutputFormat::Text => match response.status.as_str() {
+            "error" => eprintln!("Error: {}", response.error.as_deref().unwrap_or("unknown error")),
+            "dry_run" => println!("{}", response.debug.as_ref().map_or("", |debug| debug.prompt.as_str())),
+            status => {
+                println!("{}", response.output.text);
+                if status != "success" && !quiet {
+                    eprintln!("Note: the output was cut short ({})", status);
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Resolve `run`'s prompt from `--prompt`, `--prompt-file` or stdin. Text read from a
+/// file or stdin is used as is, apart from one trailing newline.
+fn read_prompt(prompt: Option<String>, prompt_file: Option<&Path>) -> anyhow::Result<String> {
+    let prompt = match (prompt, prompt_file) {
+        (Some(prompt), _) if prompt != "-" => prompt,
+        (Some(_), _) => read_stdin()?,
+        (None, Some(path)) => {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read prompt file {}", path.display()))?;
+            strip_trailing_newline(text)
+        }
+        (None, None) if !std::io::stdin().is_terminal() => read_stdin()?,
+        (None, None) => anyhow::bail!("No prompt given; use --prompt, --prompt-file or pipe it on stdin"),
+    };
+
+    // Same check and message as the server's
+    if prompt.trim().is_empty() {
+        anyhow::bail!("Validation Error: Prompt cannot be empty");
+    }
+    Ok(prompt)
+}
+
+/// Hex SHA-256 of the file at `path`.
+fn sha256_file(path: &Path) -> anyhow::Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn read_stdin() -> anyhow::Result<String> {
+    let mut text = String::new();
+    std::io::stdin().read_to_string(&mut text).context("Failed to read the prompt from stdin")?;
+    Ok(strip_trailing_newline(text))
+}
+
+fn strip_trailing_newline(mut text: String) -> String {
+    if text.ends_with('\n') {
+        text.pop();
+        if text.ends_with('\r') {
+            text.pop();
+        }
+    }
+    text
+}
 
+#[tokio::main]
+async fn main() -> anyhow::Result<ExitCode> {
     let cli = Cli::parse();
+
+    // These describe the CLI itself, so they work whatever the config says
+    match cli.command {
+        Some(Commands::Completions { shell }) => {
+            write_completions(shell, &mut std::io::stdout());
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Commands::Manpage) => {
+            clap_mangen::Man::new(command()).render(&mut std::io::stdout())?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        // These work on the file, so they don't need it to be valid
+        Some(Commands::Config { action: ConfigAction::Init { path, force } }) => {
+            let path = path.or(cli.config)
+                .or_else(EngineConfig::user_path)
+                .context("No HOME to put the config in; give a path")?;
+            if path.exists() && !force {
+                anyhow::bail!("{} already exists; pass --force to replace it", path.display());
+            }
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+            }
+            std::fs::write(&path, lie_core::config::TEMPLATE).with_context(|| format!("Failed to write {}", path.display()))?;
+            println!("Wrote {}", path.display());
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Commands::Config { action: ConfigAction::Validate { path } }) => {
+            let path = EngineConfig::file_path(path.or(cli.config).as_deref())
+                .context("No config file found (./cela.toml or ~/.config/cela/config.toml); give a path")?;
+            let problems = EngineConfig::check_file(&path)?;
+            if problems.is_empty() {
+                println!("{}: OK", path.display());
+                return Ok(ExitCode::SUCCESS);
+            }
+            eprintln!("{}: {} problem{}", path.display(), problems.len(), if problems.len() == 1 { "" } else { "s" });
+            for problem in problems {
+                eprintln!("  {}", problem.replace('\n', "\n    "));
+            }
+            return Ok(ExitCode::FAILURE);
+        }
+        _ => {}
+    }
     
-    // In a real app, load config from file
-    let mut config = EngineConfig::default();
-    
-    // Config Loading Mock-up (allow enabling memory via CLI args logic sort of)
-    // Actually, for "Run" command, we can override config.
-    // For "Memory" command, we assume default config (memory.json in cwd).
-    // Ideally we load a config file. For v1, we just enable memory if requested.
+    // `doctor` reports a broken config instead of stopping at it
+    let resolved = EngineConfig::resolve(cli.config.as_deref());
+    let config_check = matches!(cli.command, Some(Commands::Doctor { .. }))
+        .then(|| doctor::config(cli.config.as_deref(), resolved.as_ref()));
+    let mut config = match resolved {
+        Err(_) if config_check.is_some() => EngineConfig::default(),
+        resolved => resolved?,
+    };
+    cli.model.apply(&mut config);
+    if matches!(cli.command, Some(Commands::Run { quiet: true, .. })) {
+        config.logging.level = "warn".to_string();
+    }
+    let log_levels = logging::init_logging(&config.logging)?;
+
+    let mut runtimes = RuntimeFactory::new(&config);
+    runtimes.register("llamacpp", || {
+        let runtime = LlamaCppRuntime::new().map_err(|e| match e {
+            EngineError::Runtime(message) => EngineError::Runtime(format!(
+                "{}. Check that the GPU drivers this build was compiled for are installed, or use a CPU-only build", message
+            )),
+            e => e,
+        })?;
+        Ok(Box::new(runtime))
+    });
+    let openai = config.openai.clone();
+    runtimes.register("openai", move || Ok(Box::new(OpenAiRuntime::new(openai.clone()))));
+    runtimes.register("candle", || Ok(Box::new(CandleRuntime::new())));
 
-    // Initialize Runtime
-    let runtime = LlamaCppRuntime::new();
+    // Catch a wrong model path before any command starts loading it
+    let loads_model = !matches!(
+        cli.command,
+        Some(Commands::Memory { .. }
+            | Commands::Models { action: ModelsAction::List | ModelsAction::Inspect { .. } | ModelsAction::Verify { .. } | ModelsAction::Pull { .. } }
+            | Commands::Session { action: SessionAction::List | SessionAction::Delete { .. } }
+            | Commands::Config { .. }
+            | Commands::Doctor { .. }
+            | Commands::Status
+            | Commands::Stop)
+            | None
+    );
+    if loads_model && config.model.runtime == "llamacpp" {
+        check_model_file(&config.model.default_path).map_err(|e| anyhow::anyhow!(
+            "{}. Point at a GGUF model with --model <path>, model.default_path in the config file or CELA_MODEL_PATH", e
+        ))?;
+    }
+    if loads_model && config.model.runtime == "candle" {
+        lie_runtime_candle::check_model_dir(&config.model.default_path).map_err(|e| anyhow::anyhow!(
+            "{}. Point at a model directory with --model <path>, model.default_path in the config file or CELA_MODEL_PATH", e
+        ))?;
+    }
+
+    // The runtime is created on use, so commands that don't need a model don't set up llama.cpp
+    let runtime_name = config.model.runtime.clone();
+    let runtime = || runtimes.create(&runtime_name);
     
     match cli.command {
-        Some(Commands::Serve) => {
+        Some(Commands::Serve { daemon: true, .. }) => daemon::start(&config)?,
+        Some(Commands::Serve { daemon_child, .. }) => {
             config.memory.enabled = true; // Enable memory for server by default or config?
             // Let's enable it if file exists? Or just true.
             config.memory.enabled = true;
             
-            let engine = Engine::new(config, Box::new(runtime));
+            let server_config = config.server.clone();
+            let engine = Engine::builder().config(config).runtime(runtime()?).build()?;
             let engine_arc = Arc::new(engine);
-            engine_arc.init().await?;
-            
-            let server = Server::new(engine_arc);
-            server.run().await?;
+
+            // Serve while the model loads, so /v1/health can report progress
+            let mut server = Server::new(engine_arc.clone(), server_config.clone()).with_build_info(build_info(&runtime_name));
+            server.bind().await?;
+            let model_args = cli.model.clone();
+            let overrides = move |config: &mut EngineConfig| {
+                model_args.apply(config);
+                config.memory.enabled = true;
+            };
+            reload::Reloader::new(cli.config.clone(), overrides, engine_arc.clone(), server.limits(), log_levels).on_sighup()?;
+            let _pid_file = match daemon_child {
+                true => Some(daemon::PidFile::create(&server_config.pid_file)?),
+                false => None,
+            };
+            let serving = server.run();
+            tokio::pin!(serving);
+            tokio::select! {
+                result = &mut serving => result?,
+                loaded = load_model(&engine_arc) => {
+                    loaded?;
+                    engine_arc.start_idle_unload();
+                    serving.await?;
+                }
+            }
         }
-        Some(Commands::Run { prompt, max_tokens, enable_memory }) => {
+        Some(Commands::Run { prompt, prompt_file, max_tokens, temperature, top_k, top_p, json_schema, seed, enable_memory, output, quiet, usage, session, system, debug, dry_run, .. }) => {
+            let prompt = read_prompt(prompt, prompt_file.as_deref())?;
             config.memory.enabled = enable_memory;
             
-            let engine = Engine::new(config, Box::new(runtime));
+            let engine = Engine::builder().config(config).runtime(runtime()?).build()?;
             let engine_arc = Arc::new(engine);
-            engine_arc.init().await?;
+            if quiet {
+                engine_arc.init().await?;
+            } else {
+                load_model(&engine_arc).await?;
+            }
+            cancel_on_ctrl_c(&engine_arc);
             
             let mut options = InferenceOptions::default();
             if let Some(mt) = max_tokens {
                 options.max_tokens = Some(mt);
             }
+            if temperature.is_some() {
+                options.temperature = temperature;
+            }
+            options.top_k = top_k;
+            options.top_p = top_p;
+            options.seed = seed;
+            options.session = session;
+            options.system = system;
+            options.debug = debug || dry_run;
+            options.dry_run = dry_run;
+            if let Some(path) = json_schema {
+                let schema = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read JSON schema {}", path.display()))?;
+                options.response_format = Some(ResponseFormat::Json { schema: Some(schema) });
+            }
 
-            let response = engine_arc.process_request(&prompt, options).await?;
-            
-            // Output valid JSON to stdout
-            let json_output = serde_json::to_string_pretty(&response)?;
-            println!("{}", json_output);
+            let response = engine_arc.process_request(&prompt, options, None).await?;
+            print_response(&response, output, quiet)?;
+            // Kept off stdout, which holds only the result
+            if usage && response.status != "error" && !dry_run {
+                eprintln!("{}", response.usage.summary());
+            }
+            return Ok(ExitCode::from(exit_code(&response, true)));
         }
-        Some(Commands::Memory { action }) => {
-            config.memory.enabled = true; // Must be enabled to write
-            let engine = Engine::new(config, Box::new(runtime));
-            
+        Some(Commands::Chat { prompt: None, system, max_tokens, enable_memory, .. }) => {
+            // Interactive sessions follow the config's memory setting unless asked explicitly
+            config.memory.enabled |= enable_memory;
+
+            let engine = Arc::new(Engine::builder().config(config).runtime(runtime()?).build()?);
+            load_model(&engine).await?;
+            repl::run(engine, system, max_tokens).await?;
+        }
+        Some(Commands::Chat { prompt: Some(prompt), system, max_tokens, fail_on_truncation, enable_memory }) => {
+            config.memory.enabled = enable_memory;
+
+            let engine = Arc::new(Engine::builder().config(config).runtime(runtime()?).build()?);
+            load_model(&engine).await?;
+            cancel_on_ctrl_c(&engine);
+
+            let mut messages = Vec::new();
+            if let Some(system) = system {
+                messages.push(ChatMessage::new(Role::System, system));
+            }
+            messages.push(ChatMessage::new(Role::User, prompt));
+
+            let options = InferenceOptions { max_tokens, ..InferenceOptions::default() };
+
+            let response = engine.process_chat(&messages, options, None).await?;
+            println!("{}", serde_json::to_string_pretty(&response)?);
+            return Ok(ExitCode::from(exit_code(&response, fail_on_truncation)));
+        }
+        Some(Commands::Batch { input, output, concurrency, skip_existing, enable_memory }) => {
+            config.memory.enabled = enable_memory;
+            // Requests beyond the engine's queue would be turned away as busy
+            config.queue.max_queue_depth = config.queue.max_queue_depth.max(concurrency);
+
+            let engine = Arc::new(Engine::builder().config(config).runtime(runtime()?).build()?);
+            load_model(&engine).await?;
+            cancel_on_ctrl_c(&engine);
+
+            let started = std::time::Instant::now();
+            let options = batch::BatchOptions { concurrency, skip_existing };
+            let summary = batch::run(engine, &input, &output, &options).await?;
+            println!("{} in {:.1}s", summary, started.elapsed().as_secs_f64());
+            if summary.failed > 0 {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Some(Commands::Bench { prompt_tokens, gen_tokens, iterations, json }) => {
+            // Memory would add to the prompt being measured
+            config.memory.enabled = false;
+
+            let engine = Engine::builder().config(config).runtime(runtime()?).build()?;
+            load_model(&engine).await?;
+            let options = bench::BenchOptions { prompt_tokens: prompt_tokens as usize, gen_tokens, iterations: iterations as usize };
+            let report = bench::run(&engine, &options).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("{}", report);
+            }
+        }
+        Some(Commands::Tokenize { text, no_bos }) => {
+            let engine = Engine::builder().config(config).runtime(runtime()?).build()?;
+            load_model(&engine).await?;
+            let (tokens, pieces) = engine.tokenize(&text, !no_bos).await?;
+            println!("{}", serde_json::to_string_pretty(&serde_json::json!({
+                "tokens": tokens,
+                "count": tokens.len(),
+                "pieces": pieces,
+            }))?);
+        }
+        Some(Commands::Memory { namespace, action }) => {
+            // With memory off nothing is loaded or saved, so every action would be misleading
+            if !config.memory.enabled {
+                return Err(EngineError::MemoryDisabled.into());
+            }
+            // Save the change before reporting it done
+            config.memory.flush_delay_ms = 0;
+            let engine = Engine::builder().config(config).runtime(runtime()?).build()?;
+
             match action {
                 MemoryAction::Set { key, value } => {
-                    engine.memory.set_fact(&key, &value).await?;
+                    engine.memory.set_fact_ns(&namespace, &key, &value).await?;
                     println!("Fact set: {} = {}", key, value);
                 }
+                MemoryAction::Get { key } => {
+                    match engine.memory.get_fact_ns(&namespace, &key).await {
+                        Some(value) => println!("{}", value),
+                        None => anyhow::bail!("No fact named '{}'", key),
+                    }
+                }
+                MemoryAction::List => {
+                    for (key, value) in engine.memory.list_facts_ns(&namespace).await {
+                        println!("{} = {}", key, value);
+                    }
+                    let summary = engine.memory.get_summary_ns(&namespace).await;
+                    if !summary.is_empty() {
+                        println!("Summary: {}", summary);
+                    }
+                }
+                MemoryAction::Delete { key } => {
+                    if !engine.memory.delete_fact_ns(&namespace, &key).await? {
+                        anyhow::bail!("No fact named '{}'", key);
+                    }
+                    println!("Fact deleted: {}", key);
+                }
+                MemoryAction::Clear => {
+                    engine.memory.clear_ns(&namespace).await?;
+                    println!("Memory cleared.");
+                }
                 MemoryAction::Summary { text } => {
-                    engine.memory.update_summary(&text).await?;
+                    engine.memory.update_summary_ns(&namespace, &text).await?;
                     println!("Summary updated.");
                 }
             }
         }
+        Some(Commands::Models { action }) => {
+            match action {
+                ModelsAction::Info => {
+                    let engine = Engine::builder().config(config).runtime(runtime()?).build()?;
+                    load_model(&engine).await?;
+                    let info = engine.model_info().await
+                        .ok_or_else(|| anyhow::anyhow!("Runtime did not report model info"))?;
+                    println!("{}", serde_json::to_string_pretty(&info)?);
+                }
+                ModelsAction::List => {
+                    let dir = &config.model.models_dir;
+                    let paths = gguf::find_models(dir)?;
+                    if paths.is_empty() {
+                        eprintln!("No .gguf files in {} (model.models_dir)", dir.display());
+                    }
+                    for path in paths {
+                        match gguf::ModelFile::read(&path) {
+                            Ok(model) => println!(
+                                "{}\t{:.2} GB\t{}\t{:.1}B params\t{}",
+                                model.name, model.size as f64 / 1e9, model.quantization,
+                                model.parameter_count as f64 / 1e9, path.display()
+                            ),
+                            Err(e) => eprintln!("{}", e),
+                        }
+                    }
+                }
+                ModelsAction::Inspect { path } => {
+                    let file = gguf::GgufFile::read(&path)?;
+                    let inspection = serde_json::json!({
+                        "version": file.version,
+                        "size": file.file_size,
+                        "parameter_count": file.parameter_count(),
+                        "quantization": file.quantization(),
+                        "metadata": file.metadata,
+                        "tensors": file.tensors,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&inspection)?);
+                }
+                ModelsAction::Verify { path, sha256 } => {
+                    let file = gguf::GgufFile::read(&path)?;
+                    let problems = file.problems();
+                    if !problems.is_empty() {
+                        anyhow::bail!("{} is damaged:\n  {}", path.display(), problems.join("\n  "));
+                    }
+                    if let Some(expected) = sha256 {
+                        let actual = sha256_file(&path)?;
+                        if !actual.eq_ignore_ascii_case(expected.trim()) {
+                            anyhow::bail!("{}: SHA-256 is {}, expected {}", path.display(), actual, expected.trim());
+                        }
+                    }
+                    println!("{}: OK (GGUF v{}, {} tensors)", path.display(), file.version, file.tensors.len());
+                }
+                ModelsAction::Pull { source, sha256, name, force } => {
+                    let path = pull::pull(&source, &config.model.models_dir, name, sha256, force).await?;
+                    println!("Saved {}", path.display());
+                }
+            }
+        }
+        Some(Commands::Session { action }) => {
+            match action {
+                SessionAction::Create { name, text, file } => {
+                    let text = match (text, file) {
+                        (Some(text), _) => text,
+                        (None, Some(path)) => std::fs::read_to_string(&path)
+                            .with_context(|| format!("Failed to read {}", path.display()))?,
+                        (None, None) if !std::io::stdin().is_terminal() => read_stdin()?,
+                        (None, None) => anyhow::bail!("No text given; use --text, --file or pipe it on stdin"),
+                    };
+                    let engine = Engine::builder().config(config).runtime(runtime()?).build()?;
+                    load_model(&engine).await?;
+                    let context = engine.prepare_context(&name, &text).await?;
+                    println!("Session '{}' created: {} tokens, {:.1} MB", context.name, context.tokens, context.size as f64 / 1e6);
+                }
+                SessionAction::List => {
+                    let contexts = lie_core::prepared::PreparedContexts::new(&config.model.session_dir);
+                    for context in contexts.list()? {
+                        println!("{}\t{} tokens\t{:.1} MB\t{}", context.name, context.tokens, context.size as f64 / 1e6, context.model.display());
+                    }
+                }
+                SessionAction::Delete { name } => {
+                    let contexts = lie_core::prepared::PreparedContexts::new(&config.model.session_dir);
+                    if !contexts.delete(&name)? {
+                        anyhow::bail!("No session named '{}'", name);
+                    }
+                    println!("Session deleted: {}", name);
+                }
+            }
+        }
+        Some(Commands::Config { action: ConfigAction::Show { json } }) => {
+            let config = config.redacted();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&config)?);
+            } else {
+                if let Some(path) = EngineConfig::file_path(cli.config.as_deref()) {
+                    println!("# {} with the environment and flags applied", path.display());
+                }
+                print!("{}", toml::to_string_pretty(&config)?);
+            }
+        }
+        Some(Commands::Status) => {
+            if !daemon::status(&config).await? {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Some(Commands::Stop) => daemon::stop(&config).await?,
+        Some(Commands::Doctor { json, smoke }) => {
+            let mut checks: Vec<_> = config_check.into_iter().collect();
+            checks.extend(doctor::run(&config));
+            if smoke {
+                checks.push(doctor::smoke(&config, runtime()).await);
+            }
+            doctor::print(&checks, json)?;
+            if doctor::failed(&checks) {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Some(Commands::Completions { .. } | Commands::Manpage | Commands::Config { .. }) => unreachable!("handled before reading the config"),
         None => {
             println!("No command provided. Use --help");
         }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lie_core::error::ErrorCode;
+
+    #[test]
+    fn test_bash_completions() {
+        let mut script = Vec::new();
+        write_completions(clap_complete::Shell::Bash, &mut script);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("_lie()"), "{}", script);
+        for subcommand in ["serve", "run", "memory", "session"] {
+            assert!(script.contains(&format!("lie,{})", subcommand)), "{}", subcommand);
+        }
+    }
+
+    #[test]
+    fn test_command_tree_is_valid() {
+        command().debug_assert();
+    }
+
+    fn response(status: &str) -> EngineResponse {
+        EngineResponse { status: status.to_string(), ..EngineResponse::error(ErrorCode::RuntimeError, "") }
+    }
+
+    #[test]
+    fn test_strip_trailing_newline() {
+        assert_eq!(strip_trailing_newline("line one\n  line two  \n".to_string()), "line one\n  line two  ");
+        assert_eq!(strip_trailing_newline("windows\r\n".to_string()), "windows");
+        assert_eq!(strip_trailing_newline("two blank lines\n\n".to_string()), "two blank lines\n");
+        assert_eq!(strip_trailing_newline("  no newline ".to_string()), "  no newline ");
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&response("success"), true), 0);
+        assert_eq!(exit_code(&response("cancelled"), true), 0);
+        assert_eq!(exit_code(&response("error"), false), 1);
+        assert_eq!(exit_code(&response("truncated"), false), 0);
+        assert_eq!(exit_code(&response("truncated"), true), 2);
+    }
 }
@@ -0,0 +1,193 @@
+//! `lie models pull`: download a GGUF file into `model.models_dir`.
+//!
+//! The download goes to `<file>.part` and is resumed from there with a range request if it
+//! was interrupted. Only once the GGUF header and the checksum have been checked is it
+//! renamed into place, so `lie models list` never sees a partial or corrupt file. A sidecar
+//! `<file>.json` records where the file came from.
+
+use anyhow::Context;
+use indicatif::{ProgressBar, ProgressStyle};
+use lie_core::gguf::GgufFile;
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH, RANGE};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+/// Written next to a pulled model as `<file>.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PullRecord {
+    /// What was passed to `lie models pull`.
+    pub source: String,
+    pub url: String,
+    pub sha256: String,
+    pub size: u64,
+    /// Unix time of the download.
+    pub downloaded_at: u64,
+}
+
+/// The URL to download `source` from: an `http(s)://` URL as it is, or a Hugging Face
+/// `owner/repo/path/to/file.gguf` from the main branch.
+pub fn resolve_source(source: &str) -> anyhow::Result<String> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        return Ok(source.to_string());
+    }
+    let parts: Vec<&str> = source.splitn(3, '/').collect();
+    match parts[..] {
+        [owner, repo, file] if !owner.is_empty() && !repo.is_empty() && !file.is_empty() => {
+            Ok(format!("https://huggingface.co/{}/{}/resolve/main/{}", owner, repo, file))
+        }
+        _ => anyhow::bail!("'{}' is neither a URL nor a Hugging Face <owner>/<repo>/<file>", source),
+    }
+}
+
+/// The file name at the end of `url`, without any query.
+fn file_name(url: &str) -> anyhow::Result<String> {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    match path.rsplit('/').next() {
+        Some(name) if !name.is_empty() && name != ".." && name != "." => Ok(name.to_string()),
+        _ => anyhow::bail!("Can't tell the file name from {}; pass --name", url),
+    }
+}
+
+fn is_hugging_face(url: &str) -> bool {
+    let host = url.split("://").nth(1).and_then(|rest| rest.split(['/', ':']).next()).unwrap_or("");
+    host == "huggingface.co" || host == "hf.co" || host.ends_with(".huggingface.co")
+}
+
+/// Download `source` into `dir` and return where it was put. Checks against `sha256` if
+/// given, otherwise against `<url>.sha256` if the server has one.
+pub async fn pull(source: &str, dir: &Path, name: Option<String>, sha256: Option<String>, force: bool) -> anyhow::Result<PathBuf> {
+    let url = resolve_source(source)?;
+    let name = match name {
+        Some(name) => name,
+        None => file_name(&url)?,
+    };
+    let path = dir.join(&name);
+    if path.exists() && !force {
+        anyhow::bail!("{} already exists; pass --force to download it again", path.display());
+    }
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    // Hugging Face needs a token for gated and private repos. reqwest drops it on redirects
+    // to other hosts, so it doesn't reach the CDN the files are served from.
+    let token = std::env::var("HF_TOKEN").ok().filter(|token| !token.is_empty() && is_hugging_face(&url));
+    let client = reqwest::Client::new();
+    let get = |url: &str| {
+        let request = client.get(url);
+        match &token {
+            Some(token) => request.header(AUTHORIZATION, format!("Bearer {}", token)),
+            None => request,
+        }
+    };
+
+    let expected = match sha256 {
+        Some(sha256) => Some(sha256.trim().to_ascii_lowercase()),
+        None => published_checksum(get(&format!("{}.sha256", url))).await,
+    };
+
+    let part = dir.join(format!("{}.part", name));
+    download(get(&url), &part).await?;
+
+    let problem = match GgufFile::read(&part) {
+        Ok(file) => file.problems().into_iter().next(),
+        Err(e) => Some(e.to_string()),
+    };
+    if let Some(problem) = problem {
+        // Keep it: a truncated file resumes on the next pull
+        anyhow::bail!("The download is not a usable GGUF file ({}); it was kept at {}", problem, part.display());
+    }
+    let actual = crate::sha256_file(&part)?;
+    match &expected {
+        Some(expected) if *expected != actual => {
+            let _ = std::fs::remove_file(&part);
+            anyhow::bail!("SHA-256 of {} is {}, expected {}; the download was deleted", url, actual, expected);
+        }
+        Some(_) => {}
+        None => eprintln!("No checksum given or published for {}; only the GGUF header was checked", url),
+    }
+
+    std::fs::rename(&part, &path).with_context(|| format!("Failed to move the download to {}", path.display()))?;
+    let record = PullRecord {
+        source: source.to_string(),
+        url,
+        sha256: actual,
+        size: std::fs::metadata(&path)?.len(),
+        downloaded_at: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs(),
+    };
+    let sidecar = dir.join(format!("{}.json", name));
+    std::fs::write(&sidecar, serde_json::to_string_pretty(&record)?)
+        .with_context(|| format!("Failed to write {}", sidecar.display()))?;
+    Ok(path)
+}
+
+/// The checksum at a `.sha256` URL, in `sha256sum` format or on its own.
+async fn published_checksum(request: reqwest::RequestBuilder) -> Option<String> {
+    let response = request.send().await.ok()?.error_for_status().ok()?;
+    let text = response.text().await.ok()?;
+    let checksum = text.split_whitespace().next()?.to_ascii_lowercase();
+    (checksum.len() == 64 && checksum.bytes().all(|b| b.is_ascii_hexdigit())).then_some(checksum)
+}
+
+/// Download to `part`, continuing from its end if it exists.
+async fn download(request: reqwest::RequestBuilder, part: &Path) -> anyhow::Result<()> {
+    let existing = std::fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+    let request = if existing > 0 { request.header(RANGE, format!("bytes={}-", existing)) } else { request };
+    let mut response = request.send().await.context("Failed to connect")?;
+
+    let resumed = match response.status() {
+        StatusCode::PARTIAL_CONTENT => true,
+        // The part already holds the whole file
+        StatusCode::RANGE_NOT_SATISFIABLE if existing > 0 => return Ok(()),
+        status if status.is_success() => false,
+        status => anyhow::bail!("Failed to download {}: HTTP {}", response.url(), status),
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(part)
+        .with_context(|| format!("Failed to open {}", part.display()))?;
+    let start = if resumed { existing } else { 0 };
+
+    let total = response.headers().get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+        .map(|len| start + len);
+    let bar = match total {
+        _ if !std::io::stderr().is_terminal() => ProgressBar::hidden(),
+        Some(total) => ProgressBar::new(total),
+        None => ProgressBar::no_length(),
+    };
+    bar.set_style(ProgressStyle::with_template("{spinner} Downloading [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} ({eta})")?.progress_chars("=> "));
+    bar.set_position(start);
+    if resumed {
+        bar.println(format!("Resuming from {:.1} MB", start as f64 / 1e6));
+    }
+
+    while let Some(chunk) = response.chunk().await.context("The download was interrupted; run the command again to resume")? {
+        file.write_all(&chunk).with_context(|| format!("Failed to write {}", part.display()))?;
+        bar.inc(chunk.len() as u64);
+    }
+    file.flush()?;
+    bar.finish_and_clear();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_source() {
+        assert_eq!(resolve_source("https://example.com/m.gguf").unwrap(), "https://example.com/m.gguf");
+        assert_eq!(
+            resolve_source("TheBloke/TinyLlama-GGUF/tinyllama.Q4_K_M.gguf").unwrap(),
+            "https://huggingface.co/TheBloke/TinyLlama-GGUF/resolve/main/tinyllama.Q4_K_M.gguf"
+        );
+        assert!(resolve_source("tinyllama.gguf").is_err());
+        assert_eq!(file_name("https://huggingface.co/a/b/resolve/main/dir/m.gguf?download=true").unwrap(), "m.gguf");
+        assert!(file_name("https://example.com/").is_err());
+        assert!(is_hugging_face("https://huggingface.co/a/b") && !is_hugging_face("https://example.com/huggingface.co"));
+    }
+}
@@ -0,0 +1,80 @@
+//! `lie serve` reads its config again on SIGHUP and takes up the settings that can change
+//! while it runs (`config::RELOADABLE`): log levels, memory limits, queue depth, request
+//! limits and the idle unload timeout. Changes to anything else are logged as needing a
+//! restart, and a config that doesn't load or validate is ignored.
+
+use crate::logging::LogLevels;
+use lie_core::config::EngineConfig;
+use lie_core::{ConfigReload, Engine};
+use lie_server::SharedLimits;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub struct Reloader {
+    /// `--config`, if given; otherwise the default locations are searched again.
+    path: Option<PathBuf>,
+    /// Applies the command line flags and what `serve` sets itself on top of the file.
+    overrides: Box<dyn Fn(&mut EngineConfig) + Send + Sync>,
+    engine: Arc<Engine>,
+    limits: SharedLimits,
+    log_levels: LogLevels,
+}
+
+impl Reloader {
+    pub fn new(
+        path: Option<PathBuf>,
+        overrides: impl Fn(&mut EngineConfig) + Send + Sync + 'static,
+        engine: Arc<Engine>,
+        limits: SharedLimits,
+        log_levels: LogLevels,
+    ) -> Self {
+        Self { path, overrides: Box::new(overrides), engine, limits, log_levels }
+    }
+
+    /// Read the config and apply what changed.
+    pub fn reload(&self) -> anyhow::Result<ConfigReload> {
+        let mut config = EngineConfig::resolve(self.path.as_deref())?;
+        (self.overrides)(&mut config);
+        config.validate()?;
+
+        let reload = self.engine.reload_config(&config);
+        let running = self.engine.config();
+        self.limits.reload(&running.server);
+        if reload.applied.iter().any(|key| key.starts_with("logging.")) {
+            self.log_levels.reload(&running.logging)?;
+        }
+
+        if reload.applied.is_empty() {
+            tracing::info!("Config reloaded; nothing to change");
+        } else {
+            tracing::info!("Config reloaded; changed {}", reload.applied.join(", "));
+        }
+        if !reload.needs_restart.is_empty() {
+            tracing::warn!("Changes to {} need a restart to take effect", reload.needs_restart.join(", "));
+        }
+        Ok(reload)
+    }
+
+    /// Reload on every SIGHUP from now on.
+    #[cfg(unix)]
+    pub fn on_sighup(self) -> anyhow::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())
+            .map_err(|e| anyhow::anyhow!("Failed to listen for SIGHUP: {}", e))?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                if let Err(e) = self.reload() {
+                    tracing::error!("Failed to reload the config, keeping the running one: {:#}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// There's no SIGHUP to reload on.
+    #[cfg(not(unix))]
+    pub fn on_sighup(self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+//! Interactive chat in the terminal (`lie chat` without `--prompt`).
+
+use lie_core::{Engine, chat::{ChatMessage, Role}, runtime::{InferenceOptions, InferenceStatus, TokenChunk}};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::io::Write;
+use std::sync::Arc;
+
+const HELP: &str = "Commands:
+  /reset          Clear the conversation history
+  /system <text>  Set the system message (no text clears it)
+  /temp <n>       Set the temperature
+  /limit <n>      Set max tokens per reply
+  /help           Show this help
+  /exit           Quit (or Ctrl-D)
+Ctrl-C stops the reply being generated.";
+
+#[derive(Debug, PartialEq)]
+enum Input {
+    Say(String),
+    Reset,
+    System(Option<String>),
+    Temperature(f32),
+    Limit(u32),
+    Help,
+    Exit,
+}
+
+fn parse_input(line: &str) -> Result<Input, String> {
+    let line = line.trim();
+    if !line.starts_with('/') {
+        return Ok(Input::Say(line.to_string()));
+    }
+
+    let (command, arg) = line.split_once(' ').map_or((line, ""), |(c, a)| (c, a.trim()));
+    match command {
+        "/reset" => Ok(Input::Reset),
+        "/system" => Ok(Input::System((!arg.is_empty()).then(|| arg.to_string()))),
+        "/temp" => arg.parse().map(Input::Temperature).map_err(|_| format!("Invalid temperature '{}'", arg)),
+        "/limit" => match arg.parse() {
+            Ok(n) if n > 0 => Ok(Input::Limit(n)),
+            _ => Err(format!("Invalid token limit '{}'", arg)),
+        },
+        "/help" => Ok(Input::Help),
+        "/exit" | "/quit" => Ok(Input::Exit),
+        other => Err(format!("Unknown command {} (try /help)", other)),
+    }
+}
+
+/// The conversation so far plus the settings for the next reply.
+struct Conversation {
+    system: Option<String>,
+    history: Vec<ChatMessage>,
+    options: InferenceOptions,
+}
+
+impl Conversation {
+    /// Everything to send for the next turn: system message, history, then `user_text`.
+    fn messages_with(&self, user_text: &str) -> Vec<ChatMessage> {
+        let mut messages = Vec::with_capacity(self.history.len() + 2);
+        if let Some(system) = &self.system {
+            messages.push(ChatMessage::new(Role::System, system.clone()));
+        }
+        messages.extend(self.history.iter().cloned());
+        messages.push(ChatMessage::new(Role::User, user_text));
+        messages
+    }
+}
+
+pub async fn run(engine: Arc<Engine>, system: Option<String>, max_tokens: Option<u32>) -> anyhow::Result<()> {
+    let mut conversation = Conversation {
+        system,
+        history: Vec::new(),
+        options: InferenceOptions { max_tokens: max_tokens.or(Some(512)), ..InferenceOptions::default() },
+    };
+    let mut editor = DefaultEditor::new()?;
+    println!("{}\n", HELP);
+
+    loop {
+        let line = match tokio::task::block_in_place(|| editor.readline(">> ")) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                println!("(/exit or Ctrl-D to quit)");
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+
+        match parse_input(&line) {
+            Ok(Input::Say(text)) => {
+                if let Some(reply) = reply(&engine, &conversation, &text).await {
+                    conversation.history.push(ChatMessage::new(Role::User, text));
+                    conversation.history.push(ChatMessage::new(Role::Assistant, reply));
+                }
+            }
+            Ok(Input::Reset) => {
+                conversation.history.clear();
+                println!("History cleared.");
+            }
+            Ok(Input::System(text)) => {
+                println!("{}", if text.is_some() { "System message set." } else { "System message cleared." });
+                conversation.system = text;
+            }
+            Ok(Input::Temperature(t)) => {
+                conversation.options.temperature = Some(t);
+                println!("Temperature set to {}", t);
+            }
+            Ok(Input::Limit(n)) => {
+                conversation.options.max_tokens = Some(n);
+                println!("Max tokens set to {}", n);
+            }
+            Ok(Input::Help) => println!("{}", HELP),
+            Ok(Input::Exit) => break,
+            Err(e) => println!("{}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Stream the model's reply to `user_text` to the terminal and return it, or `None` if it
+/// failed. Ctrl-C stops the generation; the partial reply is kept.
+async fn reply(engine: &Engine, conversation: &Conversation, user_text: &str) -> Option<String> {
+    let messages = conversation.messages_with(user_text);
+    let mut rx = match engine.process_chat_stream(&messages, conversation.options.clone()).await {
+        Ok(rx) => rx,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return None;
+        }
+    };
+
+    let mut text = String::new();
+    let mut stdout = std::io::stdout();
+    loop {
+        let chunk = tokio::select! {
+            chunk = rx.recv() => chunk,
+            _ = tokio::signal::ctrl_c() => {
+                engine.cancel_all();
+                continue;
+            }
+        };
+        match chunk {
+            Some(TokenChunk::Token { text: piece }) => {
+                print!("{}", piece);
+                let _ = stdout.flush();
+                text.push_str(&piece);
+            }
+            Some(TokenChunk::Done { status, .. }) => {
+                match status {
+                    InferenceStatus::Cancelled => println!(" [stopped]"),
+                    InferenceStatus::Truncated => println!(" [truncated]"),
+                    _ => println!(),
+                }
+                return Some(text);
+            }
+            Some(TokenChunk::Error { message }) => {
+                println!();
+                eprintln!("Error: {}", message);
+                if message.contains("exceeds context size") {
+                    eprintln!("The conversation no longer fits in the context; /reset to start over.");
+                }
+                return None;
+            }
+            None => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_input() {
+        assert_eq!(parse_input("  hello there "), Ok(Input::Say("hello there".to_string())));
+        assert_eq!(parse_input("/reset"), Ok(Input::Reset));
+        assert_eq!(parse_input("/system Be brief."), Ok(Input::System(Some("Be brief.".to_string()))));
+        assert_eq!(parse_input("/system"), Ok(Input::System(None)));
+        assert_eq!(parse_input("/temp 0.7"), Ok(Input::Temperature(0.7)));
+        assert_eq!(parse_input("/limit 64"), Ok(Input::Limit(64)));
+        assert!(parse_input("/limit 0").is_err());
+        assert!(parse_input("/temp hot").is_err());
+        assert!(parse_input("/frobnicate").is_err());
+    }
+
+    #[test]
+    fn test_messages_with_history() {
+        let conversation = Conversation {
+            system: Some("Be brief.".to_string()),
+            history: vec![ChatMessage::new(Role::User, "Hi"), ChatMessage::new(Role::Assistant, "Hello!")],
+            options: InferenceOptions::default(),
+        };
+        let messages = conversation.messages_with("Who are you?");
+        let roles: Vec<Role> = messages.iter().map(|m| m.role).collect();
+        assert_eq!(roles, vec![Role::System, Role::User, Role::Assistant, Role::User]);
+        assert_eq!(messages[3].content, "Who are you?");
+    }
+}
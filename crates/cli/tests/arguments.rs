@@ -0,0 +1,424 @@
+//! `lie run` prompt, model and runtime arguments, and `lie memory` with memory disabled. These
+//! cases fail before a model is loaded or use the mock runtime, so a placeholder file stands
+//! in for the model.
+
+use assert_cmd::Command;
+use std::path::PathBuf;
+
+/// An empty file that passes the model path checks.
+fn placeholder_model() -> PathBuf {
+    let path = std::env::temp_dir().join(format!("lie-cli-placeholder-{}.gguf", std::process::id()));
+    std::fs::write(&path, b"").unwrap();
+    path
+}
+
+fn lie() -> Command {
+    let mut cmd = Command::cargo_bin("lie-cli").unwrap();
+    cmd.env("CELA_MODEL_PATH", placeholder_model());
+    cmd
+}
+
+fn stderr(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn test_empty_stdin_is_rejected_like_an_empty_prompt() {
+    for args in [&["run", "--prompt", "-"][..], &["run"][..]] {
+        let output = lie().args(args).write_stdin("").output().unwrap();
+        assert_eq!(output.status.code(), Some(1), "{:?}", args);
+        assert!(stderr(&output).contains("Validation Error: Prompt cannot be empty"), "{}", stderr(&output));
+    }
+
+    let output = lie().args(["run", "--prompt", "   "]).output().unwrap();
+    assert!(stderr(&output).contains("Validation Error: Prompt cannot be empty"));
+}
+
+#[test]
+fn test_blank_line_on_stdin_is_empty() {
+    let output = lie().args(["run", "--prompt", "-"]).write_stdin("\n").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Prompt cannot be empty"));
+}
+
+#[test]
+fn test_empty_prompt_file() {
+    let dir = std::env::temp_dir().join(format!("lie-cli-prompt-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("empty.txt");
+    std::fs::write(&path, "\n").unwrap();
+
+    let output = lie().args(["run", "--prompt-file"]).arg(&path).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Prompt cannot be empty"));
+}
+
+#[test]
+fn test_missing_prompt_file() {
+    let output = lie().args(["run", "--prompt-file", "/nonexistent/prompt.txt"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Failed to read prompt file /nonexistent/prompt.txt"));
+}
+
+#[test]
+fn test_missing_model_is_reported_before_loading() {
+    let output = lie().args(["run", "--prompt", "Hi", "--model", "/nonexistent/llama.gguf"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("Model file /nonexistent/llama.gguf does not exist"), "{}", stderr);
+    assert!(stderr.contains("--model <path>"), "{}", stderr);
+}
+
+#[test]
+fn test_model_must_be_gguf() {
+    let model = std::env::temp_dir().join(format!("lie-cli-model-{}.bin", std::process::id()));
+    std::fs::write(&model, b"").unwrap();
+    let output = lie().args(["serve", "--model"]).arg(&model).output().unwrap();
+    std::fs::remove_file(&model).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("expected a .gguf extension"));
+}
+
+#[test]
+fn test_ctx_size_must_be_positive() {
+    let output = lie().args(["run", "--prompt", "Hi", "--ctx-size", "0"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--ctx-size"));
+}
+
+#[test]
+fn test_prompt_and_prompt_file_conflict() {
+    let output = lie().args(["run", "--prompt", "Hi", "--prompt-file", "prompt.txt"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("cannot be used with"));
+}
+
+#[test]
+fn test_memory_requires_it_enabled() {
+    let dir = std::env::temp_dir().join(format!("lie-cli-memory-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Run where no config file is found, so memory is off by default
+    let output = lie()
+        .current_dir(&dir)
+        .env("HOME", &dir)
+        .env_remove("CELA_MEMORY_ENABLED")
+        .env("CELA_MEMORY_PATH", dir.join("memory.json"))
+        .args(["memory", "set", "user", "Divyansh"])
+        .output().unwrap();
+    let saved = dir.join("memory.json").exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Memory is disabled"), "{}", stderr(&output));
+    assert!(!saved);
+}
+
+#[test]
+fn test_bench_needs_an_iteration() {
+    let output = lie().args(["bench", "--iterations", "0"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--iterations"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_unknown_runtime_lists_registered_ones() {
+    let output = lie().args(["run", "--prompt", "Hi", "--runtime", "onnx"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Unknown runtime 'onnx' (registered: candle, llamacpp, mock, openai)"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_candle_runtime_needs_a_model_directory() {
+    let output = lie().args(["run", "--prompt", "Hi", "--runtime", "candle", "--model", "/nonexistent/model"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Model directory /nonexistent/model does not exist"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_mock_runtime_needs_no_model_file() {
+    let output = lie().args(["run", "--prompt", "Hello there", "--runtime", "mock", "--model", "/nonexistent/model.gguf"])
+        .output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(r#""text": "Hello there""#), "{}", stdout);
+}
+
+#[test]
+fn test_output_text_prints_only_the_text() {
+    let output = lie().args(["run", "--prompt", "Hello there", "--runtime", "mock", "--output", "text", "--quiet"])
+        .output().unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello there\n");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn test_output_json_compact_is_one_line() {
+    let output = lie().args(["run", "--prompt", "Hello there", "--runtime", "mock", "--output", "json-compact", "--quiet"])
+        .output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    let response: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(response["output"]["text"], "Hello there");
+}
+
+#[test]
+fn test_truncated_output_exits_with_2() {
+    let output = lie().args(["run", "--prompt", "Hello there", "--runtime", "mock", "--max-tokens", "3", "--output", "text", "--usage"])
+        .output().unwrap();
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hel\n");
+    assert!(stderr(&output).contains("Note: the output was cut short (truncated)"), "{}", stderr(&output));
+    assert!(stderr(&output).contains("generation: 3 tokens"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_error_response_exits_with_1() {
+    let dir = tempfile::tempdir().unwrap();
+    let schema = dir.path().join("schema.json");
+    std::fs::write(&schema, r#"{"type": "object"}"#).unwrap();
+    // The mock runtime ignores the grammar and echoes the prompt, which isn't JSON
+    let output = lie().args(["run", "--prompt", "Hello there", "--runtime", "mock", "--output", "text", "--json-schema"]).arg(&schema)
+        .output().unwrap();
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(output.stdout.is_empty());
+    assert!(stderr(&output).contains("Error: Output is not valid JSON"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_dry_run_prints_the_composed_prompt() {
+    let output = lie().args(["run", "--prompt", "Hello there", "--system", "Be brief.", "--dry-run", "--runtime", "mock"])
+        .output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(r#""status": "dry_run""#), "{}", stdout);
+    assert!(stdout.contains(r#""prompt": "Be brief.\n\nHello there""#), "{}", stdout);
+}
+
+#[test]
+fn test_json_logs_carry_the_request_id() {
+    let output = lie().env("CELA_LOGGING_FORMAT", "json").env("CELA_LOG", "lie_core=debug")
+        .args(["run", "--prompt", "Hi", "--runtime", "mock"])
+        .output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let logs: Vec<serde_json::Value> = stderr(&output).lines()
+        .filter(|line| line.starts_with('{'))
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let finished = logs.iter()
+        .find(|log| log["fields"]["message"].as_str().is_some_and(|message| message.starts_with("Finished")))
+        .unwrap_or_else(|| panic!("{}", stderr(&output)));
+    assert_eq!(finished["level"], "DEBUG");
+    assert_eq!(finished["target"], "lie_core");
+    assert!(finished["span"]["request_id"].is_string(), "{}", finished);
+}
+
+#[test]
+fn test_session_list_and_delete_need_no_model() {
+    let dir = std::env::temp_dir().join(format!("lie-cli-sessions-{}", std::process::id()));
+
+    let list = lie().env("CELA_MODEL_SESSION_DIR", &dir).args(["session", "list"]).output().unwrap();
+    let delete = lie().env("CELA_MODEL_SESSION_DIR", &dir).args(["session", "delete", "docs"]).output().unwrap();
+    assert!(list.status.success(), "{}", stderr(&list));
+    assert!(list.stdout.is_empty());
+    assert_eq!(delete.status.code(), Some(1));
+    assert!(stderr(&delete).contains("No session named 'docs'"), "{}", stderr(&delete));
+}
+
+#[test]
+fn test_session_create_needs_a_runtime_that_saves_state() {
+    let dir = std::env::temp_dir().join(format!("lie-cli-session-create-{}", std::process::id()));
+    let output = lie().env("CELA_MODEL_SESSION_DIR", &dir)
+        .args(["session", "create", "docs", "--text", "A long document.", "--runtime", "mock"])
+        .output().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("can't save its state"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_invalid_config_fails_before_running() {
+    let output = lie().env("CELA_MODEL_BATCH_SIZE", "0").env("CELA_QUEUE_MAX_CONCURRENT", "0")
+        .args(["run", "--prompt", "Hi", "--runtime", "mock"])
+        .output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("model.batch_size must be at least 1; queue.max_concurrent must be at least 1"), "{}", stderr(&output));
+}
+
+/// A GGUF v3 header with no metadata and no tensors.
+fn empty_gguf(dir: &std::path::Path, name: &str) -> PathBuf {
+    let mut header = b"GGUF".to_vec();
+    header.extend(3u32.to_le_bytes());
+    header.extend([0u8; 16]);
+    let path = dir.join(name);
+    std::fs::write(&path, header).unwrap();
+    path
+}
+
+#[test]
+fn test_models_list_reads_the_models_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    empty_gguf(dir.path(), "tiny.gguf");
+    let output = lie().env("CELA_MODEL_MODELS_DIR", dir.path()).args(["models", "list"]).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("tiny\t0.00 GB\tunknown\t0.0B params\t"), "{}", stdout);
+}
+
+#[test]
+fn test_models_verify() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = empty_gguf(dir.path(), "tiny.gguf");
+    let checksum = "a4e5e156ddec27e286f75328784d7106b60a4eb1d246e950a001a3f944fbda99";
+    let ok = lie().args(["models", "verify"]).arg(&path).args(["--sha256", checksum]).output().unwrap();
+    assert!(ok.status.success(), "{}", stderr(&ok));
+    assert!(String::from_utf8_lossy(&ok.stdout).contains("OK (GGUF v3, 0 tensors)"));
+
+    let mismatch = lie().args(["models", "verify"]).arg(&path).args(["--sha256", &"0".repeat(64)]).output().unwrap();
+    assert_eq!(mismatch.status.code(), Some(1));
+    assert!(stderr(&mismatch).contains(&format!("SHA-256 is {}", checksum)), "{}", stderr(&mismatch));
+
+    let not_gguf = lie().args(["models", "verify"]).arg(placeholder_model()).output().unwrap();
+    assert_eq!(not_gguf.status.code(), Some(1));
+    assert!(stderr(&not_gguf).contains("is not a valid GGUF file"), "{}", stderr(&not_gguf));
+}
+
+#[test]
+fn test_doctor_reports_each_check() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = lie().env("CELA_MEMORY_PATH", dir.path().join("memory.json"))
+        .env("CELA_MODEL_PATH", dir.path().join("missing.gguf"))
+        .args(["doctor", "--json"])
+        .output().unwrap();
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    let checks: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let status = |name: &str| checks.as_array().unwrap().iter().find(|c| c["name"] == name).map(|c| c["status"].clone());
+    assert_eq!(status("config").unwrap(), "pass");
+    assert_eq!(status("model").unwrap(), "fail");
+    assert_eq!(status("memory").unwrap(), "pass");
+    assert!(status("port").is_some() && status("gpu").is_some() && status("inference").is_none());
+
+    let smoke = lie().env("CELA_MEMORY_PATH", dir.path().join("memory.json"))
+        .env("CELA_SERVER_PORT", "0")
+        .args(["doctor", "--smoke", "--runtime", "mock"])
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&smoke.stdout);
+    assert!(smoke.status.success(), "{}", stdout);
+    assert!(stdout.contains("pass  inference  loaded the model"), "{}", stdout);
+}
+
+#[test]
+fn test_config_init_and_validate() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cela.toml");
+    let init = lie().args(["config", "init"]).arg(&path).output().unwrap();
+    assert!(init.status.success(), "{}", stderr(&init));
+    let again = lie().args(["config", "init"]).arg(&path).output().unwrap();
+    assert!(stderr(&again).contains("already exists; pass --force"), "{}", stderr(&again));
+
+    // The written file only lacks the model it points at
+    let validate = lie().args(["config", "validate"]).arg(&path).output().unwrap();
+    assert_eq!(validate.status.code(), Some(1));
+    assert!(stderr(&validate).contains("1 problem\n  model.default_path: models/default.gguf does not exist"), "{}", stderr(&validate));
+
+    std::fs::write(&path, "[model]\nruntime = \"mock\"\nbatch_size = \"big\"\ngpu_layer = 4\n").unwrap();
+    let validate = lie().arg("--config").arg(&path).args(["config", "validate"]).output().unwrap();
+    assert_eq!(validate.status.code(), Some(1));
+    assert!(stderr(&validate).contains("2 problems"), "{}", stderr(&validate));
+    assert!(stderr(&validate).contains("unknown key model.gpu_layer"), "{}", stderr(&validate));
+}
+
+#[test]
+fn test_config_show_redacts_keys() {
+    let output = lie().env("CELA_SERVER_API_KEYS", "secret-key").env("CELA_SERVER_PORT", "9001")
+        .args(["config", "show", "--json", "--ctx-size", "4096"])
+        .output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(config["server"]["api_keys"], serde_json::json!(["<redacted>"]));
+    assert_eq!(config["server"]["port"], 9001);
+    assert_eq!(config["model"]["default_context_size"], 4096);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_daemon_status_and_stop() {
+    let dir = tempfile::tempdir().unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let pid_file = dir.path().join("lie.pid");
+    let lie = || {
+        let mut cmd = lie();
+        cmd.env("CELA_SERVER_PID_FILE", &pid_file)
+            .env("CELA_SERVER_PORT", port.to_string())
+            .env("CELA_MEMORY_PATH", dir.path().join("memory.json"))
+            .env("CELA_LOGGING_FILE", dir.path().join("lie.log"))
+            .args(["--runtime", "mock"]);
+        cmd
+    };
+
+    let serve = lie().args(["serve", "--daemon"]).output().unwrap();
+    assert!(serve.status.success(), "{}", stderr(&serve));
+    assert!(pid_file.exists());
+    let again = lie().args(["serve", "--daemon"]).output().unwrap();
+    assert!(stderr(&again).contains("A server is already running"), "{}", stderr(&again));
+
+    let status = lie().arg("status").output().unwrap();
+    let stdout = String::from_utf8_lossy(&status.stdout);
+    assert!(status.status.success(), "{}", stdout);
+    assert!(stdout.contains("Running (pid") && stdout.contains("\"status\""), "{}", stdout);
+
+    let stop = lie().arg("stop").output().unwrap();
+    assert!(stop.status.success(), "{}", stderr(&stop));
+    assert!(!pid_file.exists());
+    let status = lie().arg("status").output().unwrap();
+    assert_eq!(status.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&status.stdout).starts_with("Not running"));
+}
+
+/// The body of a plain HTTP GET to the local server on `port`.
+#[cfg(unix)]
+fn http_get(port: u16, path: &str) -> String {
+    use std::io::{Read, Write};
+    let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default()
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sighup_reloads_the_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config = dir.path().join("cela.toml");
+    let log = dir.path().join("lie.log");
+    let pid_file = dir.path().join("lie.pid");
+    std::fs::write(&config, format!("[server]\nport = {}\nmax_prompt_chars = 100\n", port)).unwrap();
+    let lie = || {
+        let mut cmd = lie();
+        cmd.arg("--config").arg(&config)
+            .env("CELA_SERVER_PID_FILE", &pid_file)
+            .env("CELA_MEMORY_PATH", dir.path().join("memory.json"))
+            .env("CELA_LOGGING_FILE", &log)
+            .args(["--runtime", "mock"]);
+        cmd
+    };
+    let serve = lie().args(["serve", "--daemon"]).output().unwrap();
+    assert!(serve.status.success(), "{}", stderr(&serve));
+    assert!(http_get(port, "/v1/limits").contains("\"max_prompt_chars\":100"));
+
+    std::fs::write(&config, format!("[server]\nport = {}\nhost = \"0.0.0.0\"\nmax_prompt_chars = 200\n", port)).unwrap();
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+    assert!(std::process::Command::new("kill").args(["-HUP", pid.trim()]).status().unwrap().success());
+    let reloaded = (0..50).any(|_| {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        http_get(port, "/v1/limits").contains("\"max_prompt_chars\":200")
+    });
+    lie().arg("stop").output().unwrap();
+    let logs = std::fs::read_to_string(&log).unwrap();
+    assert!(reloaded, "{}", logs);
+    assert!(logs.contains("changed server.max_prompt_chars") && logs.contains("Changes to server.host need a restart"), "{}", logs);
+}
@@ -0,0 +1,108 @@
+//! `lie models pull` against a stand-in for a file server.
+
+use assert_cmd::Command;
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+
+/// A GGUF header with only `general.name` set.
+fn model() -> Vec<u8> {
+    let mut out = b"GGUF".to_vec();
+    out.extend(3u32.to_le_bytes());
+    out.extend(0u64.to_le_bytes());
+    out.extend(1u64.to_le_bytes());
+    out.extend(12u64.to_le_bytes());
+    out.extend(b"general.name");
+    out.extend(8u32.to_le_bytes());
+    out.extend(4u64.to_le_bytes());
+    out.extend(b"Tiny");
+    out
+}
+
+fn checksum() -> String {
+    Sha256::digest(model()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+type Ranges = Arc<Mutex<Vec<String>>>;
+
+/// Serves `model()` as `tiny.gguf`, `bad.gguf` and `plain.gguf`, honouring `Range`, with the
+/// right checksum at `tiny.gguf.sha256`, a wrong one at `bad.gguf.sha256`, and none for
+/// `plain.gguf`. Records the `Range` header of each download.
+async fn serve(Path(file): Path<String>, State(ranges): State<Ranges>, headers: HeaderMap) -> Response {
+    match file.as_str() {
+        "tiny.gguf.sha256" => return format!("{}  tiny.gguf\n", checksum()).into_response(),
+        "bad.gguf.sha256" => return "0".repeat(64).into_response(),
+        "tiny.gguf" | "bad.gguf" | "plain.gguf" => {}
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    }
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok()).unwrap_or("").to_string();
+    ranges.lock().unwrap().push(range.clone());
+    let body = model();
+    match range.strip_prefix("bytes=").and_then(|r| r.strip_suffix('-')).and_then(|r| r.parse::<usize>().ok()) {
+        Some(start) => (StatusCode::PARTIAL_CONTENT, body[start..].to_vec()).into_response(),
+        None => body.into_response(),
+    }
+}
+
+async fn server() -> (String, Ranges) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let ranges = Ranges::default();
+    let router = Router::new().route("/files/:file", get(serve)).with_state(ranges.clone());
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    (base_url, ranges)
+}
+
+fn pull(dir: &std::path::Path, args: &[&str]) -> std::process::Output {
+    Command::cargo_bin("lie-cli").unwrap()
+        .env("CELA_MODEL_MODELS_DIR", dir)
+        .args(["models", "pull"])
+        .args(args)
+        .output().unwrap()
+}
+
+fn stderr(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_checks_the_published_checksum() {
+    let (base_url, _) = server().await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = pull(dir.path(), &[&format!("{}/files/tiny.gguf", base_url)]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(std::fs::read(dir.path().join("tiny.gguf")).unwrap(), model());
+    let record: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.path().join("tiny.gguf.json")).unwrap()).unwrap();
+    assert_eq!(record["sha256"], checksum());
+    assert_eq!(record["url"], format!("{}/files/tiny.gguf", base_url));
+
+    let again = pull(dir.path(), &[&format!("{}/files/tiny.gguf", base_url)]);
+    assert_eq!(again.status.code(), Some(1));
+    assert!(stderr(&again).contains("already exists; pass --force"), "{}", stderr(&again));
+
+    let bad = pull(dir.path(), &[&format!("{}/files/bad.gguf", base_url)]);
+    assert_eq!(bad.status.code(), Some(1));
+    assert!(stderr(&bad).contains(&format!("SHA-256 of {}/files/bad.gguf is {}", base_url, checksum())), "{}", stderr(&bad));
+    assert!(!dir.path().join("bad.gguf").exists() && !dir.path().join("bad.gguf.part").exists());
+
+    let missing = pull(dir.path(), &[&format!("{}/files/missing.gguf", base_url)]);
+    assert!(stderr(&missing).contains("HTTP 404"), "{}", stderr(&missing));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_resumes_a_partial_download() {
+    let (base_url, ranges) = server().await;
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("plain.gguf.part"), &model()[..10]).unwrap();
+
+    let output = pull(dir.path(), &[&format!("{}/files/plain.gguf", base_url), "--sha256", &checksum().to_uppercase()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(*ranges.lock().unwrap(), vec!["bytes=10-"]);
+    assert_eq!(std::fs::read(dir.path().join("plain.gguf")).unwrap(), model());
+    assert!(!dir.path().join("plain.gguf.part").exists());
+}
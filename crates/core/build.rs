@@ -0,0 +1,46 @@
+//! Records the git commit and build date for `BuildInfo`.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let commit = std::env::var("CELA_GIT_COMMIT").ok().or_else(|| git(&["rev-parse", "--short=12", "HEAD"]));
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=CELA_GIT_COMMIT={}", commit);
+    }
+    // Rebuild when HEAD moves to another commit or branch
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        if let Some(reference) = git(&["symbolic-ref", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}/{}", git_dir, reference);
+        }
+    }
+    println!("cargo:rerun-if-env-changed=CELA_GIT_COMMIT");
+
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let secs = std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+    println!("cargo:rustc-env=CELA_BUILD_DATE={}", date(secs));
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !stdout.trim().is_empty()).then(|| stdout.trim().to_string())
+}
+
+/// `YYYY-MM-DD` of a Unix time, in UTC.
+fn date(secs: u64) -> String {
+    // Howard Hinnant's days-to-civil algorithm
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
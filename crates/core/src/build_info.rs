@@ -0,0 +1,41 @@
+//! What was built: version, commit and date, plus what the binary adds about its runtime.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    pub version: String,
+    /// Short hash of the commit built from; `None` outside a git checkout.
+    pub git_commit: Option<String>,
+    /// UTC date of the build, `YYYY-MM-DD`.
+    pub build_date: String,
+    /// Optional features compiled in, e.g. `cuda` or `metal`.
+    pub features: Vec<String>,
+    /// What runs inference, e.g. `llama.cpp (CPU, CUDA)`.
+    pub backend: Option<String>,
+}
+
+impl BuildInfo {
+    /// This build, without features or backend: only the binary knows which runtime it uses.
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: option_env!("CELA_GIT_COMMIT").map(str::to_string),
+            build_date: env!("CELA_BUILD_DATE").to_string(),
+            features: Vec::new(),
+            backend: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current() {
+        let info = BuildInfo::current();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.build_date.len(), 10, "{}", info.build_date);
+    }
+}
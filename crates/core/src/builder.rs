@@ -0,0 +1,134 @@
+//! `EngineBuilder`: sets up an `Engine`, checking its config first so a bad value fails at
+//! startup with the key to fix rather than on the first request.
+
+use std::fs;
+use std::path::Path;
+use crate::config::EngineConfig;
+use crate::error::EngineError;
+use crate::hooks::RequestHook;
+use crate::runtime::ModelRuntime;
+use crate::{metrics, Engine};
+
+#[derive(Default)]
+pub struct EngineBuilder {
+    config: Option<EngineConfig>,
+    runtime: Option<Box<dyn ModelRuntime>>,
+    hooks: Vec<Box<dyn RequestHook>>,
+    /// Installs the recorder passed to `metrics`; `false` if one was installed already.
+    install_recorder: Option<Box<dyn FnOnce() -> bool>>,
+}
+
+impl EngineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The config to run with; the defaults when not set.
+    pub fn config(mut self, config: EngineConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn runtime(mut self, runtime: Box<dyn ModelRuntime>) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Run `hook` around every inference, after the hooks added before it (see `hooks`).
+    pub fn hook(mut self, hook: Box<dyn RequestHook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Record the engine's metrics (see `metrics`) with `recorder`, installed as the global
+    /// recorder on `build`. Without one, nothing is recorded unless the application installs
+    /// a recorder itself.
+    pub fn metrics<R: ::metrics::Recorder + Sync + 'static>(mut self, recorder: R) -> Self {
+        self.install_recorder = Some(Box::new(move || ::metrics::set_global_recorder(recorder).is_ok()));
+        self
+    }
+
+    /// Check the config and set up the engine. Fails with `EngineError::Config` naming the
+    /// offending keys, if no runtime was given, or if memory is enabled and its file can't
+    /// be written.
+    pub fn build(self) -> Result<Engine, EngineError> {
+        let config = self.config.unwrap_or_default();
+        let runtime = self.runtime
+            .ok_or_else(|| EngineError::Config("No runtime given; call EngineBuilder::runtime".to_string()))?;
+        config.validate()?;
+        if config.memory.enabled {
+            check_writable(&config.memory.persistence_path)?;
+        }
+
+        if let Some(install) = self.install_recorder {
+            if !install() {
+                return Err(EngineError::Config("A metrics recorder is already installed".to_string()));
+            }
+            metrics::describe();
+        }
+
+        let mut engine = Engine::new(config, runtime);
+        for hook in self.hooks {
+            engine = engine.add_hook(hook);
+        }
+        Ok(engine)
+    }
+}
+
+/// Check that the directory `path` goes in exists, or can be created, and takes new files.
+pub fn check_writable(path: &Path) -> Result<(), EngineError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let unwritable = |e: std::io::Error| EngineError::Config(format!(
+        "memory.persistence_path: can't write to {}: {}", dir.display(), e
+    ));
+    fs::create_dir_all(dir).map_err(unwritable)?;
+    let probe = dir.join(format!(".cela-write-check-{}", std::process::id()));
+    fs::write(&probe, b"").map_err(unwritable)?;
+    let _ = fs::remove_file(&probe);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::mock::MockRuntime;
+
+    #[test]
+    fn test_build_checks_the_config() {
+        let err = EngineBuilder::new().build().err().unwrap();
+        assert!(err.to_string().contains("No runtime given"), "{}", err);
+
+        let mut config = EngineConfig::default();
+        config.model.batch_size = 0;
+        let err = EngineBuilder::new().config(config).runtime(Box::new(MockRuntime::default())).build().err().unwrap();
+        assert_eq!(err.to_string(), "Configuration error: model.batch_size must be at least 1");
+
+        assert!(EngineBuilder::new().runtime(Box::new(MockRuntime::default())).build().is_ok());
+    }
+
+    #[test]
+    fn test_build_checks_the_memory_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = EngineConfig::default();
+        config.memory.enabled = true;
+        config.memory.persistence_path = dir.path().join("nested/memory.json");
+        assert!(EngineBuilder::new().config(config.clone()).runtime(Box::new(MockRuntime::default())).build().is_ok());
+        assert!(dir.path().join("nested").is_dir());
+
+        // A file where the directory should be
+        fs::write(dir.path().join("file"), b"").unwrap();
+        config.memory.persistence_path = dir.path().join("file/memory.json");
+        let err = EngineBuilder::new().config(config).runtime(Box::new(MockRuntime::default())).build().err().unwrap();
+        assert!(err.to_string().contains("memory.persistence_path: can't write to"), "{}", err);
+    }
+
+    #[test]
+    fn test_metrics_recorder_is_installed_once() {
+        let build = || EngineBuilder::new().runtime(Box::new(MockRuntime::default())).metrics(::metrics::NoopRecorder).build();
+        assert!(build().is_ok());
+        assert_eq!(build().err().unwrap().to_string(), "Configuration error: A metrics recorder is already installed");
+    }
+}
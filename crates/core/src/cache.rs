@@ -0,0 +1,211 @@
+//! Responses kept for repeated deterministic requests (`cache` in the config).
+//!
+//! A response is looked up by its prompt and the options that decide what the model
+//! generates. The prompt is composed with all of memory injected, as it is before the
+//! request has a slot in the queue. How much of the injection fits is only measured under
+//! the slot. It is the same for a given model, and loading another model clears the cache.
+//!
+//! Only greedy (temperature 0) requests are cached, since sampled ones are meant to differ,
+//! and only complete responses are stored: one cut short by a deadline or a cancellation
+//! would come out different next time.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::config::CacheConfig;
+use crate::runtime::{FinishReason, InferenceOptions};
+use crate::{metrics, EngineResponse};
+
+pub struct ResponseCache {
+    config: CacheConfig,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<u64, Entry>,
+    /// Incremented on every use, to find the least recently used entry.
+    clock: u64,
+}
+
+struct Entry {
+    response: EngineResponse,
+    stored_at: Instant,
+    last_used: u64,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self { config, entries: Mutex::new(Entries::default()) }
+    }
+
+    /// The cache key of a request, or `None` if its response mustn't be cached.
+    pub fn key(&self, final_prompt: &str, options: &InferenceOptions) -> Option<u64> {
+        if !self.config.enabled || self.config.max_entries == 0 || options.no_cache || options.dry_run || options.temperature.unwrap_or(0.0) > 0.0 {
+            return None;
+        }
+        // Fields that don't change the output are left out, so they don't split the cache;
+        // the seed among them, as greedy decoding doesn't sample
+        let relevant = InferenceOptions {
+            max_time_ms: None,
+            reset_context: false,
+            n_threads: None,
+            n_threads_batch: None,
+            memory_namespace: None,
+            seed: None,
+            logit_bias: HashMap::new(),
+            ..options.clone()
+        };
+        // A `HashMap` serializes in no particular order
+        let logit_bias: BTreeMap<&String, &f32> = options.logit_bias.iter().collect();
+        let mut hasher = DefaultHasher::new();
+        final_prompt.hash(&mut hasher);
+        serde_json::to_string(&relevant).ok()?.hash(&mut hasher);
+        serde_json::to_string(&logit_bias).ok()?.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    /// The stored response for `key`, marked as `cached`, unless there is none or it has
+    /// expired.
+    pub fn get(&self, key: u64) -> Option<EngineResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = Duration::from_secs(self.config.ttl_secs);
+        if entries.by_key.get(&key).is_some_and(|entry| entry.stored_at.elapsed() >= ttl) {
+            entries.by_key.remove(&key);
+        }
+        entries.clock += 1;
+        let clock = entries.clock;
+        let Some(entry) = entries.by_key.get_mut(&key) else {
+            metrics::record_cache_miss();
+            return None;
+        };
+        entry.last_used = clock;
+        metrics::record_cache_hit();
+        Some(EngineResponse { cached: true, ..entry.response.clone() })
+    }
+
+    /// Store `response` under `key` if it is complete, making room by dropping expired
+    /// entries and then the least recently used one.
+    pub fn put(&self, key: u64, response: &EngineResponse) {
+        let complete = response.status != "error"
+            && !matches!(response.finish_reason, Some(FinishReason::Cancelled | FinishReason::Time) | None);
+        if !complete {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.by_key.contains_key(&key) && entries.by_key.len() >= self.config.max_entries {
+            let ttl = Duration::from_secs(self.config.ttl_secs);
+            entries.by_key.retain(|_, entry| entry.stored_at.elapsed() < ttl);
+        }
+        if !entries.by_key.contains_key(&key) && entries.by_key.len() >= self.config.max_entries {
+            let oldest = entries.by_key.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                entries.by_key.remove(&oldest);
+            }
+        }
+        entries.clock += 1;
+        let entry = Entry {
+            response: EngineResponse { request_id: None, queue: None, ..response.clone() },
+            stored_at: Instant::now(),
+            last_used: entries.clock,
+        };
+        entries.by_key.insert(key, entry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().by_key.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget every response, e.g. because another model was loaded.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().by_key.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use crate::OutputContent;
+
+    fn cache(max_entries: usize, ttl_secs: u64) -> ResponseCache {
+        ResponseCache::new(CacheConfig { enabled: true, max_entries, ttl_secs })
+    }
+
+    fn response(text: &str) -> EngineResponse {
+        EngineResponse {
+            status: "success".to_string(),
+            output: OutputContent { text: text.to_string(), json: None },
+            finish_reason: Some(FinishReason::Eos),
+            error: None,
+            error_info: None,
+            ..EngineResponse::error(ErrorCode::RuntimeError, "")
+        }
+    }
+
+    #[test]
+    fn test_key() {
+        let cache = cache(4, 60);
+        let options = InferenceOptions::default();
+        let key = cache.key("Hello", &options).unwrap();
+        assert_eq!(cache.key("Hello", &InferenceOptions { max_time_ms: Some(1), ..options.clone() }), Some(key));
+        assert_ne!(cache.key("Hello!", &options), Some(key));
+        assert_ne!(cache.key("Hello", &InferenceOptions { max_tokens: Some(7), ..options.clone() }), Some(key));
+        assert_eq!(cache.key("Hello", &InferenceOptions { seed: Some(42), ..options.clone() }), Some(key));
+
+        // Whatever order the biases are kept in
+        let bias: Vec<(String, f32)> = (0..32).map(|token| (token.to_string(), -1.0)).collect();
+        let forward = InferenceOptions { logit_bias: bias.iter().cloned().collect(), ..options.clone() };
+        let backward = InferenceOptions { logit_bias: bias.iter().rev().cloned().collect(), ..options.clone() };
+        assert_eq!(cache.key("Hello", &forward), cache.key("Hello", &backward));
+        assert_ne!(cache.key("Hello", &forward), Some(key));
+
+        assert_eq!(cache.key("Hello", &InferenceOptions { temperature: Some(0.7), ..options.clone() }), None);
+        assert_eq!(cache.key("Hello", &InferenceOptions { no_cache: true, ..options.clone() }), None);
+        let disabled = ResponseCache::new(CacheConfig { enabled: false, ..CacheConfig::default() });
+        assert_eq!(disabled.key("Hello", &options), None);
+    }
+
+    #[test]
+    fn test_hits_and_incomplete_responses() {
+        let cache = cache(4, 60);
+        assert!(cache.get(1).is_none());
+        cache.put(1, &response("one"));
+        let hit = cache.get(1).unwrap();
+        assert!(hit.cached);
+        assert_eq!(hit.output.text, "one");
+
+        let cancelled = EngineResponse { finish_reason: Some(FinishReason::Cancelled), ..response("two") };
+        cache.put(2, &cancelled);
+        cache.put(3, &EngineResponse::error(ErrorCode::RuntimeError, "failed"));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let cache = cache(2, 60);
+        cache.put(1, &response("one"));
+        cache.put(2, &response("two"));
+        assert!(cache.get(1).is_some());
+        cache.put(3, &response("three"));
+
+        assert!(cache.get(2).is_none(), "least recently used");
+        assert!(cache.get(1).is_some() && cache.get(3).is_some());
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_expired_entries_are_dropped() {
+        let cache = cache(2, 0);
+        cache.put(1, &response("one"));
+        assert!(cache.get(1).is_none());
+        assert!(cache.is_empty());
+    }
+}
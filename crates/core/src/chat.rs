@@ -0,0 +1,238 @@
+use serde::{Deserialize, Serialize};
+use crate::error::EngineError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self { role, content: content.into() }
+    }
+}
+
+/// Prompt formats for rendering a conversation into a single prompt.
+/// Every format ends with the opening of an assistant turn so the model continues from there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatTemplate {
+    ChatMl,
+    Llama2,
+    Llama3,
+    /// A Jinja template as shipped in GGUF `tokenizer.chat_template` metadata.
+    /// `eos_token` is the model's end-of-sequence text, which these templates place after each turn.
+    Jinja { source: String, eos_token: String },
+}
+
+impl ChatTemplate {
+    /// Used when `model.chat_template` isn't set.
+    pub const DEFAULT: ChatTemplate = ChatTemplate::ChatMl;
+
+    pub fn from_name(name: &str) -> Result<Self, EngineError> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "chatml" => Ok(ChatTemplate::ChatMl),
+            "llama2" => Ok(ChatTemplate::Llama2),
+            "llama3" => Ok(ChatTemplate::Llama3),
+            other => Err(EngineError::Config(format!(
+                "Unknown chat template '{}' (expected chatml, llama2 or llama3)", other
+            ))),
+        }
+    }
+
+    pub fn render(&self, messages: &[ChatMessage]) -> Result<String, EngineError> {
+        match self {
+            ChatTemplate::ChatMl => Ok(render_chatml(messages)),
+            ChatTemplate::Llama2 => Ok(render_llama2(messages)),
+            ChatTemplate::Llama3 => Ok(render_llama3(messages)),
+            ChatTemplate::Jinja { source, eos_token } => render_jinja(source, eos_token, messages),
+        }
+    }
+}
+
+/// Put `text` into the system slot: appended to the leading system message if there
+/// is one, otherwise as a new system message at the front.
+pub fn with_system_text(messages: &[ChatMessage], text: &str) -> Vec<ChatMessage> {
+    let mut messages = messages.to_vec();
+    if text.is_empty() {
+        return messages;
+    }
+
+    match messages.first_mut() {
+        Some(first) if first.role == Role::System => {
+            if !first.content.is_empty() {
+                first.content.push_str("\n\n");
+            }
+            first.content.push_str(text);
+        }
+        _ => messages.insert(0, ChatMessage::new(Role::System, text)),
+    }
+    messages
+}
+
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::System => "system",
+        Role::User => "user",
+        Role::Assistant => "assistant",
+    }
+}
+
+fn render_chatml(messages: &[ChatMessage]) -> String {
+    let mut prompt = String::new();
+    for message in messages {
+        prompt.push_str(&format!("<|im_start|>{}\n{}<|im_end|>\n", role_name(message.role), message.content));
+    }
+    prompt.push_str("<|im_start|>assistant\n");
+    prompt
+}
+
+/// `[INST] <<SYS>> ... <</SYS>> user [/INST] assistant </s><s>[INST] ...`.
+/// The leading BOS is left to the tokenizer.
+fn render_llama2(messages: &[ChatMessage]) -> String {
+    let mut prompt = String::new();
+    let mut system: Option<&str> = None;
+    let mut first_turn = true;
+
+    for message in messages {
+        match message.role {
+            Role::System => system = Some(&message.content),
+            Role::User => {
+                if !first_turn {
+                    prompt.push_str("<s>");
+                }
+                prompt.push_str("[INST] ");
+                if let Some(sys) = system.take() {
+                    prompt.push_str(&format!("<<SYS>>\n{}\n<</SYS>>\n\n", sys));
+                }
+                prompt.push_str(&format!("{} [/INST]", message.content));
+                first_turn = false;
+            }
+            Role::Assistant => {
+                prompt.push_str(&format!(" {} </s>", message.content));
+            }
+        }
+    }
+    prompt
+}
+
+/// Llama 3 header format. The leading `<|begin_of_text|>` is left to the tokenizer.
+fn render_llama3(messages: &[ChatMessage]) -> String {
+    let mut prompt = String::new();
+    for message in messages {
+        prompt.push_str(&format!(
+            "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+            role_name(message.role),
+            message.content
+        ));
+    }
+    prompt.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n");
+    prompt
+}
+
+/// Render a Hugging Face style chat template. Follows the conventions those templates
+/// expect: `trim_blocks`/`lstrip_blocks`, Python string methods and `raise_exception`.
+fn render_jinja(source: &str, eos_token: &str, messages: &[ChatMessage]) -> Result<String, EngineError> {
+    let mut env = minijinja::Environment::new();
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+    env.add_function("raise_exception", |message: String| -> Result<String, minijinja::Error> {
+        Err(minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, message))
+    });
+
+    // The BOS token is added by the tokenizer, so the template must not emit it again
+    env.render_str(source, minijinja::context! {
+        messages => minijinja::Value::from_serialize(messages),
+        add_generation_prompt => true,
+        bos_token => "",
+        eos_token => eos_token,
+    })
+    .map_err(|e| EngineError::Runtime(format!("Failed to render chat template: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::new(Role::System, "Be brief."),
+            ChatMessage::new(Role::User, "Hi"),
+            ChatMessage::new(Role::Assistant, "Hello!"),
+            ChatMessage::new(Role::User, "Who are you?"),
+        ]
+    }
+
+    #[test]
+    fn test_render_chatml() {
+        assert_eq!(
+            ChatTemplate::ChatMl.render(&conversation()).unwrap(),
+            "<|im_start|>system\nBe brief.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\nHello!<|im_end|>\n\
+             <|im_start|>user\nWho are you?<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn test_render_llama2() {
+        assert_eq!(
+            ChatTemplate::Llama2.render(&conversation()).unwrap(),
+            "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello! </s><s>[INST] Who are you? [/INST]"
+        );
+    }
+
+    #[test]
+    fn test_render_jinja() {
+        // Zephyr-style template, in the shape GGUF files ship it
+        let template = ChatTemplate::Jinja {
+            source: "{{ bos_token }}{% for message in messages %}\n\
+                     <|{{ message['role'] }}|>\n{{ message['content'].strip() }}{{ eos_token }}\n\
+                     {% endfor %}\n\
+                     {% if add_generation_prompt %}<|assistant|>\n{% endif %}".to_string(),
+            eos_token: "</s>".to_string(),
+        };
+
+        assert_eq!(
+            template.render(&conversation()[..3]).unwrap(),
+            "<|system|>\nBe brief.</s>\n<|user|>\nHi</s>\n<|assistant|>\nHello!</s>\n<|assistant|>\n"
+        );
+    }
+
+    #[test]
+    fn test_render_jinja_raise_exception() {
+        let template = ChatTemplate::Jinja {
+            source: "{% if messages[0]['role'] == 'system' %}{{ raise_exception('System role not supported') }}{% endif %}".to_string(),
+            eos_token: String::new(),
+        };
+        let err = template.render(&conversation()).unwrap_err();
+        assert!(err.to_string().contains("System role not supported"));
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(ChatTemplate::from_name("ChatML").unwrap(), ChatTemplate::ChatMl);
+        assert!(ChatTemplate::from_name("vicuna").is_err());
+    }
+
+    #[test]
+    fn test_with_system_text() {
+        let merged = with_system_text(&conversation(), "[Facts: user=Divyansh;]");
+        assert_eq!(merged[0].content, "Be brief.\n\n[Facts: user=Divyansh;]");
+        assert_eq!(merged.len(), 4);
+
+        let inserted = with_system_text(&conversation()[1..], "[Facts: user=Divyansh;]");
+        assert_eq!(inserted[0], ChatMessage::new(Role::System, "[Facts: user=Divyansh;]"));
+        assert_eq!(inserted.len(), 4);
+    }
+}
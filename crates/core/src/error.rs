@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -5,15 +6,166 @@ pub enum EngineError {
     #[error("Configuration error: {0}")]
     Config(String),
 
+    /// The request itself is invalid, e.g. an empty conversation or a bad JSON schema.
+    #[error("Validation Error: {0}")]
+    Validation(String),
+
     #[error("Runtime error: {0}")]
     Runtime(String),
 
+    /// A runtime backed by a remote service failed to get an answer from it. `status` is the
+    /// HTTP status the service replied with, or `None` if it couldn't be reached.
+    #[error("Runtime error: {message}")]
+    Upstream { status: Option<u16>, message: String },
+
     #[error("Model not loaded")]
     ModelNotLoaded,
 
+    /// The prompt doesn't fit in the model's context window.
+    #[error("Input length ({tokens}) exceeds context size ({context_size})")]
+    ContextOverflow { tokens: usize, context_size: usize },
+
+    /// The runtime or the loaded model can't do what was asked, e.g. produce embeddings.
+    #[error("Not supported: {0}")]
+    Unsupported(String),
+
+    /// The request ran past its `max_time_ms` and the runtime didn't stop when cancelled.
+    #[error("Request timed out after {0} ms")]
+    Timeout(u64),
+
+    /// Too many requests are already running or waiting; try again later.
+    #[error("Server is busy: the request queue is full (retry in {retry_after_secs}s)")]
+    QueueFull { retry_after_secs: u64 },
+
+    /// The addressed item, e.g. a session, doesn't exist.
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    /// Memory was to be changed while `memory.enabled` is off.
+    #[error("Memory is disabled; set memory.enabled = true in the config or CELA_MEMORY_ENABLED=true")]
+    MemoryDisabled,
+
+    /// Reading or writing persistent memory failed, or a memory limit was hit.
+    #[error("Memory error: {0}")]
+    Memory(String),
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
-    
+
     #[error("Unknown error: {0}")]
     Unknown(String),
 }
+
+impl EngineError {
+    /// The stable, machine-readable code for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            EngineError::Config(_) => ErrorCode::ConfigError,
+            EngineError::Validation(_) => ErrorCode::ValidationError,
+            EngineError::ModelNotLoaded => ErrorCode::ModelNotLoaded,
+            EngineError::ContextOverflow { .. } => ErrorCode::ContextOverflow,
+            EngineError::Unsupported(_) => ErrorCode::Unsupported,
+            EngineError::Timeout(_) => ErrorCode::Timeout,
+            EngineError::QueueFull { .. } => ErrorCode::QueueFull,
+            EngineError::NotFound(_) => ErrorCode::NotFound,
+            EngineError::MemoryDisabled => ErrorCode::MemoryDisabled,
+            EngineError::Memory(_) => ErrorCode::MemoryError,
+            EngineError::Runtime(_) | EngineError::Upstream { .. } | EngineError::Io(_) | EngineError::Unknown(_) => ErrorCode::RuntimeError,
+        }
+    }
+
+    /// Whether sending the same request again later may succeed. Usually that depends only on
+    /// the code, but a remote service may just be unreachable or overloaded for now.
+    pub fn retryable(&self) -> bool {
+        match self {
+            EngineError::Upstream { status: None, .. } => true,
+            EngineError::Upstream { status: Some(status), .. } => matches!(status, 408 | 429 | 500..=599),
+            e => e.code().retryable(),
+        }
+    }
+}
+
+/// Error codes clients can branch on. New codes may be added; existing ones don't change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    ValidationError,
+    /// Missing or invalid API key (server only).
+    Unauthorized,
+    /// The addressed resource doesn't exist.
+    NotFound,
+    ModelNotLoaded,
+    ContextOverflow,
+    Unsupported,
+    Timeout,
+    QueueFull,
+    ConfigError,
+    RuntimeError,
+    /// Memory is turned off in the config, so it can't be changed.
+    MemoryDisabled,
+    MemoryError,
+}
+
+impl ErrorCode {
+    /// Whether sending the same request again later may succeed.
+    pub fn retryable(self) -> bool {
+        matches!(self, ErrorCode::ModelNotLoaded | ErrorCode::Timeout | ErrorCode::QueueFull)
+    }
+}
+
+/// The structured form of an error in `EngineResponse::error_info`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorInfo {
+    pub code: ErrorCode,
+    pub message: String,
+    pub retryable: bool,
+    /// For errors from a remote runtime, the HTTP status its service replied with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_status: Option<u16>,
+}
+
+impl ErrorInfo {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into(), retryable: code.retryable(), upstream_status: None }
+    }
+}
+
+impl From<&EngineError> for ErrorInfo {
+    fn from(e: &EngineError) -> Self {
+        let upstream_status = match e {
+            EngineError::Upstream { status, .. } => *status,
+            _ => None,
+        };
+        Self { retryable: e.retryable(), upstream_status, ..Self::new(e.code(), e.to_string()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_serialize_snake_case() {
+        let info = ErrorInfo::from(&EngineError::ContextOverflow { tokens: 5000, context_size: 4096 });
+        assert_eq!(serde_json::to_value(&info).unwrap(), serde_json::json!({
+            "code": "context_overflow",
+            "message": "Input length (5000) exceeds context size (4096)",
+            "retryable": false,
+        }));
+        assert_eq!(serde_json::to_value(EngineError::ModelNotLoaded.code()).unwrap(), "model_not_loaded");
+    }
+
+    #[test]
+    fn test_retryable() {
+        assert!(EngineError::Timeout(100).code().retryable());
+        assert!(EngineError::QueueFull { retry_after_secs: 1 }.code().retryable());
+        assert!(!EngineError::Runtime("Decode failed".to_string()).code().retryable());
+        assert!(!EngineError::Validation("empty".to_string()).code().retryable());
+
+        let unreachable = EngineError::Upstream { status: None, message: "connection refused".to_string() };
+        assert!(ErrorInfo::from(&unreachable).retryable);
+        let overloaded = ErrorInfo::from(&EngineError::Upstream { status: Some(503), message: "busy".to_string() });
+        assert_eq!((overloaded.code, overloaded.retryable, overloaded.upstream_status), (ErrorCode::RuntimeError, true, Some(503)));
+        assert!(!EngineError::Upstream { status: Some(401), message: "bad key".to_string() }.retryable());
+    }
+}
@@ -0,0 +1,540 @@
+//! Reading the header of GGUF model files: metadata and tensor layout, without loading the
+//! weights. Used to describe the files in `model.models_dir` and to check them for damage.
+//!
+//! A GGUF file starts with the magic `GGUF`, a version (2 or 3 here), the tensor and
+//! metadata counts, then the metadata key/value pairs and a description of each tensor.
+//! The tensor data follows, aligned to `general.alignment` (32 bytes by default).
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use crate::error::EngineError;
+
+pub const MAGIC: &[u8; 4] = b"GGUF";
+const DEFAULT_ALIGNMENT: u64 = 32;
+
+/// A metadata value. Serializes as the plain JSON value.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+}
+
+impl GgufValue {
+    /// The value as an unsigned integer, if it is a non-negative integer.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            GgufValue::U8(v) => Some(v.into()),
+            GgufValue::U16(v) => Some(v.into()),
+            GgufValue::U32(v) => Some(v.into()),
+            GgufValue::U64(v) => Some(v),
+            GgufValue::I8(v) => u64::try_from(v).ok(),
+            GgufValue::I16(v) => u64::try_from(v).ok(),
+            GgufValue::I32(v) => u64::try_from(v).ok(),
+            GgufValue::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TensorInfo {
+    pub name: String,
+    pub dims: Vec<u64>,
+    /// `ggml_type` of the weights.
+    pub ggml_type: u32,
+    /// Offset of the data from the start of the data section.
+    pub offset: u64,
+}
+
+impl TensorInfo {
+    pub fn elements(&self) -> u64 {
+        self.dims.iter().product()
+    }
+
+    /// Size of the data in bytes; `None` for types this doesn't know.
+    pub fn size(&self) -> Option<u64> {
+        let (block_bytes, block_elements) = type_size(self.ggml_type)?;
+        Some(self.elements().div_ceil(block_elements) * block_bytes)
+    }
+}
+
+/// The header of a GGUF file.
+#[derive(Debug, Clone)]
+pub struct GgufFile {
+    pub version: u32,
+    pub metadata: BTreeMap<String, GgufValue>,
+    pub tensors: Vec<TensorInfo>,
+    /// Where the tensor data starts.
+    pub data_offset: u64,
+    pub file_size: u64,
+}
+
+impl GgufFile {
+    /// Read the header of the GGUF file at `path`. Fails with `EngineError::Config` if the
+    /// file isn't GGUF or its header is cut short or malformed.
+    pub fn read(path: &Path) -> Result<Self, EngineError> {
+        let file = File::open(path)
+            .map_err(|e| EngineError::Config(format!("Failed to open {}: {}", path.display(), e)))?;
+        let file_size = file.metadata()?.len();
+        let mut reader = Reader { inner: BufReader::new(file), position: 0, file_size };
+        Self::parse(&mut reader)
+            .map_err(|e| EngineError::Config(format!("{} is not a valid GGUF file: {}", path.display(), e)))
+    }
+
+    fn parse<R: Read>(reader: &mut Reader<R>) -> Result<Self, String> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("it doesn't start with GGUF".to_string());
+        }
+        let version = reader.u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(format!("unsupported version {}", version));
+        }
+        let tensor_count = reader.u64()?;
+        let metadata_count = reader.u64()?;
+
+        let mut metadata = BTreeMap::new();
+        for _ in 0..metadata_count {
+            let key = reader.string()?;
+            let value_type = reader.u32()?;
+            let value = reader.value(value_type).map_err(|e| format!("metadata {}: {}", key, e))?;
+            metadata.insert(key, value);
+        }
+
+        let mut tensors = Vec::new();
+        for _ in 0..tensor_count {
+            let name = reader.string()?;
+            let n_dims = reader.u32()?;
+            if n_dims > 8 {
+                return Err(format!("tensor {} has {} dimensions", name, n_dims));
+            }
+            let dims = (0..n_dims).map(|_| reader.u64()).collect::<Result<_, _>>()?;
+            tensors.push(TensorInfo { name, dims, ggml_type: reader.u32()?, offset: reader.u64()? });
+        }
+
+        let alignment = metadata.get("general.alignment").and_then(GgufValue::as_u64).unwrap_or(DEFAULT_ALIGNMENT);
+        if alignment == 0 {
+            return Err("general.alignment is 0".to_string());
+        }
+        let data_offset = reader.position.div_ceil(alignment) * alignment;
+        Ok(Self { version, metadata, tensors, data_offset, file_size: reader.file_size })
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).and_then(GgufValue::as_str)
+    }
+
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        self.metadata.get(key).and_then(GgufValue::as_u64)
+    }
+
+    pub fn architecture(&self) -> Option<&str> {
+        self.get_str("general.architecture")
+    }
+
+    /// Weights in all tensors together.
+    pub fn parameter_count(&self) -> u64 {
+        self.tensors.iter().map(TensorInfo::elements).sum()
+    }
+
+    /// Weight format from `general.file_type`, e.g. `Q4_K_M`, or `unknown`.
+    pub fn quantization(&self) -> String {
+        self.get_u64("general.file_type")
+            .and_then(|v| u32::try_from(v).ok())
+            .map_or_else(|| "unknown".to_string(), file_type_name)
+    }
+
+    /// Context length the model was trained with, from `<architecture>.context_length`.
+    pub fn context_length(&self) -> Option<u64> {
+        self.get_u64(&format!("{}.context_length", self.architecture()?))
+    }
+
+    /// Memory the KV cache takes for `context_size` tokens at `bytes_per_value` per cached
+    /// value, from the layer count and attention sizes. `None` if the header doesn't give them.
+    pub fn kv_cache_bytes(&self, context_size: u64, bytes_per_value: f64) -> Option<u64> {
+        let architecture = self.architecture()?;
+        let get = |key: &str| self.get_u64(&format!("{}.{}", architecture, key));
+        let layers = get("block_count")?;
+        let embedding = get("embedding_length")?;
+        let heads = get("attention.head_count")?.max(1);
+        // Grouped-query attention caches fewer heads than it attends with
+        let kv_heads = get("attention.head_count_kv").unwrap_or(heads);
+        let values = 2 * layers * context_size * (embedding * kv_heads / heads);
+        Some((values as f64 * bytes_per_value) as u64)
+    }
+
+    /// What's wrong with the tensor layout: data outside the file, misaligned or
+    /// overlapping. Empty if nothing is.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        // Without tensors there is no data section to pad the header out to
+        if self.data_offset > self.file_size && !self.tensors.is_empty() {
+            problems.push(format!("the header ends at byte {} but the file has {}", self.data_offset, self.file_size));
+            return problems;
+        }
+        let alignment = self.get_u64("general.alignment").unwrap_or(DEFAULT_ALIGNMENT);
+        let data_size = self.file_size.saturating_sub(self.data_offset);
+        let mut extents = Vec::new();
+        for tensor in &self.tensors {
+            if tensor.offset % alignment != 0 {
+                problems.push(format!("tensor {} is not aligned to {} bytes", tensor.name, alignment));
+            }
+            let Some(size) = tensor.size() else { continue };
+            match tensor.offset.checked_add(size) {
+                Some(end) if end <= data_size => extents.push((tensor.offset, end, &tensor.name)),
+                _ => problems.push(format!(
+                    "tensor {} ({} bytes at {}) goes past the end of the file; it may be truncated",
+                    tensor.name, size, tensor.offset
+                )),
+            }
+        }
+        extents.sort();
+        for pair in extents.windows(2) {
+            if pair[1].0 < pair[0].1 {
+                problems.push(format!("tensors {} and {} overlap", pair[0].2, pair[1].2));
+            }
+        }
+        problems
+    }
+}
+
+/// A model file described from its header, e.g. for listing `model.models_dir`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelFile {
+    /// `general.name`, or the file stem if the model doesn't set one.
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub architecture: String,
+    pub quantization: String,
+    pub parameter_count: u64,
+    pub context_length: Option<u64>,
+}
+
+impl ModelFile {
+    pub fn read(path: &Path) -> Result<Self, EngineError> {
+        let gguf = GgufFile::read(path)?;
+        let name = gguf.get_str("general.name").map(str::to_string)
+            .or_else(|| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+        Ok(Self {
+            name,
+            path: path.to_path_buf(),
+            size: gguf.file_size,
+            architecture: gguf.architecture().unwrap_or("unknown").to_string(),
+            quantization: gguf.quantization(),
+            parameter_count: gguf.parameter_count(),
+            context_length: gguf.context_length(),
+        })
+    }
+}
+
+/// The `.gguf` files in `dir`, by name; none if the directory doesn't exist.
+pub fn find_models(dir: &Path) -> Result<Vec<PathBuf>, EngineError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(EngineError::Runtime(format!("Failed to read {}: {}", dir.display(), e))),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gguf")) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Name of a GGUF `general.file_type` value (llama.cpp's `llama_ftype`).
+pub fn file_type_name(file_type: u32) -> String {
+    let name = match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        other => return format!("type {}", other),
+    };
+    name.to_string()
+}
+
+/// Bytes per block and elements per block of a `ggml_type`.
+fn type_size(ggml_type: u32) -> Option<(u64, u64)> {
+    Some(match ggml_type {
+        0 => (4, 1),      // F32
+        1 => (2, 1),      // F16
+        2 => (18, 32),    // Q4_0
+        3 => (20, 32),    // Q4_1
+        6 => (22, 32),    // Q5_0
+        7 => (24, 32),    // Q5_1
+        8 => (34, 32),    // Q8_0
+        9 => (36, 32),    // Q8_1
+        10 => (84, 256),  // Q2_K
+        11 => (110, 256), // Q3_K
+        12 => (144, 256), // Q4_K
+        13 => (176, 256), // Q5_K
+        14 => (210, 256), // Q6_K
+        15 => (292, 256), // Q8_K
+        16 => (66, 256),  // IQ2_XXS
+        17 => (74, 256),  // IQ2_XS
+        18 => (98, 256),  // IQ3_XXS
+        19 => (50, 256),  // IQ1_S
+        20 => (18, 32),   // IQ4_NL
+        21 => (110, 256), // IQ3_S
+        22 => (82, 256),  // IQ2_S
+        23 => (136, 256), // IQ4_XS
+        24 => (1, 1),     // I8
+        25 => (2, 1),     // I16
+        26 => (4, 1),     // I32
+        27 => (8, 1),     // I64
+        28 => (8, 1),     // F64
+        29 => (56, 256),  // IQ1_M
+        30 => (2, 1),     // BF16
+        34 => (54, 256),  // TQ1_0
+        35 => (66, 256),  // TQ2_0
+        39 => (17, 32),   // MXFP4
+        _ => return None,
+    })
+}
+
+/// Little-endian reads that keep count of the position, and refuse lengths longer than
+/// what's left of the file rather than allocating them.
+struct Reader<R> {
+    inner: R,
+    position: u64,
+    file_size: u64,
+}
+
+impl<R: Read> Reader<R> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        self.inner.read_exact(buf).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => format!("the header is cut short at byte {}", self.position),
+            _ => e.to_string(),
+        })?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut buf = [0u8; N];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    /// Check that `count` items of at least `item_size` bytes fit in the rest of the file.
+    fn check_fits(&self, count: u64, item_size: u64) -> Result<usize, String> {
+        let left = self.file_size.saturating_sub(self.position);
+        if count.saturating_mul(item_size) > left {
+            return Err(format!("a length of {} at byte {} goes past the end of the file", count, self.position));
+        }
+        usize::try_from(count).map_err(|e| e.to_string())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u64()?;
+        let mut buf = vec![0u8; self.check_fits(len, 1)?];
+        self.read_exact(&mut buf)?;
+        String::from_utf8(buf).map_err(|_| format!("invalid UTF-8 before byte {}", self.position))
+    }
+
+    fn value(&mut self, value_type: u32) -> Result<GgufValue, String> {
+        Ok(match value_type {
+            0 => GgufValue::U8(u8::from_le_bytes(self.bytes()?)),
+            1 => GgufValue::I8(i8::from_le_bytes(self.bytes()?)),
+            2 => GgufValue::U16(u16::from_le_bytes(self.bytes()?)),
+            3 => GgufValue::I16(i16::from_le_bytes(self.bytes()?)),
+            4 => GgufValue::U32(self.u32()?),
+            5 => GgufValue::I32(i32::from_le_bytes(self.bytes()?)),
+            6 => GgufValue::F32(f32::from_le_bytes(self.bytes()?)),
+            7 => GgufValue::Bool(self.bytes::<1>()?[0] != 0),
+            8 => GgufValue::String(self.string()?),
+            9 => {
+                let item_type = self.u32()?;
+                if item_type == 9 {
+                    return Err("nested arrays aren't supported".to_string());
+                }
+                let len = self.u64()?;
+                let mut items = Vec::with_capacity(self.check_fits(len, 1)?);
+                for _ in 0..len {
+                    items.push(self.value(item_type)?);
+                }
+                GgufValue::Array(items)
+            }
+            10 => GgufValue::U64(self.u64()?),
+            11 => GgufValue::I64(i64::from_le_bytes(self.bytes()?)),
+            12 => GgufValue::F64(f64::from_le_bytes(self.bytes()?)),
+            other => return Err(format!("unknown value type {}", other)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend((s.len() as u64).to_le_bytes());
+        out.extend(s.as_bytes());
+    }
+
+    /// A small GGUF file: a name, architecture and file type, and two F32 tensors of
+    /// 4x2 and 3 elements.
+    fn sample() -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(3u32.to_le_bytes());
+        out.extend(2u64.to_le_bytes());
+        out.extend(4u64.to_le_bytes());
+        string(&mut out, "general.name");
+        out.extend(8u32.to_le_bytes());
+        string(&mut out, "Tiny");
+        string(&mut out, "general.architecture");
+        out.extend(8u32.to_le_bytes());
+        string(&mut out, "llama");
+        string(&mut out, "general.file_type");
+        out.extend(4u32.to_le_bytes());
+        out.extend(0u32.to_le_bytes());
+        string(&mut out, "llama.context_length");
+        out.extend(4u32.to_le_bytes());
+        out.extend(4096u32.to_le_bytes());
+        for (name, dims, offset) in [("a", vec![4u64, 2], 0u64), ("b", vec![3], 32)] {
+            string(&mut out, name);
+            out.extend((dims.len() as u32).to_le_bytes());
+            for dim in dims {
+                out.extend(dim.to_le_bytes());
+            }
+            out.extend(0u32.to_le_bytes());
+            out.extend(offset.to_le_bytes());
+        }
+        out.resize(out.len().div_ceil(32) * 32 + 32 + 12, 0);
+        out
+    }
+
+    fn write(dir: &Path, name: &str, bytes: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_read_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write(dir.path(), "tiny.gguf", &sample());
+        let gguf = GgufFile::read(&path).unwrap();
+        assert_eq!(gguf.version, 3);
+        assert_eq!(gguf.get_str("general.name"), Some("Tiny"));
+        assert_eq!(gguf.tensors[0].dims, vec![4, 2]);
+        assert_eq!(gguf.parameter_count(), 11);
+        assert_eq!(gguf.context_length(), Some(4096));
+        assert_eq!(gguf.kv_cache_bytes(1024, 2.0), None);
+        assert!(gguf.problems().is_empty(), "{:?}", gguf.problems());
+
+        let model = ModelFile::read(&path).unwrap();
+        assert_eq!((model.name.as_str(), model.quantization.as_str()), ("Tiny", "F32"));
+        assert_eq!(serde_json::to_value(&gguf.metadata).unwrap()["llama.context_length"], 4096);
+    }
+
+    #[test]
+    fn test_kv_cache_bytes() {
+        // TinyLlama's shape: 22 layers, 2048 wide, 32 heads of which 4 are cached
+        let metadata = [
+            ("general.architecture", GgufValue::String("llama".to_string())),
+            ("llama.block_count", GgufValue::U32(22)),
+            ("llama.embedding_length", GgufValue::U32(2048)),
+            ("llama.attention.head_count", GgufValue::U32(32)),
+            ("llama.attention.head_count_kv", GgufValue::U32(4)),
+        ];
+        let gguf = GgufFile {
+            version: 3,
+            metadata: metadata.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+            tensors: Vec::new(),
+            data_offset: 0,
+            file_size: 0,
+        };
+        assert_eq!(gguf.kv_cache_bytes(2048, 2.0), Some(2 * 22 * 2048 * 256 * 2));
+    }
+
+    #[test]
+    fn test_damaged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = GgufFile::read(&write(dir.path(), "text.gguf", b"hello world")).unwrap_err().to_string();
+        assert!(err.contains("doesn't start with GGUF"), "{}", err);
+
+        let header = sample();
+        let err = GgufFile::read(&write(dir.path(), "cut.gguf", &header[..40])).unwrap_err().to_string();
+        assert!(err.contains("goes past the end of the file") || err.contains("cut short"), "{}", err);
+
+        // The header is intact, but the last tensor's data is missing
+        let path = write(dir.path(), "short.gguf", &header[..header.len() - 4]);
+        let problems = GgufFile::read(&path).unwrap().problems();
+        assert_eq!(problems, vec!["tensor b (12 bytes at 32) goes past the end of the file; it may be truncated"]);
+    }
+
+    #[test]
+    fn test_find_models() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "b.gguf", &sample());
+        write(dir.path(), "a.GGUF", &sample());
+        write(dir.path(), "notes.txt", b"");
+        let names: Vec<_> = find_models(dir.path()).unwrap().iter().map(|p| p.file_name().unwrap().to_owned()).collect();
+        assert_eq!(names, vec!["a.GGUF", "b.gguf"]);
+        assert!(find_models(&dir.path().join("missing")).unwrap().is_empty());
+    }
+}
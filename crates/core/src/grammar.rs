@@ -0,0 +1,302 @@
+//! GBNF grammars for constrained JSON output.
+//!
+//! `JSON_GRAMMAR` accepts any JSON object. `schema_to_grammar` narrows that down to a
+//! JSON Schema, covering the keywords that shape the output: `type` (including type
+//! arrays), `properties` and `required`, `items`, `enum`, `const`, `anyOf`/`oneOf` and
+//! local `$ref`s (`#/$defs/...`, `#/definitions/...`). Other keywords, such as `pattern`,
+//! `format` or length limits, are ignored.
+
+use crate::error::EngineError;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Rules for generic JSON values, shared by every grammar produced here.
+/// A macro so `JSON_GRAMMAR` can be built with `concat!`.
+macro_rules! base_rules {
+    () => {
+        r#"value ::= object | array | string | number | boolean | null
+object ::= "{" ws ( string ":" ws value ( "," ws string ":" ws value )* )? "}" ws
+array ::= "[" ws ( value ( "," ws value )* )? "]" ws
+string ::= "\"" ( [^"\\\x7F\x00-\x1F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} ) )* "\"" ws
+number ::= "-"? ( [0-9] | [1-9] [0-9]{0,15} ) ( "." [0-9]+ )? ( [eE] [-+]? [0-9]{1,16} )? ws
+integer ::= "-"? ( [0-9] | [1-9] [0-9]{0,15} ) ws
+boolean ::= ( "true" | "false" ) ws
+null ::= "null" ws
+ws ::= ( " " | "\n" [ \t]{0,20} )?
+"#
+    };
+}
+
+const BASE_RULES: &str = base_rules!();
+
+/// Any JSON object.
+pub const JSON_GRAMMAR: &str = concat!("root ::= object\n", base_rules!());
+
+/// Convert a JSON Schema document into a GBNF grammar whose root is the schema.
+pub fn schema_to_grammar(schema: &str) -> Result<String, EngineError> {
+    let schema: Value = serde_json::from_str(schema)
+        .map_err(|e| EngineError::Validation(format!("Invalid JSON schema: {}", e)))?;
+
+    let mut converter = Converter { schema: &schema, rules: Vec::new(), refs: HashMap::new() };
+    let root = converter.visit(&schema, "root")?;
+    if root != "root" {
+        converter.rules.insert(0, ("root".to_string(), root));
+    }
+
+    let mut grammar = String::new();
+    for (name, body) in &converter.rules {
+        grammar.push_str(&format!("{} ::= {}\n", name, body));
+    }
+    grammar.push_str(BASE_RULES);
+    Ok(grammar)
+}
+
+struct Converter<'a> {
+    schema: &'a Value,
+    /// Generated rules as (name, body), in the order they were created.
+    rules: Vec<(String, String)>,
+    /// Rule name for each `$ref` seen so far, so recursive schemas terminate.
+    refs: HashMap<String, String>,
+}
+
+impl Converter<'_> {
+    /// Add a rule, renaming it if `name` is taken, and return the name used.
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        let mut unique = name.to_string();
+        let mut n = 1;
+        while self.is_taken(&unique) {
+            unique = format!("{}{}", name, n);
+            n += 1;
+        }
+        self.rules.push((unique.clone(), body));
+        unique
+    }
+
+    fn is_taken(&self, name: &str) -> bool {
+        BASE_RULES.lines().any(|line| line.split(" ::= ").next() == Some(name))
+            || self.rules.iter().any(|(existing, _)| existing == name)
+    }
+
+    /// Return a grammar expression matching `schema`, adding any rules it needs.
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String, EngineError> {
+        let object = match schema {
+            Value::Object(object) => object,
+            Value::Bool(true) => return Ok("value".to_string()),
+            _ => return Err(EngineError::Validation(format!("Unsupported JSON schema at '{}': {}", name, schema))),
+        };
+
+        if let Some(reference) = object.get("$ref").and_then(Value::as_str) {
+            return self.visit_ref(reference);
+        }
+        if let Some(value) = object.get("const") {
+            return Ok(literal(value));
+        }
+        if let Some(values) = object.get("enum").and_then(Value::as_array) {
+            let alternatives: Vec<String> = values.iter().map(literal).collect();
+            return Ok(format!("( {} )", alternatives.join(" | ")));
+        }
+        if let Some(schemas) = object.get("anyOf").or_else(|| object.get("oneOf")).and_then(Value::as_array) {
+            let alternatives = schemas.iter()
+                .enumerate()
+                .map(|(i, alternative)| self.visit(alternative, &format!("{}-{}", name, i)))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(format!("( {} )", alternatives.join(" | ")));
+        }
+
+        match object.get("type") {
+            Some(Value::String(kind)) => self.visit_type(kind, object, name),
+            Some(Value::Array(kinds)) => {
+                let alternatives = kinds.iter()
+                    .map(|kind| match kind.as_str() {
+                        Some(kind) => self.visit_type(kind, object, name),
+                        None => Err(EngineError::Validation(format!("Invalid type in JSON schema at '{}'", name))),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(format!("( {} )", alternatives.join(" | ")))
+            }
+            Some(other) => Err(EngineError::Validation(format!("Invalid type in JSON schema at '{}': {}", name, other))),
+            None if object.contains_key("properties") => self.visit_type("object", object, name),
+            None => Ok("value".to_string()),
+        }
+    }
+
+    fn visit_type(&mut self, kind: &str, schema: &serde_json::Map<String, Value>, name: &str) -> Result<String, EngineError> {
+        match kind {
+            "object" => self.visit_object(schema, name),
+            "array" => {
+                let item = match schema.get("items") {
+                    Some(items) => self.visit(items, &format!("{}-item", name))?,
+                    None => "value".to_string(),
+                };
+                let body = format!(r#""[" ws ( {item} ( "," ws {item} )* )? "]" ws"#, item = item);
+                Ok(self.add_rule(name, body))
+            }
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(kind.to_string()),
+            other => Err(EngineError::Validation(format!("Unsupported type '{}' in JSON schema at '{}'", other, name))),
+        }
+    }
+
+    /// Objects list the required properties first, then the optional ones, each in
+    /// schema order. Properties not in the schema are not allowed.
+    fn visit_object(&mut self, schema: &serde_json::Map<String, Value>, name: &str) -> Result<String, EngineError> {
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return Ok("object".to_string());
+        };
+        let required: Vec<&str> = schema.get("required")
+            .and_then(Value::as_array)
+            .map(|keys| keys.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let mut required_pairs = Vec::new();
+        let mut optional_pairs = Vec::new();
+        for (key, property) in properties {
+            let value = self.visit(property, &format!("{}-{}", name, rule_name(key)))?;
+            let pair = format!(r#"{} ":" ws {}"#, literal(&Value::String(key.clone())), value);
+            if required.contains(&key.as_str()) {
+                required_pairs.push(pair);
+            } else {
+                optional_pairs.push(pair);
+            }
+        }
+
+        let mut body = String::from(r#""{" ws "#);
+        body.push_str(&required_pairs.join(r#" "," ws "#));
+        if required_pairs.is_empty() {
+            // The first property present must not be preceded by a comma
+            let alternatives: Vec<String> = (0..optional_pairs.len())
+                .map(|first| {
+                    let mut alternative = optional_pairs[first].clone();
+                    for pair in &optional_pairs[first + 1..] {
+                        alternative.push_str(&format!(r#" ( "," ws {} )?"#, pair));
+                    }
+                    alternative
+                })
+                .collect();
+            if !alternatives.is_empty() {
+                body.push_str(&format!("( {} )?", alternatives.join(" | ")));
+            }
+        } else {
+            for pair in &optional_pairs {
+                body.push_str(&format!(r#" ( "," ws {} )?"#, pair));
+            }
+        }
+        body.push_str(r#" "}" ws"#);
+
+        Ok(self.add_rule(name, body))
+    }
+
+    fn visit_ref(&mut self, reference: &str) -> Result<String, EngineError> {
+        if let Some(name) = self.refs.get(reference) {
+            return Ok(name.clone());
+        }
+
+        let target = reference.strip_prefix('#')
+            .and_then(|pointer| self.schema.pointer(pointer))
+            .ok_or_else(|| EngineError::Validation(format!("Unresolvable $ref '{}' in JSON schema", reference)))?;
+
+        // Register the rule before visiting the target so recursive references resolve to it
+        let last = reference.rsplit('/').next().unwrap_or(reference);
+        let name = self.add_rule(&format!("ref-{}", rule_name(last)), String::new());
+        self.refs.insert(reference.to_string(), name.clone());
+
+        let body = self.visit(target, &format!("{}-value", name))?;
+        if let Some(rule) = self.rules.iter_mut().find(|(existing, _)| *existing == name) {
+            rule.1 = body;
+        }
+        Ok(name)
+    }
+}
+
+/// A GBNF literal matching `value` serialized as JSON, followed by optional whitespace.
+fn literal(value: &Value) -> String {
+    let json = value.to_string();
+    let escaped = json.replace('\\', "\\\\").replace('"', "\\\"");
+    format!(r#""{}" ws"#, escaped)
+}
+
+/// GBNF rule names may only contain letters, digits and dashes.
+fn rule_name(key: &str) -> String {
+    let name: String = key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    if name.is_empty() { "property".to_string() } else { name }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule<'a>(grammar: &'a str, name: &str) -> &'a str {
+        let prefix = format!("{} ::= ", name);
+        grammar.lines()
+            .find_map(|line| line.strip_prefix(prefix.as_str()))
+            .unwrap_or_else(|| panic!("no rule '{}' in:\n{}", name, grammar))
+    }
+
+    #[test]
+    fn test_json_grammar_has_base_rules() {
+        assert!(JSON_GRAMMAR.starts_with("root ::= object\n"));
+        assert!(JSON_GRAMMAR.ends_with(BASE_RULES));
+    }
+
+    #[test]
+    fn test_object_schema() {
+        let grammar = schema_to_grammar(r#"{
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] } }
+            },
+            "required": ["name", "age"]
+        }"#).unwrap();
+
+        assert_eq!(
+            rule(&grammar, "root"),
+            r#""{" ws "\"name\"" ws ":" ws string "," ws "\"age\"" ws ":" ws integer ( "," ws "\"tags\"" ws ":" ws root-tags )? "}" ws"#
+        );
+        assert_eq!(
+            rule(&grammar, "root-tags"),
+            r#""[" ws ( ( "\"a\"" ws | "\"b\"" ws ) ( "," ws ( "\"a\"" ws | "\"b\"" ws ) )* )? "]" ws"#
+        );
+    }
+
+    #[test]
+    fn test_all_optional_properties() {
+        let grammar = schema_to_grammar(r#"{"properties": {"a": {"type": "null"}, "b": {"type": "boolean"}}}"#).unwrap();
+        assert_eq!(
+            rule(&grammar, "root"),
+            r#""{" ws ( "\"a\"" ws ":" ws null ( "," ws "\"b\"" ws ":" ws boolean )? | "\"b\"" ws ":" ws boolean )? "}" ws"#
+        );
+    }
+
+    #[test]
+    fn test_recursive_ref() {
+        let grammar = schema_to_grammar(r##"{
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": { "children": { "type": "array", "items": { "$ref": "#/$defs/node" } } }
+                }
+            },
+            "$ref": "#/$defs/node"
+        }"##).unwrap();
+
+        assert_eq!(rule(&grammar, "root"), "ref-node");
+        assert_eq!(rule(&grammar, "ref-node"), "ref-node-value");
+        assert!(rule(&grammar, "ref-node-value-children").contains("ref-node ("));
+    }
+
+    #[test]
+    fn test_type_array_and_scalar_root() {
+        let grammar = schema_to_grammar(r#"{"type": ["string", "null"]}"#).unwrap();
+        assert_eq!(rule(&grammar, "root"), "( string | null )");
+    }
+
+    #[test]
+    fn test_invalid_schema() {
+        assert!(schema_to_grammar("{not json").is_err());
+        assert!(schema_to_grammar(r#"{"type": "date"}"#).is_err());
+        assert!(schema_to_grammar(r##"{"$ref": "#/$defs/missing"}"##).is_err());
+    }
+}
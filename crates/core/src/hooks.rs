@@ -0,0 +1,137 @@
+//! Hooks that run around every inference, for adding an application's own processing
+//! without changing the engine: redacting prompts, rewriting outputs, enforcing policies.
+//!
+//! Hooks are added with `Engine::add_hook`. `before_inference` runs in the order the hooks
+//! were added, before memory is injected, and may change the prompt and options.
+//! `after_inference` runs in the opposite order, so the first hook added wraps all the
+//! others, and may change the result before the response is built from it. A hook that
+//! returns an error stops the request there; the error is returned like any other, which
+//! the server turns into an error response.
+
+use async_trait::async_trait;
+use std::sync::Arc;
+use crate::chat::{ChatMessage, Role};
+use crate::error::EngineError;
+use crate::runtime::{InferenceOptions, InferenceResult};
+
+/// A request as hooks see it.
+#[derive(Debug, Clone)]
+pub struct PromptContext {
+    pub request_id: String,
+    /// A completion's prompt; empty for chats.
+    pub prompt: String,
+    /// A chat's messages; empty for completions.
+    pub messages: Vec<ChatMessage>,
+    pub options: InferenceOptions,
+}
+
+impl PromptContext {
+    pub fn completion(request_id: &str, prompt: &str, options: InferenceOptions) -> Self {
+        Self { request_id: request_id.to_string(), prompt: prompt.to_string(), messages: Vec::new(), options }
+    }
+
+    pub fn chat(request_id: &str, messages: &[ChatMessage], options: InferenceOptions) -> Self {
+        Self { request_id: request_id.to_string(), prompt: String::new(), messages: messages.to_vec(), options }
+    }
+
+    pub fn is_chat(&self) -> bool {
+        !self.messages.is_empty()
+    }
+
+    /// The prompt, or the content of every message of a chat, for editing them all alike.
+    pub fn texts_mut(&mut self) -> Vec<&mut String> {
+        if self.is_chat() {
+            self.messages.iter_mut().map(|m| &mut m.content).collect()
+        } else {
+            vec![&mut self.prompt]
+        }
+    }
+
+    /// What the user asked for: the prompt, or a chat's last user message.
+    pub fn query(&self) -> &str {
+        if !self.is_chat() {
+            return &self.prompt;
+        }
+        self.messages.iter().rev()
+            .find(|m| m.role == Role::User)
+            .map_or("", |m| m.content.as_str())
+    }
+}
+
+#[async_trait]
+pub trait RequestHook: Send + Sync {
+    /// Called before the prompt is built. The default implementation does nothing.
+    async fn before_inference(&self, _request: &mut PromptContext) -> Result<(), EngineError> {
+        Ok(())
+    }
+
+    /// Called with the runtime's result before the response is built from it; `request` is
+    /// the request as the `before_inference` hooks left it. Streamed requests hold their
+    /// output back until it has run, and then send it as one piece. The default
+    /// implementation does nothing.
+    async fn after_inference(&self, _request: &PromptContext, _result: &mut InferenceResult) -> Result<(), EngineError> {
+        Ok(())
+    }
+}
+
+/// Trims whitespace from the start and end of the output.
+pub struct TrimWhitespace;
+
+#[async_trait]
+impl RequestHook for TrimWhitespace {
+    async fn after_inference(&self, _request: &PromptContext, result: &mut InferenceResult) -> Result<(), EngineError> {
+        let trimmed = result.text.trim();
+        if trimmed.len() != result.text.len() {
+            result.text = trimmed.to_string();
+        }
+        Ok(())
+    }
+}
+
+pub(crate) async fn before_inference(hooks: &[Arc<dyn RequestHook>], request: &mut PromptContext) -> Result<(), EngineError> {
+    for hook in hooks {
+        hook.before_inference(request).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn after_inference(hooks: &[Arc<dyn RequestHook>], request: &PromptContext, result: &mut InferenceResult) -> Result<(), EngineError> {
+    for hook in hooks.iter().rev() {
+        hook.after_inference(request, result).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{FinishReason, Usage};
+
+    #[tokio::test]
+    async fn test_trim_whitespace() {
+        let request = PromptContext::completion("1", "Hi", InferenceOptions::default());
+        let mut result = InferenceResult { text: "\n  Hello there. \n".to_string(), usage: Usage::default(), finish_reason: FinishReason::Eos, seed: None, logprobs: None };
+        TrimWhitespace.after_inference(&request, &mut result).await.unwrap();
+        assert_eq!(result.text, "Hello there.");
+    }
+
+    #[test]
+    fn test_prompt_context() {
+        let mut completion = PromptContext::completion("1", "Hi", InferenceOptions::default());
+        assert_eq!(completion.query(), "Hi");
+        assert_eq!(completion.texts_mut().len(), 1);
+
+        let messages = vec![
+            ChatMessage::new(Role::System, "Be brief."),
+            ChatMessage::new(Role::User, "Hello"),
+            ChatMessage::new(Role::Assistant, "Hi!"),
+        ];
+        let mut chat = PromptContext::chat("1", &messages, InferenceOptions::default());
+        assert!(chat.is_chat());
+        assert_eq!(chat.query(), "Hello");
+        for text in chat.texts_mut() {
+            text.make_ascii_uppercase();
+        }
+        assert_eq!(chat.messages[0].content, "BE BRIEF.");
+    }
+}
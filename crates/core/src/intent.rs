@@ -0,0 +1,148 @@
+//! Intent classification for requests with `classify_intent` set.
+//!
+//! Before the completion runs, the prompt (or a chat's last user message) is sorted into
+//! one of `intent.labels` by an `IntentClassifier`, and the label is reported in
+//! `EngineResponse::intent`. Two come built in, picked by `intent.classifier`: keyword
+//! matching, and asking the loaded model with its output limited to the labels.
+//! Applications can put their own in with `Engine::with_intent_classifier`.
+
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use crate::config::{IntentConfig, IntentMethod};
+use crate::error::EngineError;
+use crate::runtime::{InferenceOptions, ModelRuntime};
+
+#[async_trait]
+pub trait IntentClassifier: Send + Sync {
+    /// The one of `labels` that fits `text` best, or `None` if none does. `runtime` has the
+    /// model loaded and is free to use; whatever is generated with it is not part of the
+    /// response.
+    async fn classify(&self, text: &str, labels: &[String], runtime: &mut dyn ModelRuntime) -> Result<Option<String>, EngineError>;
+}
+
+/// The classifier `config` asks for.
+pub fn from_config(config: &IntentConfig) -> Box<dyn IntentClassifier> {
+    match config.classifier {
+        IntentMethod::Keywords => Box::new(KeywordClassifier::new(config.keywords.clone())),
+        IntentMethod::Model => Box::new(ModelClassifier),
+    }
+}
+
+/// Picks the label whose keywords cover the most words of the text. Keywords match whole
+/// words, ignoring case; a phrase counts as many times as it has words, so "how are you"
+/// outweighs "how". Keywords without letters or digits, such as `?`, match anywhere and
+/// count once. Ties go to the label listed first.
+pub struct KeywordClassifier {
+    keywords: BTreeMap<String, Vec<String>>,
+}
+
+impl KeywordClassifier {
+    pub fn new(keywords: BTreeMap<String, Vec<String>>) -> Self {
+        Self { keywords }
+    }
+
+    fn score(&self, label: &str, text: &str, text_words: &[String]) -> usize {
+        let Some(keywords) = self.keywords.get(label) else { return 0 };
+        keywords.iter().map(|keyword| {
+            let keyword_words = words(keyword);
+            if keyword_words.is_empty() {
+                usize::from(!keyword.is_empty() && text.contains(keyword.as_str()))
+            } else if text_words.windows(keyword_words.len()).any(|window| window == keyword_words.as_slice()) {
+                keyword_words.len()
+            } else {
+                0
+            }
+        }).sum()
+    }
+}
+
+#[async_trait]
+impl IntentClassifier for KeywordClassifier {
+    async fn classify(&self, text: &str, labels: &[String], _runtime: &mut dyn ModelRuntime) -> Result<Option<String>, EngineError> {
+        let text_words = words(text);
+        let mut best: Option<(&String, usize)> = None;
+        for label in labels {
+            let score = self.score(label, text, &text_words);
+            if score > 0 && best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((label, score));
+            }
+        }
+        Ok(best.map(|(label, _)| label.clone()))
+    }
+}
+
+/// Lowercase words of `text`, split at anything but letters, digits and apostrophes.
+fn words(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Asks the loaded model which label fits, greedily and with a grammar that only lets it
+/// answer with one of them. Runtimes that ignore grammars may answer something else, which
+/// is taken as no intent.
+pub struct ModelClassifier;
+
+impl ModelClassifier {
+    fn prompt(text: &str, labels: &[String]) -> String {
+        format!("Classify the intent of the message below as one of: {}.\n\nMessage: {}\nIntent:", labels.join(", "), text)
+    }
+
+    fn grammar(labels: &[String]) -> String {
+        let choices: Vec<String> = labels.iter()
+            .map(|label| format!("\"{}\"", label.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect();
+        format!("root ::= \" \"? ( {} )\n", choices.join(" | "))
+    }
+}
+
+#[async_trait]
+impl IntentClassifier for ModelClassifier {
+    async fn classify(&self, text: &str, labels: &[String], runtime: &mut dyn ModelRuntime) -> Result<Option<String>, EngineError> {
+        // Every token is at least a character long, so this always fits a label
+        let longest = labels.iter().map(|label| label.chars().count()).max().unwrap_or(0);
+        let options = InferenceOptions {
+            max_tokens: Some(longest as u32 + 1),
+            temperature: Some(0.0),
+            grammar: Some(Self::grammar(labels)),
+            ..InferenceOptions::default()
+        };
+        let result = runtime.infer(&Self::prompt(text, labels), options).await?;
+        let answer = result.text.trim();
+        Ok(labels.iter().find(|label| label.as_str() == answer).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::mock::MockRuntime;
+
+    fn labels() -> Vec<String> {
+        IntentConfig::default().labels
+    }
+
+    async fn classify(classifier: &dyn IntentClassifier, text: &str) -> Option<String> {
+        let mut runtime = MockRuntime::default();
+        classifier.classify(text, &labels(), &mut runtime).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_keyword_classifier() {
+        let classifier = KeywordClassifier::new(IntentConfig::default().keywords);
+        assert_eq!(classify(&classifier, "What is the capital of France?").await.as_deref(), Some("question"));
+        assert_eq!(classify(&classifier, "Write a haiku about autumn").await.as_deref(), Some("command"));
+        assert_eq!(classify(&classifier, "Hey, how are you?").await.as_deref(), Some("chitchat"));
+        assert_eq!(classify(&classifier, "Photosynthesis").await, None);
+        // Whole words only
+        assert_eq!(classify(&classifier, "Somewhat listless").await, None);
+    }
+
+    #[test]
+    fn test_model_classifier_grammar() {
+        let labels = vec!["question".to_string(), "say \"hi\"".to_string()];
+        assert_eq!(ModelClassifier::grammar(&labels), "root ::= \" \"? ( \"question\" | \"say \\\"hi\\\"\" )\n");
+    }
+}
@@ -1,21 +1,152 @@
+pub mod build_info;
 pub mod config;
 pub mod error;
 pub mod runtime;
 pub mod memory;
+pub mod sampling;
+pub mod stop;
+pub mod utf8;
+pub mod chat;
+pub mod grammar;
+pub mod queue;
+pub mod metrics;
+pub mod session;
+pub mod cache;
+pub mod prepared;
+pub mod intent;
+pub mod hooks;
+pub mod builder;
+pub mod gguf;
 
+use std::future::Future;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use crate::config::EngineConfig;
-use crate::error::EngineError;
-use crate::runtime::{ModelRuntime, ModelLoadConfig, InferenceOptions, InferenceResult, InferenceStatus, Usage};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+use tokio::sync::{mpsc, Mutex};
+use tokio_util::sync::CancellationToken;
+use arc_swap::ArcSwap;
+use crate::config::{EngineConfig, MemoryRetrieval};
+use crate::error::{EngineError, ErrorCode, ErrorInfo};
+use crate::runtime::{ModelRuntime, ModelLoadConfig, LoadProgress, LoadReport, ModelInfo, InferenceOptions, InferenceResult, InferenceStatus, FinishReason, Logprobs, OverflowStrategy, TokenChunk, Usage};
 use crate::memory::MemoryManager;
+use crate::chat::{ChatMessage, ChatTemplate, Role};
+use crate::queue::{QueueStats, RequestQueue, Slot};
+use crate::session::SessionManager;
+use crate::cache::ResponseCache;
+use crate::prepared::{PreparedContext, PreparedContexts};
+use crate::intent::IntentClassifier;
+use crate::hooks::{PromptContext, RequestHook};
 use serde::{Deserialize, Serialize};
 
+/// How far past `max_time_ms` a request may run before the engine cancels it, and how long
+/// the runtime then gets to hand back its partial output.
+const TIMEOUT_ALLOWANCE: Duration = Duration::from_millis(500);
+
+/// Context kept free for the reply when trimming session history, if the request doesn't
+/// set `max_tokens`.
+const SESSION_REPLY_RESERVE: u32 = 256;
+
+/// The model `init` loads, from the `model` section of the config.
+fn configured_model(config: &EngineConfig) -> ModelLoadConfig {
+    ModelLoadConfig {
+        model_path: config.model.default_path.clone(),
+        context_size: config.model.default_context_size,
+        gpu_layers: config.model.default_gpu_layers,
+        batch_size: config.model.batch_size,
+        n_threads: config.model.n_threads,
+        n_threads_batch: config.model.n_threads_batch,
+        use_mmap: config.model.use_mmap,
+        use_mlock: config.model.use_mlock,
+        kv_cache_type: config.model.kv_cache_type,
+    }
+}
+
+/// A random 16-digit hex id.
+pub fn random_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
 /// The main entry point for the Local AI Engine.
 pub struct Engine {
-    config: EngineConfig,
+    /// Swapped by `reload_config`, so readers take the current one each time.
+    config: ArcSwap<EngineConfig>,
     runtime: Arc<Mutex<Box<dyn ModelRuntime>>>,
     pub memory: Arc<MemoryManager>,
+    pub sessions: Arc<SessionManager>,
+    /// Texts evaluated ahead of time, in `model.session_dir` (see `prepare_context`).
+    pub prepared: PreparedContexts,
+    /// Bounds how many inference requests run and wait at once.
+    queue: RequestQueue,
+    /// Parent of every in-flight request's cancellation token; see `cancel_all`.
+    cancel_root: std::sync::Mutex<CancellationToken>,
+    load_state: Arc<std::sync::Mutex<LoadState>>,
+    load_config: std::sync::Mutex<ModelLoadConfig>,
+    /// Held while loading or unloading, so model switches happen one at a time.
+    switching: Mutex<()>,
+    /// Set while reloading a model unloaded for being idle; requests wait for that load.
+    waking: AtomicBool,
+    /// The loaded model's own chat template, kept from the load so chat requests don't
+    /// wait on the runtime for it.
+    chat_template: std::sync::Mutex<Option<ChatTemplate>>,
+    /// Responses to deterministic requests; emptied whenever another model is loaded.
+    cache: ResponseCache,
+    /// Sorts prompts into `intent.labels` for requests with `classify_intent`.
+    intent_classifier: Box<dyn IntentClassifier>,
+    /// Run around every inference, in the order they were added (see `hooks`). Shared with
+    /// the tasks of streamed requests.
+    hooks: Vec<Arc<dyn RequestHook>>,
+}
+
+/// What `Engine::reload_config` did, by config key (`memory.max_kv_entries`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ConfigReload {
+    /// Changed and in effect.
+    pub applied: Vec<String>,
+    /// Changed, but only taken up by a restart.
+    pub needs_restart: Vec<String>,
+}
+
+/// Where the engine is with loading its model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum LoadState {
+    NotLoaded,
+    /// `progress` runs from 0.0 to 1.0. Inference requests fail with `ModelNotLoaded` meanwhile.
+    Loading { progress: f32 },
+    Ready,
+    /// Unloaded after `model.idle_unload_secs` without requests; the next one loads it again.
+    Idle,
+    Failed { error: String },
+}
+
+/// Identifies a request across the logs of every stage it goes through.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub id: String,
+    pub received_at: Instant,
+}
+
+impl RequestContext {
+    /// A context with a new random id.
+    pub fn new() -> Self {
+        Self::with_id(random_id())
+    }
+
+    /// A context for a request whose id was assigned elsewhere, e.g. by a proxy.
+    pub fn with_id(id: impl Into<String>) -> Self {
+        Self { id: id.into(), received_at: Instant::now() }
+    }
+
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!("inference", request_id = %self.id)
+    }
+}
+
+impl Default for RequestContext {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// The standard JSON output for all engine requests.
@@ -25,144 +156,2747 @@ pub struct EngineResponse {
     pub intent: Option<String>,
     pub output: OutputContent,
     pub usage: Usage,
+    /// Why generation ended; `status` is derived from it. Unset on errors.
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+    /// The error message. Deprecated: kept for clients of the plain string form and
+    /// will be removed in a future version; use `error_info`.
     pub error: Option<String>,
+    /// Structured error with a stable code, set whenever `error` is.
+    #[serde(default)]
+    pub error_info: Option<ErrorInfo>,
+    /// Name of the model that produced the output.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Sampling seed; pass it back as `seed` to reproduce this output.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Position in the request queue and time spent waiting there.
+    #[serde(default)]
+    pub queue: Option<QueueStats>,
+    /// Id of the request, for correlating it with the logs.
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// The prompt was cut down to fit the context; `usage.prompt_tokens_dropped` says by how much.
+    #[serde(default)]
+    pub prompt_truncated: bool,
+    /// The response was reused from an identical earlier request (see `cache`).
+    #[serde(default)]
+    pub cached: bool,
+    /// Log-probabilities of the output tokens, when the request asked for them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Logprobs>,
+    /// What went into the request, when it asked with `debug`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug: Option<DebugInfo>,
+}
+
+/// What the engine put together for a request (see `InferenceOptions::debug`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DebugInfo {
+    /// The prompt as it went to the runtime: with the system prompt and memory added, the
+    /// chat template applied and, if needed, cut down to fit the context.
+    pub prompt: String,
+    /// The system prompt applied: the request's, `model.system_prompt`, or a chat's own
+    /// system message.
+    pub system_prompt: Option<String>,
+    /// The memory injection in the prompt; empty if there was none.
+    pub memory_injection: String,
+    /// Size of the memory injection in tokens; 0 when the runtime can't count them.
+    pub memory_tokens: u32,
+    /// The options the runtime got, with what the engine fills in (the grammar of
+    /// `response_format`, `context_shift`) set.
+    pub options: InferenceOptions,
+}
+
+/// A request's prompt as it goes to the runtime, and what went into it.
+struct ComposedPrompt {
+    text: String,
+    /// The memory injection in `text`.
+    memory: String,
+    /// Size of the memory injection in tokens.
+    memory_tokens: u32,
+    system: Option<String>,
+}
+
+impl ComposedPrompt {
+    /// `prompt` is `text` as fitted to the context, and `options` the ones it runs with.
+    fn debug_info(&self, prompt: &str, options: &InferenceOptions) -> DebugInfo {
+        DebugInfo {
+            prompt: prompt.to_string(),
+            system_prompt: self.system.clone(),
+            memory_injection: self.memory.clone(),
+            memory_tokens: self.memory_tokens,
+            options: options.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OutputContent {
     pub text: String,
+    /// The parsed output, when JSON was requested via `response_format`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json: Option<serde_json::Value>,
+}
+
+impl EngineResponse {
+    /// An error envelope with empty output and zero usage.
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::from_error_info(ErrorInfo::new(code, message))
+    }
+
+    pub fn from_error(e: &EngineError) -> Self {
+        Self::from_error_info(ErrorInfo::from(e))
+    }
+
+    fn from_error_info(info: ErrorInfo) -> Self {
+        Self {
+            status: "error".to_string(),
+            intent: None,
+            output: OutputContent { text: "".to_string(), json: None },
+            usage: Usage::default(),
+            finish_reason: None,
+            error: Some(info.message.clone()),
+            error_info: Some(info),
+            model: None,
+            seed: None,
+            queue: None,
+            request_id: None,
+            prompt_truncated: false,
+            cached: false,
+            logprobs: None,
+            debug: None,
+        }
+    }
 }
 
 impl Engine {
+    /// Set up an engine, checking its config first (see `EngineBuilder`).
+    pub fn builder() -> builder::EngineBuilder {
+        builder::EngineBuilder::new()
+    }
+
+    /// An engine running `config` on `runtime`, taking the config as it is. `builder`
+    /// checks it first, which turns mistakes into errors at startup.
     pub fn new(config: EngineConfig, runtime: Box<dyn ModelRuntime>) -> Self {
         let memory_config = config.memory.clone();
+        let session_config = config.sessions.clone();
+        let load_config = configured_model(&config);
+        let cache = ResponseCache::new(config.cache.clone());
+        let prepared = PreparedContexts::new(config.model.session_dir.clone());
+        let intent_classifier = intent::from_config(&config.intent);
         Self {
-            config,
+            queue: RequestQueue::new(&config.queue),
+            config: ArcSwap::from_pointee(config),
             runtime: Arc::new(Mutex::new(runtime)),
             memory: Arc::new(MemoryManager::new(memory_config)),
+            sessions: Arc::new(SessionManager::new(session_config)),
+            prepared,
+            cancel_root: std::sync::Mutex::new(CancellationToken::new()),
+            load_state: Arc::new(std::sync::Mutex::new(LoadState::NotLoaded)),
+            load_config: std::sync::Mutex::new(load_config),
+            switching: Mutex::new(()),
+            waking: AtomicBool::new(false),
+            chat_template: std::sync::Mutex::new(None),
+            cache,
+            intent_classifier,
+            hooks: Vec::new(),
+        }
+    }
+
+    /// Run `hook` around every inference, after the hooks added before it (see `hooks`).
+    pub fn add_hook(mut self, hook: Box<dyn RequestHook>) -> Self {
+        self.hooks.push(Arc::from(hook));
+        self
+    }
+
+    /// Classify intents with `classifier` instead of the one `intent.classifier` configures.
+    pub fn with_intent_classifier(mut self, classifier: Box<dyn IntentClassifier>) -> Self {
+        self.intent_classifier = classifier;
+        self
+    }
+
+    pub async fn init(&self) -> Result<LoadReport, EngineError> {
+        self.init_with_progress(Arc::new(|_| {})).await
+    }
+
+    /// Load the configured model, passing loading progress to `progress`, then warm it up
+    /// if `model.warmup` is set. `load_state` follows along.
+    pub async fn init_with_progress(&self, progress: LoadProgress) -> Result<LoadReport, EngineError> {
+        self.load(configured_model(&self.config.load()), progress).await
+    }
+
+    /// Load another model in place of the current one. Requests already running finish
+    /// first, or are cancelled if `cancel_in_flight` is set; new ones get `ModelNotLoaded`
+    /// until the load is done. If the new model fails to load, the old one stays.
+    pub async fn reload_model(&self, load_config: ModelLoadConfig, cancel_in_flight: bool) -> Result<LoadReport, EngineError> {
+        if cancel_in_flight {
+            self.cancel_all();
+        }
+        self.load(load_config, Arc::new(|_| {})).await
+    }
+
+    /// Unload the model, once requests already running have finished.
+    pub async fn unload_model(&self) -> Result<(), EngineError> {
+        let _switching = self.switching.lock().await;
+        self.runtime.lock().await.unload().await?;
+        self.cache.clear();
+        *self.chat_template.lock().unwrap() = None;
+        *self.load_state.lock().unwrap() = LoadState::NotLoaded;
+        metrics::set_model_loaded(false);
+        tracing::info!("Model unloaded");
+        Ok(())
+    }
+
+    async fn load(&self, load_config: ModelLoadConfig, progress: LoadProgress) -> Result<LoadReport, EngineError> {
+        let _switching = self.switching.lock().await;
+        self.load_locked(load_config, progress).await
+    }
+
+    /// `load`, for callers already holding `switching`.
+    async fn load_locked(&self, load_config: ModelLoadConfig, progress: LoadProgress) -> Result<LoadReport, EngineError> {
+        let previous_state = std::mem::replace(&mut *self.load_state.lock().unwrap(), LoadState::Loading { progress: 0.0 });
+        let previous_config = std::mem::replace(&mut *self.load_config.lock().unwrap(), load_config.clone());
+
+        let state = self.load_state.clone();
+        let progress: LoadProgress = Arc::new(move |fraction| {
+            *state.lock().unwrap() = LoadState::Loading { progress: fraction };
+            progress(fraction);
+        });
+
+        let result = self.load_runtime(&load_config, progress).await;
+        *self.load_state.lock().unwrap() = match &result {
+            // Reloading after an idle unload brings back the same model, whose responses stay good
+            Ok(_) if self.waking.load(Ordering::SeqCst) => LoadState::Ready,
+            Ok(_) => {
+                self.cache.clear();
+                LoadState::Ready
+            }
+            // Runtimes keep the old model when a new one fails to load
+            Err(e) if matches!(previous_state, LoadState::Ready | LoadState::Idle) => {
+                tracing::warn!("Failed to load {}, keeping {}: {}", load_config.model_path.display(), previous_config.model_path.display(), e);
+                *self.load_config.lock().unwrap() = previous_config;
+                previous_state
+            }
+            Err(e) => LoadState::Failed { error: e.to_string() },
+        };
+        metrics::set_model_loaded(*self.load_state.lock().unwrap() == LoadState::Ready);
+        result
+    }
+
+    async fn load_runtime(&self, load_config: &ModelLoadConfig, progress: LoadProgress) -> Result<LoadReport, EngineError> {
+        let mut runtime = self.runtime.lock().await;
+
+        let report = runtime.load_with_progress(load_config, progress).await?;
+        // Left alone by an idle unload, as the same model comes back
+        *self.chat_template.lock().unwrap() = runtime.chat_template();
+        tracing::info!(
+            "Model loaded: {} ({}/{} layers offloaded to GPU)",
+            load_config.model_path.display(),
+            report.gpu_layers_offloaded,
+            report.gpu_layers_requested
+        );
+
+        if self.config.load().model.warmup {
+            let options = InferenceOptions { max_tokens: Some(1), reset_context: true, ..InferenceOptions::default() };
+            match runtime.infer("Hello", options).await {
+                Ok(result) => tracing::info!("Warm-up finished in {} ms", result.usage.duration_ms),
+                Err(e) => tracing::warn!("Warm-up inference failed: {}", e),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Requests that may run on the runtime at once (`queue.max_concurrent`).
+    pub fn max_concurrent(&self) -> usize {
+        self.queue.max_concurrent()
+    }
+
+    pub fn load_state(&self) -> LoadState {
+        self.load_state.lock().unwrap().clone()
+    }
+
+    /// Settings of the model loaded (or being loaded); the configured ones before `init`.
+    pub fn load_config(&self) -> ModelLoadConfig {
+        self.load_config.lock().unwrap().clone()
+    }
+
+    pub fn model_path(&self) -> std::path::PathBuf {
+        self.load_config.lock().unwrap().model_path.clone()
+    }
+
+    /// The model files in `model.models_dir`, described from their headers. Files that
+    /// can't be read are skipped with a warning.
+    pub fn available_models(&self) -> Result<Vec<gguf::ModelFile>, EngineError> {
+        let mut models = Vec::new();
+        for path in gguf::find_models(&self.config.load().model.models_dir)? {
+            match gguf::ModelFile::read(&path) {
+                Ok(model) => models.push(model),
+                Err(e) => tracing::warn!("Skipping {}: {}", path.display(), e),
+            }
+        }
+        Ok(models)
+    }
+
+    /// Make sure a request can use the model: load it again if it was unloaded for being
+    /// idle, and turn the request away while any other load is in progress rather than
+    /// have it wait on that. Call this after entering the queue, so `unload_if_idle`
+    /// can't unload the model from under the request.
+    async fn ensure_loaded(&self) -> Result<(), EngineError> {
+        match self.load_state() {
+            LoadState::Idle => {}
+            LoadState::Loading { .. } if self.waking.load(Ordering::SeqCst) => {}
+            LoadState::Loading { .. } => return Err(EngineError::ModelNotLoaded),
+            _ => return Ok(()),
+        }
+
+        // Concurrent requests wait for the first one's reload instead of starting their own
+        let _switching = self.switching.lock().await;
+        if self.load_state() != LoadState::Idle {
+            return Ok(());
+        }
+        tracing::info!("Reloading {} after idle unload", self.model_path().display());
+        self.waking.store(true, Ordering::SeqCst);
+        let result = self.load_locked(self.load_config(), Arc::new(|_| {})).await;
+        self.waking.store(false, Ordering::SeqCst);
+        result.map(|_| ())
+    }
+
+    /// Unload the model if no request has run or waited for `idle`, keeping it ready to be
+    /// loaded again by the next request. Returns whether it did.
+    pub async fn unload_if_idle(&self, idle: Duration) -> Result<bool, EngineError> {
+        let _switching = self.switching.lock().await;
+        {
+            // Checked under the state lock, so a request entering the queue meanwhile
+            // either keeps the model loaded or sees it idle and reloads it
+            let mut state = self.load_state.lock().unwrap();
+            let idle_long_enough = matches!(self.queue.idle_for(), Some(idle_for) if idle_for >= idle);
+            if *state != LoadState::Ready || !idle_long_enough {
+                return Ok(false);
+            }
+            *state = LoadState::Idle;
+        }
+
+        tracing::info!("Unloading {} after {} s without requests", self.model_path().display(), idle.as_secs());
+        if let Err(e) = self.runtime.lock().await.unload().await {
+            *self.load_state.lock().unwrap() = LoadState::Ready;
+            return Err(e);
         }
+        metrics::set_model_loaded(false);
+        Ok(true)
     }
 
-    pub async fn init(&self) -> Result<(), EngineError> {
+    /// Start unloading the model whenever it has been idle for `model.idle_unload_secs`.
+    /// The setting is read on every check, so `reload_config` can set, change or clear it.
+    /// Stops when the engine is dropped.
+    pub fn start_idle_unload(self: &Arc<Self>) {
+        let engine = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                let Some(engine) = engine.upgrade() else {
+                    break;
+                };
+                let idle = engine.config.load().model.idle_unload_secs.map(Duration::from_secs);
+                if let Some(idle) = idle {
+                    if let Err(e) = engine.unload_if_idle(idle).await {
+                        tracing::warn!("Failed to unload idle model: {}", e);
+                    }
+                }
+                drop(engine);
+                // Without a timeout, only to notice one being set
+                let check_every = idle.map_or(Duration::from_secs(1), |idle| idle / 4);
+                tokio::time::sleep(check_every.clamp(Duration::from_millis(100), Duration::from_secs(30))).await;
+            }
+        });
+    }
+
+    /// The config the engine runs with, including changes `reload_config` applied.
+    pub fn config(&self) -> Arc<EngineConfig> {
+        self.config.load_full()
+    }
+
+    /// Apply the settings of `config` that can change while running (`config::RELOADABLE`)
+    /// and report the others that differ, which need a restart. Settings outside the
+    /// engine, such as the logging level, are recorded here for the caller to apply.
+    pub fn reload_config(&self, config: &EngineConfig) -> ConfigReload {
+        let current = self.config.load_full();
+        let (applied, needs_restart) = current.changed_keys(config).into_iter()
+            .partition(|key| config::is_reloadable(key));
+        let mut updated = (*current).clone();
+        updated.apply_reloadable(config);
+        self.memory.set_limits(&updated.memory);
+        self.queue.set_max_queue_depth(updated.queue.max_queue_depth);
+        self.config.store(Arc::new(updated));
+        ConfigReload { applied, needs_restart }
+    }
+
+    /// Cancel every in-flight request. Requests started afterwards are unaffected.
+    pub fn cancel_all(&self) {
+        let mut root = self.cancel_root.lock().unwrap();
+        root.cancel();
+        *root = CancellationToken::new();
+    }
+
+    /// Stop for good: cancel in-flight requests, wait for them to return and unload the
+    /// model, then write out memory changes still held back.
+    pub async fn shutdown(&self) -> Result<(), EngineError> {
+        self.cancel_all();
+        let unloaded = self.runtime.lock().await.unload().await;
+        self.memory.flush().await?;
+        unloaded
+    }
+
+    /// Replace the caller's token with one that is also cancelled by `cancel_all`.
+    fn link_cancellation(&self, options: &mut InferenceOptions) -> CancellationToken {
+        let token = self.cancel_root.lock().unwrap().child_token();
+        let caller = std::mem::replace(&mut options.cancel, token.clone());
+
+        let linked = token.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = caller.cancelled() => linked.cancel(),
+                _ = linked.cancelled() => {}
+            }
+        });
+        token
+    }
+
+    /// Embed each of `texts` with the loaded model. Queues like inference requests do.
+    pub async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, EngineError> {
+        let queued = self.queue.enter()?;
+        self.ensure_loaded().await?;
+        let _slot = queued.wait().await;
+        self.runtime.lock().await.embed(texts).await
+    }
+
+    /// How many tokens `text` takes up as a prompt, e.g. to budget against the context size.
+    /// Memory injection isn't included.
+    pub async fn count_tokens(&self, text: &str) -> Result<usize, EngineError> {
+        self.ensure_loaded().await?;
+        self.runtime.lock().await.count_tokens(text)
+    }
+
+    /// Tokenize `text`, returning the token ids and the text of each token.
+    pub async fn tokenize(&self, text: &str, add_bos: bool) -> Result<(Vec<i32>, Vec<String>), EngineError> {
+        self.ensure_loaded().await?;
+        let runtime = self.runtime.lock().await;
+        let tokens = runtime.tokenize(text, add_bos)?;
+        let pieces = runtime.token_pieces(&tokens)?;
+        Ok((tokens, pieces))
+    }
+
+    /// Metadata of the currently loaded model, if any.
+    pub async fn model_info(&self) -> Option<ModelInfo> {
+        self.runtime.lock().await.model_info()
+    }
+
+    /// Evaluate `text` and save the runtime's state after it as the prepared context `name`,
+    /// replacing any of that name. Requests with `session` set to `name` then start from
+    /// that state instead of evaluating `text`, also after a restart. Queues like inference
+    /// requests do.
+    pub async fn prepare_context(&self, name: &str, text: &str) -> Result<PreparedContext, EngineError> {
+        prepared::validate_name(name)?;
+        if text.trim().is_empty() {
+            return Err(EngineError::Validation("Session text cannot be empty".to_string()));
+        }
+        let queued = self.queue.enter()?;
+        self.ensure_loaded().await?;
+        let _slot = queued.wait().await;
         let mut runtime = self.runtime.lock().await;
-        
-        let load_config = ModelLoadConfig {
-            model_path: self.config.model.default_path.clone(),
-            context_size: self.config.model.default_context_size,
-            gpu_layers: self.config.model.default_gpu_layers,
+
+        // Evaluating the text is all there is to it; nothing is generated
+        let options = InferenceOptions { max_tokens: Some(0), max_time_ms: None, reset_context: true, ..InferenceOptions::default() };
+        let result = runtime.infer(text, options).await?;
+        let dir = self.prepared.dir();
+        std::fs::create_dir_all(dir)
+            .map_err(|e| EngineError::Runtime(format!("Failed to create {}: {}", dir.display(), e)))?;
+        let path = self.prepared.state_path(name);
+        runtime.save_session(&path).await?;
+
+        let context = PreparedContext {
+            name: name.to_string(),
+            text: text.to_string(),
+            model: self.model_path(),
+            tokens: result.usage.input_tokens,
+            size: std::fs::metadata(&path).map_or(0, |m| m.len()),
+            created_at: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs()),
         };
+        self.prepared.add(&context)?;
+        tracing::info!("Prepared session '{}': {} tokens", name, context.tokens);
+        Ok(context)
+    }
 
-        runtime.load(&load_config).await?;
-        Ok(())
+    /// The text of the prepared context `options.session`, if set.
+    fn session_text(&self, options: &InferenceOptions) -> Result<String, EngineError> {
+        match &options.session {
+            Some(name) => Ok(self.prepared.get(name)?.text),
+            None => Ok(String::new()),
+        }
     }
 
-    pub async fn process_request(&self, prompt: &str, options: InferenceOptions) -> Result<EngineResponse, EngineError> {
-        // 1. Get Memory Injection
-        let memory_context = self.memory.get_injection_text().await;
-        
-        // 2. Construct final prompt
-        let final_prompt = if !memory_context.is_empty() {
-            format!("{}{}", memory_context, prompt)
-        } else {
-            prompt.to_string()
+    /// Where the state of the prepared context `options.session` is saved, if set.
+    fn session_state(&self, options: &InferenceOptions) -> Option<std::path::PathBuf> {
+        options.session.as_ref().map(|name| self.prepared.state_path(name))
+    }
+
+    /// The system prompt for a request: its own, else `model.system_prompt`.
+    fn system_prompt(&self, options: &InferenceOptions) -> Option<String> {
+        options.system.clone().or_else(|| self.config.load().model.system_prompt.clone())
+    }
+
+    /// Take a place in the queue, load the model again if it was unloaded for being idle,
+    /// and wait for a slot. Everything that needs the model, measuring the prompt included,
+    /// happens under the slot, so a request is admitted or turned away before it waits on
+    /// the runtime.
+    async fn admit(&self) -> Result<Slot, EngineError> {
+        let queued = self.queue.enter()?;
+        self.ensure_loaded().await?;
+        Ok(queued.wait().instrument(tracing::debug_span!("queue")).await)
+    }
+
+    /// Compose the prompt of `request`, a completion or a chat. With `runtime`, the memory
+    /// injection is measured and cut down to fit; without, it's all of memory, unmeasured,
+    /// which is enough to tell requests apart (see `cache`).
+    async fn compose(&self, request: &PromptContext, runtime: Option<&mut dyn ModelRuntime>) -> Result<ComposedPrompt, EngineError> {
+        match request.is_chat() {
+            true => self.build_chat_prompt(&request.messages, &request.options, runtime).await,
+            false => self.build_prompt(&request.prompt, &request.options, runtime).await,
+        }
+    }
+
+    /// Compose a completion's prompt: the text of the prepared context `options.session`,
+    /// which has to come first for its saved state to apply, then the system prompt and a
+    /// blank line, then the memory injection (if any), then the user prompt.
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn build_prompt(&self, prompt: &str, options: &InferenceOptions, runtime: Option<&mut dyn ModelRuntime>) -> Result<ComposedPrompt, EngineError> {
+        let session_text = self.session_text(options)?;
+        let system = self.system_prompt(options);
+        let system_text = system.as_ref().map_or(String::new(), |system| format!("{}\n\n", system));
+        let before_memory = format!("{}{}", session_text, system_text);
+        let (memory_context, memory_tokens) = self.memory_injection(&format!("{}{}", before_memory, prompt), prompt, options, runtime).await?;
+        Ok(ComposedPrompt { text: format!("{}{}{}", before_memory, memory_context, prompt), memory: memory_context, memory_tokens, system })
+    }
+
+    /// The memory injection for `prompt` from `options.memory_namespace`, cut down to
+    /// `memory.max_injection_tokens` and to what the context has left after the prompt and
+    /// a reply of `options.max_tokens`. Nothing is injected when the prompt already fills
+    /// the context. Runtimes that can't count tokens get the whole injection, unmeasured,
+    /// and so does a request composed without `runtime`.
+    ///
+    /// With `memory.retrieval = "semantic"`, only the facts most similar to `query` are
+    /// injected (see `relevant_facts`).
+    async fn memory_injection(&self, prompt: &str, query: &str, options: &InferenceOptions, runtime: Option<&mut dyn ModelRuntime>) -> Result<(String, u32), EngineError> {
+        let namespace = options.memory_namespace.as_deref().unwrap_or(memory::DEFAULT_NAMESPACE);
+        let Some(runtime) = runtime else {
+            return Ok((self.memory.get_injection_text_ns(namespace).await, 0));
         };
-        
-        // 3. Inference
+        if self.memory.get_injection_text_ns(namespace).await.is_empty() {
+            return Ok((String::new(), 0));
+        }
+        let retrieval = self.config.load().memory.retrieval;
+        let keys = match retrieval {
+            MemoryRetrieval::All => None,
+            MemoryRetrieval::Semantic => self.relevant_facts(namespace, query, &mut *runtime).await?,
+        };
+        let (full, _) = self.memory.get_injection_text_for_ns(namespace, keys.as_deref(), usize::MAX, |_| Ok(0)).await?;
+        if full.is_empty() {
+            return Ok((full, 0));
+        }
+        let prompt_tokens = match runtime.count_tokens(prompt) {
+            Ok(tokens) => tokens,
+            Err(EngineError::Unsupported(_)) => return Ok((full, 0)),
+            Err(e) => return Err(e),
+        };
+
+        let free = self.load_config().context_size
+            .saturating_sub(prompt_tokens + options.max_tokens.unwrap_or(0) as usize);
+        let budget = free.min(self.config.load().memory.max_injection_tokens);
+        let (text, tokens) = self.memory
+            .get_injection_text_for_ns(namespace, keys.as_deref(), budget, |text| runtime.tokenize(text, false).map(|tokens| tokens.len()))
+            .await?;
+        if text.len() < full.len() {
+            tracing::debug!("Cut the memory injection down to {} tokens to fit the context", tokens);
+        }
+        Ok((text, u32::try_from(tokens).unwrap_or(u32::MAX)))
+    }
+
+    /// Cut `prompt` down to what the context has room for next to `options.max_tokens` of
+    /// output, as `options.on_context_overflow` says, and return it with the number of tokens
+    /// dropped. Memory is injected into what's left after the prompt, so an overlong prompt
+    /// has none left to trim. With `OverflowStrategy::Error`, or a runtime that can't
+    /// tokenize, the prompt is passed on as is for the runtime to reject. `runtime` is the
+    /// one the request holds under its slot, so it's tokenized once, after admission.
+    fn fit_to_context(&self, prompt: &str, options: &InferenceOptions, runtime: &dyn ModelRuntime) -> Result<(String, u32), EngineError> {
+        if options.on_context_overflow == OverflowStrategy::Error {
+            return Ok((prompt.to_string(), 0));
+        }
+        let total = match runtime.count_tokens(prompt) {
+            Ok(tokens) => tokens,
+            Err(EngineError::Unsupported(_)) => return Ok((prompt.to_string(), 0)),
+            Err(e) => return Err(e),
+        };
+        let context_size = self.load_config().context_size;
+        let room = context_size.saturating_sub(options.max_tokens.unwrap_or(0) as usize);
+        if total <= room {
+            return Ok((prompt.to_string(), 0));
+        }
+
+        let tokens = runtime.tokenize(prompt, false)?;
+        // BOS and whatever else the runtime adds to a prompt
+        let keep = room.saturating_sub(total.saturating_sub(tokens.len()));
+        if keep == 0 {
+            return Err(EngineError::ContextOverflow { tokens: total, context_size });
+        }
+        let kept = options.on_context_overflow.keep(&tokens, keep);
+        let dropped = tokens.len() - kept.len();
+        tracing::debug!("Dropped {} prompt tokens to fit the context", dropped);
+        Ok((runtime.detokenize(&kept)?, u32::try_from(dropped).unwrap_or(u32::MAX)))
+    }
+
+    /// Keys of the facts in `namespace` worth injecting for `query`: the `memory.top_k` most
+    /// similar to it by embedding, above `memory.min_score`. Facts without an embedding from
+    /// the current model are embedded first, and their embeddings stored. `None` (inject
+    /// every fact) if the runtime can't embed.
+    async fn relevant_facts(&self, namespace: &str, query: &str, runtime: &mut dyn ModelRuntime) -> Result<Option<Vec<String>>, EngineError> {
+        let model = self.model_path().display().to_string();
+        let missing = self.memory.facts_to_embed_ns(namespace, &model).await;
+        let mut texts: Vec<String> = missing.iter().map(|(k, v)| format!("{}: {}", k, v)).collect();
+        texts.push(query.to_string());
+
+        let mut vectors = match runtime.embed(&texts).await {
+            Ok(vectors) if vectors.len() == texts.len() => vectors,
+            Ok(vectors) => return Err(EngineError::Runtime(format!(
+                "Runtime returned {} embeddings for {} texts", vectors.len(), texts.len()
+            ))),
+            Err(EngineError::Unsupported(e)) => {
+                tracing::debug!("Injecting every fact, as semantic retrieval needs embeddings: {}", e);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let query_vector = vectors.pop().expect("one vector per text");
+        for ((key, value), vector) in missing.into_iter().zip(vectors) {
+            let embedding = memory::FactEmbedding { model: model.clone(), vector };
+            self.memory.set_embedding_ns(namespace, &key, &value, embedding).await?;
+        }
+
+        let (top_k, min_score) = {
+            let config = self.config.load();
+            (config.memory.top_k, config.memory.min_score)
+        };
+        Ok(Some(self.memory.similar_facts_ns(namespace, &model, &query_vector, top_k, min_score).await))
+    }
+
+    /// Run a completion. Failures are returned as `Err`; an `Ok` response has status
+    /// `success`, `truncated` or `cancelled`, or `error` if JSON output was requested and
+    /// the model's output isn't valid JSON.
+    ///
+    /// `context` identifies the request in the logs and the response; a new one is made
+    /// if it's `None`.
+    pub async fn process_request(&self, prompt: &str, options: InferenceOptions, context: Option<RequestContext>) -> Result<EngineResponse, EngineError> {
+        let context = context.unwrap_or_default();
+        async {
+            // 1. Hooks
+            let mut request = PromptContext::completion(&context.id, prompt, options);
+            hooks::before_inference(&self.hooks, &mut request).await?;
+
+            // 2. System prompt + memory injection + final prompt, measured once the
+            // request has its slot
+            let draft = self.compose(&request, None).await?;
+
+            // 3. Inference
+            self.run_inference(&draft, &request, &context).await
+        }.instrument(context.span()).await
+    }
+
+    /// Like `process_request`, also sending each piece of text through `tokens` as it is
+    /// generated, so callers can follow the output before the response is complete.
+    pub async fn process_request_with_progress(&self, prompt: &str, options: InferenceOptions, context: Option<RequestContext>, tokens: mpsc::UnboundedSender<TokenChunk>) -> Result<EngineResponse, EngineError> {
+        let context = context.unwrap_or_default();
+        async {
+            let mut request = PromptContext::completion(&context.id, prompt, options);
+            hooks::before_inference(&self.hooks, &mut request).await?;
+            let draft = self.compose(&request, None).await?;
+            self.run_inference_with(&draft, &request, &context, Some(tokens), None).await
+        }.instrument(context.span()).await
+    }
+
+    /// Run completions of `prompts` that share `options` together, through one place in the
+    /// queue and one `ModelRuntime::infer_batch` call, so runtimes that decode several
+    /// sequences at once can. Returns one result per prompt, in order: a prompt that can't
+    /// be prepared (e.g. too long for the context) fails on its own, while an error from
+    /// the runtime fails the whole batch. Hooks run for each prompt, but changes they make
+    /// to the options are ignored, as the prompts run with the same ones.
+    ///
+    /// A prepared context (`options.session`) can't be used: its state is that of a single
+    /// sequence, which a batch has no place to load.
+    pub async fn process_batch(&self, prompts: &[String], options: InferenceOptions, context: Option<RequestContext>) -> Result<Vec<Result<EngineResponse, EngineError>>, EngineError> {
+        if options.session.is_some() {
+            return Err(EngineError::Validation("Sessions can't be used with batches; send each prompt on its own".to_string()));
+        }
+        let context = context.unwrap_or_default();
+        async {
+            let mut results: Vec<Option<Result<EngineResponse, EngineError>>> = prompts.iter().map(|_| None).collect();
+            let mut requests = Vec::new();
+            let mut indices = Vec::new();
+            for (index, prompt) in prompts.iter().enumerate() {
+                let checked = async {
+                    let mut request = PromptContext::completion(&context.id, prompt, options.clone());
+                    hooks::before_inference(&self.hooks, &mut request).await?;
+                    request.options = options.clone();
+                    self.compose(&request, None).await?;
+                    Ok::<_, EngineError>(request)
+                }.await;
+                match checked {
+                    Ok(request) => {
+                        indices.push(index);
+                        requests.push(request);
+                    }
+                    Err(e) => results[index] = Some(Err(e)),
+                }
+            }
+
+            if !requests.is_empty() {
+                let batch = match self.infer_batch(&requests, options).await {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        metrics::record_failure();
+                        return Err(e);
+                    }
+                };
+                for (index, mut result) in indices.into_iter().zip(batch) {
+                    if let Ok(response) = &mut result {
+                        response.request_id = Some(context.id.clone());
+                    }
+                    results[index] = Some(result);
+                }
+            }
+            Ok(results.into_iter().map(|result| result.expect("every prompt has a result")).collect())
+        }.instrument(context.span()).await
+    }
+
+    /// Compose and fit each of `requests` under one place in the queue and run them with
+    /// one `infer_batch` call on the runtime, the way `infer_response` runs `infer`.
+    /// Returns one result per request; one that can't be prepared fails on its own.
+    async fn infer_batch(&self, requests: &[PromptContext], mut options: InferenceOptions) -> Result<Vec<Result<EngineResponse, EngineError>>, EngineError> {
+        let cancel = self.link_cancellation(&mut options);
+        let _cancel_on_drop = cancel.clone().drop_guard();
+        self.apply_defaults(&mut options)?;
+        let wants_json = options.response_format.is_some();
+        let max_time_ms = options.max_time_ms;
+
+        let slot = self.admit().await?;
         let mut runtime = self.runtime.lock().await;
-        let result = runtime.infer(&final_prompt, options).await;
-
-        match result {
-            Ok(inf_result) => {
-                let status_str = match inf_result.status {
-                    InferenceStatus::Success => "success",
-                    InferenceStatus::Truncated => "truncated",
-                    InferenceStatus::Error => "error",
-                }.to_string();
-
-                Ok(EngineResponse {
-                    status: status_str,
-                    intent: None,
-                    output: OutputContent {
-                        text: inf_result.text,
-                    },
-                    usage: inf_result.usage,
-                    error: None,
-                })
+        let mut results: Vec<Option<Result<EngineResponse, EngineError>>> = requests.iter().map(|_| None).collect();
+        let mut prepared = Vec::new();
+        for (index, request) in requests.iter().enumerate() {
+            let composed = match self.compose(request, Some(runtime.as_mut())).await {
+                Ok(composed) => composed,
+                Err(e) => {
+                    results[index] = Some(Err(e));
+                    continue;
+                }
+            };
+            match self.fit_to_context(&composed.text, &options, runtime.as_ref()) {
+                Ok((final_prompt, dropped)) => {
+                    let debug = options.debug.then(|| composed.debug_info(&final_prompt, &options));
+                    prepared.push((index, final_prompt, composed.memory_tokens, dropped, debug));
+                }
+                Err(e) => results[index] = Some(Err(e)),
             }
-            Err(e) => {
-                Ok(EngineResponse {
-                    status: "error".to_string(),
-                    intent: None,
-                    output: OutputContent { text: "".to_string() },
-                    usage: Usage::default(),
-                    error: Some(e.to_string()),
-                })
+        }
+
+        if options.dry_run {
+            for (index, final_prompt, memory_tokens, dropped, debug) in prepared {
+                results[index] = Some(self.dry_run_response(&final_prompt, dropped, debug, runtime.as_ref()).map(|mut response| {
+                    response.usage.memory_tokens = memory_tokens;
+                    response
+                }));
+            }
+        } else if !prepared.is_empty() {
+            let mut intents = Vec::with_capacity(prepared.len());
+            for (index, ..) in &prepared {
+                intents.push(match options.classify_intent {
+                    true => self.classify_intent(requests[*index].query(), runtime.as_mut()).await?,
+                    false => None,
+                });
+            }
+            let final_prompts: Vec<String> = prepared.iter().map(|(_, final_prompt, ..)| final_prompt.clone()).collect();
+            let (inference, timed_out) = with_deadline(runtime.infer_batch(&final_prompts, options), max_time_ms, &cancel).await?;
+            let inference = inference?;
+            if inference.len() != final_prompts.len() {
+                return Err(EngineError::Runtime(format!("Runtime returned {} results for {} prompts", inference.len(), final_prompts.len())));
+            }
+            let model = runtime.model_info().map(|info| info.name);
+            drop(runtime);
+            for ((index, _, memory_tokens, dropped, debug), (mut inf_result, intent)) in prepared.into_iter().zip(inference.into_iter().zip(intents)) {
+                if let Err(e) = hooks::after_inference(&self.hooks, &requests[index], &mut inf_result).await {
+                    metrics::record_failure();
+                    results[index] = Some(Err(e));
+                    continue;
+                }
+                let mut response = build_response(inf_result, timed_out, model.clone(), slot.stats, wants_json);
+                response.intent = intent;
+                response.usage.memory_tokens = memory_tokens;
+                response.debug = debug;
+                response.usage.prompt_tokens_dropped = dropped;
+                response.prompt_truncated = dropped > 0;
+                metrics::record_request(&response.status, &response.usage);
+                results[index] = Some(Ok(response));
             }
         }
+        Ok(results.into_iter().map(|result| result.expect("every prompt has a result")).collect())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use async_trait::async_trait;
+    /// Render a conversation with the configured chat template and run it.
+    /// Memory is injected into the system slot rather than prepended to the prompt.
+    pub async fn process_chat(&self, messages: &[ChatMessage], options: InferenceOptions, context: Option<RequestContext>) -> Result<EngineResponse, EngineError> {
+        let context = context.unwrap_or_default();
+        async {
+            let mut request = PromptContext::chat(&context.id, messages, options);
+            hooks::before_inference(&self.hooks, &mut request).await?;
+            check_messages(&request.messages)?;
+            let draft = self.compose(&request, None).await?;
+            self.run_inference(&draft, &request, &context).await
+        }.instrument(context.span()).await
+    }
 
-    struct MockRuntime;
+    /// Send `message` in a session: the stored history plus `message` is rendered like a
+    /// chat request, and on success both `message` and the reply are added to the history.
+    /// The oldest turns are dropped for good when the prompt would leave less than the
+    /// reply's `max_tokens` free in the context. Messages sent to one session at the same
+    /// time are answered one after the other, each seeing the turns before it.
+    pub async fn process_chat_in_session(&self, session_id: &str, message: &str, options: InferenceOptions, context: Option<RequestContext>) -> Result<EngineResponse, EngineError> {
+        self.session_chat(session_id, message, options, context, None).await
+    }
 
-    #[async_trait]
-    impl ModelRuntime for MockRuntime {
-        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<(), EngineError> {
-            Ok(())
+    /// Like `process_chat_in_session`, also sending each piece of the reply through `tokens`
+    /// as it is generated.
+    pub async fn process_chat_in_session_with_progress(&self, session_id: &str, message: &str, options: InferenceOptions, context: Option<RequestContext>, tokens: mpsc::UnboundedSender<TokenChunk>) -> Result<EngineResponse, EngineError> {
+        self.session_chat(session_id, message, options, context, Some(tokens)).await
+    }
+
+    async fn session_chat(&self, session_id: &str, message: &str, options: InferenceOptions, context: Option<RequestContext>, tokens: Option<mpsc::UnboundedSender<TokenChunk>>) -> Result<EngineResponse, EngineError> {
+        // Held until the reply is stored, so a turn sent meanwhile waits for this one
+        let _turn = self.sessions.lock_turn(session_id).await;
+        let session = self.sessions.get(session_id).await
+            .ok_or_else(|| EngineError::NotFound(format!("Session '{}'", session_id)))?;
+        let mut history = session.messages;
+        history.push(ChatMessage::new(Role::User, message));
+
+        let context = context.unwrap_or_default();
+        let mut dropped = 0;
+        let response = async {
+            let mut request = PromptContext::chat(&context.id, &with_system(session.system.as_deref(), &history), options);
+            hooks::before_inference(&self.hooks, &mut request).await?;
+            check_messages(&request.messages)?;
+            let draft = self.compose(&request, None).await?;
+            self.run_inference_with(&draft, &request, &context, tokens, Some(&mut dropped)).await
+        }.instrument(context.span()).await?;
+
+        if dropped > 0 {
+            tracing::debug!("Dropped the {} oldest messages of session {} to fit the context", dropped, session_id);
+            history.drain(..dropped.min(history.len() - 1));
+        }
+        if response.status != "error" {
+            history.push(ChatMessage::new(Role::Assistant, response.output.text.clone()));
+            self.sessions.set_messages(session_id, history).await?;
         }
+        Ok(response)
+    }
 
-        async fn infer(&mut self, prompt: &str, _options: InferenceOptions) -> Result<InferenceResult, EngineError> {
-            Ok(InferenceResult {
-                text: format!("Mock response to: {}", prompt),
-                usage: Usage {
-                    input_tokens: 5,
-                    output_tokens: 10,
-                    total_tokens: 15,
-                    duration_ms: 10,
-                },
-                status: InferenceStatus::Success,
-            })
+    /// Drop the oldest turns of `messages`, after their system message if any, until the
+    /// rendered prompt leaves room for the reply's `max_tokens` in the context, always
+    /// keeping the last message. Returns how many messages were dropped. Runtimes that
+    /// can't count tokens get the history untrimmed.
+    ///
+    /// Each message is tokenized once, and turns are dropped by their summed counts; the
+    /// whole prompt is only counted again to check where that says it fits.
+    fn trim_history(&self, messages: &mut Vec<ChatMessage>, options: &InferenceOptions, runtime: &dyn ModelRuntime) -> Result<usize, EngineError> {
+        let reply_tokens = options.max_tokens.unwrap_or(SESSION_REPLY_RESERVE) as usize;
+        let budget = self.load_config().context_size.saturating_sub(reply_tokens);
+        let template = self.chat_template()?;
+        let prompt_tokens = |messages: &[ChatMessage]| -> Result<Option<usize>, EngineError> {
+            let (messages, _) = self.with_system_prompt(messages, options)?;
+            match runtime.count_tokens(&template.render(&messages)?) {
+                Ok(tokens) => Ok(Some(tokens)),
+                Err(EngineError::Unsupported(_)) => Ok(None),
+                Err(e) => Err(e),
+            }
+        };
+        let Some(mut total) = prompt_tokens(messages)? else {
+            return Ok(0);
+        };
+        if total <= budget {
+            return Ok(0);
         }
 
-        async fn unload(&mut self) -> Result<(), EngineError> {
-            Ok(())
+        // Each message's text, plus its share of the template's markup
+        let counts = messages.iter().map(|m| runtime.count_tokens(&m.content)).collect::<Result<Vec<_>, _>>()?;
+        let markup = total.saturating_sub(counts.iter().sum()) / messages.len();
+        let start = usize::from(messages[0].role == Role::System);
+        let mut dropped = 0;
+        while messages.len() - start - dropped > 1 && total > budget {
+            // A whole turn at a time, so the history still starts with a user message
+            total = total.saturating_sub(counts[start + dropped] + markup);
+            dropped += 1;
+            if messages.len() - start - dropped > 1 && messages[start + dropped].role == Role::Assistant {
+                total = total.saturating_sub(counts[start + dropped] + markup);
+                dropped += 1;
+            }
+            if total <= budget {
+                let kept = [&messages[..start], &messages[start + dropped..]].concat();
+                total = prompt_tokens(&kept)?.unwrap_or(0);
+            }
         }
+        messages.drain(start..start + dropped);
+        Ok(dropped)
     }
 
-    #[tokio::test]
-    async fn test_engine_flow() {
-        let config = EngineConfig::default();
-        let runtime = MockRuntime;
-        let engine = Engine::new(config, Box::new(runtime));
+    /// `messages` with the system prompt first, unless they start with a system message,
+    /// which is kept instead of `model.system_prompt`; and that system prompt.
+    fn with_system_prompt(&self, messages: &[ChatMessage], options: &InferenceOptions) -> Result<(Vec<ChatMessage>, Option<String>), EngineError> {
+        check_messages(messages)?;
+        let has_system = messages[0].role == Role::System;
+        if has_system && options.system.is_some() {
+            return Err(EngineError::Validation("Give the system prompt either as `system` or as a system message, not both".to_string()));
+        }
+        Ok(match self.system_prompt(options) {
+            Some(system) if !has_system => (chat::with_system_text(messages, &system), Some(system)),
+            _ => (messages.to_vec(), has_system.then(|| messages[0].content.clone())),
+        })
+    }
 
-        let response = engine.process_request("Hello", InferenceOptions::default()).await.unwrap();
-        assert_eq!(response.status, "success");
-        // Verify prompt pass-through
-        assert_eq!(response.output.text, "Mock response to: Hello");
+    /// The configured chat template, then the one embedded in the model, then the generic one.
+    fn chat_template(&self) -> Result<ChatTemplate, EngineError> {
+        let configured = self.config.load().model.chat_template.clone();
+        match configured {
+            Some(name) => ChatTemplate::from_name(&name),
+            None => Ok(self.chat_template.lock().unwrap().clone().unwrap_or(ChatTemplate::DEFAULT)),
+        }
     }
 
-    #[tokio::test]
-    async fn test_memory_injection() {
-        let mut config = EngineConfig::default();
-        config.memory.enabled = true;
-        // memory.json path? Use a temp file or defaults (but default path might not be writable in some CI)
-        // For test, we trust default logic but we can manually set fact.
-        
-        let runtime = MockRuntime;
-        let engine = Engine::new(config, Box::new(runtime));
-        
-        // Inject fact
-        engine.memory.set_fact("user", "Divyansh").await.unwrap();
-        
-        // Run inference
-        let response = engine.process_request("Who am I?", InferenceOptions::default()).await.unwrap();
-        
-        // MockRuntime echoes the prompt. The prompt should now contain the injection.
-        // Expected: "Mock response to: [Facts: user=Divyansh;]\n\nWho am I?"
-        assert!(response.output.text.contains("user=Divyansh"));
+    /// Render `messages` with the system prompt, and after it the memory injection, in the
+    /// system slot. A conversation that starts with a system message keeps it instead of
+    /// `model.system_prompt`.
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn build_chat_prompt(&self, messages: &[ChatMessage], options: &InferenceOptions, runtime: Option<&mut dyn ModelRuntime>) -> Result<ComposedPrompt, EngineError> {
+        let (messages, system) = self.with_system_prompt(messages, options)?;
+        let messages = messages.as_slice();
+        // The text would go before the chat template's markup
+        if options.session.is_some() {
+            return Err(EngineError::Validation("Sessions can only be used with completions, not chat".to_string()));
+        }
+
+        let template = self.chat_template()?;
+
+        // Facts are picked by what the user last said, not by the whole conversation
+        let query = messages.iter().rev()
+            .find(|m| m.role == Role::User)
+            .map_or("", |m| m.content.as_str());
+        let (memory_context, memory_tokens) = self.memory_injection(&template.render(messages)?, query, options, runtime).await?;
+        let memory = memory_context.trim_end().to_string();
+        let messages = chat::with_system_text(messages, &memory);
+        Ok(ComposedPrompt { text: template.render(&messages)?, memory, memory_tokens, system })
+    }
+
+    /// Run `request` as the `before_inference` hooks left it. `draft` is its prompt with
+    /// all of memory injected, as composed before it has a slot; it's what the response is
+    /// cached by.
+    async fn run_inference(&self, draft: &ComposedPrompt, request: &PromptContext, context: &RequestContext) -> Result<EngineResponse, EngineError> {
+        self.run_inference_with(draft, request, context, None, None).await
     }
-}
\ No newline at end of file
+
+    /// Like `run_inference`, sending the output through `tokens` as it is generated. With
+    /// `history_dropped`, `request` is a session's chat: its oldest turns are dropped to fit
+    /// the context, and how many messages were is put there.
+    async fn run_inference_with(&self, draft: &ComposedPrompt, request: &PromptContext, context: &RequestContext, tokens: Option<mpsc::UnboundedSender<TokenChunk>>, history_dropped: Option<&mut usize>) -> Result<EngineResponse, EngineError> {
+        let options = request.options.clone();
+        let cache_key = self.cache.key(&draft.text, &options);
+        if let Some(mut response) = cache_key.and_then(|key| self.cache.get(key)) {
+            if let Some(tx) = tokens {
+                let _ = tx.send(TokenChunk::Token { text: response.output.text.clone() });
+            }
+            response.request_id = Some(context.id.clone());
+            return Ok(response);
+        }
+
+        let mut result = self.infer_response(request, options, tokens, history_dropped).await;
+        match &mut result {
+            Ok(response) => {
+                if !request.options.dry_run {
+                    metrics::record_request(&response.status, &response.usage);
+                }
+                tracing::debug!(
+                    "Finished with status {} in {} ms: {} prompt tokens, {} generated",
+                    response.status, response.usage.duration_ms, response.usage.input_tokens, response.usage.output_tokens
+                );
+                if let Some(key) = cache_key {
+                    self.cache.put(key, response);
+                }
+                response.request_id = Some(context.id.clone());
+            }
+            Err(_) => metrics::record_failure(),
+        }
+        result
+    }
+
+    async fn infer_response(&self, request: &PromptContext, mut options: InferenceOptions, tokens: Option<mpsc::UnboundedSender<TokenChunk>>, history_dropped: Option<&mut usize>) -> Result<EngineResponse, EngineError> {
+        // Dropping this future (e.g. the HTTP client went away) cancels the generation too
+        let cancel = self.link_cancellation(&mut options);
+        let _cancel_on_drop = cancel.clone().drop_guard();
+        self.apply_defaults(&mut options)?;
+        let wants_json = options.response_format.is_some();
+        let max_time_ms = options.max_time_ms;
+
+        let slot = self.admit().await?;
+        let mut runtime = self.runtime.lock().await;
+        let mut trimmed;
+        let request = match history_dropped {
+            Some(history_dropped) => {
+                trimmed = request.clone();
+                *history_dropped = self.trim_history(&mut trimmed.messages, &options, runtime.as_ref())?;
+                &trimmed
+            }
+            None => request,
+        };
+        let composed = self.compose(request, Some(runtime.as_mut())).await?;
+        let (final_prompt, dropped) = self.fit_to_context(&composed.text, &options, runtime.as_ref())?;
+        let final_prompt = final_prompt.as_str();
+        let debug = options.debug.then(|| composed.debug_info(final_prompt, &options));
+        if options.dry_run {
+            let mut response = self.dry_run_response(final_prompt, dropped, debug, runtime.as_ref())?;
+            response.usage.memory_tokens = composed.memory_tokens;
+            return Ok(response);
+        }
+
+        // Before loading the session, as the classifier may use the model too
+        let intent = match options.classify_intent {
+            true => self.classify_intent(request.query(), runtime.as_mut()).await?,
+            false => None,
+        };
+        if let Some(path) = self.session_state(&options) {
+            runtime.load_session(&path).await?;
+        }
+        // Hooks may rewrite the output, so with any added it's only sent once they have run
+        let inference = match &tokens {
+            Some(tx) if self.hooks.is_empty() => runtime.infer_stream(final_prompt, options, tx.clone()),
+            _ => runtime.infer(final_prompt, options),
+        };
+        let (result, timed_out) = with_deadline(inference, max_time_ms, &cancel).await?;
+        let model = runtime.model_info().map(|info| info.name);
+        drop(runtime);
+        let mut inf_result = result?;
+        hooks::after_inference(&self.hooks, request, &mut inf_result).await?;
+        if let Some(tx) = tokens.filter(|_| !self.hooks.is_empty()) {
+            send_whole(&tx, TokenChunk::done(&inf_result), &inf_result.text);
+        }
+        let mut response = build_response(inf_result, timed_out, model, slot.stats, wants_json);
+        response.intent = intent;
+        response.usage.memory_tokens = composed.memory_tokens;
+        response.usage.prompt_tokens_dropped = dropped;
+        response.prompt_truncated = dropped > 0;
+        response.debug = debug;
+        Ok(response)
+    }
+
+    /// Fill in what the engine decides for a request: the grammar of `response_format`, and
+    /// `model.context_shift` when `context_shift` is unset.
+    fn apply_defaults(&self, options: &mut InferenceOptions) -> Result<(), EngineError> {
+        apply_response_format(options)?;
+        options.context_shift.get_or_insert(self.config.load().model.context_shift);
+        Ok(())
+    }
+
+    /// The response to a dry run of `final_prompt`: no output, and the prompt's size in
+    /// `usage.input_tokens` when the runtime can count it.
+    fn dry_run_response(&self, final_prompt: &str, dropped: u32, debug: Option<DebugInfo>, runtime: &dyn ModelRuntime) -> Result<EngineResponse, EngineError> {
+        let input_tokens = match runtime.count_tokens(final_prompt) {
+            Ok(tokens) => u32::try_from(tokens).unwrap_or(u32::MAX),
+            Err(EngineError::Unsupported(_)) => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(EngineResponse {
+            status: "dry_run".to_string(),
+            intent: None,
+            output: OutputContent { text: String::new(), json: None },
+            usage: Usage { input_tokens, total_tokens: input_tokens, prompt_tokens_dropped: dropped, ..Usage::default() },
+            finish_reason: None,
+            error: None,
+            error_info: None,
+            model: runtime.model_info().map(|info| info.name),
+            seed: None,
+            queue: None,
+            request_id: None,
+            prompt_truncated: dropped > 0,
+            cached: false,
+            logprobs: None,
+            debug,
+        })
+    }
+
+    /// The intent of `text` by the configured classifier, logged and counted.
+    async fn classify_intent(&self, text: &str, runtime: &mut dyn ModelRuntime) -> Result<Option<String>, EngineError> {
+        let labels = self.config.load().intent.labels.clone();
+        if labels.is_empty() {
+            return Ok(None);
+        }
+        let intent = self.intent_classifier.classify(text, &labels, runtime).await?;
+        tracing::info!("Intent: {}", intent.as_deref().unwrap_or("none"));
+        metrics::record_intent(intent.as_deref());
+        Ok(intent)
+    }
+
+    /// Like `process_request`, but returns a channel yielding `TokenChunk`s as they are generated.
+    /// The last item is either `TokenChunk::Done` or `TokenChunk::Error`. Returns once the
+    /// request has its slot in the queue and its prompt is ready. With any hooks added, the
+    /// output comes as one chunk once the `after_inference` hooks have run on it.
+    pub async fn process_request_stream(&self, prompt: &str, options: InferenceOptions) -> Result<mpsc::UnboundedReceiver<TokenChunk>, EngineError> {
+        let mut request = PromptContext::completion(&random_id(), prompt, options);
+        hooks::before_inference(&self.hooks, &mut request).await?;
+        self.stream_inference(request).await
+    }
+
+    /// Like `process_chat`, but streams the reply as `process_request_stream` does.
+    pub async fn process_chat_stream(&self, messages: &[ChatMessage], options: InferenceOptions) -> Result<mpsc::UnboundedReceiver<TokenChunk>, EngineError> {
+        let mut request = PromptContext::chat(&random_id(), messages, options);
+        hooks::before_inference(&self.hooks, &mut request).await?;
+        check_messages(&request.messages)?;
+        self.stream_inference(request).await
+    }
+
+    async fn stream_inference(&self, request: PromptContext) -> Result<mpsc::UnboundedReceiver<TokenChunk>, EngineError> {
+        let mut options = request.options.clone();
+        // A stream has no response to report it in
+        if options.classify_intent {
+            return Err(EngineError::Validation("classify_intent can't be used with streaming".to_string()));
+        }
+        if options.debug || options.dry_run {
+            return Err(EngineError::Validation("debug and dry_run can't be used with streaming".to_string()));
+        }
+        // Rejects what composing would before taking a place in the queue
+        self.compose(&request, None).await?;
+        self.apply_defaults(&mut options)?;
+        let slot = self.admit().await?;
+        let mut runtime = self.runtime.clone().lock_owned().await;
+        let composed = self.compose(&request, Some(runtime.as_mut())).await?;
+        let memory_tokens = composed.memory_tokens;
+        let (final_prompt, dropped) = self.fit_to_context(&composed.text, &options, runtime.as_ref())?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let cancel = self.link_cancellation(&mut options);
+        let max_time_ms = options.max_time_ms;
+        let session_state = self.session_state(&options);
+        let hooks = self.hooks.clone();
+
+        tokio::spawn(async move {
+            let _cancel_on_drop = cancel.clone().drop_guard();
+            let _slot = slot;
+            if let Some(path) = session_state {
+                if let Err(e) = runtime.load_session(&path).await {
+                    metrics::record_failure();
+                    let _ = tx.send(TokenChunk::Error { message: e.to_string() });
+                    return;
+                }
+            }
+            let with_usage = |mut chunk: TokenChunk| {
+                if let TokenChunk::Done { usage, .. } = &mut chunk {
+                    usage.memory_tokens = memory_tokens;
+                    usage.prompt_tokens_dropped = dropped;
+                }
+                chunk
+            };
+            let outcome = if hooks.is_empty() {
+                let (runtime_tx, mut runtime_rx) = mpsc::unbounded_channel();
+                let inference = runtime.infer_stream(&final_prompt, options, runtime_tx);
+                // Pass the chunks on, adding the memory injection to the final usage. Stopping
+                // when the receiver is gone lets the runtime notice it too.
+                let forward = async {
+                    while let Some(chunk) = runtime_rx.recv().await {
+                        if tx.send(with_usage(chunk)).is_err() {
+                            break;
+                        }
+                    }
+                };
+                let (outcome, ()) = tokio::join!(with_deadline(inference, max_time_ms, &cancel), forward);
+                outcome
+            } else {
+                // Hooks may rewrite the output, so it's generated whole and sent once they have run
+                let outcome = with_deadline(runtime.infer(&final_prompt, options), max_time_ms, &cancel).await;
+                drop(runtime);
+                match outcome {
+                    Ok((Ok(mut result), timed_out)) => match hooks::after_inference(&hooks, &request, &mut result).await {
+                        Ok(()) => {
+                            send_whole(&tx, with_usage(TokenChunk::done(&result)), &result.text);
+                            Ok((Ok(result), timed_out))
+                        }
+                        Err(e) => Ok((Err(e), timed_out)),
+                    },
+                    outcome => outcome,
+                }
+            };
+            match outcome {
+                Ok((Ok(result), timed_out)) => metrics::record_request(status_name(&finish_reason(&result, timed_out).status()), &result.usage),
+                Ok((Err(e), _)) | Err(e) => {
+                    metrics::record_failure();
+                    let _ = tx.send(TokenChunk::Error { message: e.to_string() });
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+}
+
+/// Send `text` as one token, if there is any, and then `done`: the output of a stream whose
+/// hooks had to see it whole first.
+fn send_whole(tx: &mpsc::UnboundedSender<TokenChunk>, done: TokenChunk, text: &str) {
+    if !text.is_empty() {
+        let _ = tx.send(TokenChunk::Token { text: text.to_string() });
+    }
+    let _ = tx.send(done);
+}
+
+/// A chat needs a message to reply to; without any, it would be taken for a completion.
+fn check_messages(messages: &[ChatMessage]) -> Result<(), EngineError> {
+    match messages.is_empty() {
+        true => Err(EngineError::Validation("Chat request has no messages".to_string())),
+        false => Ok(()),
+    }
+}
+
+/// Run `inference`, cancelling it through `cancel` once it is `TIMEOUT_ALLOWANCE` past
+/// `max_time_ms`. Returns its output and whether the deadline was hit, or `Timeout` if it
+/// still hasn't returned `TIMEOUT_ALLOWANCE` after being cancelled.
+async fn with_deadline<T>(inference: impl Future<Output = T>, max_time_ms: Option<u64>, cancel: &CancellationToken) -> Result<(T, bool), EngineError> {
+    let Some(max_time_ms) = max_time_ms else {
+        return Ok((inference.await, false));
+    };
+    tokio::pin!(inference);
+
+    let limit = Duration::from_millis(max_time_ms) + TIMEOUT_ALLOWANCE;
+    if let Ok(output) = tokio::time::timeout(limit, &mut inference).await {
+        return Ok((output, false));
+    }
+
+    tracing::warn!("Request exceeded max_time_ms ({} ms); cancelling it", max_time_ms);
+    cancel.cancel();
+    match tokio::time::timeout(TIMEOUT_ALLOWANCE, inference).await {
+        Ok(output) => Ok((output, true)),
+        Err(_) => Err(EngineError::Timeout(max_time_ms)),
+    }
+}
+
+/// The response to a finished inference; an `error` one if JSON was asked for and the
+/// output doesn't parse.
+fn build_response(inf_result: InferenceResult, timed_out: bool, model: Option<String>, queue: QueueStats, wants_json: bool) -> EngineResponse {
+    let finish_reason = finish_reason(&inf_result, timed_out);
+    let mut response = EngineResponse {
+        status: status_name(&finish_reason.status()).to_string(),
+        intent: None,
+        output: OutputContent {
+            text: inf_result.text,
+            json: None,
+        },
+        usage: inf_result.usage,
+        finish_reason: Some(finish_reason),
+        error: None,
+        error_info: None,
+        model,
+        seed: inf_result.seed,
+        queue: Some(queue),
+        request_id: None,
+        prompt_truncated: false,
+        cached: false,
+        logprobs: inf_result.logprobs,
+        debug: None,
+    };
+
+    if wants_json {
+        match serde_json::from_str(response.output.text.trim()) {
+            Ok(value) => response.output.json = Some(value),
+            Err(e) => {
+                let info = ErrorInfo::new(ErrorCode::RuntimeError, format!("Output is not valid JSON: {}", e));
+                response.status = "error".to_string();
+                response.error = Some(info.message.clone());
+                response.error_info = Some(info);
+            }
+        }
+    }
+    response
+}
+
+/// `history` with the system message, if any, in front.
+fn with_system(system: Option<&str>, history: &[ChatMessage]) -> Vec<ChatMessage> {
+    system.map(|text| ChatMessage::new(Role::System, text))
+        .into_iter()
+        .chain(history.iter().cloned())
+        .collect()
+}
+
+/// Why a finished inference ended, as far as the engine can tell.
+fn finish_reason(result: &InferenceResult, timed_out: bool) -> FinishReason {
+    match &result.finish_reason {
+        // Stopped by the engine's deadline rather than by the caller
+        FinishReason::Cancelled if timed_out => FinishReason::Time,
+        reason => reason.clone(),
+    }
+}
+
+/// The `EngineResponse::status` for a finished inference.
+fn status_name(status: &InferenceStatus) -> &'static str {
+    match status {
+        InferenceStatus::Success => "success",
+        InferenceStatus::Truncated => "truncated",
+        InferenceStatus::Cancelled => "cancelled",
+        InferenceStatus::Error => "error",
+    }
+}
+
+/// Fill in `options.grammar` from `options.response_format`, if one was requested.
+fn apply_response_format(options: &mut InferenceOptions) -> Result<(), EngineError> {
+    if let Some(format) = &options.response_format {
+        options.grammar = Some(format.grammar()?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{KvCacheType, ResponseFormat, TokenLogprob};
+    use async_trait::async_trait;
+
+    struct MockRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for MockRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            Ok(InferenceResult {
+                text: format!("Mock response to: {}", prompt),
+                usage: Usage {
+                    input_tokens: 5,
+                    output_tokens: 10,
+                    total_tokens: 15,
+                    cached_tokens: 0,
+                    memory_tokens: 0,
+                    duration_ms: 10,
+                    ..Usage::default()
+                },
+                finish_reason: FinishReason::Eos,
+                seed: options.seed,
+                logprobs: options.logprobs.map(|_| Logprobs {
+                    content: vec![TokenLogprob { token: "Mock".to_string(), logprob: -0.5, bytes: b"Mock".to_vec(), top_logprobs: Vec::new() }],
+                }),
+            })
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_error_response_without_error_info_still_parses() {
+        let legacy = r#"{"status":"error","intent":null,"output":{"text":""},
+            "usage":{"input_tokens":0,"output_tokens":0,"total_tokens":0,"duration_ms":0},
+            "error":"Runtime error: Decode failed"}"#;
+        let response: EngineResponse = serde_json::from_str(legacy).unwrap();
+        assert_eq!(response.error.as_deref(), Some("Runtime error: Decode failed"));
+        assert!(response.error_info.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_chat_without_messages_is_a_validation_error() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime));
+        let err = engine.process_chat(&[], InferenceOptions::default(), None).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ValidationError);
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime));
+        let context = RequestContext::with_id("req-1");
+        let response = engine.process_request("Hello", InferenceOptions::default(), Some(context)).await.unwrap();
+        assert_eq!(response.request_id.as_deref(), Some("req-1"));
+
+        let response = engine.process_request("Hello", InferenceOptions::default(), None).await.unwrap();
+        assert_eq!(response.request_id.unwrap().len(), 16);
+    }
+
+    #[tokio::test]
+    async fn test_engine_flow() {
+        let config = EngineConfig::default();
+        let runtime = MockRuntime;
+        let engine = Engine::new(config, Box::new(runtime));
+
+        let response = engine.process_request("Hello", InferenceOptions::default(), None).await.unwrap();
+        assert_eq!(response.status, "success");
+        // Verify prompt pass-through
+        assert_eq!(response.output.text, "Mock response to: Hello");
+        assert_eq!(response.model, None);
+    }
+
+    #[tokio::test]
+    async fn test_seed_is_reported() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime));
+        let options = InferenceOptions { seed: Some(7), ..Default::default() };
+
+        let response = engine.process_request("Hello", options, None).await.unwrap();
+        assert_eq!(response.seed, Some(7));
+    }
+
+    #[tokio::test]
+    async fn test_logprobs_are_passed_on() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime));
+        let response = engine.process_request("Hello", InferenceOptions::default(), None).await.unwrap();
+        assert!(response.logprobs.is_none());
+        assert!(serde_json::to_value(&response).unwrap().get("logprobs").is_none());
+
+        let options = InferenceOptions { logprobs: Some(2), ..Default::default() };
+        let response = engine.process_request("Hello", options, None).await.unwrap();
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["logprobs"]["content"][0]["token"], "Mock");
+        assert_eq!(json["logprobs"]["content"][0]["logprob"], -0.5);
+    }
+
+    /// Returns the prompt as output, provided it was given a grammar.
+    struct GrammarEchoRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for GrammarEchoRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            assert!(options.grammar.is_some_and(|g| g.starts_with("root ::= ")));
+            Ok(InferenceResult {
+                text: prompt.to_string(),
+                usage: Usage::default(),
+                finish_reason: FinishReason::Eos,
+                seed: None,
+                logprobs: None,
+            })
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    /// Returns whether it was asked to shift the context.
+    struct ContextShiftEchoRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for ContextShiftEchoRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, _prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            Ok(InferenceResult {
+                text: format!("{:?}", options.context_shift),
+                usage: Usage::default(),
+                finish_reason: FinishReason::Eos,
+                seed: None,
+                logprobs: None,
+            })
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_context_shift_defaults_to_config() {
+        let mut config = EngineConfig::default();
+        let engine = Engine::new(config.clone(), Box::new(ContextShiftEchoRuntime));
+        let response = engine.process_request("Hi", InferenceOptions::default(), None).await.unwrap();
+        assert_eq!(response.output.text, "Some(false)");
+
+        config.model.context_shift = true;
+        let engine = Engine::new(config, Box::new(ContextShiftEchoRuntime));
+        let response = engine.process_request("Hi", InferenceOptions::default(), None).await.unwrap();
+        assert_eq!(response.output.text, "Some(true)");
+
+        let options = InferenceOptions { context_shift: Some(false), ..Default::default() };
+        let response = engine.process_request("Hi", options, None).await.unwrap();
+        assert_eq!(response.output.text, "Some(false)");
+    }
+
+    #[tokio::test]
+    async fn test_json_response_format() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(GrammarEchoRuntime));
+        let json = || InferenceOptions {
+            response_format: Some(ResponseFormat::Json { schema: None }),
+            ..Default::default()
+        };
+
+        let response = engine.process_request(" {\"answer\": 42}\n", json(), None).await.unwrap();
+        assert_eq!(response.status, "success");
+        assert_eq!(response.output.json, Some(serde_json::json!({"answer": 42})));
+
+        let response = engine.process_request("{\"answer\": ", json(), None).await.unwrap();
+        assert_eq!(response.status, "error");
+        assert!(response.output.json.is_none());
+        assert!(response.error.unwrap().contains("not valid JSON"));
+        assert_eq!(response.error_info.unwrap().code, ErrorCode::RuntimeError);
+
+        let bad_schema = InferenceOptions {
+            response_format: Some(ResponseFormat::Json { schema: Some("{\"type\": \"date\"}".to_string()) }),
+            ..Default::default()
+        };
+        assert!(engine.process_request("{}", bad_schema, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_count_tokens_unsupported_by_default() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime));
+        assert!(matches!(engine.count_tokens("hello").await, Err(EngineError::Unsupported(_))));
+    }
+
+    #[tokio::test]
+    async fn test_embed_unsupported_by_default() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime));
+        let err = engine.embed(&["hello".to_string()]).await.unwrap_err();
+        assert!(matches!(err, EngineError::Unsupported(_)));
+    }
+
+    #[tokio::test]
+    async fn test_memory_injection() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = EngineConfig::default();
+        config.memory.enabled = true;
+        config.memory.persistence_path = dir.path().join("memory.json");
+        
+        let runtime = MockRuntime;
+        let engine = Engine::new(config, Box::new(runtime));
+        
+        // Inject fact
+        engine.memory.set_fact("user", "Divyansh").await.unwrap();
+        
+        // Run inference
+        let response = engine.process_request("Who am I?", InferenceOptions::default(), None).await.unwrap();
+        
+        // MockRuntime echoes the prompt. The prompt should now contain the injection.
+        // Expected: "Mock response to: [Facts: user=Divyansh;]\n\nWho am I?"
+        assert!(response.output.text.contains("user=Divyansh"));
+    }
+
+    #[tokio::test]
+    async fn test_system_prompt_composition() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = EngineConfig::default();
+        config.memory.enabled = true;
+        config.memory.persistence_path = dir.path().join("memory.json");
+        config.model.system_prompt = Some("Be brief.".to_string());
+        config.model.chat_template = Some("chatml".to_string());
+        let engine = Engine::new(config, Box::new(MockRuntime));
+        engine.memory.set_fact("user", "Divyansh").await.unwrap();
+
+        // System prompt, then memory, then the prompt
+        let response = engine.process_request("Who am I?", InferenceOptions::default(), None).await.unwrap();
+        assert_eq!(response.output.text, "Mock response to: Be brief.\n\n[Facts: user=Divyansh;]\n\nWho am I?");
+        assert!(response.debug.is_none());
+
+        // The request's own replaces the configured one
+        let own = InferenceOptions { system: Some("Answer in French.".to_string()), debug: true, ..InferenceOptions::default() };
+        let response = engine.process_request("Who am I?", own.clone(), None).await.unwrap();
+        assert_eq!(response.output.text, "Mock response to: Answer in French.\n\n[Facts: user=Divyansh;]\n\nWho am I?");
+        assert_eq!(response.debug.unwrap().system_prompt.as_deref(), Some("Answer in French."));
+
+        // Chats get both in the system slot, in the same order
+        let messages = vec![ChatMessage::new(Role::User, "Who am I?")];
+        let response = engine.process_chat(&messages, InferenceOptions::default(), None).await.unwrap();
+        assert_eq!(response.output.text, "Mock response to: <|im_start|>system\nBe brief.\n\n[Facts: user=Divyansh;]<|im_end|>\n\
+            <|im_start|>user\nWho am I?<|im_end|>\n<|im_start|>assistant\n");
+
+        // ...unless the conversation has its own system message
+        let messages = vec![ChatMessage::new(Role::System, "Be verbose."), ChatMessage::new(Role::User, "Who am I?")];
+        let response = engine.process_chat(&messages, InferenceOptions { debug: true, ..InferenceOptions::default() }, None).await.unwrap();
+        assert!(response.output.text.contains("system\nBe verbose.\n\n[Facts"), "{}", response.output.text);
+        assert_eq!(response.debug.unwrap().system_prompt.as_deref(), Some("Be verbose."));
+        let err = engine.process_chat(&messages, own, None).await.unwrap_err();
+        assert!(matches!(err, EngineError::Validation(_)));
+    }
+
+    #[tokio::test]
+    async fn test_debug_and_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = EngineConfig::default();
+        config.memory.enabled = true;
+        config.memory.persistence_path = dir.path().join("memory.json");
+        config.model.system_prompt = Some("Be brief.".to_string());
+        let engine = Engine::new(config, Box::new(runtime::mock::MockRuntime::default()));
+        engine.init().await.unwrap();
+        engine.memory.set_fact("user", "Divyansh").await.unwrap();
+
+        let options = InferenceOptions { dry_run: true, response_format: Some(ResponseFormat::Json { schema: None }), ..InferenceOptions::default() };
+        let response = engine.process_request("Who am I?", options.clone(), None).await.unwrap();
+        assert_eq!(response.status, "dry_run");
+        assert_eq!(response.output.text, "");
+        assert!(response.queue.is_none() && response.debug.is_none());
+        let prompt = "Be brief.\n\n[Facts: user=Divyansh;]\n\nWho am I?";
+        // The mock runtime has a token per character, after the BOS token
+        assert_eq!(response.usage.input_tokens, prompt.chars().count() as u32 + 1);
+
+        let response = engine.process_request("Who am I?", InferenceOptions { debug: true, ..options.clone() }, None).await.unwrap();
+        let debug = response.debug.unwrap();
+        assert_eq!(debug.prompt, prompt);
+        assert_eq!(debug.system_prompt.as_deref(), Some("Be brief."));
+        assert_eq!(debug.memory_injection, "[Facts: user=Divyansh;]\n\n");
+        assert_eq!(debug.memory_tokens, debug.memory_injection.chars().count() as u32);
+        // With what the engine fills in
+        assert_eq!(debug.options.grammar.as_deref(), Some(grammar::JSON_GRAMMAR));
+        assert_eq!(debug.options.context_shift, Some(false));
+
+        let batch = engine.process_batch(&["Who am I?".to_string()], InferenceOptions { debug: true, ..options.clone() }, None).await.unwrap();
+        let response = batch[0].as_ref().unwrap();
+        assert_eq!(response.status, "dry_run");
+        assert_eq!(response.debug.as_ref().unwrap().prompt, prompt);
+
+        let err = engine.process_request_stream("Who am I?", options).await.unwrap_err();
+        assert!(matches!(err, EngineError::Validation(_)));
+    }
+
+    #[tokio::test]
+    async fn test_memory_namespace() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = EngineConfig::default();
+        config.memory.enabled = true;
+        config.memory.persistence_path = dir.path().join("memory.json");
+        let engine = Engine::new(config, Box::new(MockRuntime));
+        engine.memory.set_fact("user", "Alice").await.unwrap();
+        engine.memory.set_fact_ns("bob", "user", "Bob").await.unwrap();
+
+        let options = InferenceOptions { memory_namespace: Some("bob".to_string()), ..InferenceOptions::default() };
+        let response = engine.process_request("Who am I?", options, None).await.unwrap();
+        assert!(response.output.text.contains("user=Bob"));
+        assert!(!response.output.text.contains("Alice"));
+    }
+
+    /// Embeds texts by which of a few topics they mention; records what it embedded.
+    struct TopicEmbedRuntime(Arc<std::sync::Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl ModelRuntime for TopicEmbedRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            MockRuntime.infer(prompt, options).await
+        }
+
+        async fn embed(&mut self, texts: &[String]) -> Result<Vec<Vec<f32>>, EngineError> {
+            self.0.lock().unwrap().extend(texts.iter().cloned());
+            Ok(texts.iter()
+                .map(|text| ["lang", "pet", "city"].iter().map(|topic| if text.contains(topic) { 1.0 } else { 0.0 }).collect())
+                .collect())
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_semantic_memory_retrieval() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = EngineConfig::default();
+        config.memory.enabled = true;
+        config.memory.retrieval = MemoryRetrieval::Semantic;
+        config.memory.persistence_path = dir.path().join("memory.json");
+        let embedded = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let engine = Engine::new(config.clone(), Box::new(TopicEmbedRuntime(embedded.clone())));
+        engine.memory.set_fact("lang", "Rust").await.unwrap();
+        engine.memory.set_fact("pet", "cat").await.unwrap();
+        engine.memory.set_summary("Met last week").await.unwrap();
+
+        // Only the relevant fact is injected; the summary always is
+        let response = engine.process_request("Which lang should I use?", InferenceOptions::default(), None).await.unwrap();
+        assert!(response.output.text.contains("[Summary: Met last week]"));
+        assert!(response.output.text.contains("lang=Rust;"));
+        assert!(!response.output.text.contains("pet=cat"));
+
+        // Nothing similar enough: no facts at all
+        let response = engine.process_request("Hello", InferenceOptions::default(), None).await.unwrap();
+        assert!(!response.output.text.contains("[Facts:"));
+
+        // Facts are embedded once, and the embeddings are kept across restarts
+        engine.shutdown().await.unwrap();
+        let engine = Engine::new(config, Box::new(TopicEmbedRuntime(embedded.clone())));
+        let messages = [ChatMessage::new(chat::Role::User, "Do you remember my pet?")];
+        let response = engine.process_chat(&messages, InferenceOptions::default(), None).await.unwrap();
+        assert!(response.output.text.contains("pet=cat;"));
+        assert!(!response.output.text.contains("lang=Rust"));
+        assert_eq!(*embedded.lock().unwrap(), ["lang: Rust", "pet: cat", "Which lang should I use?", "Hello", "Do you remember my pet?"]);
+    }
+
+    #[tokio::test]
+    async fn test_engine_stream() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime));
+
+        let mut rx = engine.process_request_stream("Hello", InferenceOptions::default()).await.unwrap();
+        let mut text = String::new();
+        let mut done = None;
+        while let Some(chunk) = rx.recv().await {
+            match chunk {
+                TokenChunk::Token { text: piece } => text.push_str(&piece),
+                TokenChunk::Done { usage, status, finish_reason } => done = Some((usage, status, finish_reason)),
+                TokenChunk::Error { message } => panic!("unexpected error: {}", message),
+            }
+        }
+
+        assert_eq!(text, "Mock response to: Hello");
+        let (usage, status, finish_reason) = done.expect("stream should end with Done");
+        assert_eq!(status, InferenceStatus::Success);
+        assert_eq!(finish_reason, FinishReason::Eos);
+        assert_eq!(usage.total_tokens, 15);
+    }
+
+    #[tokio::test]
+    async fn test_request_with_progress() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let response = engine.process_request_with_progress("Hello", InferenceOptions::default(), None, tx).await.unwrap();
+        assert_eq!(response.output.text, "Mock response to: Hello");
+        assert!(response.request_id.is_some());
+
+        let mut text = String::new();
+        while let Ok(chunk) = rx.try_recv() {
+            if let TokenChunk::Token { text: piece } = chunk {
+                text.push_str(&piece);
+            }
+        }
+        assert_eq!(text, response.output.text);
+    }
+
+    #[tokio::test]
+    async fn test_process_batch() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime));
+        let prompts = vec!["Hello".to_string(), "World".to_string()];
+
+        let results = engine.process_batch(&prompts, InferenceOptions::default(), Some(RequestContext::with_id("batch-1"))).await.unwrap();
+        let texts: Vec<String> = results.into_iter().map(|result| result.unwrap().output.text).collect();
+        assert_eq!(texts, vec!["Mock response to: Hello", "Mock response to: World"]);
+        assert!(engine.process_batch(&[], InferenceOptions::default(), None).await.unwrap().is_empty());
+
+        // A prepared context's state can't be loaded for a batch
+        let with_session = InferenceOptions { session: Some("docs".to_string()), ..InferenceOptions::default() };
+        let err = engine.process_batch(&prompts, with_session, None).await.unwrap_err();
+        assert!(matches!(err, EngineError::Validation(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_engine_chat_stream() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime));
+        let messages = [ChatMessage::new(chat::Role::User, "Hi")];
+
+        let mut rx = engine.process_chat_stream(&messages, InferenceOptions::default()).await.unwrap();
+        let mut text = String::new();
+        while let Some(chunk) = rx.recv().await {
+            if let TokenChunk::Token { text: piece } = chunk {
+                text.push_str(&piece);
+            }
+        }
+        assert_eq!(text, "Mock response to: <|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n");
+    }
+
+    #[tokio::test]
+    async fn test_chat_memory_in_system_slot() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = EngineConfig::default();
+        config.memory.enabled = true;
+        config.memory.persistence_path = dir.path().join("memory.json");
+        config.model.chat_template = Some("chatml".to_string());
+        let engine = Engine::new(config, Box::new(MockRuntime));
+        engine.memory.set_fact("user", "Divyansh").await.unwrap();
+
+        let messages = vec![ChatMessage::new(chat::Role::User, "Who am I?")];
+        let response = engine.process_chat(&messages, InferenceOptions::default(), None).await.unwrap();
+
+        assert_eq!(
+            response.output.text,
+            "Mock response to: <|im_start|>system\n[Facts: user=Divyansh;]<|im_end|>\n\
+             <|im_start|>user\nWho am I?<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    /// MockRuntime that reports model metadata and an embedded chat template.
+    struct TemplateRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for TemplateRuntime {
+        async fn load(&mut self, config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            MockRuntime.load(config).await
+        }
+
+        async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            MockRuntime.infer(prompt, options).await
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+
+        fn model_info(&self) -> Option<ModelInfo> {
+            Some(ModelInfo {
+                name: "tiny-chat".to_string(),
+                architecture: "llama".to_string(),
+                parameter_count: 1_000_000,
+                quantization: "Q4_K_M".to_string(),
+                native_context_size: 4096,
+                vocab_size: 32000,
+                file_size: 1024,
+                path: "models/tiny-chat.gguf".into(),
+                context_size: 2048,
+                gpu_layers: 0,
+                n_threads: 4,
+                n_threads_batch: 4,
+                use_mmap: true,
+                use_mlock: false,
+                kv_cache_type: KvCacheType::F16,
+                loaded_at: 1_700_000_000,
+            })
+        }
+
+        fn chat_template(&self) -> Option<ChatTemplate> {
+            Some(ChatTemplate::Jinja {
+                source: "{% for m in messages %}[{{ m.role }}] {{ m.content }}{{ eos_token }}{% endfor %}[assistant] ".to_string(),
+                eos_token: "</s>".to_string(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chat_prefers_model_template() {
+        let messages = vec![
+            ChatMessage::new(chat::Role::System, "Be brief."),
+            ChatMessage::new(chat::Role::User, "Hi"),
+        ];
+
+        let engine = Engine::new(EngineConfig::default(), Box::new(TemplateRuntime));
+        engine.init().await.unwrap();
+        let response = engine.process_chat(&messages, InferenceOptions::default(), None).await.unwrap();
+        assert_eq!(response.output.text, "Mock response to: [system] Be brief.</s>[user] Hi</s>[assistant] ");
+        assert_eq!(response.model.as_deref(), Some("tiny-chat"));
+
+        // Without a model there's no template of its own to use
+        engine.unload_model().await.unwrap();
+        let response = engine.process_chat(&messages, InferenceOptions::default(), None).await.unwrap();
+        assert!(response.output.text.contains("<|im_start|>system"), "{}", response.output.text);
+
+        // An explicitly configured template still wins
+        let mut config = EngineConfig::default();
+        config.model.chat_template = Some("llama3".to_string());
+        let engine = Engine::new(config, Box::new(TemplateRuntime));
+        let response = engine.process_chat(&messages, InferenceOptions::default(), None).await.unwrap();
+        assert!(response.output.text.contains("<|start_header_id|>system<|end_header_id|>"));
+    }
+
+    #[tokio::test]
+    async fn test_chat_unknown_template() {
+        let mut config = EngineConfig::default();
+        config.model.chat_template = Some("nope".to_string());
+        let engine = Engine::new(config, Box::new(MockRuntime));
+
+        let messages = vec![ChatMessage::new(chat::Role::User, "Hi")];
+        assert!(engine.process_chat(&messages, InferenceOptions::default(), None).await.is_err());
+    }
+
+    /// Produces "partial" and then waits until the request is cancelled.
+    struct BlockingRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for BlockingRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, _prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            options.cancel.cancelled().await;
+            Ok(InferenceResult {
+                text: "partial".to_string(),
+                usage: Usage::default(),
+                finish_reason: FinishReason::Cancelled,
+                seed: None,
+                logprobs: None,
+            })
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all() {
+        let engine = Arc::new(Engine::new(EngineConfig::default(), Box::new(BlockingRuntime)));
+
+        let request = {
+            let engine = engine.clone();
+            tokio::spawn(async move { engine.process_request("Hello", InferenceOptions::default(), None).await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        engine.cancel_all();
+
+        let response = request.await.unwrap().unwrap();
+        assert_eq!(response.status, "cancelled");
+        assert_eq!(response.output.text, "partial");
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_rejects_promptly() {
+        let mut config = EngineConfig::default();
+        config.queue.max_queue_depth = 2;
+        let engine = Arc::new(Engine::new(config, Box::new(BlockingRuntime)));
+
+        let mut requests = Vec::new();
+        for _ in 0..3 {
+            let engine = engine.clone();
+            requests.push(tokio::spawn(async move { engine.process_request("Hello", InferenceOptions::default(), None).await }));
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let started = std::time::Instant::now();
+        let err = engine.process_request("Hello", InferenceOptions::default(), None).await.unwrap_err();
+        assert!(matches!(err, EngineError::QueueFull { .. }));
+        assert!(engine.process_request_stream("Hello", InferenceOptions::default()).await.is_err());
+        assert!(started.elapsed() < std::time::Duration::from_millis(100));
+
+        engine.cancel_all();
+        let mut positions = Vec::new();
+        for request in requests {
+            positions.push(request.await.unwrap().unwrap().queue.unwrap().position);
+        }
+        assert_eq!(positions, vec![0, 1, 2]);
+
+        // Places free up again once requests finish
+        assert!(engine.process_request_stream("Hello", InferenceOptions::default()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_rejects_promptly_with_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = EngineConfig::default();
+        config.queue.max_queue_depth = 0;
+        config.memory.enabled = true;
+        config.memory.persistence_path = dir.path().join("memory.json");
+        let engine = Arc::new(Engine::new(config, Box::new(BlockingRuntime)));
+        engine.memory.set_fact("user", "Divyansh").await.unwrap();
+
+        let running = {
+            let engine = engine.clone();
+            tokio::spawn(async move { engine.process_request("Hello", InferenceOptions::default(), None).await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        // Measuring the memory injection waits for the runtime, so it mustn't come first
+        let started = std::time::Instant::now();
+        let err = engine.process_request("Hello", InferenceOptions::default(), None).await.unwrap_err();
+        assert!(matches!(err, EngineError::QueueFull { .. }));
+        let messages = [ChatMessage::new(Role::User, "Hello")];
+        let err = engine.process_chat(&messages, InferenceOptions::default(), None).await.unwrap_err();
+        assert!(matches!(err, EngineError::QueueFull { .. }));
+        assert!(engine.process_chat_stream(&messages, InferenceOptions::default()).await.is_err());
+        assert!(started.elapsed() < std::time::Duration::from_millis(100));
+
+        engine.cancel_all();
+        assert_eq!(running.await.unwrap().unwrap().status, "cancelled");
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_rejects_promptly_when_truncating() {
+        let mut config = EngineConfig::default();
+        config.queue.max_queue_depth = 0;
+        let engine = Arc::new(Engine::new(config, Box::new(BlockingRuntime)));
+        let running = {
+            let engine = engine.clone();
+            tokio::spawn(async move { engine.process_request("Hello", InferenceOptions::default(), None).await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        // Fitting the prompt to the context waits for the runtime, so it mustn't come first
+        let options = InferenceOptions { on_context_overflow: OverflowStrategy::TruncateStart, ..Default::default() };
+        let started = std::time::Instant::now();
+        let err = engine.process_request("Hello", options.clone(), None).await.unwrap_err();
+        assert!(matches!(err, EngineError::QueueFull { .. }));
+        let err = engine.process_batch(&["Hello".to_string()], options.clone(), None).await.unwrap_err();
+        assert!(matches!(err, EngineError::QueueFull { .. }));
+        assert!(engine.process_request_stream("Hello", options).await.is_err());
+        assert!(started.elapsed() < std::time::Duration::from_millis(100));
+
+        engine.cancel_all();
+        assert_eq!(running.await.unwrap().unwrap().status, "cancelled");
+    }
+
+    #[tokio::test]
+    async fn test_deadline_returns_partial_output() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(BlockingRuntime));
+        let options = InferenceOptions { max_time_ms: Some(50), ..Default::default() };
+
+        let started = std::time::Instant::now();
+        let response = engine.process_request("Hello", options, None).await.unwrap();
+        assert_eq!(response.status, "truncated");
+        assert_eq!(response.finish_reason, Some(FinishReason::Time));
+        assert_eq!(response.output.text, "partial");
+        assert!(started.elapsed() < std::time::Duration::from_millis(50) + TIMEOUT_ALLOWANCE * 2);
+    }
+
+    /// Never returns, not even when cancelled.
+    struct StuckRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for StuckRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, _prompt: &str, _options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            std::future::pending().await
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deadline_times_out_stuck_runtime() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(StuckRuntime));
+        let options = || InferenceOptions { max_time_ms: Some(50), ..Default::default() };
+
+        let err = engine.process_request("Hello", options(), None).await.unwrap_err();
+        assert!(matches!(err, EngineError::Timeout(50)));
+
+        let mut rx = engine.process_request_stream("Hello", options()).await.unwrap();
+        assert!(matches!(rx.recv().await, Some(TokenChunk::Error { message }) if message.contains("timed out")));
+    }
+
+    /// Fails every request.
+    struct FailingRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for FailingRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, _prompt: &str, _options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            Err(EngineError::Runtime("Decode failed".to_string()))
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_runtime_errors_are_propagated() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(FailingRuntime));
+        let err = engine.process_request("Hello", InferenceOptions::default(), None).await.unwrap_err();
+        assert!(matches!(err, EngineError::Runtime(message) if message == "Decode failed"));
+
+        let messages = [ChatMessage::new(chat::Role::User, "Hello")];
+        assert!(engine.process_chat(&messages, InferenceOptions::default(), None).await.is_err());
+    }
+
+    /// Counts unloads and otherwise behaves like `BlockingRuntime`.
+    struct UnloadCountingRuntime {
+        unloads: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ModelRuntime for UnloadCountingRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            BlockingRuntime.infer(prompt, options).await
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            self.unloads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_cancels_and_unloads() {
+        let unloads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let engine = Arc::new(Engine::new(EngineConfig::default(), Box::new(UnloadCountingRuntime { unloads: unloads.clone() })));
+
+        let request = {
+            let engine = engine.clone();
+            tokio::spawn(async move { engine.process_request("Hello", InferenceOptions::default(), None).await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        engine.shutdown().await.unwrap();
+        assert_eq!(request.await.unwrap().unwrap().status, "cancelled");
+        assert_eq!(unloads.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_caller_token_cancels() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(BlockingRuntime));
+        let options = InferenceOptions::default();
+        let cancel = options.cancel.clone();
+
+        let (response, _) = tokio::join!(
+            engine.process_request("Hello", options, None),
+            async { cancel.cancel() },
+        );
+        assert_eq!(response.unwrap().status, "cancelled");
+    }
+
+    /// Loads only once `release` is notified; records the `max_tokens` of every inference.
+    struct SlowLoadRuntime {
+        release: Arc<tokio::sync::Notify>,
+        inferences: Arc<std::sync::Mutex<Vec<Option<u32>>>>,
+    }
+
+    #[async_trait]
+    impl ModelRuntime for SlowLoadRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            self.release.notified().await;
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            self.inferences.lock().unwrap().push(options.max_tokens);
+            MockRuntime.infer(prompt, options).await
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_progress_and_warmup() {
+        let release = Arc::new(tokio::sync::Notify::new());
+        let inferences = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut config = EngineConfig::default();
+        config.model.warmup = true;
+        let runtime = SlowLoadRuntime { release: release.clone(), inferences: inferences.clone() };
+        let engine = Arc::new(Engine::new(config, Box::new(runtime)));
+        assert_eq!(engine.load_state(), LoadState::NotLoaded);
+
+        let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let init = {
+            let engine = engine.clone();
+            let reported = reported.clone();
+            tokio::spawn(async move {
+                engine.init_with_progress(Arc::new(move |p| reported.lock().unwrap().push(p))).await
+            })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        // Requests are turned away while loading instead of waiting on it
+        assert_eq!(engine.load_state(), LoadState::Loading { progress: 0.0 });
+        let err = engine.process_request("Hello", InferenceOptions::default(), None).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ModelNotLoaded);
+
+        release.notify_one();
+        init.await.unwrap().unwrap();
+        assert_eq!(engine.load_state(), LoadState::Ready);
+        assert_eq!(*reported.lock().unwrap(), vec![0.0, 1.0]);
+        // The warm-up generated a single token
+        assert_eq!(*inferences.lock().unwrap(), vec![Some(1)]);
+    }
+
+    /// Answers with the name of the loaded model file; fails to load paths containing "bad".
+    #[derive(Default)]
+    struct SwappableRuntime {
+        loaded: Option<std::path::PathBuf>,
+        loads: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ModelRuntime for SwappableRuntime {
+        async fn load(&mut self, config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            if config.model_path.to_string_lossy().contains("bad") {
+                return Err(EngineError::Runtime("Failed to load model".to_string()));
+            }
+            // Slow enough for concurrent requests to pile up behind a load
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            self.loads.fetch_add(1, Ordering::SeqCst);
+            self.loaded = Some(config.model_path.clone());
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            let loaded = self.loaded.as_ref().ok_or(EngineError::ModelNotLoaded)?;
+            let mut result = MockRuntime.infer(prompt, options).await?;
+            result.text = loaded.display().to_string();
+            Ok(result)
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            self.loaded = None;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload_and_unload_model() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(SwappableRuntime::default()));
+        engine.init().await.unwrap();
+        let model = |path: &str| ModelLoadConfig { model_path: path.into(), ..engine.load_config() };
+        let answer = || async { engine.process_request("Hi", InferenceOptions::default(), None).await.unwrap().output.text };
+        assert_eq!(answer().await, "models/default.gguf");
+
+        engine.reload_model(model("other.gguf"), false).await.unwrap();
+        assert_eq!(answer().await, "other.gguf");
+
+        // A failed load keeps the old model in service
+        assert!(engine.reload_model(model("bad.gguf"), false).await.is_err());
+        assert_eq!(engine.load_state(), LoadState::Ready);
+        assert_eq!(engine.model_path(), std::path::PathBuf::from("other.gguf"));
+        assert_eq!(answer().await, "other.gguf");
+
+        engine.unload_model().await.unwrap();
+        assert_eq!(engine.load_state(), LoadState::NotLoaded);
+        let err = engine.process_request("Hi", InferenceOptions::default(), None).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ModelNotLoaded);
+
+        // With nothing to fall back on, a failed load is reported as such
+        assert!(engine.reload_model(model("bad.gguf"), false).await.is_err());
+        assert!(matches!(engine.load_state(), LoadState::Failed { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_response_cache() {
+        let mut config = EngineConfig::default();
+        config.cache.enabled = true;
+        let engine = Engine::new(config, Box::new(SwappableRuntime::default()));
+        engine.init().await.unwrap();
+        let ask = |options: InferenceOptions| async { engine.process_request("Hi", options, None).await.unwrap() };
+
+        let first = ask(InferenceOptions::default()).await;
+        let second = ask(InferenceOptions::default()).await;
+        assert!(!first.cached);
+        assert!(second.cached);
+        assert_eq!(second.output.text, first.output.text);
+        assert_ne!(second.request_id, first.request_id);
+
+        assert!(!ask(InferenceOptions { temperature: Some(0.8), ..InferenceOptions::default() }).await.cached);
+        assert!(!ask(InferenceOptions { no_cache: true, ..InferenceOptions::default() }).await.cached);
+        assert!(!ask(InferenceOptions { max_tokens: Some(5), ..InferenceOptions::default() }).await.cached);
+
+        // Another model's answers aren't the old one's
+        let other = ModelLoadConfig { model_path: "other.gguf".into(), ..engine.load_config() };
+        engine.reload_model(other, false).await.unwrap();
+        let after_swap = ask(InferenceOptions::default()).await;
+        assert!(!after_swap.cached);
+        assert_eq!(after_swap.output.text, "other.gguf");
+    }
+
+    /// Logs its calls to a shared list, tags the prompt and output with its name, and rejects
+    /// prompts mentioning `reject`.
+    struct Recorder {
+        name: &'static str,
+        reject: Option<&'static str>,
+        log: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl RequestHook for Recorder {
+        async fn before_inference(&self, request: &mut PromptContext) -> Result<(), EngineError> {
+            self.log.lock().unwrap().push(format!("before {} ({})", self.name, request.request_id));
+            if self.reject.is_some_and(|word| request.query().contains(word)) {
+                return Err(EngineError::Validation(format!("{} rejected the prompt", self.name)));
+            }
+            for text in request.texts_mut() {
+                text.push_str(&format!(" [{}]", self.name));
+            }
+            Ok(())
+        }
+
+        async fn after_inference(&self, _request: &PromptContext, result: &mut InferenceResult) -> Result<(), EngineError> {
+            self.log.lock().unwrap().push(format!("after {}", self.name));
+            result.text.push_str(&format!(" <{}>", self.name));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks_run_in_order() {
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime))
+            .add_hook(Box::new(Recorder { name: "a", reject: None, log: log.clone() }))
+            .add_hook(Box::new(Recorder { name: "b", reject: Some("password"), log: log.clone() }))
+            .add_hook(Box::new(hooks::TrimWhitespace));
+
+        let response = engine.process_request("Hi ", InferenceOptions::default(), Some(RequestContext::with_id("r1"))).await.unwrap();
+        assert_eq!(response.output.text, "Mock response to: Hi  [a] [b] <b> <a>");
+        assert_eq!(*log.lock().unwrap(), vec!["before a (r1)", "before b (r1)", "after b", "after a"]);
+
+        let messages = vec![ChatMessage::new(Role::User, "Hello")];
+        let chat = engine.process_chat(&messages, InferenceOptions::default(), None).await.unwrap();
+        assert!(chat.output.text.contains("Hello [a] [b]"), "{}", chat.output.text);
+    }
+
+    #[tokio::test]
+    async fn test_hook_can_reject_a_request() {
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime))
+            .add_hook(Box::new(Recorder { name: "a", reject: Some("password"), log: log.clone() }))
+            .add_hook(Box::new(Recorder { name: "b", reject: None, log: log.clone() }));
+
+        let err = engine.process_request("My password is hunter2", InferenceOptions::default(), Some(RequestContext::with_id("r1"))).await.unwrap_err();
+        assert_eq!(err.to_string(), "Validation Error: a rejected the prompt");
+        assert_eq!(EngineResponse::from_error(&err).error_info.unwrap().code, ErrorCode::ValidationError);
+        // Nothing after the hook ran, the model included
+        assert_eq!(*log.lock().unwrap(), vec!["before a (r1)"]);
+        assert!(engine.process_request_stream("password?", InferenceOptions::default()).await.is_err());
+    }
+
+    /// The text of the `Token` items of a stream, and whether it ended with `Done`.
+    async fn streamed_text(mut rx: mpsc::UnboundedReceiver<TokenChunk>) -> (String, bool) {
+        let (mut text, mut done) = (String::new(), false);
+        while let Some(chunk) = rx.recv().await {
+            match chunk {
+                TokenChunk::Token { text: piece } => text.push_str(&piece),
+                TokenChunk::Done { .. } => done = true,
+                TokenChunk::Error { message } => panic!("{}", message),
+            }
+        }
+        (text, done)
+    }
+
+    #[tokio::test]
+    async fn test_streams_send_the_output_hooks_rewrote() {
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let engine = Engine::new(EngineConfig::default(), Box::new(WordRuntime))
+            .add_hook(Box::new(Recorder { name: "a", reject: None, log: log.clone() }));
+
+        let rx = engine.process_request_stream("Hi", InferenceOptions::default()).await.unwrap();
+        assert_eq!(streamed_text(rx).await, ("fine thanks <a>".to_string(), true));
+        let messages = vec![ChatMessage::new(Role::User, "Hello")];
+        let rx = engine.process_chat_stream(&messages, InferenceOptions::default()).await.unwrap();
+        assert_eq!(streamed_text(rx).await.0, "fine thanks <a>");
+
+        // Streamed to the server's clients as it's sent to the caller, and stored in sessions
+        let session = engine.sessions.create(None).await.unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let response = engine.process_chat_in_session_with_progress(&session.id, "Hello", InferenceOptions::default(), None, tx).await.unwrap();
+        assert_eq!(streamed_text(rx).await.0, response.output.text);
+        assert_eq!(response.output.text, "fine thanks <a>");
+        assert_eq!(engine.sessions.get(&session.id).await.unwrap().messages[1].content, "fine thanks <a>");
+        assert_eq!(log.lock().unwrap().iter().filter(|call| call.starts_with("after")).count(), 3);
+    }
+
+    /// Picks the last label, whatever the text.
+    struct LastLabel;
+
+    #[async_trait::async_trait]
+    impl IntentClassifier for LastLabel {
+        async fn classify(&self, _text: &str, labels: &[String], _runtime: &mut dyn ModelRuntime) -> Result<Option<String>, EngineError> {
+            Ok(labels.last().cloned())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_intent_classification() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime));
+        let classify = InferenceOptions { classify_intent: true, ..InferenceOptions::default() };
+
+        let plain = engine.process_request("What is Rust?", InferenceOptions::default(), None).await.unwrap();
+        assert_eq!(plain.intent, None);
+        let question = engine.process_request("What is Rust?", classify.clone(), None).await.unwrap();
+        assert_eq!(question.intent.as_deref(), Some("question"));
+        assert_eq!(question.output.text, "Mock response to: What is Rust?");
+
+        // A chat is classified by its last user message, not the whole conversation
+        let messages = vec![
+            ChatMessage::new(Role::User, "Hello!"),
+            ChatMessage::new(Role::Assistant, "Hi, how can I help?"),
+            ChatMessage::new(Role::User, "Write a poem about the sea"),
+        ];
+        let chat = engine.process_chat(&messages, classify.clone(), None).await.unwrap();
+        assert_eq!(chat.intent.as_deref(), Some("command"));
+
+        let err = engine.process_request_stream("What is Rust?", classify.clone()).await.unwrap_err();
+        assert!(matches!(err, EngineError::Validation(_)));
+
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime)).with_intent_classifier(Box::new(LastLabel));
+        let custom = engine.process_request("What is Rust?", classify, None).await.unwrap();
+        assert_eq!(custom.intent.as_deref(), Some("chitchat"));
+    }
+
+    /// Saves the last prompt as its state, and answers with the state loaded and the prompt.
+    #[derive(Default)]
+    struct StateRuntime {
+        last_prompt: String,
+        loaded: String,
+    }
+
+    #[async_trait]
+    impl ModelRuntime for StateRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, _options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            self.last_prompt = prompt.to_string();
+            let text = format!("{}|{}", std::mem::take(&mut self.loaded), prompt);
+            Ok(InferenceResult { text, usage: Usage { input_tokens: 3, ..Usage::default() }, finish_reason: FinishReason::Eos, seed: None, logprobs: None })
+        }
+
+        async fn save_session(&mut self, path: &std::path::Path) -> Result<(), EngineError> {
+            Ok(std::fs::write(path, &self.last_prompt)?)
+        }
+
+        async fn load_session(&mut self, path: &std::path::Path) -> Result<(), EngineError> {
+            self.loaded = std::fs::read_to_string(path)?;
+            Ok(())
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prepared_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = EngineConfig::default();
+        config.model.session_dir = dir.path().join("sessions");
+        let engine = Engine::new(config.clone(), Box::new(StateRuntime::default()));
+
+        let prepared = engine.prepare_context("docs", "A long document. ").await.unwrap();
+        assert_eq!(prepared.tokens, 3);
+        assert_eq!(prepared.size, "A long document. ".len() as u64);
+        assert_eq!(engine.prepared.list().unwrap(), vec![prepared]);
+
+        let options = InferenceOptions { session: Some("docs".to_string()), ..InferenceOptions::default() };
+        let response = engine.process_request("Summarize it.", options.clone(), None).await.unwrap();
+        assert_eq!(response.output.text, "A long document. |A long document. Summarize it.");
+
+        let missing = InferenceOptions { session: Some("nope".to_string()), ..InferenceOptions::default() };
+        let err = engine.process_request("Hi", missing, None).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        let err = engine.process_chat(&[ChatMessage::new(Role::User, "Hi")], options, None).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ValidationError);
+        assert_eq!(engine.prepare_context("../x", "text").await.unwrap_err().code(), ErrorCode::ValidationError);
+
+        // Runtimes that can't save their state say so
+        let engine = Engine::new(config, Box::new(MockRuntime));
+        assert_eq!(engine.prepare_context("docs", "text").await.unwrap_err().code(), ErrorCode::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn test_idle_unload_and_reload() {
+        let loads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let runtime = SwappableRuntime { loads: loads.clone(), ..SwappableRuntime::default() };
+        let engine = Arc::new(Engine::new(EngineConfig::default(), Box::new(runtime)));
+        engine.init().await.unwrap();
+
+        // Not while a request is waiting, nor before the timeout
+        let queued = engine.queue.enter().unwrap();
+        assert!(!engine.unload_if_idle(Duration::ZERO).await.unwrap());
+        drop(queued);
+        assert!(!engine.unload_if_idle(Duration::from_secs(3600)).await.unwrap());
+
+        assert!(engine.unload_if_idle(Duration::ZERO).await.unwrap());
+        assert_eq!(engine.load_state(), LoadState::Idle);
+
+        // Concurrent requests share a single reload
+        let requests: Vec<_> = (0..3).map(|_| {
+            let engine = engine.clone();
+            tokio::spawn(async move { engine.process_request("Hi", InferenceOptions::default(), None).await })
+        }).collect();
+        for request in requests {
+            assert_eq!(request.await.unwrap().unwrap().output.text, "models/default.gguf");
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert_eq!(engine.load_state(), LoadState::Ready);
+    }
+
+    #[tokio::test]
+    async fn test_reload_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = EngineConfig::default();
+        config.memory.enabled = true;
+        config.memory.persistence_path = dir.path().join("memory.json");
+        config.memory.max_kv_entries = 1;
+        let engine = Arc::new(Engine::new(config.clone(), Box::new(MockRuntime)));
+        engine.init().await.unwrap();
+        engine.start_idle_unload();
+        engine.memory.set_fact("a", "1").await.unwrap();
+        assert!(engine.memory.set_fact("b", "2").await.is_err());
+
+        config.memory.max_kv_entries = 2;
+        config.model.idle_unload_secs = Some(0);
+        config.server.port = 9000;
+        let reload = engine.reload_config(&config);
+        assert_eq!(reload.applied, ["model.idle_unload_secs", "memory.max_kv_entries"]);
+        assert_eq!(reload.needs_restart, ["server.port"]);
+        assert_eq!(engine.config().server.port, 8080);
+
+        engine.memory.set_fact("b", "2").await.unwrap();
+        // The idle check picks up the timeout it didn't have at the start
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while engine.load_state() != LoadState::Idle {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }).await.unwrap();
+    }
+
+    /// One token per word; replies "fine thanks".
+    struct WordRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for WordRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            let mut result = MockRuntime.infer(prompt, options).await?;
+            result.text = "fine thanks".to_string();
+            Ok(result)
+        }
+
+        fn tokenize(&self, text: &str, _add_bos: bool) -> Result<Vec<i32>, EngineError> {
+            Ok(text.split_whitespace().map(|_| 0).collect())
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    /// Tokenizes numbers to themselves, with a BOS of 0, and echoes the prompt.
+    struct NumberRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for NumberRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, _options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            Ok(InferenceResult {
+                text: prompt.to_string(),
+                usage: Usage::default(),
+                finish_reason: FinishReason::Eos,
+                seed: None,
+                logprobs: None,
+            })
+        }
+
+        fn tokenize(&self, text: &str, add_bos: bool) -> Result<Vec<i32>, EngineError> {
+            let bos = add_bos.then_some(0);
+            Ok(bos.into_iter().chain(text.split_whitespace().map(|word| word.parse().unwrap())).collect())
+        }
+
+        fn token_pieces(&self, tokens: &[i32]) -> Result<Vec<String>, EngineError> {
+            Ok(tokens.iter().map(|token| format!("{} ", token)).collect())
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_overlong_prompt_is_truncated() {
+        let mut config = EngineConfig::default();
+        config.model.default_context_size = 10;
+        let engine = Engine::new(config, Box::new(NumberRuntime));
+        let prompt = "1 2 3 4 5 6 7 8 9 10";
+        let options = |on_context_overflow, max_tokens| InferenceOptions { on_context_overflow, max_tokens: Some(max_tokens), ..Default::default() };
+
+        // 11 tokens with the BOS; 6 fit next to 4 of output
+        let response = engine.process_request(prompt, options(OverflowStrategy::TruncateStart, 4), None).await.unwrap();
+        assert_eq!(response.output.text, "6 7 8 9 10 ");
+        assert!(response.prompt_truncated);
+        assert_eq!(response.usage.prompt_tokens_dropped, 5);
+
+        let response = engine.process_request(prompt, options(OverflowStrategy::TruncateMiddle, 4), None).await.unwrap();
+        assert_eq!(response.output.text, "1 2 8 9 10 ");
+
+        // Left for the runtime to reject
+        let response = engine.process_request(prompt, options(OverflowStrategy::Error, 4), None).await.unwrap();
+        assert_eq!(response.output.text, prompt);
+        assert!(!response.prompt_truncated);
+
+        let response = engine.process_request("1 2 3", options(OverflowStrategy::TruncateEnd, 4), None).await.unwrap();
+        assert_eq!(response.output.text, "1 2 3");
+        assert!(!response.prompt_truncated);
+
+        // No room for any prompt
+        let result = engine.process_request(prompt, options(OverflowStrategy::TruncateEnd, 9), None).await;
+        assert!(matches!(result, Err(EngineError::ContextOverflow { tokens: 11, context_size: 10 })));
+    }
+
+    #[tokio::test]
+    async fn test_session_history_is_trimmed_to_fit() {
+        let mut config = EngineConfig::default();
+        config.model.default_context_size = 60;
+        let engine = Engine::new(config, Box::new(WordRuntime));
+        let session = engine.sessions.create(Some("Be brief.".to_string())).await.unwrap();
+        let options = || InferenceOptions { max_tokens: Some(10), ..InferenceOptions::default() };
+
+        for turn in 0..6 {
+            let message = format!("turn {} one two three four five six seven eight", turn);
+            let response = engine.process_chat_in_session(&session.id, &message, options(), None).await.unwrap();
+            assert_eq!(response.output.text, "fine thanks");
+        }
+
+        let history = engine.sessions.get(&session.id).await.unwrap().messages;
+        // Old turns were dropped whole, the latest ones kept
+        assert!(history.len() < 12);
+        assert_eq!(history.len() % 2, 0);
+        assert_eq!(history[0].role, Role::User);
+        assert!(history[history.len() - 2].content.starts_with("turn 5"));
+        let prompt = engine.build_chat_prompt(&with_system(Some("Be brief."), &history[..history.len() - 1]), &InferenceOptions::default(), None).await.unwrap().text;
+        assert!(engine.count_tokens(&prompt).await.unwrap() <= 50);
+    }
+
+    /// Like `WordRuntime`, adding up how many words it has tokenized.
+    struct CountingRuntime(Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait]
+    impl ModelRuntime for CountingRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            WordRuntime.infer(prompt, options).await
+        }
+
+        fn tokenize(&self, text: &str, add_bos: bool) -> Result<Vec<i32>, EngineError> {
+            let tokens = WordRuntime.tokenize(text, add_bos)?;
+            self.0.fetch_add(tokens.len(), Ordering::SeqCst);
+            Ok(tokens)
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_history_is_tokenized_once_per_turn() {
+        let mut config = EngineConfig::default();
+        config.model.default_context_size = 60;
+        let tokenized = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let engine = Engine::new(config, Box::new(CountingRuntime(tokenized.clone())));
+        let session = engine.sessions.create(None).await.unwrap();
+        let history: Vec<ChatMessage> = (0..100)
+            .map(|i| ChatMessage::new(if i % 2 == 0 { Role::User } else { Role::Assistant }, format!("message {} one two three", i)))
+            .collect();
+        engine.sessions.set_messages(&session.id, history).await.unwrap();
+
+        let options = InferenceOptions { max_tokens: Some(10), ..InferenceOptions::default() };
+        engine.process_chat_in_session(&session.id, "and now", options, None).await.unwrap();
+        let history = engine.sessions.get(&session.id).await.unwrap().messages;
+        assert!(history.len() < 20);
+        assert_eq!(history[0].role, Role::User);
+
+        // The whole history is about 600 words; re-rendering it per dropped turn would
+        // tokenize tens of thousands
+        assert!(tokenized.load(Ordering::SeqCst) < 2000, "{} words tokenized", tokenized.load(Ordering::SeqCst));
+    }
+
+    /// Like `WordRuntime`, taking a while over each reply.
+    struct SlowWordRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for SlowWordRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            WordRuntime.infer(prompt, options).await
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_session_turns_are_all_kept() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(SlowWordRuntime));
+        let session = engine.sessions.create(None).await.unwrap();
+
+        let (first, second) = tokio::join!(
+            engine.process_chat_in_session(&session.id, "first", InferenceOptions::default(), None),
+            engine.process_chat_in_session(&session.id, "second", InferenceOptions::default(), None),
+        );
+        first.unwrap();
+        second.unwrap();
+
+        // One turn after the other, neither overwriting the other's history
+        let history = engine.sessions.get(&session.id).await.unwrap().messages;
+        let contents: Vec<&str> = history.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["first", "fine thanks", "second", "fine thanks"]);
+    }
+
+    #[tokio::test]
+    async fn test_session_keeps_an_oversized_message() {
+        let mut config = EngineConfig::default();
+        config.model.default_context_size = 20;
+        let engine = Engine::new(config, Box::new(WordRuntime));
+        let session = engine.sessions.create(None).await.unwrap();
+
+        engine.process_chat_in_session(&session.id, "hello", InferenceOptions::default(), None).await.unwrap();
+        let long = "word ".repeat(50);
+        engine.process_chat_in_session(&session.id, &long, InferenceOptions::default(), None).await.unwrap();
+
+        // Everything before it goes, but the message itself is still sent
+        let history = engine.sessions.get(&session.id).await.unwrap().messages;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].content, long);
+
+        let err = engine.process_chat_in_session("missing", "hi", InferenceOptions::default(), None).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_memory_injection_fits_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = EngineConfig::default();
+        config.model.default_context_size = 40;
+        config.memory.enabled = true;
+        config.memory.persistence_path = dir.path().join("memory.json");
+        let engine = Engine::new(config, Box::new(WordRuntime));
+        engine.memory.set_summary(&"word ".repeat(30)).await.unwrap();
+        engine.memory.set_fact("user", "Divyansh").await.unwrap();
+        let options = || InferenceOptions { max_tokens: Some(10), ..InferenceOptions::default() };
+
+        // 40 - 5 prompt - 10 reply leaves 25 tokens for memory
+        let response = engine.process_request("one two three four five", options(), None).await.unwrap();
+        assert!((2..=25).contains(&response.usage.memory_tokens), "{}", response.usage.memory_tokens);
+
+        let mut rx = engine.process_request_stream("one two three four five", options()).await.unwrap();
+        let mut memory_tokens = None;
+        while let Some(chunk) = rx.recv().await {
+            if let TokenChunk::Done { usage, .. } = chunk {
+                memory_tokens = Some(usage.memory_tokens);
+            }
+        }
+        assert_eq!(memory_tokens, Some(response.usage.memory_tokens));
+
+        // A prompt that fills the context gets no memory at all
+        let response = engine.process_request(&"word ".repeat(35), options(), None).await.unwrap();
+        assert_eq!(response.usage.memory_tokens, 0);
+    }
+}
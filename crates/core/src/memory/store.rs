@@ -0,0 +1,444 @@
+//! Where `MemoryManager` keeps memory between runs, selected by `memory.backend`.
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, Transaction};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use super::{remove_if_empty, write_atomic, write_atomic_blocking, FactEmbedding, MemoryData, DEFAULT_NAMESPACE};
+use crate::error::EngineError;
+
+/// Storage behind a `MemoryManager`. The manager keeps everything in memory and passes
+/// each change on to the store, one at a time.
+#[async_trait]
+pub trait MemoryStore: Send + Sync {
+    /// Read everything stored, by namespace. Called once, before any other method.
+    fn load(&mut self) -> Result<HashMap<String, MemoryData>, EngineError>;
+
+    /// Add or replace a fact, making it the most recently written one. Replacing its value
+    /// drops its embedding.
+    async fn save_fact(&self, namespace: &str, key: &str, value: &str) -> Result<(), EngineError>;
+
+    /// Attach an embedding to an existing fact, until the fact changes or is deleted.
+    async fn save_embedding(&self, namespace: &str, key: &str, embedding: &FactEmbedding) -> Result<(), EngineError>;
+
+    /// Replace a namespace's summary; an empty one removes it.
+    async fn save_summary(&self, namespace: &str, summary: &str) -> Result<(), EngineError>;
+
+    /// Remove a fact, or with no `key`, the whole namespace.
+    async fn delete(&self, namespace: &str, key: Option<&str>) -> Result<(), EngineError>;
+
+    /// The stored facts of a namespace, least recently written first.
+    async fn list(&self, namespace: &str) -> Result<Vec<(String, String)>, EngineError>;
+
+    /// Write out any changes held back so far. Stores that write every change as it comes
+    /// don't need to do anything.
+    async fn flush(&self) -> Result<(), EngineError> {
+        Ok(())
+    }
+}
+
+/// Version of the `memory.json` layout. Version 1 files held a single namespace's
+/// `summary` and `kv_store` at the top level; they are read into `DEFAULT_NAMESPACE`.
+pub(crate) const FILE_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct MemoryFile {
+    version: u32,
+    namespaces: HashMap<String, MemoryData>,
+}
+
+/// All memory in one JSON file, rewritten (atomically) as a whole. A burst of changes is
+/// written once: after `flush_delay` without further changes, or once `flush_max_pending`
+/// changes are waiting, whichever comes first. A zero delay writes every change right away.
+pub struct JsonStore {
+    file: Arc<JsonFile>,
+    flush_delay: Duration,
+    flush_max_pending: usize,
+}
+
+struct JsonFile {
+    path: PathBuf,
+    /// Held while writing, so an older snapshot can never be written after a newer one.
+    state: Mutex<JsonState>,
+    /// Woken on every held-back change, restarting the flusher's wait.
+    changed: Notify,
+    /// Whether a task is waiting to flush held-back changes.
+    flusher_running: AtomicBool,
+    /// Times the file was written.
+    writes: Arc<AtomicUsize>,
+}
+
+struct JsonState {
+    /// What the file holds, once flushed.
+    data: HashMap<String, MemoryData>,
+    /// Changes not written yet.
+    pending: usize,
+}
+
+impl JsonStore {
+    pub fn new(path: &Path, flush_delay: Duration, flush_max_pending: usize) -> Self {
+        let file = JsonFile {
+            path: path.to_path_buf(),
+            state: Mutex::new(JsonState { data: HashMap::new(), pending: 0 }),
+            changed: Notify::new(),
+            flusher_running: AtomicBool::new(false),
+            writes: Arc::new(AtomicUsize::new(0)),
+        };
+        Self { file: Arc::new(file), flush_delay, flush_max_pending }
+    }
+
+    /// Counts the times the file is written, for tests that check writes are coalesced.
+    #[cfg(test)]
+    pub(super) fn write_counter(&self) -> Arc<AtomicUsize> {
+        self.file.writes.clone()
+    }
+
+    /// Apply `f` to the data, then write it now or leave it to the flusher.
+    async fn change(&self, f: impl FnOnce(&mut HashMap<String, MemoryData>)) -> Result<(), EngineError> {
+        let mut state = self.file.state.lock().await;
+        f(&mut state.data);
+        state.pending += 1;
+        if self.flush_delay.is_zero() || state.pending >= self.flush_max_pending {
+            return self.file.write(&mut state).await;
+        }
+        drop(state);
+
+        self.file.changed.notify_one();
+        if !self.file.flusher_running.swap(true, Ordering::SeqCst) {
+            let (file, delay) = (self.file.clone(), self.flush_delay);
+            tokio::spawn(async move {
+                // Any change after this point starts a new flusher; any before it is
+                // already in the data this one writes
+                while tokio::time::timeout(delay, file.changed.notified()).await.is_ok() {}
+                file.flusher_running.store(false, Ordering::SeqCst);
+                if let Err(e) = file.flush().await {
+                    tracing::error!("{}; retrying on the next change", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+impl JsonFile {
+    async fn flush(&self) -> Result<(), EngineError> {
+        let mut state = self.state.lock().await;
+        if state.pending > 0 {
+            self.write(&mut state).await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&self, state: &mut JsonState) -> Result<(), EngineError> {
+        let json = to_json(&state.data)?;
+        write_atomic(&self.path, json.as_bytes()).await
+            .map_err(|e| EngineError::Memory(format!("Failed to save {}: {}", self.path.display(), e)))?;
+        state.pending = 0;
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+fn to_json(data: &HashMap<String, MemoryData>) -> Result<String, EngineError> {
+    let file = MemoryFile { version: FILE_VERSION, namespaces: data.clone() };
+    serde_json::to_string_pretty(&file)
+        .map_err(|e| EngineError::Memory(format!("Serialization error: {}", e)))
+}
+
+/// Last resort for changes nobody flushed, e.g. when the engine is dropped without
+/// `shutdown`.
+impl Drop for JsonStore {
+    fn drop(&mut self) {
+        // Busy only while the flusher writes, and then it writes everything
+        let Ok(mut state) = self.file.state.try_lock() else {
+            return;
+        };
+        if state.pending == 0 {
+            return;
+        }
+        let path = &self.file.path;
+        let saved = to_json(&state.data)
+            .and_then(|json| write_atomic_blocking(path, json.as_bytes())
+                .map_err(|e| EngineError::Memory(format!("Failed to save {}: {}", path.display(), e))));
+        match saved {
+            Ok(()) => state.pending = 0,
+            Err(e) => tracing::error!("{}; the last {} memory changes are lost", e, state.pending),
+        }
+    }
+}
+
+#[async_trait]
+impl MemoryStore for JsonStore {
+    fn load(&mut self) -> Result<HashMap<String, MemoryData>, EngineError> {
+        let path = &self.file.path;
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        let data = load_data(path)?;
+        let file = Arc::get_mut(&mut self.file).expect("no flusher before the first change");
+        file.state.get_mut().data = data.clone();
+        Ok(data)
+    }
+
+    async fn save_fact(&self, namespace: &str, key: &str, value: &str) -> Result<(), EngineError> {
+        self.change(|data| data.entry(namespace.to_string()).or_default().set_fact(key, value)).await
+    }
+
+    async fn save_embedding(&self, namespace: &str, key: &str, embedding: &FactEmbedding) -> Result<(), EngineError> {
+        self.change(|data| {
+            if let Some(memory) = data.get_mut(namespace) {
+                memory.set_embedding(key, embedding.clone());
+            }
+        }).await
+    }
+
+    async fn save_summary(&self, namespace: &str, summary: &str) -> Result<(), EngineError> {
+        self.change(|data| {
+            data.entry(namespace.to_string()).or_default().set_summary(summary);
+            remove_if_empty(data, namespace);
+        }).await
+    }
+
+    async fn delete(&self, namespace: &str, key: Option<&str>) -> Result<(), EngineError> {
+        self.change(|data| match key {
+            Some(key) => {
+                if let Some(memory) = data.get_mut(namespace) {
+                    memory.delete_fact(key);
+                }
+                remove_if_empty(data, namespace);
+            }
+            None => {
+                data.remove(namespace);
+            }
+        }).await
+    }
+
+    async fn list(&self, namespace: &str) -> Result<Vec<(String, String)>, EngineError> {
+        let state = self.file.state.lock().await;
+        Ok(state.data.get(namespace)
+            .map(|memory| memory.facts_by_age().into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+            .unwrap_or_default())
+    }
+
+    async fn flush(&self) -> Result<(), EngineError> {
+        self.file.flush().await
+    }
+}
+
+pub(crate) fn load_data(path: &Path) -> Result<HashMap<String, MemoryData>, EngineError> {
+    let content = fs::read_to_string(path)?;
+    let corrupt = |e: serde_json::Error| EngineError::Memory(format!("{} is corrupt: {}", path.display(), e));
+    let value: serde_json::Value = serde_json::from_str(&content).map_err(corrupt)?;
+
+    match value.get("version").and_then(|v| v.as_u64()) {
+        None => {
+            let memory: MemoryData = serde_json::from_value(value).map_err(corrupt)?;
+            tracing::info!("Reading {} from the single-namespace layout; it is rewritten on the next save", path.display());
+            let mut data = HashMap::new();
+            data.insert(DEFAULT_NAMESPACE.to_string(), memory);
+            remove_if_empty(&mut data, DEFAULT_NAMESPACE);
+            Ok(data)
+        }
+        Some(version) if version <= FILE_VERSION as u64 => {
+            let file: MemoryFile = serde_json::from_value(value).map_err(corrupt)?;
+            Ok(file.namespaces)
+        }
+        Some(version) => Err(EngineError::Memory(format!(
+            "{} has version {}, newer than this build supports ({})", path.display(), version, FILE_VERSION
+        ))),
+    }
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS facts (
+    namespace TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    -- Increases with every write, to tell the least recently written facts
+    written INTEGER NOT NULL,
+    PRIMARY KEY (namespace, key)
+);
+CREATE TABLE IF NOT EXISTS summaries (
+    namespace TEXT PRIMARY KEY,
+    summary TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS embeddings (
+    namespace TEXT NOT NULL,
+    key TEXT NOT NULL,
+    model TEXT NOT NULL,
+    -- Little-endian f32s
+    vector BLOB NOT NULL,
+    PRIMARY KEY (namespace, key)
+);
+";
+
+/// Memory in a SQLite database: a row per fact and per summary, each change in its own
+/// transaction. Other processes can write to the same database; waits for their locks
+/// are capped by `BUSY_TIMEOUT`.
+pub struct SqliteStore {
+    path: PathBuf,
+    /// Opened on first use, and again on the next call after failing to open.
+    conn: Arc<std::sync::Mutex<Option<Connection>>>,
+}
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl SqliteStore {
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf(), conn: Arc::new(std::sync::Mutex::new(None)) }
+    }
+
+    /// Run `f` on the connection, opening it first if needed.
+    fn with_conn<T>(path: &Path, conn: &std::sync::Mutex<Option<Connection>>, f: impl FnOnce(&mut Connection) -> rusqlite::Result<T>) -> Result<T, EngineError> {
+        let sqlite_error = |e: rusqlite::Error| EngineError::Memory(format!("SQLite error in {}: {}", path.display(), e));
+        let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+        if conn.is_none() {
+            let opened = Connection::open(path).map_err(sqlite_error)?;
+            opened.busy_timeout(BUSY_TIMEOUT).map_err(sqlite_error)?;
+            opened.execute_batch(SCHEMA).map_err(sqlite_error)?;
+            *conn = Some(opened);
+        }
+        f(conn.as_mut().expect("opened above")).map_err(sqlite_error)
+    }
+
+    /// Run `f` in a transaction on the blocking pool, committing if it succeeds.
+    async fn write(&self, f: impl FnOnce(&Transaction) -> rusqlite::Result<()> + Send + 'static) -> Result<(), EngineError> {
+        let (path, conn) = (self.path.clone(), self.conn.clone());
+        tokio::task::spawn_blocking(move || {
+            Self::with_conn(&path, &conn, |conn| {
+                let tx = conn.transaction()?;
+                f(&tx)?;
+                tx.commit()
+            })
+        })
+        .await
+        .map_err(|e| EngineError::Memory(format!("Memory store task failed: {}", e)))?
+    }
+}
+
+#[async_trait]
+impl MemoryStore for SqliteStore {
+    fn load(&mut self) -> Result<HashMap<String, MemoryData>, EngineError> {
+        let result = Self::with_conn(&self.path, &self.conn, |conn| {
+            let mut data: HashMap<String, MemoryData> = HashMap::new();
+            let mut facts = conn.prepare("SELECT namespace, key, value FROM facts ORDER BY written")?;
+            let mut rows = facts.query([])?;
+            while let Some(row) = rows.next()? {
+                let namespace: String = row.get(0)?;
+                data.entry(namespace).or_default().set_fact(&row.get::<_, String>(1)?, &row.get::<_, String>(2)?);
+            }
+            let mut summaries = conn.prepare("SELECT namespace, summary FROM summaries")?;
+            let mut rows = summaries.query([])?;
+            while let Some(row) = rows.next()? {
+                let namespace: String = row.get(0)?;
+                data.entry(namespace).or_default().set_summary(&row.get::<_, String>(1)?);
+            }
+            let mut embeddings = conn.prepare("SELECT namespace, key, model, vector FROM embeddings")?;
+            let mut rows = embeddings.query([])?;
+            while let Some(row) = rows.next()? {
+                if let Some(memory) = data.get_mut(&row.get::<_, String>(0)?) {
+                    let embedding = FactEmbedding { model: row.get(2)?, vector: blob_to_vector(&row.get::<_, Vec<u8>>(3)?) };
+                    memory.set_embedding(&row.get::<_, String>(1)?, embedding);
+                }
+            }
+            Ok(data)
+        });
+        if result.is_err() {
+            // Let the next call open the file afresh, e.g. after it was moved aside
+            *self.conn.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+        result
+    }
+
+    async fn save_fact(&self, namespace: &str, key: &str, value: &str) -> Result<(), EngineError> {
+        let (namespace, key, value) = (namespace.to_string(), key.to_string(), value.to_string());
+        self.write(move |tx| {
+            tx.execute(
+                "DELETE FROM embeddings WHERE namespace = ?1 AND key = ?2
+                 AND ?3 IS NOT (SELECT value FROM facts WHERE namespace = ?1 AND key = ?2)",
+                params![namespace, key, value],
+            )?;
+            tx.execute(
+                "INSERT INTO facts (namespace, key, value, written)
+                 VALUES (?1, ?2, ?3, (SELECT COALESCE(MAX(written), 0) + 1 FROM facts))
+                 ON CONFLICT (namespace, key) DO UPDATE SET value = excluded.value, written = excluded.written",
+                params![namespace, key, value],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn save_embedding(&self, namespace: &str, key: &str, embedding: &FactEmbedding) -> Result<(), EngineError> {
+        let (namespace, key, model, vector) = (namespace.to_string(), key.to_string(), embedding.model.clone(), vector_to_blob(&embedding.vector));
+        self.write(move |tx| {
+            tx.execute(
+                "INSERT INTO embeddings (namespace, key, model, vector)
+                 SELECT ?1, ?2, ?3, ?4 WHERE EXISTS (SELECT 1 FROM facts WHERE namespace = ?1 AND key = ?2)
+                 ON CONFLICT (namespace, key) DO UPDATE SET model = excluded.model, vector = excluded.vector",
+                params![namespace, key, model, vector],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn save_summary(&self, namespace: &str, summary: &str) -> Result<(), EngineError> {
+        let (namespace, summary) = (namespace.to_string(), summary.to_string());
+        self.write(move |tx| {
+            if summary.is_empty() {
+                tx.execute("DELETE FROM summaries WHERE namespace = ?1", params![namespace])?;
+            } else {
+                tx.execute(
+                    "INSERT INTO summaries (namespace, summary) VALUES (?1, ?2)
+                     ON CONFLICT (namespace) DO UPDATE SET summary = excluded.summary",
+                    params![namespace, summary],
+                )?;
+            }
+            Ok(())
+        }).await
+    }
+
+    async fn delete(&self, namespace: &str, key: Option<&str>) -> Result<(), EngineError> {
+        let (namespace, key) = (namespace.to_string(), key.map(str::to_string));
+        self.write(move |tx| {
+            match key {
+                Some(key) => {
+                    tx.execute("DELETE FROM facts WHERE namespace = ?1 AND key = ?2", params![namespace, key])?;
+                    tx.execute("DELETE FROM embeddings WHERE namespace = ?1 AND key = ?2", params![namespace, key])?;
+                }
+                None => {
+                    tx.execute("DELETE FROM facts WHERE namespace = ?1", params![namespace])?;
+                    tx.execute("DELETE FROM embeddings WHERE namespace = ?1", params![namespace])?;
+                    tx.execute("DELETE FROM summaries WHERE namespace = ?1", params![namespace])?;
+                }
+            }
+            Ok(())
+        }).await
+    }
+
+    async fn list(&self, namespace: &str) -> Result<Vec<(String, String)>, EngineError> {
+        let (path, conn, namespace) = (self.path.clone(), self.conn.clone(), namespace.to_string());
+        tokio::task::spawn_blocking(move || {
+            Self::with_conn(&path, &conn, |conn| {
+                let mut statement = conn.prepare("SELECT key, value FROM facts WHERE namespace = ?1 ORDER BY written")?;
+                let rows = statement.query_map(params![namespace], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                rows.collect()
+            })
+        })
+        .await
+        .map_err(|e| EngineError::Memory(format!("Memory store task failed: {}", e)))?
+    }
+}
+
+fn vector_to_blob(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn blob_to_vector(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4).map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect()
+}
@@ -0,0 +1,74 @@
+//! Engine metrics, emitted through the `metrics` facade.
+//!
+//! Nothing is recorded unless the application installs a recorder; the server installs a
+//! Prometheus one and serves it at `/metrics`.
+
+use ::metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit};
+use crate::runtime::Usage;
+
+/// Finished inference requests, labelled by `status` (`success`, `truncated`,
+/// `cancelled` or `error`).
+pub const REQUESTS_TOTAL: &str = "lie_requests_total";
+pub const INPUT_TOKENS_TOTAL: &str = "lie_input_tokens_total";
+pub const OUTPUT_TOKENS_TOTAL: &str = "lie_output_tokens_total";
+pub const INFERENCE_DURATION_SECONDS: &str = "lie_inference_duration_seconds";
+/// Requests running or waiting for the model.
+pub const QUEUE_DEPTH: &str = "lie_queue_depth";
+pub const MEMORY_FACTS: &str = "lie_memory_facts";
+/// 1 while a model is loaded, else 0.
+pub const MODEL_LOADED: &str = "lie_model_loaded";
+/// Cacheable requests answered from the response cache, and those that weren't.
+pub const CACHE_HITS_TOTAL: &str = "lie_cache_hits_total";
+pub const CACHE_MISSES_TOTAL: &str = "lie_cache_misses_total";
+/// Classified requests, labelled by `intent` (`none` when no label fit).
+pub const INTENTS_TOTAL: &str = "lie_intents_total";
+
+/// Register descriptions and units with the installed recorder.
+pub fn describe() {
+    describe_counter!(REQUESTS_TOTAL, "Finished inference requests by status");
+    describe_counter!(INPUT_TOKENS_TOTAL, Unit::Count, "Prompt tokens processed");
+    describe_counter!(OUTPUT_TOKENS_TOTAL, Unit::Count, "Tokens generated");
+    describe_histogram!(INFERENCE_DURATION_SECONDS, Unit::Seconds, "Time spent in the runtime per request");
+    describe_gauge!(QUEUE_DEPTH, "Requests running or waiting for the model");
+    describe_gauge!(MEMORY_FACTS, "Facts in persistent memory");
+    describe_gauge!(MODEL_LOADED, "Whether a model is loaded");
+    describe_counter!(CACHE_HITS_TOTAL, "Requests answered from the response cache");
+    describe_counter!(CACHE_MISSES_TOTAL, "Cacheable requests not found in the response cache");
+    describe_counter!(INTENTS_TOTAL, "Requests classified by intent");
+}
+
+pub(crate) fn record_request(status: &str, usage: &Usage) {
+    counter!(REQUESTS_TOTAL, "status" => status.to_string()).increment(1);
+    counter!(INPUT_TOKENS_TOTAL).increment(usage.input_tokens as u64);
+    counter!(OUTPUT_TOKENS_TOTAL).increment(usage.output_tokens as u64);
+    histogram!(INFERENCE_DURATION_SECONDS).record(usage.duration_ms as f64 / 1000.0);
+}
+
+/// A request that failed without producing a response.
+pub(crate) fn record_failure() {
+    counter!(REQUESTS_TOTAL, "status" => "error").increment(1);
+}
+
+pub(crate) fn record_cache_hit() {
+    counter!(CACHE_HITS_TOTAL).increment(1);
+}
+
+pub(crate) fn record_cache_miss() {
+    counter!(CACHE_MISSES_TOTAL).increment(1);
+}
+
+pub(crate) fn record_intent(intent: Option<&str>) {
+    counter!(INTENTS_TOTAL, "intent" => intent.unwrap_or("none").to_string()).increment(1);
+}
+
+pub(crate) fn set_queue_depth(depth: usize) {
+    gauge!(QUEUE_DEPTH).set(depth as f64);
+}
+
+pub(crate) fn set_memory_facts(count: usize) {
+    gauge!(MEMORY_FACTS).set(count as f64);
+}
+
+pub(crate) fn set_model_loaded(loaded: bool) {
+    gauge!(MODEL_LOADED).set(if loaded { 1.0 } else { 0.0 });
+}
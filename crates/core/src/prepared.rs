@@ -0,0 +1,163 @@
+//! Prepared contexts: texts evaluated ahead of time by `Engine::prepare_context`, such as a
+//! long system prompt or a document. The runtime's state after evaluating one is saved to
+//! `<name>.bin` in `model.session_dir`, with `<name>.json` describing it. A request naming it
+//! in `InferenceOptions::session` gets the text put before its prompt, and the runtime loads
+//! that state instead of evaluating the text again, also after a restart.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::EngineError;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PreparedContext {
+    pub name: String,
+    /// What was evaluated.
+    pub text: String,
+    /// The model it was evaluated with; the saved state only works with that one.
+    pub model: PathBuf,
+    /// Tokens the text took up.
+    pub tokens: u32,
+    /// Size of the saved state in bytes.
+    pub size: u64,
+    /// Unix timestamp (seconds).
+    pub created_at: u64,
+}
+
+/// `~/.cache/cela/sessions`, the default `model.session_dir`.
+pub fn default_dir() -> PathBuf {
+    let cache = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")).unwrap_or_else(|| PathBuf::from(".cache"));
+    cache.join("cela/sessions")
+}
+
+/// Check that `name` can be used as a file name: 1 to 64 ASCII letters, digits, `-`, `_`
+/// or `.`, not starting with a `.`.
+pub fn validate_name(name: &str) -> Result<(), EngineError> {
+    let valid = (1..=64).contains(&name.len())
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(EngineError::Validation(format!(
+            "Invalid session name '{}': use 1 to 64 letters, digits, '-', '_' or '.', not starting with '.'", name
+        )));
+    }
+    Ok(())
+}
+
+/// The prepared contexts in a directory.
+pub struct PreparedContexts {
+    dir: PathBuf,
+}
+
+impl PreparedContexts {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Where the runtime state of `name` is saved.
+    pub fn state_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.bin", name))
+    }
+
+    fn info_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    pub fn get(&self, name: &str) -> Result<PreparedContext, EngineError> {
+        validate_name(name)?;
+        let path = self.info_path(name);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(EngineError::NotFound(format!("No session '{}' in {}; create it with `lie session create`", name, self.dir.display())));
+            }
+            Err(e) => return Err(EngineError::Runtime(format!("Failed to read {}: {}", path.display(), e))),
+        };
+        serde_json::from_str(&content)
+            .map_err(|e| EngineError::Runtime(format!("Failed to parse {}: {}", path.display(), e)))
+    }
+
+    /// Every prepared context, by name. None if the directory doesn't exist yet.
+    pub fn list(&self) -> Result<Vec<PreparedContext>, EngineError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(EngineError::Runtime(format!("Failed to read {}: {}", self.dir.display(), e))),
+        };
+        let mut contexts = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let name = match (path.extension(), path.file_stem()) {
+                (Some(ext), Some(stem)) if ext == "json" => stem.to_string_lossy().into_owned(),
+                _ => continue,
+            };
+            contexts.push(self.get(&name)?);
+        }
+        contexts.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(contexts)
+    }
+
+    /// Record `context`, whose state has been saved to `state_path`.
+    pub(crate) fn add(&self, context: &PreparedContext) -> Result<(), EngineError> {
+        let path = self.info_path(&context.name);
+        let content = serde_json::to_string_pretty(context)
+            .map_err(|e| EngineError::Runtime(format!("Failed to serialize session '{}': {}", context.name, e)))?;
+        fs::write(&path, content)
+            .map_err(|e| EngineError::Runtime(format!("Failed to write {}: {}", path.display(), e)))
+    }
+
+    /// Delete `name`'s description and state. `false` if there is no such context.
+    pub fn delete(&self, name: &str) -> Result<bool, EngineError> {
+        validate_name(name)?;
+        let mut found = false;
+        for path in [self.info_path(name), self.state_path(name)] {
+            match fs::remove_file(&path) {
+                Ok(()) => found = true,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(EngineError::Runtime(format!("Failed to delete {}: {}", path.display(), e))),
+            }
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(name: &str) -> PreparedContext {
+        PreparedContext { name: name.to_string(), text: "A long document.".to_string(), model: "model.gguf".into(), tokens: 5, size: 64, created_at: 1700000000 }
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("docs-v1.2_final").is_ok());
+        for name in ["", "../etc", "a/b", ".hidden", &"x".repeat(65)] {
+            assert_eq!(validate_name(name).unwrap_err().code(), crate::error::ErrorCode::ValidationError, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_add_list_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let contexts = PreparedContexts::new(dir.path().join("sessions"));
+        assert!(contexts.list().unwrap().is_empty());
+
+        fs::create_dir_all(contexts.dir()).unwrap();
+        contexts.add(&context("b")).unwrap();
+        contexts.add(&context("a")).unwrap();
+        fs::write(contexts.state_path("a"), b"state").unwrap();
+        let names: Vec<String> = contexts.list().unwrap().into_iter().map(|c| c.name).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(contexts.get("a").unwrap(), context("a"));
+
+        assert!(contexts.delete("a").unwrap());
+        assert!(!contexts.state_path("a").exists());
+        assert!(!contexts.delete("a").unwrap());
+        assert_eq!(contexts.get("a").unwrap_err().code(), crate::error::ErrorCode::NotFound);
+    }
+}
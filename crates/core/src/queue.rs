@@ -0,0 +1,225 @@
+//! Admission control for requests that need the runtime.
+//!
+//! At most `max_concurrent` requests run at once and at most `max_queue_depth` wait
+//! behind them; anything beyond that is turned away with `EngineError::QueueFull`
+//! right away instead of parking on the runtime lock indefinitely.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::config::QueueConfig;
+use crate::error::EngineError;
+use crate::metrics;
+
+/// How a request fared in the queue before it started running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct QueueStats {
+    /// How many requests had to finish before this one could start; 0 means it started
+    /// right away.
+    pub position: usize,
+    /// Time spent waiting for a slot.
+    pub wait_ms: u64,
+}
+
+pub struct RequestQueue {
+    slots: Arc<Semaphore>,
+    max_concurrent: usize,
+    /// Changes with `set_max_queue_depth`.
+    max_queue_depth: AtomicUsize,
+    /// Requests running or waiting.
+    admitted: Arc<AtomicUsize>,
+    /// Moving average of how long a request holds its slot, for `Retry-After` estimates.
+    avg_run_ms: Arc<AtomicU64>,
+    created: Instant,
+    /// When the last request left the queue, in ms since `created`.
+    last_left_ms: Arc<AtomicU64>,
+}
+
+/// A request's place in the queue. Leaving the queue (dropping this or the `Slot`
+/// it turns into) frees the place for someone else.
+pub struct QueuedRequest {
+    slots: Arc<Semaphore>,
+    admitted: Arc<AtomicUsize>,
+    avg_run_ms: Arc<AtomicU64>,
+    created: Instant,
+    last_left_ms: Arc<AtomicU64>,
+    position: usize,
+    arrived: Instant,
+}
+
+/// Permission to use the runtime, held for the duration of the request.
+pub struct Slot {
+    _permit: OwnedSemaphorePermit,
+    request: QueuedRequest,
+    started: Instant,
+    pub stats: QueueStats,
+}
+
+impl RequestQueue {
+    pub fn new(config: &QueueConfig) -> Self {
+        let max_concurrent = config.max_concurrent.max(1);
+        Self {
+            slots: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            max_queue_depth: AtomicUsize::new(config.max_queue_depth),
+            admitted: Arc::new(AtomicUsize::new(0)),
+            avg_run_ms: Arc::new(AtomicU64::new(0)),
+            created: Instant::now(),
+            last_left_ms: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Take a place in the queue, or fail with `QueueFull` if there is none left.
+    /// Doesn't wait; call `QueuedRequest::wait` for that.
+    pub fn enter(&self) -> Result<QueuedRequest, EngineError> {
+        let capacity = self.max_concurrent + self.max_queue_depth.load(Ordering::SeqCst);
+        let ahead = self.admitted.fetch_add(1, Ordering::SeqCst);
+        if ahead >= capacity {
+            self.admitted.fetch_sub(1, Ordering::SeqCst);
+            return Err(EngineError::QueueFull { retry_after_secs: self.retry_after_secs() });
+        }
+        metrics::set_queue_depth(ahead + 1);
+
+        Ok(QueuedRequest {
+            slots: self.slots.clone(),
+            admitted: self.admitted.clone(),
+            avg_run_ms: self.avg_run_ms.clone(),
+            created: self.created,
+            last_left_ms: self.last_left_ms.clone(),
+            position: ahead.saturating_sub(self.max_concurrent - 1),
+            arrived: Instant::now(),
+        })
+    }
+
+    /// Let `depth` requests wait from now on. Requests already waiting beyond a lowered
+    /// depth keep their place.
+    pub fn set_max_queue_depth(&self, depth: usize) {
+        self.max_queue_depth.store(depth, Ordering::SeqCst);
+    }
+
+    /// Requests allowed to use the runtime at once.
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    /// How long the queue has been empty, or `None` if a request is running or waiting.
+    pub fn idle_for(&self) -> Option<Duration> {
+        if self.admitted.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+        let last_left = Duration::from_millis(self.last_left_ms.load(Ordering::SeqCst));
+        Some(self.created.elapsed().saturating_sub(last_left))
+    }
+
+    /// Rough time until a place frees up: one average run per wave of waiting requests.
+    fn retry_after_secs(&self) -> u64 {
+        let avg_run = Duration::from_millis(self.avg_run_ms.load(Ordering::Relaxed));
+        let waves = (self.max_queue_depth.load(Ordering::Relaxed) / self.max_concurrent + 1) as u32;
+        (avg_run * waves).as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+impl QueuedRequest {
+    /// Wait until a slot is free.
+    pub async fn wait(self) -> Slot {
+        let permit = self.slots.clone().acquire_owned().await
+            .expect("the queue semaphore is never closed");
+        let stats = QueueStats {
+            position: self.position,
+            wait_ms: self.arrived.elapsed().as_millis() as u64,
+        };
+        Slot { _permit: permit, request: self, started: Instant::now(), stats }
+    }
+}
+
+impl Drop for QueuedRequest {
+    fn drop(&mut self) {
+        self.last_left_ms.store(self.created.elapsed().as_millis() as u64, Ordering::SeqCst);
+        let admitted = self.admitted.fetch_sub(1, Ordering::SeqCst);
+        metrics::set_queue_depth(admitted - 1);
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let run_ms = self.started.elapsed().as_millis() as u64;
+        let avg = &self.request.avg_run_ms;
+        let previous = avg.load(Ordering::Relaxed);
+        let updated = if previous == 0 { run_ms } else { (previous * 3 + run_ms) / 4 };
+        avg.store(updated, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(max_concurrent: usize, max_queue_depth: usize) -> RequestQueue {
+        RequestQueue::new(&QueueConfig { max_concurrent, max_queue_depth })
+    }
+
+    #[tokio::test]
+    async fn test_rejects_beyond_capacity() {
+        let queue = queue(1, 2);
+        let first = queue.enter().unwrap();
+        let second = queue.enter().unwrap();
+        let third = queue.enter().unwrap();
+        assert_eq!((first.position, second.position, third.position), (0, 1, 2));
+
+        let err = queue.enter().err().unwrap();
+        assert!(matches!(err, EngineError::QueueFull { retry_after_secs } if retry_after_secs >= 1));
+
+        // Leaving the queue frees the place
+        drop(second);
+        assert!(queue.enter().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_set_max_queue_depth() {
+        let queue = queue(1, 0);
+        let _running = queue.enter().unwrap();
+        assert!(queue.enter().is_err());
+        queue.set_max_queue_depth(1);
+        let _waiting = queue.enter().unwrap();
+        assert!(queue.enter().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_slots_are_exclusive() {
+        let queue = queue(1, 1);
+        let running = queue.enter().unwrap().wait().await;
+        assert_eq!(running.stats.position, 0);
+
+        let waiting = tokio::spawn(queue.enter().unwrap().wait());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        drop(running);
+        let slot = waiting.await.unwrap();
+        assert_eq!(slot.stats.position, 1);
+        assert!(slot.stats.wait_ms >= 50);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_slots() {
+        let queue = queue(2, 0);
+        let _a = queue.enter().unwrap().wait().await;
+        let b = queue.enter().unwrap();
+        assert_eq!(b.position, 0);
+        let _b = tokio::time::timeout(Duration::from_millis(100), b.wait()).await.unwrap();
+        assert!(queue.enter().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_idle_for() {
+        let queue = queue(1, 1);
+        let waiting = queue.enter().unwrap();
+        assert_eq!(queue.idle_for(), None);
+
+        drop(waiting);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(queue.idle_for().unwrap() >= Duration::from_millis(50));
+    }
+}
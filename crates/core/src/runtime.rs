@@ -1,14 +1,117 @@
+pub mod conformance;
+pub mod mock;
+pub mod registry;
+
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use crate::chat::ChatTemplate;
 use crate::error::EngineError;
+use crate::grammar;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InferenceOptions {
     pub max_tokens: Option<u32>,
     pub max_time_ms: Option<u64>,
     pub temperature: Option<f32>,
+    /// Sample only from the `top_k` most likely tokens. Ignored at temperature 0.
+    pub top_k: Option<u32>,
+    /// Nucleus sampling: sample only from the most likely tokens whose probabilities
+    /// add up to `top_p`. Ignored at temperature 0.
+    pub top_p: Option<f32>,
+    /// Sample only from tokens at least `min_p` times as likely as the most likely one.
+    pub min_p: Option<f32>,
+    /// Locally typical sampling mass. Ignored at temperature 0.
+    pub typical_p: Option<f32>,
+    /// Added to the logits of every token the key string tokenizes to, at each step.
+    /// A bias of -100 or lower (or `f32::NEG_INFINITY`) bans those tokens.
+    #[serde(default)]
+    pub logit_bias: HashMap<String, f32>,
+    /// Constrain the output format. The engine turns this into `grammar` and checks the
+    /// result afterwards.
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+    /// GBNF grammar the output must follow. Runtimes that can't enforce grammars ignore it.
+    #[serde(default)]
+    pub grammar: Option<String>,
+    /// Seed for the sampling RNG. The same seed, prompt and options reproduce the same output.
+    /// A random seed is used when unset; it is reported back in `InferenceResult::seed`.
+    pub seed: Option<u64>,
+    /// Penalize tokens that occurred in the last `repeat_last_n` tokens; 1.0 disables it.
+    pub repeat_penalty: Option<f32>,
+    /// How many recent tokens the penalties look at (default 64).
+    pub repeat_last_n: Option<u32>,
+    /// Lowers a token's logit by this much for each recent occurrence.
+    pub frequency_penalty: Option<f32>,
+    /// Lowers a token's logit by this much if it occurred recently at all.
+    pub presence_penalty: Option<f32>,
+    /// Let the penalties see the prompt tokens too, not just generated ones.
+    #[serde(default)]
+    pub penalize_prompt: bool,
+    /// Generation ends where the output reaches any of these, which is left out of the text.
     pub stop_sequences: Vec<String>,
+    /// Discard any cached context before running, instead of reusing the common prefix.
+    #[serde(default)]
+    pub reset_context: bool,
+    /// Override the model's `n_threads` and `n_threads_batch` for this request. The runtime
+    /// may have to recreate its context to apply them, discarding the cached prompt.
+    #[serde(default)]
+    pub n_threads: Option<u32>,
+    #[serde(default)]
+    pub n_threads_batch: Option<u32>,
+    /// Report the log-probability of each generated token and of the `logprobs` most likely
+    /// alternatives (at most `MAX_LOGPROBS`) in `InferenceResult::logprobs`.
+    #[serde(default)]
+    pub logprobs: Option<u32>,
+    /// When the context fills up during generation, discard the older half of what follows
+    /// the first `n_keep` tokens and carry on, instead of stopping with `FinishReason::Length`.
+    /// The engine fills in `model.context_shift` when unset.
+    #[serde(default)]
+    pub context_shift: Option<bool>,
+    /// Tokens at the start of the context that a context shift never discards. Defaults to
+    /// the whole prompt.
+    #[serde(default)]
+    pub n_keep: Option<u32>,
+    /// What the engine does with a prompt that leaves no room for `max_tokens` of output.
+    #[serde(default)]
+    pub on_context_overflow: OverflowStrategy,
+    /// Memory namespace to inject from (`memory::DEFAULT_NAMESPACE` when unset). Used by
+    /// the engine; runtimes ignore it.
+    #[serde(default)]
+    pub memory_namespace: Option<String>,
+    /// Always generate, rather than reuse a cached response (see `cache`). Used by the
+    /// engine; runtimes ignore it.
+    #[serde(default)]
+    pub no_cache: bool,
+    /// Prepared context to start from (see `Engine::prepare_context`): its text goes before
+    /// the prompt, and the state saved for it is loaded instead of evaluating it. Used by the
+    /// engine; runtimes ignore it.
+    #[serde(default)]
+    pub session: Option<String>,
+    /// Classify the prompt's intent before the completion and report it in
+    /// `EngineResponse::intent` (see `intent`). Used by the engine; runtimes ignore it.
+    #[serde(default)]
+    pub classify_intent: bool,
+    /// System prompt, in place of `model.system_prompt`. Used by the engine; runtimes ignore it.
+    #[serde(default)]
+    pub system: Option<String>,
+    /// Report what went into the request in `EngineResponse::debug`. Used by the engine;
+    /// runtimes ignore it.
+    #[serde(default)]
+    pub debug: bool,
+    /// Build the prompt as usual but don't run it: the response has no output, and the
+    /// prompt's size in `usage.prompt_tokens`. Used by the engine; runtimes ignore it.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Stops generation when cancelled; the runtime returns what it produced so far
+    /// with `FinishReason::Cancelled`.
+    #[serde(skip)]
+    pub cancel: CancellationToken,
 }
 
 impl Default for InferenceOptions {
@@ -17,7 +120,88 @@ impl Default for InferenceOptions {
             max_tokens: Some(128),
             max_time_ms: Some(30000), // 30s default timeout
             temperature: Some(0.0),
+            top_k: None,
+            top_p: None,
+            min_p: None,
+            typical_p: None,
+            logit_bias: HashMap::new(),
+            response_format: None,
+            grammar: None,
+            seed: None,
+            repeat_penalty: None,
+            repeat_last_n: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            penalize_prompt: false,
             stop_sequences: vec![],
+            reset_context: false,
+            n_threads: None,
+            n_threads_batch: None,
+            logprobs: None,
+            context_shift: None,
+            n_keep: None,
+            on_context_overflow: OverflowStrategy::Error,
+            memory_namespace: None,
+            no_cache: false,
+            session: None,
+            classify_intent: false,
+            system: None,
+            debug: false,
+            dry_run: false,
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+/// How a prompt too long for the context is handled (see `InferenceOptions::on_context_overflow`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowStrategy {
+    /// Fail with `EngineError::ContextOverflow`.
+    #[default]
+    Error,
+    /// Drop tokens from the start of the prompt.
+    TruncateStart,
+    /// Drop tokens from the end of the prompt.
+    TruncateEnd,
+    /// Drop tokens from the middle, keeping the start and the end.
+    TruncateMiddle,
+}
+
+impl OverflowStrategy {
+    /// `keep` of `tokens`, dropping the rest where this strategy says. `Error` keeps them all.
+    pub fn keep<T: Clone>(&self, tokens: &[T], keep: usize) -> Vec<T> {
+        let keep = keep.min(tokens.len());
+        match self {
+            OverflowStrategy::Error => tokens.to_vec(),
+            OverflowStrategy::TruncateStart => tokens[tokens.len() - keep..].to_vec(),
+            OverflowStrategy::TruncateEnd => tokens[..keep].to_vec(),
+            OverflowStrategy::TruncateMiddle => {
+                let head = keep / 2;
+                let tail = keep - head;
+                [&tokens[..head], &tokens[tokens.len() - tail..]].concat()
+            }
+        }
+    }
+}
+
+/// Requested shape of the output.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ResponseFormat {
+    /// A JSON object, optionally matching a JSON Schema document.
+    Json {
+        #[serde(default)]
+        schema: Option<String>,
+    },
+}
+
+impl ResponseFormat {
+    /// The GBNF grammar that enforces this format.
+    pub fn grammar(&self) -> Result<String, EngineError> {
+        match self {
+            ResponseFormat::Json { schema: Some(schema) } => grammar::schema_to_grammar(schema),
+            ResponseFormat::Json { schema: None } => Ok(grammar::JSON_GRAMMAR.to_string()),
         }
     }
 }
@@ -27,6 +211,107 @@ pub struct ModelLoadConfig {
     pub model_path: PathBuf,
     pub context_size: usize,
     pub gpu_layers: usize,
+    /// Maximum number of prompt tokens decoded per batch (llama.cpp `n_batch`).
+    pub batch_size: usize,
+    /// CPU threads for generation; every CPU available to the process when unset.
+    #[serde(default)]
+    pub n_threads: Option<usize>,
+    /// CPU threads for evaluating prompt batches; `n_threads` when unset.
+    #[serde(default)]
+    pub n_threads_batch: Option<usize>,
+    /// Memory-map the model file instead of reading it into memory.
+    #[serde(default = "default_use_mmap")]
+    pub use_mmap: bool,
+    /// Lock the model's memory so the OS can't swap it out.
+    #[serde(default)]
+    pub use_mlock: bool,
+    /// Precision of the KV cache's keys and values.
+    #[serde(default)]
+    pub kv_cache_type: KvCacheType,
+}
+
+fn default_use_mmap() -> bool {
+    true
+}
+
+impl ModelLoadConfig {
+    /// The thread counts to use, with unset ones filled in.
+    pub fn threads(&self) -> Threads {
+        let n_threads = self.n_threads.unwrap_or_else(available_cpus);
+        Threads { n_threads, n_threads_batch: self.n_threads_batch.unwrap_or(n_threads) }
+    }
+}
+
+/// CPU threads a runtime computes with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Threads {
+    /// For generating, one token at a time.
+    pub n_threads: usize,
+    /// For evaluating prompt batches.
+    pub n_threads_batch: usize,
+}
+
+/// Data type of the KV cache. The quantized types take roughly a half (`q8_0`) or a
+/// quarter (`q4_0`) of the memory of `f16`, for a small loss in output quality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum KvCacheType {
+    #[default]
+    #[serde(rename = "f16")]
+    F16,
+    #[serde(rename = "q8_0")]
+    Q8,
+    #[serde(rename = "q4_0")]
+    Q4,
+}
+
+/// CPUs this process may run on, which respects affinity masks and container limits.
+fn available_cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Receives model loading progress, from 0.0 to 1.0.
+pub type LoadProgress = Arc<dyn Fn(f32) + Send + Sync>;
+
+/// What the runtime actually did when loading a model.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct LoadReport {
+    pub gpu_layers_requested: usize,
+    pub gpu_layers_offloaded: usize,
+}
+
+/// Describes the loaded model, read from its metadata after `load()`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ModelInfo {
+    /// `general.name`, or the file stem if the model doesn't set one.
+    pub name: String,
+    pub architecture: String,
+    pub parameter_count: u64,
+    /// Weight format, e.g. `Q4_K_M` or `F16`.
+    pub quantization: String,
+    /// Context length the model was trained with.
+    pub native_context_size: u32,
+    pub vocab_size: u32,
+    pub file_size: u64,
+    pub path: PathBuf,
+    /// Context size the model was loaded with.
+    pub context_size: u32,
+    /// Layers actually offloaded to the GPU.
+    pub gpu_layers: usize,
+    /// Threads the model runs with, unless a request asks for others.
+    #[serde(default)]
+    pub n_threads: usize,
+    #[serde(default)]
+    pub n_threads_batch: usize,
+    /// Whether the model file is memory-mapped and its memory locked. These can differ from
+    /// what was asked for when the platform doesn't support them.
+    #[serde(default)]
+    pub use_mmap: bool,
+    #[serde(default)]
+    pub use_mlock: bool,
+    #[serde(default)]
+    pub kv_cache_type: KvCacheType,
+    /// Unix timestamp (seconds) of when the model finished loading.
+    pub loaded_at: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -34,14 +319,134 @@ pub struct Usage {
     pub input_tokens: u32,
     pub output_tokens: u32,
     pub total_tokens: u32,
+    /// Input tokens served from the runtime's KV cache instead of being evaluated again.
+    #[serde(default)]
+    pub cached_tokens: u32,
+    /// Of `cached_tokens`, those restored from a saved prompt prefix (e.g. a system prompt
+    /// and memory shared by earlier requests) rather than left over from the last request.
+    #[serde(default)]
+    pub cached_prefix_tokens: u32,
+    /// Input tokens taken up by injected memory (see `MemoryConfig::max_injection_tokens`).
+    #[serde(default)]
+    pub memory_tokens: u32,
+    /// Total time taken: `prompt_eval_ms`, `generation_ms` and the runtime's overhead.
     pub duration_ms: u64,
+    /// Time spent evaluating the prompt tokens that weren't cached.
+    #[serde(default)]
+    pub prompt_eval_ms: u64,
+    /// Time spent generating the output.
+    #[serde(default)]
+    pub generation_ms: u64,
+    /// Output tokens per second of `generation_ms`.
+    #[serde(default)]
+    pub tokens_per_second: f64,
+    /// Times the context was shifted to make room for more output (see
+    /// `InferenceOptions::context_shift`).
+    #[serde(default)]
+    pub context_shifts: u32,
+    /// Prompt tokens dropped to make it fit the context (see
+    /// `InferenceOptions::on_context_overflow`).
+    #[serde(default)]
+    pub prompt_tokens_dropped: u32,
+}
+
+/// Whether a generation that started at `started` is past `max_time_ms`. Compared as
+/// durations, so a limit of 0 is up as soon as the clock has moved at all.
+pub fn time_is_up(started: Instant, max_time_ms: u64) -> bool {
+    started.elapsed() > Duration::from_millis(max_time_ms)
+}
+
+/// `tokens` generated in `elapsed`, per second; 0 if no time passed.
+pub fn tokens_per_second(tokens: u32, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    tokens as f64 / elapsed.as_secs_f64()
+}
+
+impl Usage {
+    /// A one-line performance summary, e.g. for printing after a response.
+    pub fn summary(&self) -> String {
+        format!(
+            "prompt: {} tokens ({} cached) in {} ms, generation: {} tokens in {} ms ({:.1} tokens/s), total {} ms",
+            self.input_tokens, self.cached_tokens, self.prompt_eval_ms,
+            self.output_tokens, self.generation_ms, self.tokens_per_second, self.duration_ms
+        )
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InferenceResult {
     pub text: String,
     pub usage: Usage,
-    pub status: InferenceStatus,
+    pub finish_reason: FinishReason,
+    /// Seed the sampler used, if the runtime samples with one.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Set when `InferenceOptions::logprobs` asked for them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Logprobs>,
+}
+
+/// Most alternatives `InferenceOptions::logprobs` can ask for per token.
+pub const MAX_LOGPROBS: u32 = 10;
+
+/// Log-probabilities of the generated tokens, laid out like OpenAI's `logprobs`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Logprobs {
+    /// One entry per generated token, in order.
+    pub content: Vec<TokenLogprob>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenLogprob {
+    pub token: String,
+    /// Natural log of the token's probability under the model, before sampling settings.
+    pub logprob: f32,
+    /// The token's UTF-8 bytes, which may be part of a character.
+    pub bytes: Vec<u8>,
+    /// The most likely tokens at this position, most likely first.
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Vec<u8>,
+}
+
+impl InferenceResult {
+    pub fn status(&self) -> InferenceStatus {
+        self.finish_reason.status()
+    }
+}
+
+/// Why generation ended.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model produced an end-of-generation token.
+    Eos,
+    /// `max_tokens` were generated, or the context is full and couldn't be shifted.
+    Length,
+    /// `max_time_ms` ran out.
+    Time,
+    /// The output reached one of `stop_sequences`.
+    Stop { sequence: String },
+    /// Cancelled, or nobody was listening to the stream any more.
+    Cancelled,
+}
+
+impl FinishReason {
+    /// The coarser status reported before finish reasons existed.
+    pub fn status(&self) -> InferenceStatus {
+        match self {
+            FinishReason::Eos | FinishReason::Stop { .. } => InferenceStatus::Success,
+            FinishReason::Length | FinishReason::Time => InferenceStatus::Truncated,
+            FinishReason::Cancelled => InferenceStatus::Cancelled,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -49,17 +454,192 @@ pub struct InferenceResult {
 pub enum InferenceStatus {
     Success,
     Truncated,
+    Cancelled,
     Error,
 }
 
+/// A single item of streamed inference output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TokenChunk {
+    /// A detokenized piece of generated text.
+    Token { text: String },
+    /// Generation finished; always the last item of a successful stream.
+    Done { usage: Usage, status: InferenceStatus, finish_reason: FinishReason },
+    /// Generation failed; no further items follow.
+    Error { message: String },
+}
+
+impl TokenChunk {
+    /// The `Done` item for a finished inference.
+    pub fn done(result: &InferenceResult) -> Self {
+        TokenChunk::Done { usage: result.usage.clone(), status: result.status(), finish_reason: result.finish_reason.clone() }
+    }
+}
+
 #[async_trait]
 pub trait ModelRuntime: Send + Sync {
-    /// Initialize and load the model.
-    async fn load(&mut self, config: &ModelLoadConfig) -> Result<(), EngineError>;
+    /// Initialize and load the model, replacing the loaded one if any. If loading fails,
+    /// the model loaded before must stay usable.
+    async fn load(&mut self, config: &ModelLoadConfig) -> Result<LoadReport, EngineError>;
+
+    /// Like `load`, reporting progress along the way.
+    ///
+    /// The default implementation reports 0.0 before and 1.0 after `load`.
+    async fn load_with_progress(&mut self, config: &ModelLoadConfig, progress: LoadProgress) -> Result<LoadReport, EngineError> {
+        progress(0.0);
+        let report = self.load(config).await?;
+        progress(1.0);
+        Ok(report)
+    }
 
     /// Perform inference with strict limits.
     async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError>;
 
+    /// Perform inference, sending each generated piece through `tx` as it is produced,
+    /// followed by a final `TokenChunk::Done`.
+    ///
+    /// The default implementation runs `infer` and sends the whole text as one chunk.
+    async fn infer_stream(&mut self, prompt: &str, options: InferenceOptions, tx: mpsc::UnboundedSender<TokenChunk>) -> Result<InferenceResult, EngineError> {
+        let result = self.infer(prompt, options).await?;
+        if !result.text.is_empty() {
+            let _ = tx.send(TokenChunk::Token { text: result.text.clone() });
+        }
+        let _ = tx.send(TokenChunk::done(&result));
+        Ok(result)
+    }
+
+    /// Perform inference on each of `prompts` with the same options, returning one result
+    /// per prompt in the same order. Runtimes that can decode several sequences at once
+    /// override this to generate them together.
+    ///
+    /// The default implementation runs `infer` on one prompt after the other.
+    async fn infer_batch(&mut self, prompts: &[String], options: InferenceOptions) -> Result<Vec<InferenceResult>, EngineError> {
+        let mut results = Vec::with_capacity(prompts.len());
+        for prompt in prompts {
+            results.push(self.infer(prompt, options.clone()).await?);
+        }
+        Ok(results)
+    }
+
+    /// Embed each of `texts` as one vector.
+    ///
+    /// The default implementation reports that embeddings aren't supported.
+    async fn embed(&mut self, _texts: &[String]) -> Result<Vec<Vec<f32>>, EngineError> {
+        Err(EngineError::Unsupported("this runtime does not produce embeddings".to_string()))
+    }
+
+    /// Token ids `text` is split into by the loaded model's tokenizer, optionally with the
+    /// beginning-of-sequence token prompts get.
+    fn tokenize(&self, _text: &str, _add_bos: bool) -> Result<Vec<i32>, EngineError> {
+        Err(EngineError::Unsupported("this runtime does not expose its tokenizer".to_string()))
+    }
+
+    /// The text of each token, special tokens included, for inspecting a tokenization.
+    fn token_pieces(&self, _tokens: &[i32]) -> Result<Vec<String>, EngineError> {
+        Err(EngineError::Unsupported("this runtime does not expose its tokenizer".to_string()))
+    }
+
+    /// The text of `tokens` as a whole, with characters split across tokens put back together.
+    ///
+    /// The default implementation joins their `token_pieces`.
+    fn detokenize(&self, tokens: &[i32]) -> Result<String, EngineError> {
+        self.token_pieces(tokens).map(|pieces| pieces.concat())
+    }
+
+    /// How many tokens `text` takes up as a prompt.
+    fn count_tokens(&self, text: &str) -> Result<usize, EngineError> {
+        self.tokenize(text, true).map(|tokens| tokens.len())
+    }
+
+    /// Save the state left by evaluating the last prompt (e.g. the KV cache) to `path`, so
+    /// `load_session` can restore it, also in another process.
+    ///
+    /// The default implementation reports that saving state isn't supported.
+    async fn save_session(&mut self, _path: &Path) -> Result<(), EngineError> {
+        Err(EngineError::Unsupported("this runtime can't save its state".to_string()))
+    }
+
+    /// Restore the state saved to `path` by `save_session`, so a prompt starting with the
+    /// one evaluated then doesn't have to evaluate that part again. Fails with a
+    /// `Validation` error if it was saved with another model or context settings.
+    ///
+    /// The default implementation reports that saving state isn't supported.
+    async fn load_session(&mut self, _path: &Path) -> Result<(), EngineError> {
+        Err(EngineError::Unsupported("this runtime can't save its state".to_string()))
+    }
+
     /// Unload the model to free resources.
     async fn unload(&mut self) -> Result<(), EngineError>;
-}
\ No newline at end of file
+
+    /// Metadata of the loaded model, or `None` if nothing is loaded.
+    fn model_info(&self) -> Option<ModelInfo> {
+        None
+    }
+
+    /// The chat template embedded in the loaded model, if it ships one.
+    fn chat_template(&self) -> Option<ChatTemplate> {
+        None
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_summary() {
+        let usage = Usage {
+            input_tokens: 8,
+            output_tokens: 12,
+            total_tokens: 20,
+            cached_tokens: 3,
+            duration_ms: 460,
+            prompt_eval_ms: 150,
+            generation_ms: 300,
+            tokens_per_second: 40.0,
+            ..Usage::default()
+        };
+        assert_eq!(
+            usage.summary(),
+            "prompt: 8 tokens (3 cached) in 150 ms, generation: 12 tokens in 300 ms (40.0 tokens/s), total 460 ms"
+        );
+
+        // Responses from before the breakdown still parse
+        let old: Usage = serde_json::from_str(r#"{"input_tokens": 1, "output_tokens": 2, "total_tokens": 3, "duration_ms": 4}"#).unwrap();
+        assert_eq!(old.generation_ms, 0);
+    }
+
+    #[test]
+    fn test_time_is_up() {
+        let started = Instant::now();
+        assert!(!time_is_up(started, 60_000));
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(time_is_up(started, 0));
+        assert_eq!(tokens_per_second(10, Duration::from_millis(500)), 20.0);
+        assert_eq!(tokens_per_second(10, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn test_threads() {
+        let config = |n_threads, n_threads_batch| ModelLoadConfig {
+            model_path: "model.gguf".into(), context_size: 2048, gpu_layers: 0, batch_size: 512, n_threads, n_threads_batch,
+            use_mmap: true, use_mlock: false, kv_cache_type: KvCacheType::F16,
+        };
+        assert_eq!(config(Some(4), Some(8)).threads(), Threads { n_threads: 4, n_threads_batch: 8 });
+        assert_eq!(config(Some(4), None).threads(), Threads { n_threads: 4, n_threads_batch: 4 });
+        let all = config(None, None).threads();
+        assert!(all.n_threads >= 1);
+        assert_eq!(all.n_threads_batch, all.n_threads);
+    }
+
+    #[test]
+    fn test_overflow_strategies() {
+        let tokens = [1, 2, 3, 4, 5, 6, 7];
+        assert_eq!(OverflowStrategy::TruncateStart.keep(&tokens, 3), [5, 6, 7]);
+        assert_eq!(OverflowStrategy::TruncateEnd.keep(&tokens, 3), [1, 2, 3]);
+        assert_eq!(OverflowStrategy::TruncateMiddle.keep(&tokens, 3), [1, 6, 7]);
+        assert_eq!(OverflowStrategy::TruncateMiddle.keep(&tokens, 4), [1, 2, 6, 7]);
+        assert_eq!(OverflowStrategy::Error.keep(&tokens, 3), tokens);
+        assert_eq!(OverflowStrategy::TruncateStart.keep(&tokens, 10), tokens);
+    }
+}
@@ -0,0 +1,125 @@
+//! Behavior every `ModelRuntime` has to share, so that requests mean the same whichever
+//! runtime serves them. Runtimes run `check_all` from their tests against a loaded model.
+//!
+//! The checks only assume that greedy decoding (temperature 0) from an empty context is
+//! deterministic; they don't depend on what the model says. They panic on a mismatch.
+
+use tokio::sync::mpsc;
+use super::{FinishReason, InferenceOptions, InferenceResult, ModelRuntime, TokenChunk};
+
+/// Run every check below with `prompt`, which should make the model say a few words.
+pub async fn check_all(runtime: &mut dyn ModelRuntime, prompt: &str) {
+    check_max_tokens(runtime, prompt).await;
+    check_stop_sequences(runtime, prompt).await;
+    check_time_limit(runtime, prompt).await;
+    check_cancellation(runtime, prompt).await;
+    check_streaming(runtime, prompt).await;
+    check_batch(runtime, prompt).await;
+}
+
+/// Greedy, from an empty context and without a time limit, so runs can be compared.
+fn greedy(max_tokens: u32) -> InferenceOptions {
+    InferenceOptions {
+        max_tokens: Some(max_tokens),
+        max_time_ms: None,
+        temperature: Some(0.0),
+        reset_context: true,
+        ..InferenceOptions::default()
+    }
+}
+
+fn check_usage(result: &InferenceResult) {
+    let usage = &result.usage;
+    assert!(usage.input_tokens > 0, "the prompt counts as input: {:?}", usage);
+    assert_eq!(usage.total_tokens, usage.input_tokens + usage.output_tokens, "{:?}", usage);
+}
+
+/// Output stops at `max_tokens` with `Length`, unless the model ended it first; 0 generates nothing.
+pub async fn check_max_tokens(runtime: &mut dyn ModelRuntime, prompt: &str) {
+    let result = runtime.infer(prompt, greedy(4)).await.expect("inference");
+    check_usage(&result);
+    assert!(result.usage.output_tokens <= 4, "{:?}", result.usage);
+    match result.finish_reason {
+        FinishReason::Length => assert_eq!(result.usage.output_tokens, 4),
+        FinishReason::Eos => assert!(result.usage.output_tokens < 4),
+        other => panic!("unexpected finish reason {:?}", other),
+    }
+
+    let result = runtime.infer(prompt, greedy(0)).await.expect("inference");
+    assert_eq!((result.text.as_str(), result.usage.output_tokens), ("", 0));
+    assert_eq!(result.finish_reason, FinishReason::Length);
+}
+
+/// The output ends right before the first stop sequence, which is left out of it.
+pub async fn check_stop_sequences(runtime: &mut dyn ModelRuntime, prompt: &str) {
+    let full = runtime.infer(prompt, greedy(16)).await.expect("inference").text;
+    let chars: Vec<char> = full.chars().collect();
+    if chars.len() < 3 {
+        // Too little output to stop in the middle of
+        return;
+    }
+    let stop: String = chars[1..3].iter().collect();
+    let expected = &full[..full.find(&stop).expect("stop sequence taken from the output")];
+
+    let options = InferenceOptions { stop_sequences: vec![stop.clone()], ..greedy(16) };
+    let result = runtime.infer(prompt, options).await.expect("inference");
+    check_usage(&result);
+    assert_eq!(result.text, expected, "output for stop sequence {:?}", stop);
+    assert_eq!(result.finish_reason, FinishReason::Stop { sequence: stop });
+}
+
+/// A request whose time is up before generation starts ends with `Time`, not an error.
+pub async fn check_time_limit(runtime: &mut dyn ModelRuntime, prompt: &str) {
+    let options = InferenceOptions { max_time_ms: Some(0), ..greedy(64) };
+    let result = runtime.infer(prompt, options).await.expect("inference");
+    assert_eq!(result.finish_reason, FinishReason::Time);
+}
+
+/// A request cancelled up front ends with `Cancelled`, not an error.
+pub async fn check_cancellation(runtime: &mut dyn ModelRuntime, prompt: &str) {
+    let options = greedy(64);
+    options.cancel.cancel();
+    let result = runtime.infer(prompt, options).await.expect("inference");
+    assert_eq!(result.finish_reason, FinishReason::Cancelled);
+}
+
+/// Streamed pieces add up to the same text as without streaming, and `Done` comes last.
+pub async fn check_streaming(runtime: &mut dyn ModelRuntime, prompt: &str) {
+    let expected = runtime.infer(prompt, greedy(8)).await.expect("inference");
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let result = runtime.infer_stream(prompt, greedy(8), tx).await.expect("streamed inference");
+    assert_eq!(result.text, expected.text);
+    assert_eq!(result.finish_reason, expected.finish_reason);
+
+    let mut streamed = String::new();
+    let mut done = None;
+    while let Some(chunk) = rx.recv().await {
+        assert!(done.is_none(), "chunk after Done: {:?}", chunk);
+        match chunk {
+            TokenChunk::Token { text } => streamed.push_str(&text),
+            TokenChunk::Done { usage, finish_reason, .. } => done = Some((usage, finish_reason)),
+            TokenChunk::Error { message } => panic!("stream error: {}", message),
+        }
+    }
+    assert_eq!(streamed, result.text);
+    let (usage, finish_reason) = done.expect("a Done chunk");
+    assert_eq!(usage.output_tokens, result.usage.output_tokens);
+    assert_eq!(finish_reason, result.finish_reason);
+}
+
+/// A batch gives one result per prompt, in order, each within the limits it was given.
+pub async fn check_batch(runtime: &mut dyn ModelRuntime, prompt: &str) {
+    let prompts = vec![prompt.to_string(), format!("{} {}", prompt, prompt)];
+    let results = runtime.infer_batch(&prompts, greedy(4)).await.expect("batched inference");
+    assert_eq!(results.len(), 2);
+    for result in &results {
+        check_usage(result);
+        assert!(result.usage.output_tokens <= 4, "{:?}", result.usage);
+        assert!(matches!(result.finish_reason, FinishReason::Length | FinishReason::Eos), "{:?}", result.finish_reason);
+    }
+    assert!(results[1].usage.input_tokens > results[0].usage.input_tokens, "results out of order");
+
+    let results = runtime.infer_batch(&[], greedy(4)).await.expect("empty batch");
+    assert!(results.is_empty());
+}
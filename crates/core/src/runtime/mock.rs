@@ -0,0 +1,281 @@
+//! A runtime that needs no model file, for testing the server, the CLI and clients end to end.
+//!
+//! Its tokenizer makes one token of every character, and it answers a prompt by echoing it
+//! back one token at a time, as many tokens as `MockConfig::tokens` asks for. It honors
+//! `max_tokens`, `max_time_ms`, `stop_sequences` and cancellation like a real runtime, and a
+//! prompt with a line starting with `FAIL_PREFIX` makes it fail.
+
+use async_trait::async_trait;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use super::{FinishReason, InferenceOptions, InferenceResult, LoadReport, ModelInfo, ModelLoadConfig, ModelRuntime, TokenChunk, Usage};
+use crate::config::MockConfig;
+use crate::error::EngineError;
+use crate::stop::StopSequences;
+
+/// A prompt line starting with this fails the inference with `EngineError::Runtime`. Lines
+/// rather than the whole prompt, so it also works through chat templates.
+pub const FAIL_PREFIX: &str = "MOCK_FAIL";
+
+/// Token id of the beginning-of-sequence token; ids below it are Unicode code points.
+const BOS: i32 = char::MAX as i32 + 1;
+
+#[derive(Default)]
+pub struct MockRuntime {
+    config: MockConfig,
+    info: Option<ModelInfo>,
+}
+
+impl MockRuntime {
+    pub fn new(config: MockConfig) -> Self {
+        Self { config, info: None }
+    }
+
+    async fn generate(&self, prompt: &str, options: &InferenceOptions, tx: Option<&mpsc::UnboundedSender<TokenChunk>>) -> Result<InferenceResult, EngineError> {
+        if self.info.is_none() {
+            return Err(EngineError::ModelNotLoaded);
+        }
+        if prompt.lines().any(|line| line.starts_with(FAIL_PREFIX)) {
+            return Err(EngineError::Runtime(format!("Mock failure: the prompt asked for it with {}", FAIL_PREFIX)));
+        }
+        let started = Instant::now();
+        let reply_tokens = self.config.tokens.map_or(prompt.chars().count(), |n| n as usize);
+        let max_tokens = options.max_tokens.map_or(usize::MAX, |n| n as usize);
+        let delay = Duration::from_millis(self.config.token_delay_ms);
+        let stop_sequences = StopSequences::new(&options.stop_sequences);
+
+        let mut text = String::new();
+        let mut streamed = 0;
+        let mut output_tokens = 0;
+        let mut finish_reason = FinishReason::Eos;
+        for c in prompt.chars().cycle().take(reply_tokens) {
+            if output_tokens >= max_tokens {
+                finish_reason = FinishReason::Length;
+                break;
+            }
+            if options.cancel.is_cancelled() {
+                finish_reason = FinishReason::Cancelled;
+                break;
+            }
+            if options.max_time_ms.is_some_and(|ms| super::time_is_up(started, ms)) {
+                finish_reason = FinishReason::Time;
+                break;
+            }
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+
+            text.push(c);
+            output_tokens += 1;
+            if let Some((at, sequence)) = stop_sequences.find(&text) {
+                finish_reason = FinishReason::Stop { sequence: sequence.to_string() };
+                text.truncate(at);
+                break;
+            }
+            // Hold back what could still become a stop sequence, as real runtimes do
+            let safe = stop_sequences.safe_len(&text);
+            if let Some(tx) = tx {
+                if safe > streamed && tx.send(TokenChunk::Token { text: text[streamed..safe].to_string() }).is_err() {
+                    finish_reason = FinishReason::Cancelled;
+                    break;
+                }
+            }
+            streamed = streamed.max(safe);
+        }
+        if let Some(tx) = tx {
+            if text.len() > streamed {
+                let _ = tx.send(TokenChunk::Token { text: text[streamed..].to_string() });
+            }
+        }
+
+        let input_tokens = u32::try_from(prompt.chars().count() + 1).unwrap_or(u32::MAX);
+        let output_tokens = u32::try_from(output_tokens).unwrap_or(u32::MAX);
+        let duration_ms = started.elapsed().as_millis() as u64;
+        Ok(InferenceResult {
+            text,
+            usage: Usage {
+                input_tokens,
+                output_tokens,
+                total_tokens: input_tokens.saturating_add(output_tokens),
+                duration_ms,
+                generation_ms: duration_ms,
+                tokens_per_second: if duration_ms == 0 { 0.0 } else { output_tokens as f64 * 1000.0 / duration_ms as f64 },
+                ..Usage::default()
+            },
+            finish_reason,
+            seed: None,
+            logprobs: None,
+        })
+    }
+}
+
+#[async_trait]
+impl ModelRuntime for MockRuntime {
+    /// "Loads" any path, whether or not a file exists there.
+    async fn load(&mut self, config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+        let context_size = u32::try_from(config.context_size).unwrap_or(u32::MAX);
+        let threads = config.threads();
+        self.info = Some(ModelInfo {
+            name: mock_name(&config.model_path),
+            architecture: "mock".to_string(),
+            parameter_count: 0,
+            quantization: "none".to_string(),
+            native_context_size: context_size,
+            vocab_size: BOS as u32 + 1,
+            file_size: 0,
+            path: config.model_path.clone(),
+            context_size,
+            gpu_layers: 0,
+            n_threads: threads.n_threads,
+            n_threads_batch: threads.n_threads_batch,
+            use_mmap: false,
+            use_mlock: false,
+            kv_cache_type: config.kv_cache_type,
+            loaded_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        });
+        Ok(LoadReport { gpu_layers_requested: config.gpu_layers, gpu_layers_offloaded: 0 })
+    }
+
+    async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+        self.generate(prompt, &options, None).await
+    }
+
+    async fn infer_stream(&mut self, prompt: &str, options: InferenceOptions, tx: mpsc::UnboundedSender<TokenChunk>) -> Result<InferenceResult, EngineError> {
+        let result = self.generate(prompt, &options, Some(&tx)).await?;
+        let _ = tx.send(TokenChunk::done(&result));
+        Ok(result)
+    }
+
+    fn tokenize(&self, text: &str, add_bos: bool) -> Result<Vec<i32>, EngineError> {
+        if self.info.is_none() {
+            return Err(EngineError::ModelNotLoaded);
+        }
+        let bos = add_bos.then_some(BOS);
+        Ok(bos.into_iter().chain(text.chars().map(|c| c as i32)).collect())
+    }
+
+    fn token_pieces(&self, tokens: &[i32]) -> Result<Vec<String>, EngineError> {
+        tokens.iter().map(|&token| match token {
+            BOS => Ok("<s>".to_string()),
+            _ => u32::try_from(token).ok()
+                .and_then(char::from_u32)
+                .map(String::from)
+                .ok_or_else(|| EngineError::Validation(format!("Unknown token id: {}", token))),
+        }).collect()
+    }
+
+    fn detokenize(&self, tokens: &[i32]) -> Result<String, EngineError> {
+        let text = tokens.iter().filter(|&&token| token != BOS).map(|&token| {
+            u32::try_from(token).ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| EngineError::Validation(format!("Unknown token id: {}", token)))
+        });
+        text.collect()
+    }
+
+    async fn unload(&mut self) -> Result<(), EngineError> {
+        self.info = None;
+        Ok(())
+    }
+
+    fn model_info(&self) -> Option<ModelInfo> {
+        self.info.clone()
+    }
+}
+
+fn mock_name(path: &Path) -> String {
+    path.file_stem().map_or_else(|| "mock".to_string(), |stem| stem.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn loaded(config: MockConfig) -> MockRuntime {
+        let mut runtime = MockRuntime::new(config);
+        runtime.load(&ModelLoadConfig {
+            model_path: "models/none.gguf".into(),
+            context_size: 512,
+            gpu_layers: 0,
+            batch_size: 512,
+            n_threads: Some(1),
+            n_threads_batch: None,
+            use_mmap: true,
+            use_mlock: false,
+            kv_cache_type: Default::default(),
+        }).await.unwrap();
+        runtime
+    }
+
+    fn options(max_tokens: Option<u32>) -> InferenceOptions {
+        InferenceOptions { max_tokens, max_time_ms: None, ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn test_echoes_the_prompt() {
+        let mut runtime = MockRuntime::default();
+        assert!(matches!(runtime.infer("hello", options(None)).await, Err(EngineError::ModelNotLoaded)));
+
+        let mut runtime = loaded(MockConfig::default()).await;
+        assert_eq!(runtime.model_info().unwrap().name, "none");
+
+        let result = runtime.infer("héllo", options(None)).await.unwrap();
+        assert_eq!((result.text.as_str(), result.finish_reason), ("héllo", FinishReason::Eos));
+        assert_eq!((result.usage.input_tokens, result.usage.output_tokens), (6, 5));
+
+        let result = runtime.infer("hello world", options(Some(5))).await.unwrap();
+        assert_eq!((result.text.as_str(), result.finish_reason), ("hello", FinishReason::Length));
+    }
+
+    #[tokio::test]
+    async fn test_configured_reply_length() {
+        let mut runtime = loaded(MockConfig { tokens: Some(7), token_delay_ms: 0 }).await;
+        let result = runtime.infer("abc", options(None)).await.unwrap();
+        assert_eq!(result.text, "abcabca");
+
+        let mut runtime = loaded(MockConfig { tokens: Some(100), token_delay_ms: 20 }).await;
+        let options = InferenceOptions { max_time_ms: Some(50), ..options(None) };
+        let result = runtime.infer("abc", options).await.unwrap();
+        assert_eq!(result.finish_reason, FinishReason::Time);
+        assert!(result.text.len() < 10, "{}", result.text);
+    }
+
+    #[tokio::test]
+    async fn test_stop_sequence_is_never_streamed() {
+        let mut runtime = loaded(MockConfig::default()).await;
+        let options = InferenceOptions { stop_sequences: vec!["</a>".to_string()], ..options(None) };
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let result = runtime.infer_stream("42</a> and more", options, tx).await.unwrap();
+        assert_eq!(result.text, "42");
+        assert_eq!(result.finish_reason, FinishReason::Stop { sequence: "</a>".to_string() });
+
+        let mut streamed = String::new();
+        while let Ok(TokenChunk::Token { text }) = rx.try_recv() {
+            streamed.push_str(&text);
+        }
+        assert_eq!(streamed, "42");
+    }
+
+    #[tokio::test]
+    async fn test_fails_on_demand() {
+        let mut runtime = loaded(MockConfig::default()).await;
+        let err = runtime.infer("<|user|>\nMOCK_FAIL please", options(None)).await.unwrap_err();
+        assert!(matches!(err, EngineError::Runtime(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        let mut runtime = loaded(MockConfig { tokens: Some(12), token_delay_ms: 0 }).await;
+        crate::runtime::conformance::check_all(&mut runtime, "The capital of France is").await;
+    }
+
+    #[tokio::test]
+    async fn test_tokenizer_round_trips() {
+        let runtime = loaded(MockConfig::default()).await;
+        let tokens = runtime.tokenize("héllo", true).unwrap();
+        assert_eq!(tokens.len(), 6);
+        assert_eq!(runtime.detokenize(&tokens).unwrap(), "héllo");
+        assert_eq!(runtime.token_pieces(&tokens[..3]).unwrap(), vec!["<s>", "h", "é"]);
+    }
+}
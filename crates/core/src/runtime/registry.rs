@@ -0,0 +1,66 @@
+//! Runtimes by name, so `model.runtime` can pick the backend.
+
+use std::collections::BTreeMap;
+use super::mock::MockRuntime;
+use super::ModelRuntime;
+use crate::config::EngineConfig;
+use crate::error::EngineError;
+
+/// Creates a runtime, e.g. `LlamaCppRuntime::new` boxed.
+pub type RuntimeConstructor = Box<dyn Fn() -> Result<Box<dyn ModelRuntime>, EngineError> + Send + Sync>;
+
+/// The runtimes an application can create. Crates providing a runtime don't depend on each
+/// other, so the application registers the ones it links in.
+pub struct RuntimeFactory {
+    constructors: BTreeMap<String, RuntimeConstructor>,
+}
+
+impl RuntimeFactory {
+    /// A factory that knows only the built-in `mock` runtime (see `MockRuntime`), with
+    /// `config.mock` as its settings.
+    pub fn new(config: &EngineConfig) -> Self {
+        let mut factory = Self { constructors: BTreeMap::new() };
+        let mock = config.mock.clone();
+        factory.register("mock", move || Ok(Box::new(MockRuntime::new(mock.clone()))));
+        factory
+    }
+
+    /// Make `constructor` available as `name`, replacing any runtime registered under it.
+    pub fn register<F>(&mut self, name: impl Into<String>, constructor: F)
+    where
+        F: Fn() -> Result<Box<dyn ModelRuntime>, EngineError> + Send + Sync + 'static,
+    {
+        self.constructors.insert(name.into(), Box::new(constructor));
+    }
+
+    /// Registered names, in alphabetical order.
+    pub fn names(&self) -> Vec<&str> {
+        self.constructors.keys().map(String::as_str).collect()
+    }
+
+    /// A new runtime of the kind registered as `name`.
+    pub fn create(&self, name: &str) -> Result<Box<dyn ModelRuntime>, EngineError> {
+        let constructor = self.constructors.get(name).ok_or_else(|| EngineError::Config(format!(
+            "Unknown runtime '{}' (registered: {})", name, self.names().join(", ")
+        )))?;
+        constructor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_by_name() {
+        let mut factory = RuntimeFactory::new(&EngineConfig::default());
+        factory.register("other", || Err(EngineError::Runtime("no backend".to_string())));
+        assert_eq!(factory.names(), vec!["mock", "other"]);
+
+        assert!(factory.create("mock").unwrap().model_info().is_none());
+        assert!(matches!(factory.create("other"), Err(EngineError::Runtime(_))));
+
+        let err = factory.create("llamacpp").err().unwrap().to_string();
+        assert!(err.contains("Unknown runtime 'llamacpp' (registered: mock, other)"), "{}", err);
+    }
+}
@@ -0,0 +1,660 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use crate::error::EngineError;
+use crate::runtime::InferenceOptions;
+
+/// A token considered for sampling, as reported by the backend.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub id: i32,
+    pub logit: f32,
+}
+
+/// Penalties for tokens seen in the last `last_n` accepted tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Penalties {
+    /// Divides positive logits and multiplies negative ones; 1.0 disables it.
+    pub repeat_penalty: f32,
+    /// How many of the most recently accepted tokens are considered.
+    pub last_n: usize,
+    /// Subtracted from the logit once per occurrence.
+    pub frequency_penalty: f32,
+    /// Subtracted from the logit once if the token occurred at all.
+    pub presence_penalty: f32,
+}
+
+impl Default for Penalties {
+    fn default() -> Self {
+        Self {
+            repeat_penalty: 1.0,
+            last_n: 64,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+        }
+    }
+}
+
+impl Penalties {
+    /// The penalties `options` asks for, with the defaults for what it leaves unset.
+    pub fn from_options(options: &InferenceOptions) -> Self {
+        let defaults = Self::default();
+        Self {
+            repeat_penalty: options.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            last_n: options.repeat_last_n.map_or(defaults.last_n, |n| n as usize),
+            frequency_penalty: options.frequency_penalty.unwrap_or(defaults.frequency_penalty),
+            presence_penalty: options.presence_penalty.unwrap_or(defaults.presence_penalty),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.last_n > 0
+            && (self.repeat_penalty != 1.0 || self.frequency_penalty != 0.0 || self.presence_penalty != 0.0)
+    }
+}
+
+/// Backend-agnostic token sampler.
+///
+/// Penalties for recently accepted tokens and the logit bias are applied first. After that a
+/// temperature of 0.0 (or below) selects the highest logit (greedy) and
+/// ignores every other setting. Any positive temperature runs the chain
+/// temperature → top-k → typical-p → top-p → min-p and draws a token from
+/// what is left. Each filter works on the survivors of the previous one, so
+/// any combination can be set.
+pub struct Sampler {
+    temperature: f32,
+    top_k: Option<u32>,
+    top_p: Option<f32>,
+    min_p: Option<f32>,
+    typical_p: Option<f32>,
+    penalties: Penalties,
+    logit_bias: HashMap<i32, f32>,
+    /// Tokens never penalized, e.g. EOS, so generation can still end.
+    exempt: Vec<i32>,
+    history: VecDeque<i32>,
+    rng: StdRng,
+}
+
+impl Sampler {
+    pub fn new(temperature: f32, seed: u64) -> Self {
+        Self {
+            temperature,
+            top_k: None,
+            top_p: None,
+            min_p: None,
+            typical_p: None,
+            penalties: Penalties::default(),
+            logit_bias: HashMap::new(),
+            exempt: Vec::new(),
+            history: VecDeque::new(),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// A sampler with the temperature, truncation and penalties of `options`. `logit_bias` is
+    /// `options.logit_bias` with its strings resolved to token ids (see `token_bias`).
+    pub fn from_options(options: &InferenceOptions, seed: u64, logit_bias: HashMap<i32, f32>) -> Self {
+        Self::new(options.temperature.unwrap_or(0.0), seed)
+            .with_top_k(options.top_k)
+            .with_top_p(options.top_p)
+            .with_min_p(options.min_p)
+            .with_typical_p(options.typical_p)
+            .with_logit_bias(logit_bias)
+            .with_penalties(Penalties::from_options(options))
+    }
+
+    pub fn with_penalties(mut self, penalties: Penalties) -> Self {
+        self.penalties = penalties;
+        self
+    }
+
+    /// Add a fixed amount to the logits of specific tokens. A bias of `BAN_BIAS` or
+    /// lower (including `f32::NEG_INFINITY`) removes the token from consideration.
+    pub fn with_logit_bias(mut self, logit_bias: HashMap<i32, f32>) -> Self {
+        self.logit_bias = logit_bias;
+        self
+    }
+
+    /// Never apply penalties to `token`.
+    pub fn exempt_from_penalties(mut self, token: i32) -> Self {
+        self.exempt.push(token);
+        self
+    }
+
+    /// Record a token as part of the recent history that penalties look at.
+    pub fn accept(&mut self, token: i32) {
+        if self.penalties.last_n == 0 {
+            return;
+        }
+        if self.history.len() == self.penalties.last_n {
+            self.history.pop_front();
+        }
+        self.history.push_back(token);
+    }
+
+    /// Only consider the `top_k` most likely tokens.
+    pub fn with_top_k(mut self, top_k: Option<u32>) -> Self {
+        self.top_k = top_k;
+        self
+    }
+
+    /// Only consider the smallest set of most likely tokens whose probabilities
+    /// add up to at least `top_p` (nucleus sampling).
+    pub fn with_top_p(mut self, top_p: Option<f32>) -> Self {
+        self.top_p = top_p;
+        self
+    }
+
+    /// Only consider tokens at least `min_p` times as likely as the most likely one.
+    pub fn with_min_p(mut self, min_p: Option<f32>) -> Self {
+        self.min_p = min_p;
+        self
+    }
+
+    /// Locally typical sampling: only consider the tokens whose surprise is closest to
+    /// the distribution's entropy, up to a cumulative probability of `typical_p`.
+    pub fn with_typical_p(mut self, typical_p: Option<f32>) -> Self {
+        self.typical_p = typical_p;
+        self
+    }
+
+    /// Pick the next token from `candidates`. Returns `None` if there are no candidates.
+    pub fn sample(&mut self, candidates: &[Candidate]) -> Option<i32> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let penalize = self.penalties.is_active() && !self.history.is_empty();
+        let candidates = if penalize || !self.logit_bias.is_empty() {
+            let mut adjusted = candidates.to_vec();
+            if penalize {
+                apply_penalties(&mut adjusted, &self.history, &self.penalties, &self.exempt);
+            }
+            apply_logit_bias(&mut adjusted, &self.logit_bias);
+            Cow::Owned(adjusted)
+        } else {
+            Cow::Borrowed(candidates)
+        };
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        if self.temperature <= 0.0 {
+            return greedy(&candidates);
+        }
+
+        let mut candidates = candidates.into_owned();
+        candidates.sort_by(|a, b| b.logit.partial_cmp(&a.logit).unwrap_or(std::cmp::Ordering::Equal));
+
+        if let Some(k) = self.top_k {
+            candidates.truncate((k as usize).max(1));
+        }
+
+        // (token, probability) pairs, most likely first
+        let probs = softmax(&candidates, self.temperature);
+        let mut pool: Vec<(i32, f32)> = candidates.iter().map(|c| c.id).zip(probs).collect();
+
+        if let Some(p) = self.typical_p {
+            keep_typical(&mut pool, p);
+        }
+        if let Some(p) = self.top_p {
+            keep_nucleus(&mut pool, p);
+        }
+        if let Some(p) = self.min_p {
+            keep_min_p(&mut pool, p);
+        }
+
+        let draw: f32 = self.rng.gen();
+        let mut cumulative = 0.0;
+        for (id, p) in &pool {
+            cumulative += p;
+            if draw < cumulative {
+                return Some(*id);
+            }
+        }
+
+        // Floating point rounding can leave the cumulative sum just below 1.0
+        pool.last().map(|(id, _)| *id)
+    }
+}
+
+/// Lower the logits of tokens that occur in `history`, except those in `exempt`.
+fn apply_penalties(candidates: &mut [Candidate], history: &VecDeque<i32>, penalties: &Penalties, exempt: &[i32]) {
+    let mut counts: HashMap<i32, u32> = HashMap::new();
+    for &token in history {
+        *counts.entry(token).or_default() += 1;
+    }
+
+    for candidate in candidates.iter_mut() {
+        let Some(&count) = counts.get(&candidate.id) else { continue };
+        if exempt.contains(&candidate.id) {
+            continue;
+        }
+
+        if candidate.logit > 0.0 {
+            candidate.logit /= penalties.repeat_penalty;
+        } else {
+            candidate.logit *= penalties.repeat_penalty;
+        }
+        candidate.logit -= count as f32 * penalties.frequency_penalty + penalties.presence_penalty;
+    }
+}
+
+/// A logit bias at or below this bans the token outright.
+pub const BAN_BIAS: f32 = -100.0;
+
+/// Add each token's bias to its logit, dropping banned tokens.
+fn apply_logit_bias(candidates: &mut Vec<Candidate>, logit_bias: &HashMap<i32, f32>) {
+    if logit_bias.is_empty() {
+        return;
+    }
+    candidates.retain_mut(|candidate| match logit_bias.get(&candidate.id) {
+        Some(&bias) if bias <= BAN_BIAS => false,
+        Some(&bias) => {
+            candidate.logit += bias;
+            true
+        }
+        None => true,
+    });
+}
+
+/// Log-probability of `chosen` among `candidates` and the `top_n` most likely candidates
+/// with theirs, most likely first. These come from the logits as they are, before
+/// temperature, penalties or truncation.
+pub fn logprobs(candidates: &[Candidate], chosen: i32, top_n: usize) -> (f32, Vec<(i32, f32)>) {
+    let max_logit = candidates.iter().map(|c| c.logit).fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = max_logit + candidates.iter().map(|c| (c.logit - max_logit).exp()).sum::<f32>().ln();
+
+    let chosen = candidates.iter().find(|c| c.id == chosen).map_or(f32::NEG_INFINITY, |c| c.logit - log_sum);
+    let by_logit = |a: &Candidate, b: &Candidate| b.logit.partial_cmp(&a.logit).unwrap_or(std::cmp::Ordering::Equal);
+    let mut top = candidates.to_vec();
+    let top_n = top_n.min(top.len());
+    if top_n < top.len() {
+        top.select_nth_unstable_by(top_n, by_logit);
+        top.truncate(top_n);
+    }
+    top.sort_by(by_logit);
+    (chosen, top.into_iter().map(|c| (c.id, c.logit - log_sum)).collect())
+}
+
+/// Resolve string keyed biases to token ids with the runtime's `tokenize`. A string that
+/// spans several tokens biases each of them.
+pub fn token_bias<E: std::fmt::Display>(logit_bias: &HashMap<String, f32>, tokenize: impl Fn(&str) -> Result<Vec<i32>, E>) -> Result<HashMap<i32, f32>, EngineError> {
+    let mut bias = HashMap::new();
+    for (text, value) in logit_bias {
+        let tokens = tokenize(text)
+            .map_err(|e| EngineError::Runtime(format!("Failed to tokenize logit bias '{}': {}", text, e)))?;
+        for token in tokens {
+            *bias.entry(token).or_insert(0.0) += value;
+        }
+    }
+    Ok(bias)
+}
+
+/// A fresh seed for callers that didn't ask for a specific one.
+pub fn random_seed() -> u64 {
+    rand::random()
+}
+
+fn greedy(candidates: &[Candidate]) -> Option<i32> {
+    candidates
+        .iter()
+        .max_by(|a, b| a.logit.partial_cmp(&b.logit).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|c| c.id)
+}
+
+/// Number of leading `probs` needed to reach a cumulative probability of `p`.
+/// Always at least one.
+fn nucleus_len(probs: impl IntoIterator<Item = f32>, p: f32) -> usize {
+    let mut cumulative = 0.0;
+    let mut len = 0;
+    for prob in probs {
+        cumulative += prob;
+        len += 1;
+        if cumulative >= p {
+            break;
+        }
+    }
+    len.max(1)
+}
+
+/// Rescale the probabilities in `pool` to sum to 1.0 again after filtering.
+fn normalize(pool: &mut [(i32, f32)]) {
+    let total: f32 = pool.iter().map(|(_, p)| p).sum();
+    if total > 0.0 {
+        for (_, p) in pool.iter_mut() {
+            *p /= total;
+        }
+    }
+}
+
+/// Top-p: keep the most likely tokens up to a cumulative probability of `p`.
+fn keep_nucleus(pool: &mut Vec<(i32, f32)>, p: f32) {
+    let keep = nucleus_len(pool.iter().map(|(_, q)| *q), p);
+    pool.truncate(keep);
+    normalize(pool);
+}
+
+/// Min-p: keep tokens whose probability is at least `p` times the highest one.
+fn keep_min_p(pool: &mut Vec<(i32, f32)>, p: f32) {
+    let Some(&(_, max)) = pool.first() else { return };
+    let threshold = max * p;
+    pool.retain(|(_, q)| *q >= threshold);
+    normalize(pool);
+}
+
+/// Typical-p: rank tokens by how far their surprise (-ln p) is from the entropy and
+/// keep the closest ones up to a cumulative probability of `p`. `pool` stays sorted
+/// by probability.
+fn keep_typical(pool: &mut Vec<(i32, f32)>, p: f32) {
+    let entropy: f32 = -pool.iter()
+        .filter(|(_, q)| *q > 0.0)
+        .map(|(_, q)| q * q.ln())
+        .sum::<f32>();
+    let distance = |q: f32| (-q.ln() - entropy).abs();
+
+    let mut by_typicality = pool.clone();
+    by_typicality.sort_by(|a, b| distance(a.1).partial_cmp(&distance(b.1)).unwrap_or(std::cmp::Ordering::Equal));
+    let keep = nucleus_len(by_typicality.iter().map(|(_, q)| *q), p);
+    by_typicality.truncate(keep);
+
+    pool.retain(|(id, _)| by_typicality.iter().any(|(kept, _)| kept == id));
+    normalize(pool);
+}
+
+/// Temperature-scaled softmax. Subtracts the max logit for numerical stability.
+fn softmax(candidates: &[Candidate], temperature: f32) -> Vec<f32> {
+    let max_logit = candidates
+        .iter()
+        .map(|c| c.logit)
+        .fold(f32::NEG_INFINITY, f32::max);
+
+    let exps: Vec<f32> = candidates
+        .iter()
+        .map(|c| ((c.logit - max_logit) / temperature).exp())
+        .collect();
+    let sum: f32 = exps.iter().sum();
+
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flat_candidates(n: i32) -> Vec<Candidate> {
+        (0..n).map(|id| Candidate { id, logit: 1.0 }).collect()
+    }
+
+    fn draw_sequence(sampler: &mut Sampler, candidates: &[Candidate], n: usize) -> Vec<i32> {
+        (0..n).map(|_| sampler.sample(candidates).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_greedy_at_zero_temperature() {
+        let candidates = vec![
+            Candidate { id: 0, logit: 0.5 },
+            Candidate { id: 1, logit: 3.0 },
+            Candidate { id: 2, logit: 1.0 },
+        ];
+        let mut a = Sampler::new(0.0, 1);
+        let mut b = Sampler::new(0.0, 2);
+
+        let run_a = draw_sequence(&mut a, &candidates, 20);
+        let run_b = draw_sequence(&mut b, &candidates, 20);
+        assert_eq!(run_a, run_b);
+        assert!(run_a.iter().all(|&id| id == 1));
+    }
+
+    #[test]
+    fn test_temperature_seeds_differ() {
+        let candidates = flat_candidates(50);
+        let mut a = Sampler::new(1.0, 1);
+        let mut b = Sampler::new(1.0, 2);
+
+        assert_ne!(
+            draw_sequence(&mut a, &candidates, 32),
+            draw_sequence(&mut b, &candidates, 32)
+        );
+    }
+
+    #[test]
+    fn test_same_seed_same_sequence() {
+        let candidates = flat_candidates(50);
+        let mut a = Sampler::new(1.0, 42).with_top_k(Some(20)).with_top_p(Some(0.9));
+        let mut b = Sampler::new(1.0, 42).with_top_k(Some(20)).with_top_p(Some(0.9));
+
+        assert_eq!(
+            draw_sequence(&mut a, &candidates, 32),
+            draw_sequence(&mut b, &candidates, 32)
+        );
+    }
+
+    #[test]
+    fn test_low_temperature_concentrates_mass() {
+        let candidates = vec![
+            Candidate { id: 0, logit: 2.0 },
+            Candidate { id: 1, logit: 1.0 },
+        ];
+        let cold = softmax(&candidates, 0.1);
+        let hot = softmax(&candidates, 2.0);
+        assert!(cold[0] > 0.99);
+        assert!(hot[0] < 0.7);
+    }
+
+    fn skewed_candidates() -> Vec<Candidate> {
+        // Probabilities at temperature 1.0: roughly 0.64, 0.24, 0.09, 0.03
+        vec![
+            Candidate { id: 3, logit: 0.0 },
+            Candidate { id: 0, logit: 3.0 },
+            Candidate { id: 2, logit: 1.0 },
+            Candidate { id: 1, logit: 2.0 },
+        ]
+    }
+
+    fn distinct(ids: Vec<i32>) -> Vec<i32> {
+        let mut ids = ids;
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    #[test]
+    fn test_top_k() {
+        let mut sampler = Sampler::new(1.0, 7).with_top_k(Some(2));
+        let drawn = distinct(draw_sequence(&mut sampler, &skewed_candidates(), 200));
+        assert_eq!(drawn, vec![0, 1]);
+
+        let mut single = Sampler::new(1.0, 7).with_top_k(Some(1));
+        assert!(draw_sequence(&mut single, &skewed_candidates(), 50).iter().all(|&id| id == 0));
+    }
+
+    #[test]
+    fn test_top_p() {
+        // 0.64 + 0.24 reaches 0.8, so only the two most likely tokens remain
+        let mut sampler = Sampler::new(1.0, 7).with_top_p(Some(0.8));
+        let drawn = distinct(draw_sequence(&mut sampler, &skewed_candidates(), 200));
+        assert_eq!(drawn, vec![0, 1]);
+
+        // A tiny nucleus still keeps the best token
+        let mut narrow = Sampler::new(1.0, 7).with_top_p(Some(0.01));
+        assert!(draw_sequence(&mut narrow, &skewed_candidates(), 50).iter().all(|&id| id == 0));
+    }
+
+    #[test]
+    fn test_top_k_and_top_p_ignored_when_greedy() {
+        let mut sampler = Sampler::new(0.0, 7).with_top_k(Some(3)).with_top_p(Some(0.9));
+        assert!(draw_sequence(&mut sampler, &skewed_candidates(), 20).iter().all(|&id| id == 0));
+    }
+
+    #[test]
+    fn test_apply_penalties() {
+        let mut candidates = vec![
+            Candidate { id: 0, logit: 2.0 },
+            Candidate { id: 1, logit: -1.0 },
+            Candidate { id: 2, logit: 1.0 },
+            Candidate { id: 9, logit: 1.5 },
+        ];
+        let history: VecDeque<i32> = vec![0, 1, 0, 9].into();
+        let penalties = Penalties {
+            repeat_penalty: 2.0,
+            last_n: 64,
+            frequency_penalty: 0.5,
+            presence_penalty: 0.25,
+        };
+
+        apply_penalties(&mut candidates, &history, &penalties, &[9]);
+
+        // 2.0 / 2 - 2 * 0.5 - 0.25
+        assert_eq!(candidates[0].logit, -0.25);
+        // -1.0 * 2 - 1 * 0.5 - 0.25
+        assert_eq!(candidates[1].logit, -2.75);
+        // Not in the history
+        assert_eq!(candidates[2].logit, 1.0);
+        // Exempt
+        assert_eq!(candidates[3].logit, 1.5);
+    }
+
+    #[test]
+    fn test_penalties_break_greedy_loop() {
+        let candidates = vec![
+            Candidate { id: 0, logit: 2.0 },
+            Candidate { id: 1, logit: 1.5 },
+        ];
+        let penalties = Penalties { repeat_penalty: 1.5, last_n: 1, ..Penalties::default() };
+        let mut sampler = Sampler::new(0.0, 0).with_penalties(penalties);
+
+        let mut sequence = Vec::new();
+        for _ in 0..4 {
+            let id = sampler.sample(&candidates).unwrap();
+            sampler.accept(id);
+            sequence.push(id);
+        }
+        // Only the previous token counts, so the penalty moves back and forth
+        assert_eq!(sequence, vec![0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_logit_bias() {
+        let mut candidates = skewed_candidates();
+        apply_logit_bias(&mut candidates, &HashMap::from([(0, f32::NEG_INFINITY), (3, 5.0), (2, -100.0)]));
+
+        let ids: Vec<i32> = candidates.iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![3, 1]);
+        assert_eq!(candidates[0].logit, 5.0);
+    }
+
+    #[test]
+    fn test_token_bias_and_penalties_from_options() {
+        // One token per character
+        let tokenize = |text: &str| Ok::<_, EngineError>(text.chars().map(|c| c as i32).collect());
+        let bias = token_bias(&HashMap::from([("ab".to_string(), 2.0), ("b".to_string(), -1.0)]), tokenize).unwrap();
+        assert_eq!(bias, HashMap::from([('a' as i32, 2.0), ('b' as i32, 1.0)]));
+        let err = token_bias(&HashMap::from([("x".to_string(), 1.0)]), |_| Err("no vocab")).unwrap_err();
+        assert!(err.to_string().contains("Failed to tokenize logit bias 'x': no vocab"), "{}", err);
+
+        let options = InferenceOptions { repeat_penalty: Some(1.3), presence_penalty: Some(0.5), ..InferenceOptions::default() };
+        let penalties = Penalties::from_options(&options);
+        assert_eq!(penalties, Penalties { repeat_penalty: 1.3, presence_penalty: 0.5, ..Penalties::default() });
+    }
+
+    #[test]
+    fn test_banned_token_never_sampled() {
+        let ban = HashMap::from([(0, f32::NEG_INFINITY)]);
+
+        let mut greedy = Sampler::new(0.0, 7).with_logit_bias(ban.clone());
+        assert!(draw_sequence(&mut greedy, &skewed_candidates(), 20).iter().all(|&id| id == 1));
+
+        let mut hot = Sampler::new(2.0, 7).with_logit_bias(ban.clone());
+        assert!(!draw_sequence(&mut hot, &skewed_candidates(), 500).contains(&0));
+
+        let only_banned = [Candidate { id: 0, logit: 1.0 }];
+        assert_eq!(Sampler::new(1.0, 7).with_logit_bias(ban).sample(&only_banned), None);
+    }
+
+    #[test]
+    fn test_nucleus_len() {
+        assert_eq!(nucleus_len([0.5, 0.3, 0.2], 0.5), 1);
+        assert_eq!(nucleus_len([0.5, 0.3, 0.2], 0.7), 2);
+        assert_eq!(nucleus_len([0.5, 0.3, 0.2], 1.0), 3);
+    }
+
+    fn ids(pool: &[(i32, f32)]) -> Vec<i32> {
+        pool.iter().map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn test_min_p() {
+        let mut pool = vec![(0, 0.5), (1, 0.3), (2, 0.15), (3, 0.05)];
+        // Threshold 0.25: 0.15 and 0.05 are dropped
+        keep_min_p(&mut pool, 0.5);
+        assert_eq!(ids(&pool), vec![0, 1]);
+        assert!((pool[0].1 - 0.625).abs() < 1e-6);
+
+        let mut all = vec![(0, 0.5), (1, 0.5)];
+        keep_min_p(&mut all, 1.0);
+        assert_eq!(ids(&all), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_typical_p() {
+        // Entropy is about 1.03 nats, surprise about 0.58, 1.27, 1.97 and 3.98, so the
+        // typicality order is 1, 0, 2, 3; the unlikely tail token 3 is dropped first.
+        let mut pool = vec![(0, 0.6), (1, 0.3), (2, 0.15), (3, 0.02)];
+        normalize(&mut pool);
+        keep_typical(&mut pool, 0.9);
+        assert_eq!(ids(&pool), vec![0, 1, 2]);
+
+        let mut half = vec![(0, 0.6), (1, 0.3), (2, 0.15), (3, 0.02)];
+        normalize(&mut half);
+        keep_typical(&mut half, 0.5);
+        assert_eq!(ids(&half), vec![0, 1]);
+
+        // A small mass keeps only the single most typical token
+        let mut narrow = vec![(0, 0.6), (1, 0.3), (2, 0.15), (3, 0.02)];
+        normalize(&mut narrow);
+        keep_typical(&mut narrow, 0.1);
+        assert_eq!(ids(&narrow), vec![1]);
+    }
+
+    #[test]
+    fn test_filters_compose() {
+        // top-p 0.95 drops token 3, then min-p 0.3 drops token 2 (0.09 < 0.3 * 0.66)
+        let mut sampler = Sampler::new(1.0, 7).with_top_p(Some(0.95)).with_min_p(Some(0.3));
+        let drawn = distinct(draw_sequence(&mut sampler, &skewed_candidates(), 200));
+        assert_eq!(drawn, vec![0, 1]);
+
+        let mut all = Sampler::new(1.0, 7)
+            .with_top_k(Some(3))
+            .with_typical_p(Some(0.9))
+            .with_top_p(Some(0.9))
+            .with_min_p(Some(0.05));
+        assert!(all.sample(&skewed_candidates()).is_some());
+    }
+
+    #[test]
+    fn test_empty_candidates() {
+        let mut sampler = Sampler::new(1.0, 0);
+        assert_eq!(sampler.sample(&[]), None);
+    }
+
+    #[test]
+    fn test_logprobs() {
+        // Probabilities 1/8, 2/8 and 5/8
+        let candidates = [
+            Candidate { id: 0, logit: 0.0 },
+            Candidate { id: 1, logit: 2f32.ln() },
+            Candidate { id: 2, logit: 5f32.ln() },
+        ];
+        let (chosen, top) = logprobs(&candidates, 1, 2);
+        assert!((chosen - 0.25f32.ln()).abs() < 1e-5);
+        assert_eq!(top.iter().map(|(id, _)| *id).collect::<Vec<_>>(), [2, 1]);
+        assert!((top[0].1 - 0.625f32.ln()).abs() < 1e-5);
+
+        let (_, all) = logprobs(&candidates, 1, 10);
+        assert_eq!(all.len(), 3);
+        assert!(logprobs(&candidates, 1, 0).1.is_empty());
+    }
+}
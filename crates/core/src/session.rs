@@ -0,0 +1,222 @@
+//! Conversations kept by the engine, so clients only send the new message each turn
+//! (see `Engine::process_chat_in_session`).
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+use crate::chat::ChatMessage;
+use crate::config::SessionConfig;
+use crate::error::EngineError;
+use crate::memory::{backup_corrupt_file, write_atomic};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    /// Sent ahead of the history on every turn; never trimmed.
+    pub system: Option<String>,
+    /// User and assistant turns, oldest first.
+    pub messages: Vec<ChatMessage>,
+    /// Unix timestamps (seconds).
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// A session without its messages, as listed by `SessionManager::list`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub id: String,
+    pub system: Option<String>,
+    /// Number of messages in the history.
+    pub messages: usize,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+pub struct SessionManager {
+    config: SessionConfig,
+    sessions: RwLock<HashMap<String, Session>>,
+    /// Serializes saves so an older snapshot can never be written after a newer one.
+    save_lock: Mutex<()>,
+    /// One lock per session with a turn in progress; see `lock_turn`.
+    turns: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl SessionManager {
+    /// Loads persisted sessions synchronously, like `MemoryManager::new`.
+    pub fn new(config: SessionConfig) -> Self {
+        let sessions = if config.persist && config.persistence_path.exists() {
+            match load_sessions(&config) {
+                Ok(sessions) => sessions,
+                Err(e) => {
+                    match backup_corrupt_file(&config.persistence_path) {
+                        Ok(backup) => tracing::error!(
+                            "{}; moved it to {} and starting without sessions", e, backup.display()
+                        ),
+                        Err(backup_err) => tracing::error!(
+                            "{}; could not back it up either: {}", e, backup_err
+                        ),
+                    }
+                    HashMap::new()
+                }
+            }
+        } else {
+            HashMap::new()
+        };
+
+        Self {
+            config,
+            sessions: RwLock::new(sessions),
+            save_lock: Mutex::new(()),
+            turns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn create(&self, system: Option<String>) -> Result<Session, EngineError> {
+        let now = unix_now();
+        let session = Session {
+            id: crate::random_id(),
+            system,
+            messages: Vec::new(),
+            created_at: now,
+            updated_at: now,
+        };
+
+        let mut sessions = self.sessions.write().await;
+        sessions.retain(|_, s| !self.is_expired(s, now));
+        sessions.insert(session.id.clone(), session.clone());
+        drop(sessions);
+        self.save().await?;
+        Ok(session)
+    }
+
+    /// The session, unless it doesn't exist or has expired.
+    pub async fn get(&self, id: &str) -> Option<Session> {
+        let sessions = self.sessions.read().await;
+        sessions.get(id).filter(|s| !self.is_expired(s, unix_now())).cloned()
+    }
+
+    /// The sessions that haven't expired, most recently used first.
+    pub async fn list(&self) -> Vec<SessionSummary> {
+        let now = unix_now();
+        let sessions = self.sessions.read().await;
+        let mut summaries: Vec<SessionSummary> = sessions.values()
+            .filter(|s| !self.is_expired(s, now))
+            .map(|s| SessionSummary {
+                id: s.id.clone(),
+                system: s.system.clone(),
+                messages: s.messages.len(),
+                created_at: s.created_at,
+                updated_at: s.updated_at,
+            })
+            .collect();
+        summaries.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then_with(|| a.id.cmp(&b.id)));
+        summaries
+    }
+
+    /// Remove a session. Returns `false` if there was no such session.
+    pub async fn delete(&self, id: &str) -> Result<bool, EngineError> {
+        let removed = self.sessions.write().await.remove(id).is_some();
+        if removed {
+            self.save().await?;
+        }
+        Ok(removed)
+    }
+
+    /// Wait until no other turn of session `id` is in progress, and keep others waiting
+    /// while the guard is held, so each turn reads the history the previous one stored.
+    pub(crate) async fn lock_turn(&self, id: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut turns = self.turns.lock().await;
+            // Locks nobody holds or waits for are only kept by the map
+            turns.retain(|_, lock| Arc::strong_count(lock) > 1);
+            turns.entry(id.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+
+    /// Replace the history of a session after a turn.
+    pub(crate) async fn set_messages(&self, id: &str, messages: Vec<ChatMessage>) -> Result<(), EngineError> {
+        let now = unix_now();
+        let mut sessions = self.sessions.write().await;
+        let session = sessions.get_mut(id)
+            .filter(|s| !self.is_expired(s, now))
+            .ok_or_else(|| EngineError::NotFound(format!("Session '{}'", id)))?;
+        session.messages = messages;
+        session.updated_at = now;
+        drop(sessions);
+        self.save().await
+    }
+
+    fn is_expired(&self, session: &Session, now: u64) -> bool {
+        now.saturating_sub(session.updated_at) >= self.config.ttl_secs
+    }
+
+    async fn save(&self) -> Result<(), EngineError> {
+        if self.config.persist {
+            let _guard = self.save_lock.lock().await;
+            let json = {
+                let sessions = self.sessions.read().await;
+                serde_json::to_string_pretty(&*sessions)
+                    .map_err(|e| EngineError::Memory(format!("Serialization error: {}", e)))?
+            };
+            let path = &self.config.persistence_path;
+            write_atomic(path, json.as_bytes()).await
+                .map_err(|e| EngineError::Memory(format!("Failed to save {}: {}", path.display(), e)))?;
+        }
+        Ok(())
+    }
+}
+
+fn load_sessions(config: &SessionConfig) -> Result<HashMap<String, Session>, EngineError> {
+    let path = &config.persistence_path;
+    let content = fs::read_to_string(path)?;
+    serde_json::from_str(&content)
+        .map_err(|e| EngineError::Memory(format!("{} is corrupt: {}", path.display(), e)))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::Role;
+
+    fn config(dir: &tempfile::TempDir, ttl_secs: u64) -> SessionConfig {
+        SessionConfig { ttl_secs, persist: true, persistence_path: dir.path().join("sessions.json") }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = SessionManager::new(config(&dir, 3600));
+        let session = manager.create(Some("Be brief.".to_string())).await.unwrap();
+        manager.set_messages(&session.id, vec![ChatMessage::new(Role::User, "Hi")]).await.unwrap();
+
+        let reloaded = SessionManager::new(config(&dir, 3600));
+        let listed = reloaded.list().await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].id.as_str(), listed[0].messages), (session.id.as_str(), 1));
+        let restored = reloaded.get(&session.id).await.unwrap();
+        assert_eq!(restored.system.as_deref(), Some("Be brief."));
+        assert_eq!(restored.messages.len(), 1);
+
+        assert!(reloaded.delete(&session.id).await.unwrap());
+        assert!(!reloaded.delete(&session.id).await.unwrap());
+        assert!(SessionManager::new(config(&dir, 3600)).get(&session.id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expired_sessions_are_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = SessionManager::new(config(&dir, 0));
+        let session = manager.create(None).await.unwrap();
+        assert!(manager.get(&session.id).await.is_none());
+        assert!(manager.list().await.is_empty());
+        assert!(matches!(manager.set_messages(&session.id, Vec::new()).await, Err(EngineError::NotFound(_))));
+    }
+}
@@ -0,0 +1,98 @@
+//! Stop sequences: where generated text has to end, and how much of it can be streamed
+//! before the rest of a possible stop sequence arrives.
+
+use tokio::sync::mpsc;
+use crate::runtime::TokenChunk;
+
+pub struct StopSequences {
+    sequences: Vec<String>,
+}
+
+impl StopSequences {
+    /// Empty sequences are ignored; they would stop generation before it starts.
+    pub fn new(sequences: &[String]) -> Self {
+        Self { sequences: sequences.iter().filter(|s| !s.is_empty()).cloned().collect() }
+    }
+
+    /// The earliest stop sequence in `text`: where it starts, and which one. Of several
+    /// starting at the same place, the one listed first wins.
+    pub fn find(&self, text: &str) -> Option<(usize, &str)> {
+        self.sequences.iter()
+            .filter_map(|s| text.find(s.as_str()).map(|at| (at, s.as_str())))
+            .min_by_key(|(at, _)| *at)
+    }
+
+    /// How much of `text` can be passed on: all of it but a tail that more text could
+    /// still turn into a stop sequence.
+    pub fn safe_len(&self, text: &str) -> usize {
+        let longest = self.sequences.iter().map(String::len).max().unwrap_or(0);
+        text.char_indices()
+            .map(|(at, _)| at)
+            .filter(|&at| text.len() - at < longest)
+            .find(|&at| self.sequences.iter().any(|s| s.starts_with(&text[at..])))
+            .unwrap_or(text.len())
+    }
+}
+
+/// Send `text[*streamed..up_to]`, if there is any, and advance `streamed`. Runtimes pass
+/// `safe_len` of their output as `up_to` while generating, and its whole length at the end.
+/// Returns `false` if the receiver is gone.
+pub fn stream_text(tx: Option<&mpsc::UnboundedSender<TokenChunk>>, text: &str, streamed: &mut usize, up_to: usize) -> bool {
+    let Some(tx) = tx else {
+        return true;
+    };
+    if up_to <= *streamed {
+        return true;
+    }
+    let piece = text[*streamed..up_to].to_string();
+    *streamed = up_to;
+    tx.send(TokenChunk::Token { text: piece }).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stops(sequences: &[&str]) -> StopSequences {
+        StopSequences::new(&sequences.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_find_earliest() {
+        let stop = stops(&["END", "\n\n", ""]);
+        assert_eq!(stop.find("one\n\ntwo END"), Some((3, "\n\n")));
+        assert_eq!(stop.find("one END\n\n"), Some((4, "END")));
+        assert_eq!(stop.find("no stop here"), None);
+        assert_eq!(stops(&[]).find("anything"), None);
+    }
+
+    #[test]
+    fn test_safe_len_holds_back_partial_matches() {
+        let stop = stops(&["</answer>"]);
+        assert_eq!(stop.safe_len("42</ans"), 2);
+        assert_eq!(stop.safe_len("42<"), 2);
+        assert_eq!(stop.safe_len("42 is it"), 8);
+        // Multi-byte text is only ever cut between characters
+        assert_eq!(stops(&["é!"]).safe_len("café"), 3);
+        assert_eq!(stops(&[]).safe_len("café"), 5);
+    }
+
+    #[test]
+    fn test_stream_text() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut streamed = 0;
+        assert!(stream_text(Some(&tx), "Hello wor", &mut streamed, 6));
+        assert!(stream_text(Some(&tx), "Hello wor", &mut streamed, 6));
+        assert!(stream_text(Some(&tx), "Hello world", &mut streamed, 11));
+        let pieces: Vec<String> = std::iter::from_fn(|| match rx.try_recv() {
+            Ok(TokenChunk::Token { text }) => Some(text),
+            _ => None,
+        }).collect();
+        assert_eq!(pieces, ["Hello ", "world"]);
+        assert_eq!(streamed, 11);
+
+        drop(rx);
+        assert!(!stream_text(Some(&tx), "Hello world!", &mut streamed, 12));
+        assert!(stream_text(None, "Hello world!", &mut streamed, 12));
+    }
+}
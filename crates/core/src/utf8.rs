@@ -0,0 +1,80 @@
+//! Turning generated token bytes into text. A token can end partway through a multi-byte
+//! character (half an emoji, part of a CJK character); the rest arrives with the next one.
+
+#[derive(Default)]
+pub struct Utf8Buffer {
+    /// The start of a character whose remaining bytes haven't arrived yet.
+    pending: Vec<u8>,
+}
+
+impl Utf8Buffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a token's bytes and return the text completed so far. Bytes that can never be
+    /// valid UTF-8 become U+FFFD; an incomplete character at the end is kept for later.
+    pub fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut text = String::new();
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(complete) => {
+                    text.push_str(complete);
+                    self.pending.clear();
+                    return text;
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    text.push_str(std::str::from_utf8(&self.pending[..valid]).expect("checked valid"));
+                    match e.error_len() {
+                        // Cut off at the end: wait for the next token
+                        None => {
+                            self.pending.drain(..valid);
+                            return text;
+                        }
+                        Some(invalid) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.drain(..valid + invalid);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whatever is still held back once generation ends, which can only be an incomplete
+    /// character: U+FFFD, or nothing.
+    pub fn finish(self) -> String {
+        String::from_utf8_lossy(&self.pending).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(tokens: &[&[u8]]) -> Vec<String> {
+        let mut buffer = Utf8Buffer::new();
+        let mut pieces: Vec<String> = tokens.iter().map(|bytes| buffer.push(bytes)).collect();
+        pieces.push(buffer.finish());
+        pieces
+    }
+
+    #[test]
+    fn test_split_characters_are_joined() {
+        // "😀" is F0 9F 98 80
+        assert_eq!(decode(&[b"Hi ", &[0xF0, 0x9F], &[0x98, 0x80], b"!"]), ["Hi ", "", "😀", "!", ""]);
+        // "日本" is E6 97 A5 E6 9C AC; the middle token ends one character and starts the next
+        assert_eq!(decode(&[&[0xE6], &[0x97, 0xA5, 0xE6, 0x9C], &[0xAC]]), ["", "日", "本", ""]);
+    }
+
+    #[test]
+    fn test_invalid_bytes_are_replaced() {
+        assert_eq!(decode(&[&[b'a', 0xFF, b'b']]), ["a\u{FFFD}b", ""]);
+        // A character that never completes
+        assert_eq!(decode(&[b"end ", &[0xE6, 0x97]]), ["end ", "", "\u{FFFD}"]);
+        // A lead byte followed by something that can't continue it
+        assert_eq!(decode(&[&[0xE6], b"x"]), ["", "\u{FFFD}x", ""]);
+    }
+}
@@ -0,0 +1,202 @@
+//! Puts prompts that span several lines together from the lines typed at the prompt: a
+//! `/paste` block ended by a lone `.`, text between `"""` lines, and lines ending in a
+//! backslash. Their text is kept exactly as typed.
+
+/// What was entered at the prompt.
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    /// A single line: a prompt or a command.
+    Line(String),
+    /// A prompt of several lines, never taken as a command.
+    Block(String),
+}
+
+#[derive(Debug, Default, PartialEq)]
+enum Mode {
+    #[default]
+    Idle,
+    /// After `/paste`, until a lone `.`.
+    Paste,
+    /// After an opening `"""`, until the closing one.
+    Quotes,
+    /// After a line ending in a backslash, until one that doesn't.
+    Continued,
+}
+
+#[derive(Default)]
+pub struct Collector {
+    mode: Mode,
+    lines: Vec<String>,
+}
+
+const QUOTES: &str = "\"\"\"";
+
+impl Collector {
+    /// Whether a prompt is being put together.
+    pub fn is_collecting(&self) -> bool {
+        self.mode != Mode::Idle
+    }
+
+    /// What to show at the prompt.
+    pub fn prompt(&self) -> &'static str {
+        match self.mode {
+            Mode::Idle => ">>",
+            _ => "..",
+        }
+    }
+
+    /// Take every line from now on until a lone `.`.
+    pub fn start_paste(&mut self) {
+        self.mode = Mode::Paste;
+    }
+
+    /// Add a line; returns the input once it's complete.
+    pub fn push(&mut self, line: &str) -> Option<Input> {
+        match self.mode {
+            // An entry recalled from the history may span lines already
+            Mode::Idle if line.contains('\n') => Some(Input::Block(line.to_string())),
+            Mode::Idle => match line.trim_start().strip_prefix(QUOTES) {
+                Some(rest) => match rest.strip_suffix(QUOTES) {
+                    // """text""" on one line
+                    Some(text) => Some(Input::Block(text.to_string())),
+                    None => {
+                        self.mode = Mode::Quotes;
+                        if !rest.is_empty() {
+                            self.lines.push(rest.to_string());
+                        }
+                        None
+                    }
+                },
+                None => match line.strip_suffix('\\') {
+                    Some(start) => {
+                        self.mode = Mode::Continued;
+                        self.lines.push(start.to_string());
+                        None
+                    }
+                    None => Some(Input::Line(line.to_string())),
+                },
+            },
+            Mode::Paste if line == "." => self.finish(),
+            Mode::Paste => {
+                self.lines.push(line.to_string());
+                None
+            }
+            Mode::Quotes => match line.trim_end().strip_suffix(QUOTES) {
+                Some(end) => {
+                    if !end.is_empty() {
+                        self.lines.push(end.to_string());
+                    }
+                    self.finish()
+                }
+                None => {
+                    self.lines.push(line.to_string());
+                    None
+                }
+            },
+            Mode::Continued => match line.strip_suffix('\\') {
+                Some(more) => {
+                    self.lines.push(more.to_string());
+                    None
+                }
+                None => {
+                    self.lines.push(line.to_string());
+                    self.finish()
+                }
+            },
+        }
+    }
+
+    /// End the prompt being put together (Ctrl-D) and return it, if it has any text.
+    pub fn finish(&mut self) -> Option<Input> {
+        self.mode = Mode::Idle;
+        let text = std::mem::take(&mut self.lines).join("\n");
+        (!text.is_empty()).then_some(Input::Block(text))
+    }
+
+    /// Drop the prompt being put together (Ctrl-C).
+    pub fn discard(&mut self) {
+        self.mode = Mode::Idle;
+        self.lines.clear();
+    }
+}
+
+/// Open `$VISUAL` or `$EDITOR` (`vi` if neither is set) on an empty file and return what
+/// was saved in it, without the newline editors add at the end. `None` if nothing was.
+pub fn edit() -> anyhow::Result<Option<String>> {
+    let editor = std::env::var("VISUAL").or_else(|_| std::env::var("EDITOR"))
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+    // e.g. EDITOR="code --wait"
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+    let file = tempfile::Builder::new().prefix("lie-prompt-").suffix(".txt").tempfile()
+        .map_err(|e| anyhow::anyhow!("Failed to create a file to edit: {}", e))?;
+    let status = std::process::Command::new(program).args(parts).arg(file.path()).status()
+        .map_err(|e| anyhow::anyhow!("Failed to start the editor '{}': {}", editor, e))?;
+    if !status.success() {
+        anyhow::bail!("The editor '{}' exited with {}; nothing was sent", editor, status);
+    }
+    let text = std::fs::read_to_string(file.path())
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", file.path().display(), e))?;
+    let text = text.strip_suffix('\n').map(|t| t.strip_suffix('\r').unwrap_or(t)).unwrap_or(&text);
+    Ok((!text.trim().is_empty()).then(|| text.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(lines: &[&str]) -> Vec<Input> {
+        let mut collector = Collector::default();
+        lines.iter().filter_map(|line| {
+            if *line == "/paste" && !collector.is_collecting() {
+                collector.start_paste();
+                return None;
+            }
+            collector.push(line)
+        }).collect()
+    }
+
+    fn block(text: &str) -> Input {
+        Input::Block(text.to_string())
+    }
+
+    #[test]
+    fn test_paste() {
+        assert_eq!(collect(&["/paste", "fn main() {", "    println!(\"hi\");  ", "", "}", ".", "next"]), [
+            block("fn main() {\n    println!(\"hi\");  \n\n}"),
+            Input::Line("next".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!(collect(&["\"\"\"Summarize:", "  a  b", "\"\"\""]), [block("Summarize:\n  a  b")]);
+        assert_eq!(collect(&["\"\"\"", "/usage", "end\"\"\""]), [block("/usage\nend")]);
+        assert_eq!(collect(&["\"\"\"one line\"\"\""]), [block("one line")]);
+    }
+
+    #[test]
+    fn test_backslash_continuation() {
+        assert_eq!(collect(&["first \\", "  second\\", "third", "fourth"]), [
+            block("first \n  second\nthird"),
+            Input::Line("fourth".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_finish_and_discard() {
+        let mut collector = Collector::default();
+        collector.start_paste();
+        assert_eq!(collector.push("a"), None);
+        assert_eq!(collector.prompt(), "..");
+        assert_eq!(collector.finish(), Some(block("a")));
+        assert!(!collector.is_collecting());
+
+        collector.push("\"\"\"b");
+        collector.discard();
+        assert_eq!(collector.push("c"), Some(Input::Line("c".to_string())));
+        assert_eq!(collector.push("d\ne"), Some(block("d\ne")));
+    }
+}
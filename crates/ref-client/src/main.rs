@@ -1,10 +1,44 @@
+use clap::Parser;
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use input::{Collector, Input};
+use transcript::{Transcript, Turn};
 
-const SERVER_URL: &str = "http://127.0.0.1:8080";
+mod input;
+mod sse;
+mod transcript;
+
+/// Environment variable holding the API key to send, for servers with `api_keys` set.
+const API_KEY_VAR: &str = "CELA_API_KEY";
+
+#[derive(Parser)]
+#[command(about = "Interactive client for the Local AI Engine server")]
+struct Args {
+    /// Server to connect to; https:// for a server with server.tls set
+    #[arg(long, default_value = "http://127.0.0.1:8080")]
+    url: String,
+
+    /// Trust this PEM certificate, e.g. the server's own self-signed one
+    #[arg(long, value_name = "PATH")]
+    ca_cert: Option<std::path::PathBuf>,
+
+    /// Accept any server certificate. Only for testing: the connection can be intercepted
+    #[arg(long)]
+    insecure: bool,
+
+    /// Where to keep the prompt history [default: ~/.cache/cela/ref-client-history]
+    #[arg(long, value_name = "PATH")]
+    history: Option<PathBuf>,
+
+    /// Save the conversation here after every turn, and continue it from here on start.
+    /// Markdown if it ends in .md, JSON otherwise
+    #[arg(long, value_name = "PATH")]
+    autosave: Option<PathBuf>,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 struct HealthResponse {
@@ -12,6 +46,13 @@ struct HealthResponse {
     version: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct ReadyResponse {
+    state: String,
+    progress: Option<f32>,
+    error: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct RequestLimits {
     max_tokens: Option<u32>,
@@ -23,16 +64,362 @@ struct RequestLimits {
 struct CompletionRequest {
     prompt: String,
     limits: Option<RequestLimits>,
+    /// Left out unless set, for servers from before streaming
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+/// Print the error of a response. Structured errors carry a stable code; older servers
+/// only send `error`.
+fn print_error(body: &serde_json::Value) {
+    if let Some(info) = body.get("error_info").filter(|i| !i.is_null()) {
+        println!("\nError [{}]: {}{}",
+            info["code"].as_str().unwrap_or("unknown"),
+            info["message"].as_str().unwrap_or_default(),
+            if info["retryable"].as_bool() == Some(true) { " (retryable)" } else { "" });
+    } else if let Some(error) = body.get("error").and_then(|e| e.as_str()) {
+        println!("\nError: {}", error);
+    }
+}
+
+/// Print the usage and why generation ended.
+fn print_stats(body: &serde_json::Value) {
+    if let Some(usage) = body.get("usage").filter(|_| body["status"] != "error") {
+        println!("Perf: prompt {} tokens in {} ms, generated {} tokens in {} ms ({:.1} tokens/s)",
+            usage["input_tokens"], usage["prompt_eval_ms"].as_u64().unwrap_or(0),
+            usage["output_tokens"], usage["generation_ms"].as_u64().unwrap_or(0),
+            usage["tokens_per_second"].as_f64().unwrap_or(0.0));
+    }
+
+    // Older servers don't report why generation ended
+    if let Some(reason) = body.get("finish_reason").filter(|r| !r.is_null()) {
+        match reason["sequence"].as_str() {
+            Some(sequence) => println!("Finished: {} ({:?})", reason["type"].as_str().unwrap_or("unknown"), sequence),
+            None => println!("Finished: {}", reason["type"].as_str().unwrap_or("unknown")),
+        }
+    }
+}
+
+/// Print a response that arrived in one piece: the whole JSON contract, or just the text.
+fn print_response(body: &serde_json::Value, as_json: bool) -> Result<(), Box<dyn Error>> {
+    let text = body.get("output").and_then(|o| o.get("text")).and_then(|t| t.as_str());
+    if as_json {
+        // Pretty print the JSON contract
+        println!("{}", serde_json::to_string_pretty(body)?);
+        print_error(body);
+        // Extract text for convenience
+        if let Some(text) = text {
+            println!("\n--- Parsed Output ---\n{}\n---------------------", text);
+        }
+    } else {
+        if let Some(text) = text {
+            println!("{}", text);
+        }
+        print_error(body);
+    }
+    print_stats(body);
+    Ok(())
+}
+
+/// The JSON body of a response, or a message saying why the request failed.
+async fn api_json(resp: reqwest::Response) -> Result<serde_json::Value, String> {
+    let status = resp.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(format!("Unauthorized: set {} to one of the server's API keys", API_KEY_VAR));
+    }
+    let text = resp.text().await.map_err(|e| format!("Failed to read the response: {}", e))?;
+    let body: serde_json::Value = serde_json::from_str(&text).unwrap_or(serde_json::Value::Null);
+    if status.is_success() {
+        return Ok(body);
+    }
+    let message = body.pointer("/error_info/message")
+        .or_else(|| body.get("error"))
+        .and_then(|m| m.as_str())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("HTTP {}", status));
+    Err(message)
+}
+
+/// Send a completion request and print the response: as it's generated if the server
+/// streams it, otherwise the whole JSON contract once it's done. Returns the response,
+/// unless the request was refused outright.
+async fn complete(client: &reqwest::Client, server_url: &str, req: &CompletionRequest) -> Result<Option<serde_json::Value>, Box<dyn Error>> {
+    if !req.stream {
+        println!("Sending request...");
+    }
+    let mut resp = client.post(format!("{}/v1/completion", server_url))
+        .json(req)
+        .send()
+        .await?;
+    if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+        println!("Unauthorized: set {} to one of the server's API keys", API_KEY_VAR);
+        return Ok(None);
+    }
+
+    let streamed = resp.headers().get(reqwest::header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"text/event-stream"));
+    if !streamed {
+        let body: serde_json::Value = resp.json().await?;
+        print_response(&body, true)?;
+        return Ok(Some(body));
+    }
+
+    let mut events = sse::EventReader::default();
+    let mut stdout = std::io::stdout();
+    let mut response = None;
+    while let Some(chunk) = resp.chunk().await? {
+        for event in events.push(&chunk) {
+            let data: serde_json::Value = serde_json::from_str(&event.data)?;
+            match event.name.as_str() {
+                "token" => {
+                    print!("{}", data["text"].as_str().unwrap_or_default());
+                    stdout.flush()?;
+                }
+                "done" => {
+                    println!();
+                    print_stats(&data);
+                    response = Some(data);
+                }
+                "error" => {
+                    println!();
+                    print_error(&data);
+                    response = Some(data);
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(response)
+}
+
+/// Send the next message of a server session and print the reply. Sessions don't stream,
+/// so it's printed once it's complete.
+async fn send_to_session(client: &reqwest::Client, server_url: &str, session: &str, req: &CompletionRequest) -> Result<Option<serde_json::Value>, Box<dyn Error>> {
+    let body = serde_json::json!({ "message": req.prompt, "limits": req.limits });
+    let resp = client.post(format!("{}/v1/sessions/{}/messages", server_url, session))
+        .json(&body)
+        .send()
+        .await?;
+    if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+        println!("Unauthorized: set {} to one of the server's API keys", API_KEY_VAR);
+        return Ok(None);
+    }
+    let body: serde_json::Value = resp.json().await?;
+    print_response(&body, !req.stream)?;
+    Ok(Some(body))
+}
+
+/// The turn a successful response adds to the transcript.
+fn turn(prompt: &str, body: &serde_json::Value, wall_ms: u64) -> Option<Turn> {
+    if body["status"] == "error" {
+        return None;
+    }
+    let usage = &body["usage"];
+    Some(Turn {
+        prompt: prompt.to_string(),
+        response: body.pointer("/output/text").and_then(|t| t.as_str()).unwrap_or_default().to_string(),
+        input_tokens: usage["input_tokens"].as_u64().unwrap_or(0),
+        output_tokens: usage["output_tokens"].as_u64().unwrap_or(0),
+        wall_ms,
+    })
+}
+
+/// The turns of a server session's history: each user message with the reply to it.
+fn session_turns(session: &serde_json::Value) -> Vec<Turn> {
+    let messages = session["messages"].as_array().cloned().unwrap_or_default();
+    let mut turns = Vec::new();
+    let mut messages = messages.iter().peekable();
+    while let Some(message) = messages.next() {
+        if message["role"] != "user" {
+            continue;
+        }
+        let response = match messages.peek() {
+            Some(reply) if reply["role"] == "assistant" => messages.next().and_then(|r| r["content"].as_str()),
+            _ => None,
+        };
+        turns.push(Turn {
+            prompt: message["content"].as_str().unwrap_or_default().to_string(),
+            response: response.unwrap_or_default().to_string(),
+            input_tokens: 0,
+            output_tokens: 0,
+            wall_ms: 0,
+        });
+    }
+    turns
+}
+
+/// `/session new [system prompt]`, `/session list`, `/session use <id>` and `/session off`.
+async fn session_command(client: &reqwest::Client, server_url: &str, args: &str, transcript: &mut Transcript) -> Result<(), String> {
+    let (command, rest) = args.split_once(' ').map(|(c, r)| (c, r.trim())).unwrap_or((args, ""));
+    let send = |request: reqwest::RequestBuilder| async move {
+        api_json(request.send().await.map_err(|e| format!("Request failed: {}", e))?).await
+    };
+    match command {
+        "new" => {
+            let system = (!rest.is_empty()).then_some(rest);
+            let session = send(client.post(format!("{}/v1/sessions", server_url)).json(&serde_json::json!({ "system": system }))).await?;
+            let id = session["id"].as_str().ok_or("The server didn't return a session id")?;
+            *transcript = Transcript { session: Some(id.to_string()), turns: Vec::new() };
+            println!("Started session {}; the server keeps the history from now on", id);
+        }
+        "list" => {
+            let list = send(client.get(format!("{}/v1/sessions", server_url))).await
+                .map_err(|e| format!("Failed to list sessions: {}", e))?;
+            let sessions = list["sessions"].as_array().cloned().unwrap_or_default();
+            if sessions.is_empty() {
+                println!("No sessions");
+            }
+            for session in sessions {
+                let id = session["id"].as_str().unwrap_or_default();
+                let current = if transcript.session.as_deref() == Some(id) { "*" } else { " " };
+                println!("{} {}  {} messages  updated {}  {}",
+                    current, id, session["messages"], age(session["updated_at"].as_u64().unwrap_or(0)),
+                    session["system"].as_str().map(|s| format!("{:?}", s)).unwrap_or_default());
+            }
+        }
+        "use" if !rest.is_empty() => {
+            let session = send(client.get(format!("{}/v1/sessions/{}", server_url, rest))).await?;
+            *transcript = Transcript { session: Some(rest.to_string()), turns: session_turns(&session) };
+            println!("Using session {} ({} turns so far)", rest, transcript.turns.len());
+        }
+        "off" => match transcript.session.take() {
+            Some(id) => println!("Left session {}; prompts are sent on their own again", id),
+            None => println!("Not in a session"),
+        },
+        _ => return Err("Usage: /session new [system prompt] | list | use <id> | off".to_string()),
+    }
+    Ok(())
+}
+
+/// `/mem list`, `/mem set <key> <value>`, `/mem del <key>`, `/mem summary <text>`,
+/// `/mem clear` and `/mem show [prompt]`.
+async fn mem_command(client: &reqwest::Client, server_url: &str, args: &str, last_prompt: Option<&str>) -> Result<(), String> {
+    let (command, rest) = args.split_once(' ').map(|(c, r)| (c, r.trim())).unwrap_or((args, ""));
+    let send = |request: reqwest::RequestBuilder| async move {
+        api_json(request.send().await.map_err(|e| format!("Request failed: {}", e))?).await
+    };
+    let memory = format!("{}/v1/memory", server_url);
+    match command {
+        "list" => {
+            let facts = send(client.get(format!("{}/facts", memory))).await?;
+            let facts = facts["facts"].as_array().cloned().unwrap_or_default();
+            if facts.is_empty() {
+                println!("No facts");
+            }
+            for fact in facts {
+                println!("  {} = {}", fact["key"].as_str().unwrap_or_default(), fact["value"].as_str().unwrap_or_default());
+            }
+            let summary = send(client.get(format!("{}/summary", memory))).await?;
+            match summary["summary"].as_str().filter(|s| !s.is_empty()) {
+                Some(summary) => println!("Summary: {}", summary),
+                None => println!("No summary"),
+            }
+        }
+        "set" => {
+            let (key, value) = rest.split_once(' ').ok_or("Usage: /mem set <key> <value>")?;
+            send(client.post(format!("{}/facts", memory)).json(&serde_json::json!({ "key": key, "value": value.trim() }))).await?;
+            println!("Set {}", key);
+        }
+        "del" if !rest.is_empty() => {
+            let mut url = reqwest::Url::parse(&format!("{}/facts", memory)).map_err(|e| e.to_string())?;
+            url.path_segments_mut().map_err(|()| "Invalid server URL")?.push(rest);
+            send(client.delete(url)).await?;
+            println!("Deleted {}", rest);
+        }
+        "summary" if !rest.is_empty() => {
+            let summary = send(client.put(format!("{}/summary", memory)).json(&serde_json::json!({ "summary": rest }))).await?;
+            println!("Summary: {}", summary["summary"].as_str().unwrap_or_default());
+        }
+        "clear" => {
+            send(client.delete(memory)).await?;
+            println!("Cleared the facts and the summary");
+        }
+        "show" => {
+            let prompt = Some(rest).filter(|p| !p.is_empty()).or(last_prompt)
+                .ok_or("Usage: /mem show <prompt>; without one, the last prompt is used")?;
+            let body = serde_json::json!({ "prompt": prompt, "dry_run": true, "debug": true });
+            let response = send(client.post(format!("{}/v1/completion", server_url)).json(&body)).await?;
+            let debug = &response["debug"];
+            match debug["memory_injection"].as_str().filter(|m| !m.is_empty()) {
+                Some(injection) => println!("{} tokens added ahead of {:?}:\n{}", debug["memory_tokens"], prompt, injection.trim_end()),
+                None => println!("Nothing would be added ahead of {:?}", prompt),
+            }
+        }
+        _ => return Err("Usage: /mem list | set <key> <value> | del <key> | summary <text> | clear | show [prompt]".to_string()),
+    }
+    Ok(())
+}
+
+/// How long ago a unix timestamp was, roughly.
+fn age(timestamp: u64) -> String {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    match now.saturating_sub(timestamp) {
+        secs if secs < 60 => format!("{} s ago", secs),
+        secs if secs < 3600 => format!("{} min ago", secs / 60),
+        secs => format!("{} h ago", secs / 3600),
+    }
+}
+
+/// Load a saved transcript. A session it was in that the server no longer has is dropped,
+/// so new prompts aren't sent to it.
+async fn restore(client: &reqwest::Client, server_url: &str, path: &Path) -> Result<Transcript, Box<dyn Error>> {
+    let mut transcript = Transcript::load(path)?;
+    if let Some(id) = &transcript.session {
+        let resp = client.get(format!("{}/v1/sessions/{}", server_url, id)).send().await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            println!("Session {} is gone from the server; new prompts start a fresh conversation", id);
+            transcript.session = None;
+        }
+    }
+    println!("Loaded {} turns from {}", transcript.turns.len(), path.display());
+    Ok(transcript)
+}
+
+fn print_usage(transcript: &Transcript) {
+    let usage = transcript.usage();
+    println!("{} turns, {} tokens ({} prompt + {} generated), {:.1} s waiting for responses",
+        usage.turns, usage.input_tokens + usage.output_tokens, usage.input_tokens, usage.output_tokens,
+        usage.wall_ms as f64 / 1000.0);
+    if let Some(session) = &transcript.session {
+        println!("Session {}", session);
+    }
+}
+
+/// Whether versions `a` and `b` are compatible as Cargo sees it: the same major version,
+/// and for 0.x the same minor version too.
+fn is_compatible(a: &str, b: &str) -> bool {
+    let parts = |v: &str| -> Vec<u64> { v.split('.').take(2).map(|p| p.parse().unwrap_or(u64::MAX)).collect() };
+    match (parts(a).as_slice(), parts(b).as_slice()) {
+        ([0, a_minor], [0, b_minor]) => a_minor == b_minor,
+        ([a_major, ..], [b_major, ..]) => a_major == b_major,
+        _ => false,
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let server_url = args.url.trim_end_matches('/');
     println!("=== Local AI Engine Reference Client ===");
-    println!("Connecting to {}...", SERVER_URL);
+    println!("Connecting to {}...", server_url);
 
     // 1. Health Check
-    let client = reqwest::Client::new();
-    let health_resp = client.get(format!("{}/v1/health", SERVER_URL))
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Ok(key) = std::env::var(API_KEY_VAR) {
+        let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", key.trim()))?;
+        value.set_sensitive(true);
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+    let mut builder = reqwest::Client::builder().default_headers(headers);
+    if let Some(path) = &args.ca_cert {
+        let pem = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+    if args.insecure {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    let client = builder.build()?;
+    let health_resp = client.get(format!("{}/v1/health", server_url))
         .send()
         .await;
 
@@ -41,6 +428,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
             if resp.status().is_success() {
                 let health_json: HealthResponse = resp.json().await?;
                 println!("Server OK: v{}", health_json.version);
+                let ours = env!("CARGO_PKG_VERSION");
+                if !is_compatible(ours, &health_json.version) {
+                    println!("Warning: this client is v{} and may not work with a v{} server", ours, health_json.version);
+                }
             } else {
                 println!("Server returned status: {}", resp.status());
                 return Ok(())
@@ -53,88 +444,212 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    println!("\nType your prompt. Special commands:");
+    // 2. Wait for the model to finish loading
+    loop {
+        let ready: ReadyResponse = client.get(format!("{}/v1/ready", server_url)).send().await?.json().await?;
+        match ready.state.as_str() {
+            "ready" => break,
+            "loading" => println!("Model loading ({:.0}%)...", ready.progress.unwrap_or(0.0) * 100.0),
+            "failed" => {
+                println!("Model failed to load: {}", ready.error.unwrap_or_default());
+                return Ok(())
+            }
+            other => println!("Model {}, waiting...", other.replace('_', " ")),
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+
+    println!("\nType your prompt. For one of several lines, end each line but the last with \\,");
+    println!("or put it between lines of \"\"\". Special commands:");
+    println!("  /paste       Paste a prompt of several lines; end it with a lone . or Ctrl-D");
+    println!("  /edit        Write the prompt in $EDITOR and send it when you save and quit");
     println!("  /limit <n>   Set max tokens (default 128)");
     println!("  /temp <n>    Set temperature (default 0.0)");
+    println!("  /nostream    Toggle streaming; off prints the whole JSON response");
+    println!("  /save <file> Save the conversation, as Markdown if the name ends in .md, JSON otherwise");
+    println!("  /load <file> Continue a conversation saved as JSON");
+    println!("  /session new [system] | list | use <id> | off");
+    println!("               Keep the conversation on the server, so each prompt continues it");
+    println!("  /usage       Tokens and time used by the conversation so far");
+    println!("  /mem list | set <key> <value> | del <key> | summary <text> | clear");
+    println!("               Look at and change the server's memory");
+    println!("  /mem show [prompt]");
+    println!("               The memory the server adds ahead of the prompt (default: the last one)");
     println!("  /exit        Quit");
 
-    // 2. REPL
+    // 3. REPL
     let mut rl = DefaultEditor::new()?;
+    let history = args.history.clone()
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache/cela/ref-client-history")));
+    if let Some(path) = history.as_ref().filter(|path| path.exists()) {
+        if let Err(e) = rl.load_history(path) {
+            println!("Failed to read the history from {}: {}", path.display(), e);
+        }
+    }
+    let mut transcript = Transcript::default();
+    if let Some(path) = args.autosave.as_ref().filter(|path| path.exists()) {
+        match restore(&client, server_url, path).await {
+            Ok(restored) => transcript = restored,
+            Err(e) => println!("Not continuing from {}: {}", path.display(), e),
+        }
+    }
     let mut current_max_tokens = 128;
     let mut current_temp = 0.0;
+    let mut stream = true;
+    let mut last_prompt: Option<String> = None;
+
+    let mut input = Collector::default();
 
     loop {
-        let readline = rl.readline(">>");
-        match readline {
-            Ok(line) => {
-                let line = line.trim();
-                if line.is_empty() {
+        let entry = match rl.readline(input.prompt()) {
+            Ok(line) if !input.is_collecting() && line.trim() == "/paste" => {
+                input.start_paste();
+                println!("Paste the prompt, then end it with a line holding only . or with Ctrl-D");
+                continue;
+            }
+            Ok(line) => input.push(&line),
+            Err(ReadlineError::Eof) if input.is_collecting() => input.finish(),
+            Err(ReadlineError::Interrupted) if input.is_collecting() => {
+                input.discard();
+                println!("Discarded the prompt");
+                continue;
+            }
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
+                println!("Exiting...");
+                break;
+            },
+            Err(err) => {
+                println!("Error: {:?}", err);
+                break;
+            }
+        };
+        let (entry, multi_line) = match entry {
+            Some(Input::Line(line)) if line.trim() == "/edit" => match input::edit() {
+                Ok(Some(text)) => (text, true),
+                Ok(None) => {
+                    println!("Nothing to send");
                     continue;
                 }
-                
-                rl.add_history_entry(line)?;
-
-                if line.starts_with("/exit") {
-                    break;
-                } else if line.starts_with("/limit ") {
-                    if let Ok(n) = line.trim_start_matches("/limit ").trim().parse::<u32>() {
-                        current_max_tokens = n;
-                        println!("Max tokens set to {}", n);
-                    } else {
-                        println!("Invalid number");
-                    }
-                    continue;
-                } else if line.starts_with("/temp ") {
-                    if let Ok(n) = line.trim_start_matches("/temp ").trim().parse::<f32>() {
-                        current_temp = n;
-                        println!("Temperature set to {}", n);
-                    } else {
-                        println!("Invalid number");
-                    }
+                Err(e) => {
+                    println!("{}", e);
                     continue;
                 }
+            },
+            Some(Input::Line(line)) => (line, false),
+            Some(Input::Block(text)) => (text, true),
+            None => continue,
+        };
+        let line = entry.trim();
+        if line.is_empty() {
+            continue;
+        }
+        // Prompts of several lines go out and into the history as they were entered
+        let prompt = if multi_line { entry.as_str() } else { line };
+        rl.add_history_entry(prompt)?;
 
-                // 3. Send Request
-                println!("Sending request...");
-                let req = CompletionRequest {
-                    prompt: line.to_string(),
-                    limits: Some(RequestLimits {
-                        max_tokens: Some(current_max_tokens),
-                        max_time_ms: None,
-                        temperature: Some(current_temp),
-                    }),
-                };
-
-                let resp = client.post(format!("{}/v1/completion", SERVER_URL))
-                    .json(&req)
-                    .send()
-                    .await;
-
-                match resp {
-                    Ok(r) => {
-                        let json_body: serde_json::Value = r.json().await?;
-                        // Pretty print the JSON contract
-                        println!("{}", serde_json::to_string_pretty(&json_body)?);
-                        
-                        // Extract text for convenience
-                        if let Some(text) = json_body.get("output").and_then(|o| o.get("text")).and_then(|t| t.as_str()) {
-                            println!("\n--- Parsed Output ---\n{}
----------------------", text);
+        // Commands are single lines
+        if !multi_line {
+            if line.starts_with("/exit") {
+                break;
+            } else if line.starts_with("/limit ") {
+                if let Ok(n) = line.trim_start_matches("/limit ").trim().parse::<u32>() {
+                    current_max_tokens = n;
+                    println!("Max tokens set to {}", n);
+                } else {
+                    println!("Invalid number");
+                }
+                continue;
+            } else if line.starts_with("/temp ") {
+                if let Ok(n) = line.trim_start_matches("/temp ").trim().parse::<f32>() {
+                    current_temp = n;
+                    println!("Temperature set to {}", n);
+                } else {
+                    println!("Invalid number");
+                }
+                continue;
+            } else if line == "/nostream" {
+                stream = !stream;
+                println!("Streaming {}", if stream { "on" } else { "off: responses are printed as JSON" });
+                continue;
+            } else if let Some(file) = line.strip_prefix("/save ") {
+                match transcript.save(Path::new(file.trim())) {
+                    Ok(()) => println!("Saved {} turns to {}", transcript.turns.len(), file.trim()),
+                    Err(e) => println!("{}", e),
+                }
+                continue;
+            } else if let Some(file) = line.strip_prefix("/load ") {
+                match restore(&client, server_url, Path::new(file.trim())).await {
+                    Ok(restored) => transcript = restored,
+                    Err(e) => println!("{}", e),
+                }
+                continue;
+            } else if let Some(args) = line.strip_prefix("/session") {
+                if let Err(e) = session_command(&client, server_url, args.trim(), &mut transcript).await {
+                    println!("{}", e);
+                }
+                continue;
+            } else if line == "/usage" {
+                print_usage(&transcript);
+                continue;
+            } else if let Some(args) = line.strip_prefix("/mem") {
+                if let Err(e) = mem_command(&client, server_url, args.trim(), last_prompt.as_deref()).await {
+                    println!("{}", e);
+                }
+                continue;
+            }
+        }
+
+        // 3. Send Request
+        last_prompt = Some(prompt.to_string());
+        let req = CompletionRequest {
+            prompt: prompt.to_string(),
+            limits: Some(RequestLimits {
+                max_tokens: Some(current_max_tokens),
+                max_time_ms: None,
+                temperature: Some(current_temp),
+            }),
+            stream,
+        };
+
+        // Dropping the request closes the connection, which cancels it on the server
+        let started = std::time::Instant::now();
+        let request = async {
+            match &transcript.session {
+                Some(session) => send_to_session(&client, server_url, session, &req).await,
+                None => complete(&client, server_url, &req).await,
+            }
+        };
+        let response = tokio::select! {
+            result = request => result,
+            _ = tokio::signal::ctrl_c() => {
+                println!("\nCancelled");
+                continue;
+            }
+        };
+        match response {
+            Ok(Some(body)) => {
+                if let Some(turn) = turn(prompt, &body, started.elapsed().as_millis() as u64) {
+                    transcript.turns.push(turn);
+                    if let Some(path) = &args.autosave {
+                        if let Err(e) = transcript.save(path) {
+                            println!("{}", e);
                         }
                     }
-                    Err(e) => println!("Request failed: {}", e),
                 }
-            },
-            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
-                println!("Exiting...");
-                break;
-            },
-            Err(err) => {
-                println!("Error: {:?}", err);
-                break;
             }
+            Ok(None) => {}
+            Err(e) => println!("Request failed: {}", e),
         }
     }
 
+    if let Some(path) = &history {
+        let saved = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => std::fs::create_dir_all(dir).map_err(|e| e.to_string()),
+            _ => Ok(()),
+        }.and_then(|()| rl.save_history(path).map_err(|e| e.to_string()));
+        if let Err(e) = saved {
+            println!("Failed to save the history to {}: {}", path.display(), e);
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,66 @@
+//! Reads the server-sent events `/v1/completion` answers with when asked to `stream`.
+
+#[derive(Debug, PartialEq)]
+pub struct Event {
+    /// The `event:` field; `message` when there is none.
+    pub name: String,
+    /// The `data:` lines, joined with newlines.
+    pub data: String,
+}
+
+/// Collects the chunks of an event stream, however they are split, into events.
+#[derive(Default)]
+pub struct EventReader {
+    buffer: Vec<u8>,
+}
+
+impl EventReader {
+    /// Add `chunk` and return the events it completed.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Event> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+            let block: Vec<u8> = self.buffer.drain(..end + 2).collect();
+            if let Some(event) = parse(&String::from_utf8_lossy(&block)) {
+                events.push(event);
+            }
+        }
+        events
+    }
+}
+
+fn parse(block: &str) -> Option<Event> {
+    let mut name = None;
+    let mut data: Vec<&str> = Vec::new();
+    for line in block.lines() {
+        // Lines starting with a colon are comments, e.g. keep-alives
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => name = Some(value.to_string()),
+            "data" => data.push(value),
+            _ => {}
+        }
+    }
+    if name.is_none() && data.is_empty() {
+        return None;
+    }
+    Some(Event { name: name.unwrap_or_else(|| "message".to_string()), data: data.join("\n") })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_split_across_chunks() {
+        let mut reader = EventReader::default();
+        assert!(reader.push(b"event: token\ndata: {\"text\":\"Hel").is_empty());
+        let events = reader.push("lo ü\"}\n\n: keep-alive\n\nevent: done\ndata: a\ndata: b\n\ndata: x".as_bytes());
+        assert_eq!(events, [
+            Event { name: "token".to_string(), data: "{\"text\":\"Hello ü\"}".to_string() },
+            Event { name: "done".to_string(), data: "a\nb".to_string() },
+        ]);
+        assert_eq!(reader.push(b"\n\n"), [Event { name: "message".to_string(), data: "x".to_string() }]);
+    }
+}
@@ -0,0 +1,118 @@
+//! The conversation so far, for `/save`, `/load`, `/usage` and the autosave file.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Transcript {
+    /// The server session the conversation continues in, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    pub turns: Vec<Turn>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Turn {
+    pub prompt: String,
+    pub response: String,
+    /// Zero for turns taken over from a server session, which doesn't keep usage.
+    #[serde(default)]
+    pub input_tokens: u64,
+    #[serde(default)]
+    pub output_tokens: u64,
+    /// Time from sending the prompt to having the whole response, as the client saw it.
+    #[serde(default)]
+    pub wall_ms: u64,
+}
+
+/// What the turns of a transcript add up to.
+#[derive(Debug, Default, PartialEq)]
+pub struct Usage {
+    pub turns: usize,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub wall_ms: u64,
+}
+
+impl Transcript {
+    /// Read a transcript saved as JSON. Markdown is for reading only.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if is_markdown(path) {
+            anyhow::bail!("Only JSON transcripts can be loaded; save with a .json name to load it later");
+        }
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("{} isn't a saved transcript: {}", path.display(), e))
+    }
+
+    /// Write the transcript as Markdown if `path` ends in `.md`, as JSON otherwise.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let content = if is_markdown(path) { self.markdown() } else { serde_json::to_string_pretty(self)? };
+        std::fs::write(path, content).map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))
+    }
+
+    pub fn markdown(&self) -> String {
+        let mut out = String::from("# Conversation\n");
+        if let Some(session) = &self.session {
+            out.push_str(&format!("\nSession `{}`\n", session));
+        }
+        for turn in &self.turns {
+            out.push_str(&format!("\n## You\n\n{}\n\n## Assistant\n\n{}\n", turn.prompt, turn.response));
+            if turn.wall_ms > 0 {
+                out.push_str(&format!(
+                    "\n_{} prompt + {} generated tokens, {:.1} s_\n",
+                    turn.input_tokens, turn.output_tokens, turn.wall_ms as f64 / 1000.0
+                ));
+            }
+        }
+        out
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.turns.iter().fold(Usage::default(), |usage, turn| Usage {
+            turns: usage.turns + 1,
+            input_tokens: usage.input_tokens + turn.input_tokens,
+            output_tokens: usage.output_tokens + turn.output_tokens,
+            wall_ms: usage.wall_ms + turn.wall_ms,
+        })
+    }
+}
+
+fn is_markdown(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcript() -> Transcript {
+        Transcript {
+            session: Some("3f9c0a7d5e21b648".to_string()),
+            turns: vec![
+                Turn { prompt: "Hi".to_string(), response: "Hello!".to_string(), input_tokens: 3, output_tokens: 2, wall_ms: 1500 },
+                Turn { prompt: "Bye".to_string(), response: "Bye.".to_string(), input_tokens: 0, output_tokens: 0, wall_ms: 0 },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.json");
+        transcript().save(&path).unwrap();
+        assert_eq!(Transcript::load(&path).unwrap(), transcript());
+
+        let markdown = dir.path().join("chat.md");
+        transcript().save(&markdown).unwrap();
+        let content = std::fs::read_to_string(&markdown).unwrap();
+        assert!(content.contains("## You\n\nHi\n\n## Assistant\n\nHello!\n\n_3 prompt + 2 generated tokens, 1.5 s_\n"));
+        assert!(Transcript::load(&markdown).is_err());
+    }
+
+    #[test]
+    fn test_usage() {
+        assert_eq!(transcript().usage(), Usage { turns: 2, input_tokens: 3, output_tokens: 2, wall_ms: 1500 });
+    }
+}
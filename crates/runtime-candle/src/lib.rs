@@ -0,0 +1,258 @@
+mod model;
+
+use async_trait::async_trait;
+use candle_core::Device;
+use lie_core::chat::ChatTemplate;
+use lie_core::error::EngineError;
+use lie_core::runtime::{InferenceOptions, InferenceResult, KvCacheType, LoadReport, ModelInfo, ModelLoadConfig, ModelRuntime, TokenChunk};
+use model::{read_tokenizer_config, special_token, Model, Weights};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Runs llama-architecture models from Hugging Face files (`config.json`, `tokenizer.json`
+/// and `*.safetensors` in one directory) with candle, in pure Rust.
+#[derive(Default)]
+pub struct CandleRuntime {
+    /// `None` until `load` succeeds. Shared with the blocking threads generating with it.
+    model: Option<Arc<Model>>,
+    chat_template: Option<ChatTemplate>,
+    model_info: Option<ModelInfo>,
+}
+
+impl CandleRuntime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn model(&self) -> Result<&Arc<Model>, EngineError> {
+        self.model.as_ref().ok_or(EngineError::ModelNotLoaded)
+    }
+
+    /// Generate on the blocking pool, since a forward pass takes as long as it takes.
+    async fn generate(&self, prompt: &str, options: InferenceOptions, tx: Option<mpsc::UnboundedSender<TokenChunk>>) -> Result<InferenceResult, EngineError> {
+        let model = self.model()?.clone();
+        let prompt = prompt.to_string();
+        let result = tokio::task::spawn_blocking(move || model.generate(&prompt, &options, tx.as_ref()))
+            .await
+            .map_err(|e| EngineError::Runtime(format!("Inference task failed: {}", e)))??;
+        record_usage(&result);
+        Ok(result)
+    }
+}
+
+/// Fill in the token counts of the current `infer`/`infer_stream` span.
+fn record_usage(result: &InferenceResult) {
+    let span = tracing::Span::current();
+    span.record("input_tokens", result.usage.input_tokens);
+    span.record("output_tokens", result.usage.output_tokens);
+}
+
+/// The GPU backends compiled into candle, as Cargo features of this crate.
+pub fn build_features() -> Vec<String> {
+    let features = [("cuda", cfg!(feature = "cuda")), ("metal", cfg!(feature = "metal"))];
+    features.into_iter().filter(|(_, enabled)| *enabled).map(|(name, _)| name.to_string()).collect()
+}
+
+/// `candle` and the device it runs models on when asked to offload, e.g. `candle (CPU, CUDA)`.
+pub fn backend_description() -> String {
+    if candle_core::utils::cuda_is_available() {
+        "candle (CPU, CUDA)".to_string()
+    } else if candle_core::utils::metal_is_available() {
+        "candle (CPU, Metal)".to_string()
+    } else {
+        "candle (CPU)".to_string()
+    }
+}
+
+/// Check that `path` is a directory with the files a model is loaded from, so a wrong path
+/// gets a clear error instead of a load failure.
+pub fn check_model_dir(path: &Path) -> Result<(), EngineError> {
+    if !path.exists() {
+        return Err(EngineError::Config(format!("Model directory {} does not exist", path.display())));
+    }
+    if !path.is_dir() {
+        return Err(EngineError::Config(format!(
+            "Model path {} is not a directory (expected config.json, tokenizer.json and .safetensors files)", path.display()
+        )));
+    }
+    for file in ["config.json", "tokenizer.json"] {
+        if !path.join(file).is_file() {
+            return Err(EngineError::Config(format!("Model directory {} has no {}", path.display(), file)));
+        }
+    }
+    Ok(())
+}
+
+/// The device to run on. candle keeps a whole model on one device, so any `gpu_layers`
+/// moves all of it to the GPU.
+fn device(gpu_layers: usize) -> Result<Device, EngineError> {
+    if gpu_layers == 0 {
+        return Ok(Device::Cpu);
+    }
+    let device = if cfg!(feature = "cuda") {
+        Device::new_cuda(0)
+    } else if cfg!(feature = "metal") {
+        Device::new_metal(0)
+    } else {
+        tracing::warn!("gpu_layers = {} requested but this build has no GPU backend; running on CPU", gpu_layers);
+        return Ok(Device::Cpu);
+    };
+    device.map_err(|e| EngineError::Runtime(format!("Failed to open the GPU: {}", e)))
+}
+
+/// The `chat_template` of `tokenizer_config.json`, if present, paired with its EOS text.
+/// Templates given as a list by name use the one named `default`.
+fn read_chat_template(dir: &Path) -> Option<ChatTemplate> {
+    let tokenizer_config = read_tokenizer_config(dir)?;
+    let template = tokenizer_config.get("chat_template")?;
+    let source = template.as_str().or_else(|| {
+        template.as_array()?.iter()
+            .find(|named| named.get("name").and_then(|name| name.as_str()) == Some("default"))?
+            .get("template")?
+            .as_str()
+    })?;
+    let eos_token = special_token(&tokenizer_config, "eos_token").unwrap_or_default();
+    Some(ChatTemplate::Jinja { source: source.to_string(), eos_token })
+}
+
+#[async_trait]
+impl ModelRuntime for CandleRuntime {
+    /// `batch_size` doesn't apply: candle evaluates a prompt in one pass. Threads are those
+    /// of candle's global pool (`RAYON_NUM_THREADS`) and the KV cache has the model's
+    /// precision; `model_info` reports what is used rather than what was asked for.
+    #[tracing::instrument(skip_all, fields(path = %config.model_path.display(), context_size = config.context_size))]
+    async fn load(&mut self, config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+        if config.context_size == 0 {
+            return Err(EngineError::Config(format!("Invalid context size: {}", config.context_size)));
+        }
+        check_model_dir(&config.model_path)?;
+        if config.use_mlock {
+            tracing::warn!("use_mlock = true requested but the candle runtime can't lock model memory; ignoring it");
+        }
+        if config.kv_cache_type != KvCacheType::F16 {
+            tracing::warn!(kv_cache_type = ?config.kv_cache_type, "The candle runtime can't quantize the KV cache; keeping it at the model's precision");
+        }
+
+        let device = device(config.gpu_layers)?;
+        let on_gpu = !device.is_cpu();
+        let dir = config.model_path.clone();
+        let (context_size, use_mmap) = (config.context_size, config.use_mmap);
+        // Reading the weights takes seconds; keep it off the async executor
+        let (model, weights) = tokio::task::spawn_blocking(move || {
+            let weights = Weights::read(&dir)?;
+            let model = Model::load(&dir, &weights.files, device, context_size, use_mmap)?;
+            Ok::<_, EngineError>((model, weights))
+        })
+        .await
+        .map_err(|e| EngineError::Runtime(format!("Model loading task failed: {}", e)))??;
+
+        let model_config = model.config();
+        // Counted as llama.cpp does, with the output layer as one more
+        let gpu_layers_offloaded = if on_gpu { model_config.num_hidden_layers + 1 } else { 0 };
+        let n_threads = candle_core::utils::get_num_threads();
+        let name = config.model_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        self.model_info = Some(ModelInfo {
+            name,
+            architecture: "llama".to_string(),
+            parameter_count: weights.parameter_count,
+            quantization: weights.dtype,
+            native_context_size: u32::try_from(model_config.max_position_embeddings).unwrap_or(u32::MAX),
+            vocab_size: u32::try_from(model_config.vocab_size).unwrap_or(0),
+            file_size: weights.files.iter().filter_map(|path| std::fs::metadata(path).ok()).map(|m| m.len()).sum(),
+            path: config.model_path.clone(),
+            context_size: u32::try_from(context_size).unwrap_or(u32::MAX),
+            gpu_layers: gpu_layers_offloaded,
+            n_threads,
+            n_threads_batch: n_threads,
+            use_mmap,
+            use_mlock: false,
+            kv_cache_type: KvCacheType::F16,
+            loaded_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        });
+        self.chat_template = read_chat_template(&config.model_path);
+        self.model = Some(Arc::new(model));
+        Ok(LoadReport {
+            gpu_layers_requested: config.gpu_layers,
+            gpu_layers_offloaded,
+        })
+    }
+
+    #[tracing::instrument(skip_all, fields(input_tokens, output_tokens))]
+    async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+        self.generate(prompt, options, None).await
+    }
+
+    #[tracing::instrument(skip_all, fields(input_tokens, output_tokens))]
+    async fn infer_stream(&mut self, prompt: &str, options: InferenceOptions, tx: mpsc::UnboundedSender<TokenChunk>) -> Result<InferenceResult, EngineError> {
+        let result = self.generate(prompt, options, Some(tx.clone())).await?;
+        let _ = tx.send(TokenChunk::done(&result));
+        Ok(result)
+    }
+
+    async fn unload(&mut self) -> Result<(), EngineError> {
+        self.model = None;
+        self.chat_template = None;
+        self.model_info = None;
+        Ok(())
+    }
+
+    fn tokenize(&self, text: &str, add_bos: bool) -> Result<Vec<i32>, EngineError> {
+        let tokens = self.model()?.tokenize(text, add_bos)?;
+        Ok(tokens.into_iter().map(|token| token as i32).collect())
+    }
+
+    fn token_pieces(&self, tokens: &[i32]) -> Result<Vec<String>, EngineError> {
+        let model = self.model()?;
+        tokens.iter().map(|&token| model.decode(&[token_id(token)?])).collect()
+    }
+
+    fn detokenize(&self, tokens: &[i32]) -> Result<String, EngineError> {
+        let tokens = tokens.iter().map(|&token| token_id(token)).collect::<Result<Vec<_>, _>>()?;
+        self.model()?.decode(&tokens)
+    }
+
+    fn model_info(&self) -> Option<ModelInfo> {
+        self.model_info.clone()
+    }
+
+    fn chat_template(&self) -> Option<ChatTemplate> {
+        self.chat_template.clone()
+    }
+}
+
+fn token_id(token: i32) -> Result<u32, EngineError> {
+    u32::try_from(token).map_err(|_| EngineError::Runtime(format!("Invalid token id: {}", token)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_model_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check_model_dir(&dir.path().join("missing")).unwrap_err().to_string().contains("does not exist"));
+        assert!(check_model_dir(dir.path()).unwrap_err().to_string().contains("has no config.json"));
+
+        std::fs::write(dir.path().join("config.json"), "{}").unwrap();
+        std::fs::write(dir.path().join("tokenizer.json"), "{}").unwrap();
+        check_model_dir(dir.path()).unwrap();
+        assert!(check_model_dir(&dir.path().join("config.json")).unwrap_err().to_string().contains("is not a directory"));
+    }
+
+    #[test]
+    fn test_read_chat_template() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read_chat_template(dir.path()), None);
+
+        let config = r#"{"chat_template": "{{ messages }}", "eos_token": {"content": "</s>"}}"#;
+        std::fs::write(dir.path().join("tokenizer_config.json"), config).unwrap();
+        assert_eq!(read_chat_template(dir.path()), Some(ChatTemplate::Jinja { source: "{{ messages }}".to_string(), eos_token: "</s>".to_string() }));
+
+        let config = r#"{"chat_template": [{"name": "tool_use", "template": "t"}, {"name": "default", "template": "d"}], "eos_token": "<|end|>"}"#;
+        std::fs::write(dir.path().join("tokenizer_config.json"), config).unwrap();
+        assert_eq!(read_chat_template(dir.path()), Some(ChatTemplate::Jinja { source: "d".to_string(), eos_token: "<|end|>".to_string() }));
+    }
+}
@@ -0,0 +1,351 @@
+use candle_core::{DType, Device, Tensor};
+use candle_core::safetensors::MmapedSafetensors;
+use candle_nn::VarBuilder;
+use candle_transformers::models::llama::{Cache, Config, Llama, LlamaConfig, LlamaEosToks};
+use lie_core::error::EngineError;
+use lie_core::runtime::{time_is_up, tokens_per_second, FinishReason, InferenceOptions, InferenceResult, Logprobs, TokenChunk, TokenLogprob, TopLogprob, Usage, MAX_LOGPROBS};
+use lie_core::sampling::{self, Candidate, Sampler};
+use lie_core::stop::{stream_text, StopSequences};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokenizers::Tokenizer;
+use tokio::sync::mpsc;
+
+/// A llama-architecture model loaded from a directory of Hugging Face files, with its
+/// tokenizer. Every generation gets a KV cache of its own; nothing is cached between them.
+pub(crate) struct Model {
+    llama: Llama,
+    config: Config,
+    tokenizer: Tokenizer,
+    device: Device,
+    dtype: DType,
+    bos: Option<u32>,
+    /// Tokens that end generation: the config's `eos_token_id` and the tokenizer's `eos_token`.
+    eos: Vec<u32>,
+    context_size: usize,
+}
+
+/// What the weight files hold, for `ModelInfo`.
+pub(crate) struct Weights {
+    pub files: Vec<PathBuf>,
+    pub parameter_count: u64,
+    /// Data type of the stored weights, e.g. `BF16`.
+    pub dtype: String,
+}
+
+impl Weights {
+    pub fn read(dir: &Path) -> Result<Self, EngineError> {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+            .map_err(|e| EngineError::Config(format!("Failed to read model directory {}: {}", dir.display(), e)))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("safetensors")))
+            .collect();
+        if files.is_empty() {
+            return Err(EngineError::Config(format!("Model directory {} has no .safetensors files", dir.display())));
+        }
+        files.sort();
+        // Only the headers are read here
+        // SAFETY: the files are only read, and the model is loaded from them right after
+        let tensors = unsafe { MmapedSafetensors::multi(&files) }
+            .map_err(|e| EngineError::Runtime(format!("Failed to read the weights: {}", e)))?;
+        let views = tensors.tensors();
+        let parameter_count = views.iter().map(|(_, view)| view.shape().iter().product::<usize>() as u64).sum();
+        let dtype = views.first().map_or_else(|| "unknown".to_string(), |(_, view)| format!("{:?}", view.dtype()));
+        Ok(Self { files, parameter_count, dtype })
+    }
+}
+
+impl Model {
+    /// Load `config.json`, `tokenizer.json` and the weights from `dir` onto `device`. Takes
+    /// seconds, so it runs on a blocking thread.
+    pub fn load(dir: &Path, weights: &[PathBuf], device: Device, context_size: usize, use_mmap: bool) -> Result<Self, EngineError> {
+        let config_path = dir.join("config.json");
+        let config = std::fs::read_to_string(&config_path)
+            .map_err(|e| EngineError::Config(format!("Failed to read {}: {}", config_path.display(), e)))?;
+        let config: LlamaConfig = serde_json::from_str(&config)
+            .map_err(|e| EngineError::Config(format!("{} is not a llama model config: {}", config_path.display(), e)))?;
+        let config = config.into_config(false);
+        if context_size > config.max_position_embeddings {
+            return Err(EngineError::Config(format!(
+                "Context size ({}) exceeds the model's trained context ({})", context_size, config.max_position_embeddings
+            )));
+        }
+
+        let tokenizer_path = dir.join("tokenizer.json");
+        let tokenizer = Tokenizer::from_file(&tokenizer_path)
+            .map_err(|e| EngineError::Config(format!("Failed to load {}: {}", tokenizer_path.display(), e)))?;
+
+        // Half precision only pays off on a GPU; candle's CPU kernels are fastest in F32
+        let dtype = if device.is_cpu() { DType::F32 } else { DType::F16 };
+        let vars = if use_mmap {
+            // SAFETY: the weight files must not change while the model is loaded, as with llama.cpp
+            unsafe { VarBuilder::from_mmaped_safetensors(weights, dtype, &device) }
+        } else {
+            weights.iter()
+                .map(|path| candle_core::safetensors::load(path, &device))
+                .collect::<Result<Vec<_>, _>>()
+                .map(|files| VarBuilder::from_tensors(files.into_iter().flatten().collect(), dtype, &device))
+        };
+        let vars = vars.map_err(|e| EngineError::Runtime(format!("Failed to read the weights: {}", e)))?;
+        let llama = Llama::load(vars, &config)
+            .map_err(|e| EngineError::Runtime(format!("Failed to load model: {}", e)))?;
+
+        let mut eos = match &config.eos_token_id {
+            Some(LlamaEosToks::Single(id)) => vec![*id],
+            Some(LlamaEosToks::Multiple(ids)) => ids.clone(),
+            None => Vec::new(),
+        };
+        let eos_token = read_tokenizer_config(dir).and_then(|tokenizer_config| special_token(&tokenizer_config, "eos_token"));
+        if let Some(id) = eos_token.and_then(|token| tokenizer.token_to_id(&token)) {
+            if !eos.contains(&id) {
+                eos.push(id);
+            }
+        }
+
+        Ok(Self { llama, bos: config.bos_token_id, config, tokenizer, device, dtype, eos, context_size })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Prompts start with the BOS token when `add_bos` is set and the model has one.
+    pub fn tokenize(&self, text: &str, add_bos: bool) -> Result<Vec<u32>, EngineError> {
+        let encoding = self.tokenizer.encode(text, false)
+            .map_err(|e| EngineError::Runtime(format!("Tokenization failed: {}", e)))?;
+        let bos = self.bos.filter(|_| add_bos);
+        Ok(bos.into_iter().chain(encoding.get_ids().iter().copied()).collect())
+    }
+
+    pub fn decode(&self, tokens: &[u32]) -> Result<String, EngineError> {
+        let vocab_size = self.tokenizer.get_vocab_size(true);
+        if let Some(token) = tokens.iter().find(|&&token| token as usize >= vocab_size) {
+            return Err(EngineError::Runtime(format!("Invalid token id: {}", token)));
+        }
+        self.tokenizer.decode(tokens, false)
+            .map_err(|e| EngineError::Runtime(format!("Detokenization failed: {}", e)))
+    }
+
+    /// Generated text, without special tokens.
+    fn text(&self, tokens: &[u32]) -> Result<String, EngineError> {
+        self.tokenizer.decode(tokens, true)
+            .map_err(|e| EngineError::Runtime(format!("Detokenization failed: {}", e)))
+    }
+
+    /// The logits of the token following `tokens`, which go at `pos` after what `cache` holds.
+    fn forward(&self, tokens: &[u32], pos: usize, cache: &mut Cache) -> Result<Vec<f32>, EngineError> {
+        let logits = Tensor::new(tokens, &self.device)
+            .and_then(|input| input.unsqueeze(0))
+            .and_then(|input| self.llama.forward(&input, pos, cache))
+            .and_then(|logits| logits.squeeze(0))
+            .and_then(|logits| logits.to_vec1::<f32>())
+            .map_err(|e| EngineError::Runtime(format!("Decode failed: {}", e)))?;
+        Ok(logits)
+    }
+
+    /// The sampler for a generation following `prompt`.
+    fn sampler(&self, options: &InferenceOptions, seed: u64, prompt: &[u32]) -> Result<Sampler, EngineError> {
+        let logit_bias = sampling::token_bias(&options.logit_bias, |text| {
+            self.tokenize(text, false).map(|tokens| tokens.into_iter().map(|token| token as i32).collect())
+        })?;
+        let mut sampler = Sampler::from_options(options, seed, logit_bias);
+        for &eos in &self.eos {
+            sampler = sampler.exempt_from_penalties(eos as i32);
+        }
+        if options.penalize_prompt {
+            for &token in prompt {
+                sampler.accept(token as i32);
+            }
+        }
+        Ok(sampler)
+    }
+
+    fn token_logprob(&self, candidates: &[Candidate], token: i32, top_n: usize) -> Result<TokenLogprob, EngineError> {
+        let (logprob, top) = sampling::logprobs(candidates, token, top_n);
+        let top_logprobs = top.into_iter()
+            .map(|(id, logprob)| {
+                let token = self.decode(&[id as u32])?;
+                Ok(TopLogprob { bytes: token.clone().into_bytes(), token, logprob })
+            })
+            .collect::<Result<_, EngineError>>()?;
+        let text = self.decode(&[token as u32])?;
+        Ok(TokenLogprob { bytes: text.clone().into_bytes(), token: text, logprob, top_logprobs })
+    }
+
+    /// Run the full tokenize/decode/sample loop, with the limits the llama.cpp runtime has.
+    /// The context isn't shifted when it fills up; generation ends with `Length`.
+    pub fn generate(&self, prompt: &str, options: &InferenceOptions, tx: Option<&mpsc::UnboundedSender<TokenChunk>>) -> Result<InferenceResult, EngineError> {
+        let start_time = Instant::now();
+        let prompt_tokens = self.tokenize(prompt, true)?;
+        if prompt_tokens.len() > self.context_size {
+            return Err(EngineError::ContextOverflow { tokens: prompt_tokens.len(), context_size: self.context_size });
+        }
+        let Some(&last_prompt_token) = prompt_tokens.last() else {
+            return Err(EngineError::Validation("The prompt is empty and the model has no BOS token".to_string()));
+        };
+        let input_tokens_count = prompt_tokens.len() as u32;
+
+        let mut cache = Cache::new(true, self.dtype, &self.config, &self.device)
+            .map_err(|e| EngineError::Runtime(format!("Failed to create the KV cache: {}", e)))?;
+        let mut finish_reason = None;
+        let prompt_eval_start = Instant::now();
+        // The whole prompt goes in one pass: candle's attention mask assumes an empty cache
+        // for more than one token at a time
+        let mut logits = Vec::new();
+        if options.cancel.is_cancelled() {
+            finish_reason = Some(FinishReason::Cancelled);
+        } else {
+            logits = self.forward(&prompt_tokens, 0, &mut cache)?;
+        }
+        let prompt_eval = prompt_eval_start.elapsed();
+
+        let mut response_tokens = Vec::new();
+        let mut output_string = String::new();
+        // Streamed text trails the output by whatever could still become a stop sequence
+        let stop_sequences = StopSequences::new(&options.stop_sequences);
+        let mut streamed = 0;
+        let max_gen_tokens = options.max_tokens.unwrap_or(128);
+        let max_time_ms = options.max_time_ms.unwrap_or(30000);
+
+        let mut current_pos = prompt_tokens.len();
+        let seed = options.seed.unwrap_or_else(sampling::random_seed);
+        let mut sampler = self.sampler(options, seed, &prompt_tokens)?;
+        let mut logprobs = Vec::new();
+        let mut text = TextStream::new(last_prompt_token);
+        let generation_start = Instant::now();
+        while finish_reason.is_none() {
+            if response_tokens.len() as u32 >= max_gen_tokens {
+                finish_reason = Some(FinishReason::Length);
+                break;
+            }
+
+            if options.cancel.is_cancelled() {
+                finish_reason = Some(FinishReason::Cancelled);
+                break;
+            }
+
+            if time_is_up(start_time, max_time_ms) {
+                finish_reason = Some(FinishReason::Time);
+                break;
+            }
+
+            if current_pos >= self.context_size {
+                finish_reason = Some(FinishReason::Length);
+                break;
+            }
+
+            let candidates: Vec<Candidate> = logits.iter()
+                .enumerate()
+                .map(|(id, &logit)| Candidate { id: id as i32, logit })
+                .collect();
+            // Greedy at temperature 0.0, the temperature/truncation chain otherwise
+            let next_token = sampler.sample(&candidates)
+                .ok_or_else(|| EngineError::Runtime("No candidates found".to_string()))?;
+
+            if self.eos.contains(&(next_token as u32)) {
+                finish_reason = Some(FinishReason::Eos);
+                break;
+            }
+
+            if let Some(top_n) = options.logprobs {
+                logprobs.push(self.token_logprob(&candidates, next_token, top_n.min(MAX_LOGPROBS) as usize)?);
+            }
+            response_tokens.push(next_token as u32);
+            sampler.accept(next_token);
+
+            output_string.push_str(&text.push(self, next_token as u32)?);
+            if let Some((at, sequence)) = stop_sequences.find(&output_string) {
+                finish_reason = Some(FinishReason::Stop { sequence: sequence.to_string() });
+                output_string.truncate(at);
+                break;
+            }
+            let safe = stop_sequences.safe_len(&output_string);
+            if !stream_text(tx, &output_string, &mut streamed, safe) {
+                // Receiver dropped: nobody is listening any more
+                finish_reason = Some(FinishReason::Cancelled);
+                break;
+            }
+
+            logits = self.forward(&[next_token as u32], current_pos, &mut cache)?;
+            current_pos += 1;
+        }
+
+        // Past a stop sequence the rest is dropped anyway
+        if !matches!(finish_reason, Some(FinishReason::Stop { .. })) {
+            output_string.push_str(&text.finish(self)?);
+        }
+        // Whatever was held back didn't turn into a stop sequence after all
+        stream_text(tx, &output_string, &mut streamed, output_string.len());
+        let generation = generation_start.elapsed();
+
+        let output_tokens_count = response_tokens.len() as u32;
+        Ok(InferenceResult {
+            text: output_string,
+            usage: Usage {
+                input_tokens: input_tokens_count,
+                output_tokens: output_tokens_count,
+                total_tokens: input_tokens_count + output_tokens_count,
+                duration_ms: start_time.elapsed().as_millis() as u64,
+                prompt_eval_ms: prompt_eval.as_millis() as u64,
+                generation_ms: generation.as_millis() as u64,
+                tokens_per_second: tokens_per_second(output_tokens_count, generation),
+                ..Usage::default()
+            },
+            finish_reason: finish_reason.expect("set before leaving the loop"),
+            seed: Some(seed),
+            logprobs: options.logprobs.map(|_| Logprobs { content: logprobs }),
+        })
+    }
+}
+
+/// Turns generated tokens into text as they come. Tokenizers decode a token differently
+/// depending on what precedes it (a leading space, a character split across byte tokens),
+/// so each piece is the difference between decoding the tokens since the last piece with
+/// and without the new ones. It starts from the last prompt token, so the first output
+/// token keeps its leading space.
+struct TextStream {
+    tokens: Vec<u32>,
+    /// Where the tokens decoded for the next piece start, and where they end.
+    prev: usize,
+    current: usize,
+}
+
+impl TextStream {
+    fn new(last_prompt_token: u32) -> Self {
+        Self { tokens: vec![last_prompt_token], prev: 0, current: 1 }
+    }
+
+    /// The text `token` adds, or nothing while it ends in an incomplete character.
+    fn push(&mut self, model: &Model, token: u32) -> Result<String, EngineError> {
+        let before = model.text(&self.tokens[self.prev..self.current])?;
+        self.tokens.push(token);
+        let after = model.text(&self.tokens[self.prev..])?;
+        match after.get(before.len()..) {
+            Some(piece) if !piece.is_empty() && !piece.ends_with('\u{FFFD}') => {
+                self.prev = self.current;
+                self.current = self.tokens.len();
+                Ok(piece.to_string())
+            }
+            _ => Ok(String::new()),
+        }
+    }
+
+    /// Whatever is still held back, incomplete characters included.
+    fn finish(self, model: &Model) -> Result<String, EngineError> {
+        let before = model.text(&self.tokens[self.prev..self.current])?;
+        let after = model.text(&self.tokens[self.prev..])?;
+        Ok(after.get(before.len()..).unwrap_or_default().to_string())
+    }
+}
+
+/// `tokenizer_config.json`, if the model directory has one.
+pub(crate) fn read_tokenizer_config(dir: &Path) -> Option<serde_json::Value> {
+    let content = std::fs::read_to_string(dir.join("tokenizer_config.json")).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// A special token of `tokenizer_config.json`, given as text or as `{"content": text}`.
+pub(crate) fn special_token(tokenizer_config: &serde_json::Value, key: &str) -> Option<String> {
+    let token = tokenizer_config.get(key)?;
+    token.as_str().or_else(|| token.get("content")?.as_str()).map(str::to_string)
+}
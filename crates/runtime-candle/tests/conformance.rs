@@ -0,0 +1,99 @@
+//! The shared `ModelRuntime` checks, against a tiny llama model with random weights made
+//! for the test, or against a real model directory if `CELA_TEST_CANDLE_MODEL` points at one.
+
+use candle_core::{DType, Device};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::llama::{Llama, LlamaConfig};
+use lie_core::runtime::{conformance, FinishReason, InferenceOptions, KvCacheType, ModelLoadConfig, ModelRuntime};
+use lie_runtime_candle::CandleRuntime;
+use std::path::{Path, PathBuf};
+use tokenizers::models::wordlevel::WordLevel;
+use tokenizers::pre_tokenizers::whitespace::Whitespace;
+use tokenizers::Tokenizer;
+
+const WORDS: &[&str] = &["<unk>", "<s>", "</s>", "the", "capital", "of", "france", "is", "paris", "a", "city", "and", "river", "big", "old", "new"];
+
+/// Write a two-layer llama with random weights and a word-level tokenizer to `dir`.
+fn write_tiny_model(dir: &Path) {
+    let config = serde_json::json!({
+        "hidden_size": 32,
+        "intermediate_size": 64,
+        "vocab_size": WORDS.len(),
+        "num_hidden_layers": 2,
+        "num_attention_heads": 4,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-5,
+        "bos_token_id": 1,
+        "eos_token_id": 2,
+        "max_position_embeddings": 128,
+    });
+    std::fs::write(dir.join("config.json"), config.to_string()).unwrap();
+
+    let llama_config: LlamaConfig = serde_json::from_value(config).unwrap();
+    let vars = VarMap::new();
+    Llama::load(VarBuilder::from_varmap(&vars, DType::F32, &Device::Cpu), &llama_config.into_config(false)).unwrap();
+    vars.save(dir.join("model.safetensors")).unwrap();
+
+    let vocab = WORDS.iter().enumerate().map(|(id, word)| (word.to_string(), id as u32)).collect();
+    let model = WordLevel::builder().vocab(vocab).unk_token("<unk>".to_string()).build().unwrap();
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+    tokenizer.save(dir.join("tokenizer.json"), false).unwrap();
+}
+
+fn load_config(model_path: PathBuf, context_size: usize) -> ModelLoadConfig {
+    ModelLoadConfig {
+        model_path,
+        context_size,
+        gpu_layers: 0,
+        batch_size: 512,
+        n_threads: None,
+        n_threads_batch: None,
+        use_mmap: true,
+        use_mlock: false,
+        kv_cache_type: KvCacheType::F16,
+    }
+}
+
+#[tokio::test]
+async fn test_conformance() {
+    let dir = tempfile::tempdir().unwrap();
+    write_tiny_model(dir.path());
+    let mut runtime = CandleRuntime::new();
+    runtime.load(&load_config(dir.path().to_path_buf(), 64)).await.unwrap();
+    conformance::check_all(&mut runtime, "the capital of france is").await;
+
+    let Some(model_path) = std::env::var_os("CELA_TEST_CANDLE_MODEL").map(PathBuf::from) else { return };
+    let mut runtime = CandleRuntime::new();
+    runtime.load(&load_config(model_path, 2048)).await.unwrap();
+    conformance::check_all(&mut runtime, "The capital of France is").await;
+}
+
+#[tokio::test]
+async fn test_tiny_model() {
+    let dir = tempfile::tempdir().unwrap();
+    write_tiny_model(dir.path());
+    let mut runtime = CandleRuntime::new();
+    assert!(runtime.load(&load_config(dir.path().to_path_buf(), 256)).await.unwrap_err().to_string().contains("trained context (128)"));
+    runtime.load(&load_config(dir.path().to_path_buf(), 8)).await.unwrap();
+
+    assert_eq!(runtime.tokenize("the capital", true).unwrap(), [1, 3, 4]);
+    assert_eq!(runtime.detokenize(&[3, 4]).unwrap(), "the capital");
+    let info = runtime.model_info().unwrap();
+    assert_eq!((info.architecture.as_str(), info.vocab_size, info.native_context_size, info.quantization.as_str()), ("llama", 16, 128, "F32"));
+    assert!(info.parameter_count > 0);
+
+    // Prompts over the context fail; output ends when the context is full
+    let err = runtime.infer("the capital of france is a big old city", InferenceOptions::default()).await.unwrap_err();
+    assert!(err.to_string().contains("exceeds context size"), "{}", err);
+    let options = InferenceOptions { max_tokens: Some(100), temperature: Some(0.0), ..InferenceOptions::default() };
+    let result = runtime.infer("the capital of france", options).await.unwrap();
+    assert!(matches!(result.finish_reason, FinishReason::Length | FinishReason::Eos), "{:?}", result.finish_reason);
+    assert!(result.usage.total_tokens <= 8, "{:?}", result.usage);
+
+    // The same seed samples the same output
+    let sampled = InferenceOptions { max_tokens: Some(6), temperature: Some(1.0), seed: Some(7), ..InferenceOptions::default() };
+    let first = runtime.infer("the", sampled.clone()).await.unwrap();
+    let second = runtime.infer("the", sampled).await.unwrap();
+    assert_eq!((first.text, first.seed), (second.text, Some(7)));
+}
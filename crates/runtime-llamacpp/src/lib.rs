@@ -1,157 +1,395 @@
+mod session;
+
 use async_trait::async_trait;
+use lie_core::chat::ChatTemplate;
 use lie_core::error::EngineError;
-use lie_core::runtime::{InferenceOptions, ModelLoadConfig, ModelRuntime, InferenceResult, InferenceStatus, Usage};
-use llama_cpp_2::context::params::LlamaContextParams;
+use lie_core::gguf::file_type_name;
+use lie_core::utf8::Utf8Buffer;
+use lie_core::runtime::{InferenceOptions, KvCacheType, ModelLoadConfig, LoadProgress, LoadReport, ModelInfo, ModelRuntime, InferenceResult, TokenChunk};
 use llama_cpp_2::model::params::LlamaModelParams;
-use llama_cpp_2::model::{LlamaModel, AddBos, Special};
+use llama_cpp_2::model::{AddBos, LlamaModel, Special};
+use llama_cpp_2::token::LlamaToken;
 use llama_cpp_2::llama_backend::LlamaBackend;
-use llama_cpp_2::llama_batch::LlamaBatch;
-use std::num::NonZeroU32;
-use std::time::Instant;
+use llama_cpp_2::LlamaBackendDeviceType;
+use session::{StateOrigin, Worker};
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
 
 pub struct LlamaCppRuntime {
-    backend: LlamaBackend,
-    model: Option<LlamaModel>,
+    backend: Arc<LlamaBackend>,
+    /// Runs the loaded model and its context; `None` until `load` succeeds.
+    worker: Option<Worker>,
+    /// Shared with the worker, for tokenizer calls that don't need the context.
+    model: Option<Arc<LlamaModel>>,
+    chat_template: Option<ChatTemplate>,
+    model_info: Option<ModelInfo>,
 }
 
+/// The backend shared by every runtime of the process. llama.cpp initializes it once per
+/// process, so it is only freed (and can be initialized again) when no runtime is left.
+static BACKEND: Mutex<Weak<LlamaBackend>> = Mutex::new(Weak::new());
+
 impl LlamaCppRuntime {
-    pub fn new() -> Self {
-        Self {
-            backend: LlamaBackend::init().unwrap(),
+    /// Fails with `EngineError::Runtime` if the llama.cpp backend can't be initialized.
+    pub fn new() -> Result<Self, EngineError> {
+        Ok(Self {
+            backend: shared_backend()?,
+            worker: None,
             model: None,
+            chat_template: None,
+            model_info: None,
+        })
+    }
+
+    /// What states saved from the loaded model's context are made with.
+    fn state_origin(&self) -> Result<StateOrigin, EngineError> {
+        let info = self.model_info.as_ref().ok_or(EngineError::ModelNotLoaded)?;
+        Ok(StateOrigin {
+            model: info.name.clone(),
+            model_size: info.file_size,
+            parameter_count: info.parameter_count,
+            context_size: info.context_size,
+            kv_cache_type: info.kv_cache_type,
+        })
+    }
+}
+
+fn shared_backend() -> Result<Arc<LlamaBackend>, EngineError> {
+    let mut shared = BACKEND.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(backend) = shared.upgrade() {
+        return Ok(backend);
+    }
+    let backend = Arc::new(LlamaBackend::init()
+        .map_err(|e| EngineError::Runtime(format!("Failed to initialize the llama.cpp backend: {}", e)))?);
+    *shared = Arc::downgrade(&backend);
+    Ok(backend)
+}
+
+/// The model's `tokenizer.chat_template` metadata, if present, paired with its EOS text.
+fn read_chat_template(model: &LlamaModel) -> Option<ChatTemplate> {
+    let source = model.chat_template(None).ok()?.to_string().ok()?;
+    let eos_token = model.token_to_str(model.token_eos(), Special::Tokenize).unwrap_or_default();
+    Some(ChatTemplate::Jinja { source, eos_token })
+}
+
+fn read_model_info(model: &LlamaModel, path: &Path) -> ModelInfo {
+    let name = model.meta_val_str("general.name").ok()
+        .or_else(|| path.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .unwrap_or_default();
+    let quantization = model.meta_val_str("general.file_type").ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .map(file_type_name)
+        .unwrap_or_else(|| "unknown".to_string());
+
+    ModelInfo {
+        name,
+        architecture: model.meta_val_str("general.architecture").unwrap_or_else(|_| "unknown".to_string()),
+        parameter_count: model.n_params(),
+        quantization,
+        native_context_size: model.n_ctx_train(),
+        vocab_size: u32::try_from(model.n_vocab()).unwrap_or(0),
+        file_size: std::fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        path: path.to_path_buf(),
+        context_size: 0,
+        gpu_layers: 0,
+        n_threads: 0,
+        n_threads_batch: 0,
+        use_mmap: false,
+        use_mlock: false,
+        kv_cache_type: KvCacheType::F16,
+        loaded_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+    }
+}
+
+/// Whether the model at `path` can be locked in RAM. mlock fails with ENOMEM when the
+/// process's memlock limit is below the model size, so check up front and warn instead.
+fn can_mlock(backend: &LlamaBackend, path: &Path) -> bool {
+    if !backend.supports_mlock() {
+        tracing::warn!("use_mlock requested but this platform can't lock memory; loading without it");
+        return false;
+    }
+    let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    match memlock_limit() {
+        Some(limit) if limit < size => {
+            tracing::warn!(
+                "use_mlock requested but the memlock limit ({} bytes) is below the model size ({} bytes), so mlock would fail with ENOMEM; loading without it. Raise the limit with `ulimit -l`",
+                limit, size
+            );
+            false
         }
+        _ => true,
     }
 }
 
-#[async_trait]
-impl ModelRuntime for LlamaCppRuntime {
-    async fn load(&mut self, config: &ModelLoadConfig) -> Result<(), EngineError> {
-        let model_params = LlamaModelParams::default();
-        let model_path_str = config.model_path.to_str()
-            .ok_or_else(|| EngineError::Config("Invalid model path".to_string()))?;
+/// The soft RLIMIT_MEMLOCK in bytes, or `None` if it is unlimited, unknown or doesn't apply
+/// (root can lock any amount).
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // rlim_t isn't u64 on every unix
+fn memlock_limit() -> Option<u64> {
+    // SAFETY: geteuid has no preconditions; getrlimit only writes to `limit`
+    if unsafe { libc::geteuid() } == 0 {
+        return None;
+    }
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+        tracing::warn!("Failed to read the memlock limit: {}", std::io::Error::last_os_error());
+        return None;
+    }
+    (limit.rlim_cur != libc::RLIM_INFINITY).then_some(limit.rlim_cur as u64)
+}
 
-        let model = LlamaModel::load_from_file(&self.backend, model_path_str, &model_params)
-            .map_err(|e| EngineError::Runtime(format!("Failed to load model: {}", e)))?;
+#[cfg(not(unix))]
+fn memlock_limit() -> Option<u64> {
+    None
+}
 
-        self.model = Some(model);
-        Ok(())
+/// Stop a worker thread and free its model. Joining waits for the thread to release
+/// its memory, so it is done on the blocking pool rather than the async executor.
+async fn stop_worker(worker: Option<Worker>) {
+    if let Some(worker) = worker {
+        let _ = tokio::task::spawn_blocking(move || drop(worker)).await;
     }
+}
 
-    async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
-        let start_time = Instant::now();
-        let model = self.model.as_ref().ok_or(EngineError::ModelNotLoaded)?;
-        
-        let n_ctx_size = 2048; // TODO: Get from model or config
-        
-        let ctx_params = LlamaContextParams::default()
-            .with_n_ctx(Some(NonZeroU32::new(n_ctx_size).unwrap()));
-            
-        let mut ctx = model.new_context(&self.backend, ctx_params)
-            .map_err(|e| EngineError::Runtime(format!("Failed to create context: {}", e)))?;
-
-        // 1. Tokenize (AddBos::Always)
-        let tokens_list = model.str_to_token(prompt, AddBos::Always)
-            .map_err(|e| EngineError::Runtime(format!("Tokenization failed: {}", e)))?;
-            
-        let input_tokens_count = tokens_list.len() as u32;
+/// Fill in the token counts of the current `infer`/`infer_stream` span.
+fn record_usage(result: &InferenceResult) {
+    let span = tracing::Span::current();
+    span.record("input_tokens", result.usage.input_tokens);
+    span.record("output_tokens", result.usage.output_tokens);
+    span.record("cached_tokens", result.usage.cached_tokens);
+}
 
-        // Context Limit Check
-        if input_tokens_count as u32 > n_ctx_size {
-             return Err(EngineError::Runtime(format!("Input length ({}) exceeds context size ({})", input_tokens_count, n_ctx_size)));
-        }
+/// The GPU backends compiled into llama.cpp, as Cargo features of this crate.
+pub fn build_features() -> Vec<String> {
+    let features = [("cuda", cfg!(feature = "cuda")), ("metal", cfg!(feature = "metal")), ("vulkan", cfg!(feature = "vulkan"))];
+    features.into_iter().filter(|(_, enabled)| *enabled).map(|(name, _)| name.to_string()).collect()
+}
 
-        // 2. Prepare batch
-        let mut batch = LlamaBatch::new(2048, 1); 
-        let last_index = (input_tokens_count as i32) - 1;
-        
-        for (i, token) in tokens_list.iter().enumerate() {
-            let is_last = i as i32 == last_index;
-            batch.add(*token, i as i32, &[0], is_last)
-                .map_err(|e| EngineError::Runtime(format!("Batch add failed: {}", e)))?;
+/// `llama.cpp` and the ggml backends it found devices for, e.g. `llama.cpp (CPU, CUDA)`.
+pub fn backend_description() -> String {
+    let mut backends: Vec<String> = Vec::new();
+    for device in llama_cpp_2::list_llama_ggml_backend_devices() {
+        if !backends.contains(&device.backend) {
+            backends.push(device.backend);
         }
+    }
+    if backends.is_empty() {
+        return "llama.cpp".to_string();
+    }
+    format!("llama.cpp ({})", backends.join(", "))
+}
+
+/// The GPUs llama.cpp found to offload layers to, e.g. `CUDA0: NVIDIA GeForce RTX 3080
+/// (9.5 GB free)`. Empty for CPU-only builds or when no device is present.
+pub fn gpu_devices() -> Vec<String> {
+    llama_cpp_2::list_llama_ggml_backend_devices().into_iter()
+        .filter(|device| matches!(device.device_type, LlamaBackendDeviceType::Gpu | LlamaBackendDeviceType::IntegratedGpu))
+        .map(|device| format!("{}: {} ({:.1} GB free)", device.name, device.description, device.memory_free as f64 / 1e9))
+        .collect()
+}
+
+/// Check that `path` is an existing `.gguf` file, so a wrong path gets a clear error
+/// instead of a llama.cpp load failure.
+pub fn check_model_file(path: &Path) -> Result<(), EngineError> {
+    if !path.exists() {
+        return Err(EngineError::Config(format!("Model file {} does not exist", path.display())));
+    }
+    if !path.is_file() {
+        return Err(EngineError::Config(format!("Model path {} is not a file", path.display())));
+    }
+    let is_gguf = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gguf"));
+    if !is_gguf {
+        return Err(EngineError::Config(format!("Model file {} is not a GGUF file (expected a .gguf extension)", path.display())));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl ModelRuntime for LlamaCppRuntime {
+    async fn load(&mut self, config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+        self.load_with_progress(config, Arc::new(|_| {})).await
+    }
 
-        // 3. Decode
-        ctx.decode(&mut batch)
-            .map_err(|e| EngineError::Runtime(format!("Decode failed: {}", e)))?;
-
-        // 4. Generation Loop
-        let mut response_tokens = Vec::new();
-        let max_gen_tokens = options.max_tokens.unwrap_or(128);
-        let max_time_ms = options.max_time_ms.unwrap_or(30000); // 30s hard limit
-        
-        let mut current_pos = input_tokens_count as i32;
-        let mut completion_status = InferenceStatus::Success;
-
-        for _ in 0..max_gen_tokens {
-            // Check Time Limit
-            if start_time.elapsed().as_millis() as u64 > max_time_ms {
-                completion_status = InferenceStatus::Truncated;
-                break;
-            }
-            
-            // Check Context Limit (Soft check, though batch/ctx might err first)
-            if current_pos as u32 >= n_ctx_size {
-                 completion_status = InferenceStatus::Truncated;
-                 break;
-            }
-
-            let candidates = ctx.candidates_ith(batch.n_tokens() - 1);
-            
-            // Greedy Sampling (Logits)
-            let next_token_data = candidates.max_by(|a, b| a.logit().partial_cmp(&b.logit()).unwrap_or(std::cmp::Ordering::Equal))
-                .ok_or_else(|| EngineError::Runtime("No candidates found".to_string()))?;
-                
-            let next_token = next_token_data.id();
-            
-            if next_token == model.token_eos() {
-                break;
-            }
-
-            response_tokens.push(next_token);
-
-            batch.clear();
-            batch.add(next_token, current_pos, &[0], true)
-                 .map_err(|e| EngineError::Runtime(format!("Batch add failed in loop: {}", e)))?;
-            
-            current_pos += 1;
-
-            ctx.decode(&mut batch)
-                .map_err(|e| EngineError::Runtime(format!("Decode loop failed: {}", e)))?;
+    /// The llama-cpp-2 bindings don't expose llama.cpp's own progress callback, so
+    /// progress moves in coarse steps: reading the weights is most of the work.
+    #[tracing::instrument(skip_all, fields(path = %config.model_path.display(), context_size = config.context_size))]
+    async fn load_with_progress(&mut self, config: &ModelLoadConfig, progress: LoadProgress) -> Result<LoadReport, EngineError> {
+        progress(0.0);
+        let context_size = u32::try_from(config.context_size)
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| EngineError::Config(format!("Invalid context size: {}", config.context_size)))?;
+        if config.batch_size == 0 {
+            return Err(EngineError::Config("Invalid batch size: 0".to_string()));
         }
-        
-        // If we hit max_gen_tokens without EOS, status is Truncated?
-        // Actually, if loop finishes normally, it means we hit limit.
-        // If we broke due to EOS, we are good.
-        if completion_status == InferenceStatus::Success && response_tokens.len() as u32 == max_gen_tokens {
-             completion_status = InferenceStatus::Truncated;
+        // A batch can never hold more than the whole context
+        let batch_size = u32::try_from(config.batch_size).unwrap_or(u32::MAX).min(context_size);
+
+        let gpu_layers = if config.gpu_layers > 0 && !self.backend.supports_gpu_offload() {
+            tracing::warn!(
+                "gpu_layers = {} requested but this build has no GPU backend; running on CPU",
+                config.gpu_layers
+            );
+            0
+        } else {
+            config.gpu_layers
+        };
+
+        check_model_file(&config.model_path)?;
+        let model_path_str = config.model_path.to_str()
+            .ok_or_else(|| EngineError::Config("Invalid model path".to_string()))?
+            .to_string();
+
+        if !config.use_mmap {
+            tracing::warn!("use_mmap = false requested but the llama.cpp bindings can't turn memory mapping off; mapping the model anyway");
         }
+        let use_mmap = self.backend.supports_mmap();
+        let use_mlock = config.use_mlock && can_mlock(&self.backend, &config.model_path);
+
+        // Reading the weights takes seconds; keep it off the async executor
+        let backend = self.backend.clone();
+        let n_gpu_layers = u32::try_from(gpu_layers).unwrap_or(u32::MAX);
+        let model = tokio::task::spawn_blocking(move || {
+            let model_params = LlamaModelParams::default()
+                .with_n_gpu_layers(n_gpu_layers)
+                .with_use_mlock(use_mlock);
+            LlamaModel::load_from_file(&backend, model_path_str, &model_params)
+        })
+        .await
+        .map_err(|e| EngineError::Runtime(format!("Model loading task failed: {}", e)))?
+        .map_err(|e| EngineError::Runtime(format!("Failed to load model: {}", e)))?;
+        progress(0.9);
 
-        // 5. Detokenize
-        let mut output_string = String::new();
-        for token in response_tokens.iter() {
-             let piece = model.token_to_str(*token, Special::Plaintext) 
-                 .map_err(|e| EngineError::Runtime(format!("Detokenization failed: {}", e)))?;
-             output_string.push_str(&piece);
+        let n_ctx_train = model.n_ctx_train();
+        if context_size > n_ctx_train {
+            return Err(EngineError::Config(format!(
+                "Context size ({}) exceeds the model's trained context ({})", context_size, n_ctx_train
+            )));
         }
 
-        let output_tokens_count = response_tokens.len() as u32;
-        let total_tokens_count = input_tokens_count + output_tokens_count;
-        let duration_ms = start_time.elapsed().as_millis() as u64;
-
-        Ok(InferenceResult {
-            text: output_string,
-            usage: Usage {
-                input_tokens: input_tokens_count,
-                output_tokens: output_tokens_count,
-                total_tokens: total_tokens_count,
-                duration_ms,
-            },
-            status: completion_status,
+        let chat_template = read_chat_template(&model);
+
+        // llama.cpp counts the output layer as one extra offloadable layer
+        let gpu_layers_offloaded = gpu_layers.min(model.n_layer() as usize + 1);
+
+        let mut model_info = read_model_info(&model, &config.model_path);
+        model_info.context_size = context_size;
+        model_info.gpu_layers = gpu_layers_offloaded;
+        let threads = config.threads();
+        model_info.n_threads = threads.n_threads;
+        model_info.n_threads_batch = threads.n_threads_batch;
+        model_info.use_mmap = use_mmap;
+        model_info.use_mlock = use_mlock;
+        model_info.kv_cache_type = config.kv_cache_type;
+        tracing::info!(use_mmap, use_mlock, kv_cache_type = ?config.kv_cache_type, "Model memory settings");
+
+        let model = Arc::new(model);
+        let worker = Worker::spawn(self.backend.clone(), model.clone(), context_size, batch_size, threads, config.kv_cache_type).await?;
+
+        // Release our reference to the old model first, so the old worker frees it
+        self.model = Some(model);
+        stop_worker(self.worker.replace(worker)).await;
+        self.chat_template = chat_template;
+        self.model_info = Some(model_info);
+        progress(1.0);
+        Ok(LoadReport {
+            gpu_layers_requested: config.gpu_layers,
+            gpu_layers_offloaded,
         })
     }
 
+    #[tracing::instrument(skip_all, fields(input_tokens, output_tokens, cached_tokens))]
+    async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+        let worker = self.worker.as_ref().ok_or(EngineError::ModelNotLoaded)?;
+        let result = worker.generate(prompt, options, None).await?;
+        record_usage(&result);
+        Ok(result)
+    }
+
+    #[tracing::instrument(skip_all, fields(input_tokens, output_tokens, cached_tokens))]
+    async fn infer_stream(&mut self, prompt: &str, options: InferenceOptions, tx: mpsc::UnboundedSender<TokenChunk>) -> Result<InferenceResult, EngineError> {
+        let worker = self.worker.as_ref().ok_or(EngineError::ModelNotLoaded)?;
+        let result = worker.generate(prompt, options, Some(tx.clone())).await?;
+        record_usage(&result);
+        let _ = tx.send(TokenChunk::done(&result));
+        Ok(result)
+    }
+
+    /// Decodes up to 8 prompts at a time as separate sequences of one batch.
+    async fn infer_batch(&mut self, prompts: &[String], options: InferenceOptions) -> Result<Vec<InferenceResult>, EngineError> {
+        let worker = self.worker.as_ref().ok_or(EngineError::ModelNotLoaded)?;
+        worker.generate_batch(prompts, options).await
+    }
+
+    async fn embed(&mut self, texts: &[String]) -> Result<Vec<Vec<f32>>, EngineError> {
+        let worker = self.worker.as_ref().ok_or(EngineError::ModelNotLoaded)?;
+        worker.embed(texts).await
+    }
+
+    /// Saves the KV cache and the tokens it holds. Loading the state only works with the
+    /// same model, context size and KV cache type.
+    async fn save_session(&mut self, path: &Path) -> Result<(), EngineError> {
+        let worker = self.worker.as_ref().ok_or(EngineError::ModelNotLoaded)?;
+        worker.save_state(path, self.state_origin()?).await
+    }
+
+    async fn load_session(&mut self, path: &Path) -> Result<(), EngineError> {
+        let worker = self.worker.as_ref().ok_or(EngineError::ModelNotLoaded)?;
+        worker.load_state(path, self.state_origin()?).await
+    }
+
     async fn unload(&mut self) -> Result<(), EngineError> {
         self.model = None;
+        stop_worker(self.worker.take()).await;
+        self.chat_template = None;
+        self.model_info = None;
         Ok(())
     }
+
+    fn tokenize(&self, text: &str, add_bos: bool) -> Result<Vec<i32>, EngineError> {
+        let model = self.model.as_ref().ok_or(EngineError::ModelNotLoaded)?;
+        let add_bos = if add_bos { AddBos::Always } else { AddBos::Never };
+        let tokens = model.str_to_token(text, add_bos)
+            .map_err(|e| EngineError::Runtime(format!("Tokenization failed: {}", e)))?;
+        Ok(tokens.into_iter().map(|token| token.0).collect())
+    }
+
+    fn token_pieces(&self, tokens: &[i32]) -> Result<Vec<String>, EngineError> {
+        let model = self.model.as_ref().ok_or(EngineError::ModelNotLoaded)?;
+        let n_vocab = model.n_vocab();
+        tokens.iter()
+            .map(|&token| {
+                if token < 0 || token >= n_vocab {
+                    return Err(EngineError::Runtime(format!("Invalid token id: {}", token)));
+                }
+                // Byte-fallback tokens can hold part of a UTF-8 character
+                model.token_to_bytes(LlamaToken(token), Special::Tokenize)
+                    .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                    .map_err(|e| EngineError::Runtime(format!("Detokenization failed: {}", e)))
+            })
+            .collect()
+    }
+
+    fn detokenize(&self, tokens: &[i32]) -> Result<String, EngineError> {
+        let model = self.model.as_ref().ok_or(EngineError::ModelNotLoaded)?;
+        let mut text = Utf8Buffer::new();
+        let mut detokenized = String::new();
+        for &token in tokens {
+            let bytes = model.token_to_bytes(LlamaToken(token), Special::Tokenize)
+                .map_err(|e| EngineError::Runtime(format!("Detokenization failed: {}", e)))?;
+            detokenized.push_str(&text.push(&bytes));
+        }
+        detokenized.push_str(&text.finish());
+        Ok(detokenized)
+    }
+
+    fn model_info(&self) -> Option<ModelInfo> {
+        self.model_info.clone()
+    }
+
+    fn chat_template(&self) -> Option<ChatTemplate> {
+        self.chat_template.clone()
+    }
 }
@@ -0,0 +1,948 @@
+//! The decode loop and the thread it runs on.
+//!
+//! `LlamaContext` borrows the model and isn't `Send`, so it can't be stored on
+//! `LlamaCppRuntime` (which must be `Send + Sync`). Instead a worker thread owns the
+//! model and one long-lived context, and the runtime talks to it over a channel.
+//! Keeping the context alive lets consecutive requests reuse the KV cache for the
+//! token prefix they share. A long prefix shared that way (typically the system prompt and
+//! injected memory) is also saved as a copy of the KV state, so a request starting with it
+//! skips evaluating it even after other prompts have taken over the cache in between. The
+//! copy costs as much memory as that part of the KV cache.
+//!
+//! Embeddings need a context created in embedding mode, so the worker creates a
+//! second context the first time one is asked for and keeps it next to the first.
+//!
+//! Because all decoding happens on that thread, a long generation never occupies a
+//! tokio worker: the async side only sends a command and awaits the reply.
+//!
+//! The state of the context can also be saved to a file and loaded again, in this or another
+//! process (`ModelRuntime::save_session`). The file starts with a line identifying the format,
+//! then a JSON line saying which model and context settings it was made with and which
+//! tokens it holds, then llama.cpp's state data.
+//!
+//! Batches of prompts get a context of their own for the duration of the batch, with one
+//! sequence per prompt, so they are generated together in shared decode calls.
+
+use lie_core::error::EngineError;
+use lie_core::runtime::{time_is_up, tokens_per_second, FinishReason, InferenceOptions, InferenceResult, KvCacheType, Logprobs, Threads, TokenChunk, TokenLogprob, TopLogprob, Usage, MAX_LOGPROBS};
+use lie_core::stop::{stream_text, StopSequences};
+use lie_core::utf8::Utf8Buffer;
+use lie_core::sampling::{self, Candidate, Sampler};
+use llama_cpp_2::context::params::{self, LlamaContextParams, LlamaPoolingType};
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::llama_batch::LlamaBatch;
+use llama_cpp_2::model::{AddBos, LlamaModel, Special};
+use llama_cpp_2::sampling::LlamaSampler;
+use llama_cpp_2::token::LlamaToken;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{BufRead, BufReader, Read};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+
+/// Most prompts of a batch generated together; bigger batches run in groups of this many.
+/// Each one takes its own share of the KV cache.
+const MAX_SEQUENCES: usize = 8;
+
+/// Shortest prefix shared by consecutive requests that is saved; shorter ones are cheap to
+/// evaluate again.
+const MIN_SAVED_PREFIX: usize = 32;
+
+/// First line of a saved state file.
+const STATE_MAGIC: &[u8] = b"CELA-STATE 1\n";
+
+enum Command {
+    Generate {
+        prompt: String,
+        options: Box<InferenceOptions>,
+        tx: Option<mpsc::UnboundedSender<TokenChunk>>,
+        reply: oneshot::Sender<Result<InferenceResult, EngineError>>,
+    },
+    GenerateBatch {
+        prompts: Vec<String>,
+        options: Box<InferenceOptions>,
+        reply: oneshot::Sender<Result<Vec<InferenceResult>, EngineError>>,
+    },
+    Embed {
+        texts: Vec<String>,
+        reply: oneshot::Sender<Result<Vec<Vec<f32>>, EngineError>>,
+    },
+    SaveState {
+        path: PathBuf,
+        origin: StateOrigin,
+        reply: oneshot::Sender<Result<(), EngineError>>,
+    },
+    LoadState {
+        path: PathBuf,
+        origin: StateOrigin,
+        reply: oneshot::Sender<Result<(), EngineError>>,
+    },
+}
+
+/// What a saved state was made with. It only fits a context of the same model, size and
+/// KV cache type.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct StateOrigin {
+    pub(crate) model: String,
+    pub(crate) model_size: u64,
+    pub(crate) parameter_count: u64,
+    pub(crate) context_size: u32,
+    pub(crate) kv_cache_type: KvCacheType,
+}
+
+impl fmt::Display for StateOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kv_cache_type = serde_json::to_value(self.kv_cache_type).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default();
+        write!(f, "{} ({} bytes) with a context of {} and a {} KV cache", self.model, self.model_size, self.context_size, kv_cache_type)
+    }
+}
+
+/// The JSON line of a saved state file.
+#[derive(Serialize, Deserialize)]
+struct StateHeader {
+    origin: StateOrigin,
+    /// The tokens whose keys/values the state holds.
+    tokens: Vec<i32>,
+}
+
+/// Handle to the thread that runs a loaded model. Dropping it stops the thread,
+/// which frees the model unless another `Arc` to it is still around.
+pub(crate) struct Worker {
+    commands: Option<std_mpsc::Sender<Command>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    /// Start a thread for `model` and wait until its context has been created.
+    pub(crate) async fn spawn(backend: Arc<LlamaBackend>, model: Arc<LlamaModel>, context_size: u32, batch_size: u32, threads: Threads, kv_cache_type: KvCacheType) -> Result<Self, EngineError> {
+        let (commands, rx) = std_mpsc::channel::<Command>();
+        let (ready_tx, ready_rx) = oneshot::channel();
+
+        let handle = std::thread::Builder::new()
+            .name("llama-worker".to_string())
+            .spawn(move || {
+                let mut session = match Session::new(&backend, &model, context_size, batch_size, threads, kv_cache_type) {
+                    Ok(session) => {
+                        let _ = ready_tx.send(Ok(()));
+                        session
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+
+                while let Ok(command) = rx.recv() {
+                    match command {
+                        Command::Generate { prompt, options, tx, reply } => {
+                            let _ = reply.send(session.generate(&prompt, *options, tx.as_ref()));
+                        }
+                        Command::GenerateBatch { prompts, options, reply } => {
+                            let _ = reply.send(session.generate_batch(&prompts, *options));
+                        }
+                        Command::Embed { texts, reply } => {
+                            let _ = reply.send(session.embed(&texts));
+                        }
+                        Command::SaveState { path, origin, reply } => {
+                            let _ = reply.send(session.save_state(&path, origin));
+                        }
+                        Command::LoadState { path, origin, reply } => {
+                            let _ = reply.send(session.load_state(&path, &origin));
+                        }
+                    }
+                }
+            })
+            .map_err(|e| EngineError::Runtime(format!("Failed to start inference thread: {}", e)))?;
+
+        ready_rx.await
+            .map_err(|_| EngineError::Runtime("Inference thread exited during startup".to_string()))??;
+
+        Ok(Self { commands: Some(commands), handle: Some(handle) })
+    }
+
+    /// Run a generation on the worker. When `tx` is given, each detokenized piece is
+    /// sent through it as soon as it is generated.
+    pub(crate) async fn generate(&self, prompt: &str, options: InferenceOptions, tx: Option<mpsc::UnboundedSender<TokenChunk>>) -> Result<InferenceResult, EngineError> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Command::Generate { prompt: prompt.to_string(), options: Box::new(options), tx, reply })?;
+
+        reply_rx.await
+            .map_err(|_| EngineError::Runtime("Inference thread stopped mid-request".to_string()))?
+    }
+
+    /// Generate a completion of each of `prompts` on the worker, several at a time.
+    pub(crate) async fn generate_batch(&self, prompts: &[String], options: InferenceOptions) -> Result<Vec<InferenceResult>, EngineError> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Command::GenerateBatch { prompts: prompts.to_vec(), options: Box::new(options), reply })?;
+
+        reply_rx.await
+            .map_err(|_| EngineError::Runtime("Inference thread stopped mid-request".to_string()))?
+    }
+
+    /// Compute one mean-pooled, normalized embedding per text on the worker.
+    pub(crate) async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, EngineError> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Command::Embed { texts: texts.to_vec(), reply })?;
+
+        reply_rx.await
+            .map_err(|_| EngineError::Runtime("Inference thread stopped mid-request".to_string()))?
+    }
+
+    /// Save the state of the worker's context to `path`.
+    pub(crate) async fn save_state(&self, path: &Path, origin: StateOrigin) -> Result<(), EngineError> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Command::SaveState { path: path.to_path_buf(), origin, reply })?;
+
+        reply_rx.await
+            .map_err(|_| EngineError::Runtime("Inference thread stopped mid-request".to_string()))?
+    }
+
+    /// Load the state saved to `path` into the worker's context, which must match `origin`.
+    pub(crate) async fn load_state(&self, path: &Path, origin: StateOrigin) -> Result<(), EngineError> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.send(Command::LoadState { path: path.to_path_buf(), origin, reply })?;
+
+        reply_rx.await
+            .map_err(|_| EngineError::Runtime("Inference thread stopped mid-request".to_string()))?
+    }
+
+    fn send(&self, command: Command) -> Result<(), EngineError> {
+        self.commands.as_ref()
+            .and_then(|commands| commands.send(command).ok())
+            .ok_or_else(|| EngineError::Runtime("Inference thread has stopped".to_string()))
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // Closing the channel ends the worker loop; joining makes sure the model is freed
+        // before a replacement is loaded.
+        self.commands.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// A context plus a record of which tokens its KV cache currently holds.
+struct Session<'m> {
+    backend: &'m LlamaBackend,
+    model: &'m LlamaModel,
+    ctx: LlamaContext<'m>,
+    /// Embedding-mode context, created on first use.
+    embed_ctx: Option<LlamaContext<'m>>,
+    n_ctx: u32,
+    n_batch: u32,
+    /// Threads `ctx` was created with, and those requests get unless they ask otherwise.
+    threads: Threads,
+    default_threads: Threads,
+    kv_cache_type: KvCacheType,
+    /// Tokens whose keys/values are in the KV cache (sequence 0), in position order.
+    cached: Vec<LlamaToken>,
+    prefix: Option<SavedPrefix>,
+}
+
+/// A copy of the context's state while its KV cache held just `tokens`.
+struct SavedPrefix {
+    tokens: Vec<LlamaToken>,
+    state: Vec<u8>,
+}
+
+impl<'m> Session<'m> {
+    fn new(backend: &'m LlamaBackend, model: &'m LlamaModel, n_ctx: u32, n_batch: u32, threads: Threads, kv_cache_type: KvCacheType) -> Result<Self, EngineError> {
+        let ctx = model.new_context(backend, context_params(n_ctx, n_batch, threads, kv_cache_type))
+            .map_err(|e| EngineError::Runtime(format!("Failed to create context: {}", e)))?;
+
+        Ok(Self { backend, model, ctx, embed_ctx: None, n_ctx, n_batch, threads, default_threads: threads, kv_cache_type, cached: Vec::new(), prefix: None })
+    }
+
+    /// Recreate the context if it doesn't run with `threads`. The bindings can't change
+    /// the thread counts of an existing context, so this discards the KV cache.
+    fn use_threads(&mut self, threads: Threads) -> Result<(), EngineError> {
+        if threads == self.threads {
+            return Ok(());
+        }
+        self.ctx = self.model.new_context(self.backend, context_params(self.n_ctx, self.n_batch, threads, self.kv_cache_type))
+            .map_err(|e| EngineError::Runtime(format!("Failed to create context: {}", e)))?;
+        self.cached.clear();
+        self.threads = threads;
+        Ok(())
+    }
+
+    fn embed(&mut self, texts: &[String]) -> Result<Vec<Vec<f32>>, EngineError> {
+        let model = self.model;
+        let n_ctx = self.n_ctx;
+
+        if self.embed_ctx.is_none() {
+            // Each text is decoded as a single batch, so the batch spans the whole context
+            let params = context_params(n_ctx, n_ctx, self.default_threads, self.kv_cache_type)
+                .with_n_ubatch(n_ctx)
+                .with_embeddings(true)
+                .with_pooling_type(LlamaPoolingType::Mean);
+            let ctx = model.new_context(self.backend, params)
+                .map_err(|e| EngineError::Runtime(format!("Failed to create embedding context: {}", e)))?;
+            self.embed_ctx = Some(ctx);
+        }
+        let ctx = self.embed_ctx.as_mut().expect("embedding context was just created");
+
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for text in texts {
+            let tokens = model.str_to_token(text, AddBos::Always)
+                .map_err(|e| EngineError::Runtime(format!("Tokenization failed: {}", e)))?;
+            if tokens.len() > n_ctx as usize {
+                return Err(EngineError::ContextOverflow { tokens: tokens.len(), context_size: n_ctx as usize });
+            }
+
+            let mut batch = LlamaBatch::new(tokens.len(), 1);
+            for (pos, token) in tokens.iter().enumerate() {
+                batch.add(*token, pos as i32, &[0], true)
+                    .map_err(|e| EngineError::Runtime(format!("Batch add failed: {}", e)))?;
+            }
+
+            ctx.clear_kv_cache();
+            ctx.decode(&mut batch)
+                .map_err(|e| EngineError::Runtime(format!("Decode failed: {}", e)))?;
+
+            let embedding = ctx.embeddings_seq_ith(0)
+                .map_err(|e| EngineError::Unsupported(format!("the loaded model does not produce embeddings ({})", e)))?;
+            embeddings.push(normalize(embedding));
+        }
+        Ok(embeddings)
+    }
+
+    fn generate(&mut self, prompt: &str, options: InferenceOptions, tx: Option<&mpsc::UnboundedSender<TokenChunk>>) -> Result<InferenceResult, EngineError> {
+        self.use_threads(self.requested_threads(&options))?;
+        if options.reset_context {
+            self.reset();
+            self.prefix = None;
+        }
+
+        let result = self.run(prompt, options, tx);
+        if result.is_err() {
+            // A failed decode can leave the cache out of step with `cached`
+            self.reset();
+        }
+        result
+    }
+
+    /// The threads `options` asks for, falling back to the model's.
+    fn requested_threads(&self, options: &InferenceOptions) -> Threads {
+        Threads {
+            n_threads: options.n_threads.map_or(self.default_threads.n_threads, |n| n as usize),
+            n_threads_batch: options.n_threads_batch.map_or(self.default_threads.n_threads_batch, |n| n as usize),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ctx.clear_kv_cache();
+        self.cached.clear();
+    }
+
+    /// A copy of the context's state, KV cache included.
+    fn state(&self) -> Vec<u8> {
+        let mut state = vec![0; self.ctx.get_state_size()];
+        // SAFETY: `state` is as large as llama.cpp says the state is
+        let written = unsafe { self.ctx.copy_state_data(state.as_mut_ptr()) };
+        state.truncate(written);
+        state
+    }
+
+    /// Save the state as the prefix to restore later. The KV cache must hold `cached` only.
+    fn save_prefix(&mut self) {
+        self.prefix = Some(SavedPrefix { tokens: self.cached.clone(), state: self.state() });
+    }
+
+    /// Write the state, for the tokens in the KV cache, to `path`.
+    fn save_state(&mut self, path: &Path, origin: StateOrigin) -> Result<(), EngineError> {
+        let header = StateHeader { origin, tokens: self.cached.iter().map(|token| token.0).collect() };
+        let mut data = STATE_MAGIC.to_vec();
+        serde_json::to_writer(&mut data, &header)
+            .map_err(|e| EngineError::Runtime(format!("Failed to serialize the session state: {}", e)))?;
+        data.push(b'\n');
+        data.extend_from_slice(&self.state());
+        std::fs::write(path, data)
+            .map_err(|e| EngineError::Runtime(format!("Failed to write {}: {}", path.display(), e)))
+    }
+
+    /// Replace the KV cache with the state saved to `path`, unless it already starts with
+    /// the tokens saved there. Fails with a `Validation` error if the state doesn't match
+    /// `origin`.
+    fn load_state(&mut self, path: &Path, origin: &StateOrigin) -> Result<(), EngineError> {
+        let read_error = |e: std::io::Error| EngineError::Runtime(format!("Failed to read {}: {}", path.display(), e));
+        let file = std::fs::File::open(path).map_err(read_error)?;
+        let mut reader = BufReader::new(file);
+
+        let mut magic = Vec::new();
+        reader.read_until(b'\n', &mut magic).map_err(read_error)?;
+        let mut header = Vec::new();
+        reader.read_until(b'\n', &mut header).map_err(read_error)?;
+        let header: StateHeader = match serde_json::from_slice(&header) {
+            Ok(header) if magic == STATE_MAGIC => header,
+            _ => return Err(EngineError::Validation(format!("{} is not a saved session state", path.display()))),
+        };
+        if header.origin != *origin {
+            return Err(EngineError::Validation(format!(
+                "Session state {} was saved with {}, but {} is loaded; create the session again with this model",
+                path.display(), header.origin, origin
+            )));
+        }
+
+        let tokens: Vec<LlamaToken> = header.tokens.into_iter().map(LlamaToken).collect();
+        if self.cached.starts_with(&tokens) {
+            return Ok(());
+        }
+        let mut state = Vec::new();
+        reader.read_to_end(&mut state).map_err(read_error)?;
+        // SAFETY: the state was saved from a context of this model with the same size and
+        // KV cache type, as checked above
+        let read = unsafe { self.ctx.set_state_data(&state) };
+        if read != state.len() {
+            self.reset();
+            return Err(EngineError::Runtime(format!("Failed to restore the session state {}", path.display())));
+        }
+        self.cached = tokens;
+        Ok(())
+    }
+
+    /// Replace the KV cache with the saved prefix. `false` if there is none, or if it
+    /// couldn't be restored, which empties the cache and forgets the prefix.
+    fn restore_prefix(&mut self) -> bool {
+        let Some(prefix) = self.prefix.take() else {
+            return false;
+        };
+        // SAFETY: the state was saved from a context of this model with the same size and
+        // KV cache type; contexts are only ever recreated with other thread counts
+        let read = unsafe { self.ctx.set_state_data(&prefix.state) };
+        if read != prefix.state.len() {
+            tracing::warn!("Failed to restore the saved prompt prefix; evaluating it again");
+            self.reset();
+            return false;
+        }
+        self.cached = prefix.tokens.clone();
+        self.prefix = Some(prefix);
+        true
+    }
+
+    /// Drop everything in the KV cache from position `keep` onwards.
+    fn truncate_cache(&mut self, keep: usize) {
+        if keep >= self.cached.len() {
+            return;
+        }
+        match self.ctx.clear_kv_cache_seq(Some(0), Some(keep as u32), None) {
+            Ok(true) => self.cached.truncate(keep),
+            // Some architectures can't remove a partial range; start over instead
+            _ => self.reset(),
+        }
+    }
+
+    /// Make room in a full context: discard the older half of the tokens after the first
+    /// `keep` and move the rest down. Returns how many positions were freed, 0 if none
+    /// could be.
+    fn shift_context(&mut self, keep: usize) -> Result<usize, EngineError> {
+        let len = self.cached.len();
+        let discard = len.saturating_sub(keep) / 2;
+        if discard == 0 {
+            return Ok(0);
+        }
+        // Some architectures can't remove a partial range, so can't shift either
+        if !matches!(self.ctx.clear_kv_cache_seq(Some(0), Some(keep as u32), Some((keep + discard) as u32)), Ok(true)) {
+            return Ok(0);
+        }
+        self.ctx.kv_cache_seq_add(0, Some((keep + discard) as u32), Some(len as u32), -(discard as i32))
+            .map_err(|e| EngineError::Runtime(format!("Context shift failed: {}", e)))?;
+        self.cached.drain(keep..keep + discard);
+        Ok(discard)
+    }
+
+    /// The log-probability of `token` and the `top_n` most likely alternatives to it.
+    fn token_logprob(&self, candidates: &[Candidate], token: LlamaToken, top_n: usize) -> Result<TokenLogprob, EngineError> {
+        let (logprob, top) = sampling::logprobs(candidates, token.0, top_n);
+        let bytes = |token: i32| self.model.token_to_bytes(LlamaToken(token), Special::Plaintext)
+            .map_err(|e| EngineError::Runtime(format!("Detokenization failed: {}", e)));
+        let top_logprobs = top.into_iter()
+            .map(|(id, logprob)| {
+                let bytes = bytes(id)?;
+                Ok(TopLogprob { token: String::from_utf8_lossy(&bytes).into_owned(), logprob, bytes })
+            })
+            .collect::<Result<_, EngineError>>()?;
+        let bytes = bytes(token.0)?;
+        Ok(TokenLogprob { token: String::from_utf8_lossy(&bytes).into_owned(), logprob, bytes, top_logprobs })
+    }
+
+    /// The sampler for a generation following `prompt`.
+    fn sampler(&self, options: &InferenceOptions, seed: u64, prompt: &[LlamaToken]) -> Result<Sampler, EngineError> {
+        let logit_bias = sampling::token_bias(&options.logit_bias, |text| {
+            self.model.str_to_token(text, AddBos::Never).map(|tokens| tokens.into_iter().map(|token| token.0).collect())
+        })?;
+        let mut sampler = Sampler::from_options(options, seed, logit_bias)
+            .exempt_from_penalties(self.model.token_eos().0);
+        if options.penalize_prompt {
+            for token in prompt {
+                sampler.accept(token.0);
+            }
+        }
+        Ok(sampler)
+    }
+
+    /// Grammar constraints come from llama.cpp's own sampler: it masks out every token the
+    /// grammar doesn't allow next, and our sampler picks among the rest.
+    fn grammar(&self, options: &InferenceOptions) -> Result<Option<LlamaSampler>, EngineError> {
+        options.grammar.as_deref()
+            .map(|grammar| LlamaSampler::grammar(self.model, grammar, "root"))
+            .transpose()
+            .map_err(|e| EngineError::Runtime(format!("Failed to load grammar: {}", e)))
+    }
+
+    /// Run the full tokenize/decode/sample loop.
+    fn run(&mut self, prompt: &str, options: InferenceOptions, tx: Option<&mpsc::UnboundedSender<TokenChunk>>) -> Result<InferenceResult, EngineError> {
+        let start_time = Instant::now();
+        let model = self.model;
+        let n_ctx_size = self.n_ctx;
+
+        // 1. Tokenize (AddBos::Always)
+        let tokens_list = model.str_to_token(prompt, AddBos::Always)
+            .map_err(|e| EngineError::Runtime(format!("Tokenization failed: {}", e)))?;
+
+        let input_tokens_count = tokens_list.len() as u32;
+
+        // Context Limit Check
+        if input_tokens_count > n_ctx_size {
+             return Err(EngineError::ContextOverflow { tokens: input_tokens_count as usize, context_size: n_ctx_size as usize });
+        }
+
+        // 2. Reuse the cached prefix: what the last request left, or the saved prefix if that
+        // covers more of the prompt. At least the last prompt token is always decoded, since
+        // its logits are needed to sample the first output token.
+        let reusable = |cached: &[LlamaToken]| common_prefix_len(cached, &tokens_list)
+            .min(tokens_list.len().saturating_sub(1));
+        let restore = self.prefix.as_ref().is_some_and(|prefix| {
+            reusable(&prefix.tokens) == prefix.tokens.len() && prefix.tokens.len() > reusable(&self.cached)
+        });
+        let cached_prefix_tokens = if restore && self.restore_prefix() { self.cached.len() } else { 0 };
+        self.truncate_cache(reusable(&self.cached));
+        let cached_tokens = self.cached.len();
+        // A long prefix shared with the last request is likely to come again
+        let shared = cached_prefix_tokens == 0 && cached_tokens >= MIN_SAVED_PREFIX;
+        if shared && self.prefix.as_ref().is_none_or(|prefix| prefix.tokens != self.cached) {
+            self.save_prefix();
+        }
+
+        // 3. Decode the uncached suffix in chunks of n_batch tokens.
+        // Logits are only requested for the final prompt token.
+        let n_batch = self.n_batch as usize;
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let mut finish_reason = None;
+        let prompt_eval_start = Instant::now();
+
+        for chunk in tokens_list[cached_tokens..].chunks(n_batch) {
+            if options.cancel.is_cancelled() {
+                finish_reason = Some(FinishReason::Cancelled);
+                break;
+            }
+
+            batch.clear();
+            let chunk_start = self.cached.len();
+            for (i, token) in chunk.iter().enumerate() {
+                let pos = chunk_start + i;
+                let is_last = pos + 1 == tokens_list.len();
+                batch.add(*token, pos as i32, &[0], is_last)
+                    .map_err(|e| EngineError::Runtime(format!("Batch add failed: {}", e)))?;
+            }
+
+            self.ctx.decode(&mut batch)
+                .map_err(|e| EngineError::Runtime(format!("Decode failed: {}", e)))?;
+            self.cached.extend_from_slice(chunk);
+        }
+        let prompt_eval = prompt_eval_start.elapsed();
+
+        // 4. Generation Loop
+        let mut response_tokens = Vec::new();
+        let mut output_string = String::new();
+        // Streamed text trails the output by whatever could still become a stop sequence
+        let stop_sequences = StopSequences::new(&options.stop_sequences);
+        let mut streamed = 0;
+        let max_gen_tokens = options.max_tokens.unwrap_or(128);
+        let max_time_ms = options.max_time_ms.unwrap_or(30000); // 30s hard limit
+
+        let mut current_pos = input_tokens_count as i32;
+        let seed = options.seed.unwrap_or_else(sampling::random_seed);
+        let mut sampler = self.sampler(&options, seed, &tokens_list)?;
+        let mut grammar = self.grammar(&options)?;
+
+        let mut logprobs = Vec::new();
+        let context_shift = options.context_shift.unwrap_or(false);
+        let n_keep = options.n_keep.map_or(tokens_list.len(), |n| n as usize);
+        let mut context_shifts = 0;
+        // Characters split across tokens are only added once complete
+        let mut utf8 = Utf8Buffer::new();
+        let generation_start = Instant::now();
+        while finish_reason.is_none() {
+            if response_tokens.len() as u32 >= max_gen_tokens {
+                finish_reason = Some(FinishReason::Length);
+                break;
+            }
+
+            if options.cancel.is_cancelled() {
+                finish_reason = Some(FinishReason::Cancelled);
+                break;
+            }
+
+            // Check Time Limit
+            if time_is_up(start_time, max_time_ms) {
+                finish_reason = Some(FinishReason::Time);
+                break;
+            }
+
+            // Check Context Limit (Soft check, though batch/ctx might err first)
+            if current_pos as u32 >= n_ctx_size {
+                let freed = if context_shift { self.shift_context(n_keep)? } else { 0 };
+                if freed == 0 {
+                    finish_reason = Some(FinishReason::Length);
+                    break;
+                }
+                current_pos -= freed as i32;
+                context_shifts += 1;
+            }
+
+            let candidates = candidates(&self.ctx, batch.n_tokens() - 1, grammar.as_ref());
+
+            // Greedy at temperature 0.0, the temperature/truncation chain otherwise
+            let next_token = sampler.sample(&candidates)
+                .map(LlamaToken)
+                .ok_or_else(|| EngineError::Runtime("No candidates found".to_string()))?;
+
+            // EOS or an end-of-turn token; once a grammar is complete only these are allowed
+            if model.is_eog_token(next_token) {
+                finish_reason = Some(FinishReason::Eos);
+                break;
+            }
+
+            if let Some(top_n) = options.logprobs {
+                logprobs.push(self.token_logprob(&candidates, next_token, top_n.min(MAX_LOGPROBS) as usize)?);
+            }
+            response_tokens.push(next_token);
+            sampler.accept(next_token.0);
+            if let Some(grammar) = grammar.as_mut() {
+                grammar.accept(next_token);
+            }
+
+            let bytes = model.token_to_bytes(next_token, Special::Plaintext)
+                .map_err(|e| EngineError::Runtime(format!("Detokenization failed: {}", e)))?;
+            output_string.push_str(&utf8.push(&bytes));
+            if let Some((at, sequence)) = stop_sequences.find(&output_string) {
+                finish_reason = Some(FinishReason::Stop { sequence: sequence.to_string() });
+                output_string.truncate(at);
+                break;
+            }
+            let safe = stop_sequences.safe_len(&output_string);
+            if !stream_text(tx, &output_string, &mut streamed, safe) {
+                // Receiver dropped: nobody is listening any more
+                finish_reason = Some(FinishReason::Cancelled);
+                break;
+            }
+
+            batch.clear();
+            batch.add(next_token, current_pos, &[0], true)
+                 .map_err(|e| EngineError::Runtime(format!("Batch add failed in loop: {}", e)))?;
+
+            current_pos += 1;
+
+            self.ctx.decode(&mut batch)
+                .map_err(|e| EngineError::Runtime(format!("Decode loop failed: {}", e)))?;
+            self.cached.push(next_token);
+        }
+
+        // Past a stop sequence the rest is dropped anyway
+        if !matches!(finish_reason, Some(FinishReason::Stop { .. })) {
+            output_string.push_str(&utf8.finish());
+        }
+        // Whatever was held back didn't turn into a stop sequence after all
+        stream_text(tx, &output_string, &mut streamed, output_string.len());
+        let generation = generation_start.elapsed();
+        if context_shifts > 0 {
+            // What followed the kept tokens was evaluated with context that's gone now, so
+            // it mustn't be reused as if it were a plain prefix
+            self.truncate_cache(n_keep);
+        }
+
+        let output_tokens_count = response_tokens.len() as u32;
+        let total_tokens_count = input_tokens_count + output_tokens_count;
+        let duration_ms = start_time.elapsed().as_millis() as u64;
+
+        Ok(InferenceResult {
+            text: output_string,
+            usage: Usage {
+                input_tokens: input_tokens_count,
+                output_tokens: output_tokens_count,
+                total_tokens: total_tokens_count,
+                cached_tokens: cached_tokens as u32,
+                cached_prefix_tokens: cached_prefix_tokens as u32,
+                // Filled in by the engine, which did the injecting
+                memory_tokens: 0,
+                duration_ms,
+                prompt_eval_ms: prompt_eval.as_millis() as u64,
+                generation_ms: generation.as_millis() as u64,
+                tokens_per_second: tokens_per_second(output_tokens_count, generation),
+                context_shifts,
+                // Filled in by the engine, which did the truncating
+                prompt_tokens_dropped: 0,
+            },
+            finish_reason: finish_reason.expect("set before leaving the loop"),
+            seed: Some(seed),
+            logprobs: options.logprobs.map(|_| Logprobs { content: logprobs }),
+        })
+    }
+
+    /// Generate a completion of each of `prompts`, up to `MAX_SEQUENCES` at a time.
+    fn generate_batch(&mut self, prompts: &[String], options: InferenceOptions) -> Result<Vec<InferenceResult>, EngineError> {
+        let mut results = Vec::with_capacity(prompts.len());
+        for group in prompts.chunks(MAX_SEQUENCES.min(self.n_batch as usize)) {
+            results.extend(self.run_batch(group, &options)?);
+        }
+        Ok(results)
+    }
+
+    /// Generate `prompts` together, one sequence each, in a context made for the batch:
+    /// every sequence gets room for its prompt and `max_tokens`, up to `n_ctx`. The
+    /// context isn't shifted when a sequence fills its share; it ends with `Length`.
+    /// Nothing is cached between batches.
+    fn run_batch(&mut self, prompts: &[String], options: &InferenceOptions) -> Result<Vec<InferenceResult>, EngineError> {
+        let start_time = Instant::now();
+        let model = self.model;
+        let max_gen_tokens = options.max_tokens.unwrap_or(128);
+        let max_time_ms = options.max_time_ms.unwrap_or(30000);
+        let stop_sequences = StopSequences::new(&options.stop_sequences);
+
+        let mut sequences = Vec::with_capacity(prompts.len());
+        for prompt in prompts {
+            let tokens = model.str_to_token(prompt, AddBos::Always)
+                .map_err(|e| EngineError::Runtime(format!("Tokenization failed: {}", e)))?;
+            if tokens.len() > self.n_ctx as usize {
+                return Err(EngineError::ContextOverflow { tokens: tokens.len(), context_size: self.n_ctx as usize });
+            }
+            let seed = options.seed.unwrap_or_else(sampling::random_seed);
+            sequences.push(Sequence {
+                sampler: self.sampler(options, seed, &tokens)?,
+                grammar: self.grammar(options)?,
+                seed,
+                output: Vec::new(),
+                text: String::new(),
+                utf8: Utf8Buffer::new(),
+                logprobs: Vec::new(),
+                pos: tokens.len() as i32,
+                logits_at: 0,
+                finish_reason: None,
+                prompt: tokens,
+            });
+        }
+        if sequences.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let longest = sequences.iter().map(|sequence| sequence.prompt.len()).max().unwrap_or(0);
+        let per_sequence = (longest + max_gen_tokens as usize).min(self.n_ctx as usize) as u32;
+        let n_seq = sequences.len() as u32;
+        let n_batch = self.n_batch.min(per_sequence * n_seq);
+        let params = context_params(per_sequence * n_seq, n_batch, self.requested_threads(options), self.kv_cache_type)
+            .with_n_seq_max(n_seq);
+        let mut ctx = model.new_context(self.backend, params)
+            .map_err(|e| EngineError::Runtime(format!("Failed to create batch context: {}", e)))?;
+
+        // Every prompt token but the last, without logits, then the last ones together, so
+        // all sequences have their logits in the same batch
+        let prompt_eval_start = Instant::now();
+        let mut batch = LlamaBatch::new(n_batch as usize, n_seq as i32);
+        let leading: Vec<(LlamaToken, i32, i32)> = sequences.iter().enumerate()
+            .flat_map(|(seq, sequence)| {
+                let n = sequence.prompt.len() - 1;
+                sequence.prompt[..n].iter().enumerate().map(move |(pos, token)| (*token, pos as i32, seq as i32))
+            })
+            .collect();
+        let mut cancelled = false;
+        for chunk in leading.chunks(n_batch as usize) {
+            if options.cancel.is_cancelled() {
+                cancelled = true;
+                break;
+            }
+            batch.clear();
+            for &(token, pos, seq) in chunk {
+                batch.add(token, pos, &[seq], false)
+                    .map_err(|e| EngineError::Runtime(format!("Batch add failed: {}", e)))?;
+            }
+            ctx.decode(&mut batch)
+                .map_err(|e| EngineError::Runtime(format!("Decode failed: {}", e)))?;
+        }
+        if !cancelled {
+            batch.clear();
+            for (seq, sequence) in sequences.iter_mut().enumerate() {
+                sequence.logits_at = batch.n_tokens();
+                let last = sequence.prompt.len() - 1;
+                batch.add(sequence.prompt[last], last as i32, &[seq as i32], true)
+                    .map_err(|e| EngineError::Runtime(format!("Batch add failed: {}", e)))?;
+            }
+            ctx.decode(&mut batch)
+                .map_err(|e| EngineError::Runtime(format!("Decode failed: {}", e)))?;
+        }
+        let prompt_eval = prompt_eval_start.elapsed();
+
+        // Each step samples a token for every unfinished sequence and decodes them together
+        let generation_start = Instant::now();
+        loop {
+            let stopped = if cancelled || options.cancel.is_cancelled() {
+                Some(FinishReason::Cancelled)
+            } else if time_is_up(start_time, max_time_ms) {
+                Some(FinishReason::Time)
+            } else {
+                None
+            };
+
+            batch.clear();
+            for (seq, sequence) in sequences.iter_mut().enumerate() {
+                if sequence.finish_reason.is_some() {
+                    continue;
+                }
+                if let Some(reason) = &stopped {
+                    sequence.finish_reason = Some(reason.clone());
+                    continue;
+                }
+                if sequence.output.len() as u32 >= max_gen_tokens || sequence.pos as u32 >= per_sequence {
+                    sequence.finish_reason = Some(FinishReason::Length);
+                    continue;
+                }
+
+                let candidates = candidates(&ctx, sequence.logits_at, sequence.grammar.as_ref());
+                let next_token = sequence.sampler.sample(&candidates)
+                    .map(LlamaToken)
+                    .ok_or_else(|| EngineError::Runtime("No candidates found".to_string()))?;
+                if model.is_eog_token(next_token) {
+                    sequence.finish_reason = Some(FinishReason::Eos);
+                    continue;
+                }
+
+                if let Some(top_n) = options.logprobs {
+                    sequence.logprobs.push(self.token_logprob(&candidates, next_token, top_n.min(MAX_LOGPROBS) as usize)?);
+                }
+                sequence.output.push(next_token);
+                sequence.sampler.accept(next_token.0);
+                if let Some(grammar) = sequence.grammar.as_mut() {
+                    grammar.accept(next_token);
+                }
+
+                let bytes = model.token_to_bytes(next_token, Special::Plaintext)
+                    .map_err(|e| EngineError::Runtime(format!("Detokenization failed: {}", e)))?;
+                sequence.text.push_str(&sequence.utf8.push(&bytes));
+                if let Some((at, stop)) = stop_sequences.find(&sequence.text) {
+                    sequence.finish_reason = Some(FinishReason::Stop { sequence: stop.to_string() });
+                    sequence.text.truncate(at);
+                    continue;
+                }
+
+                sequence.logits_at = batch.n_tokens();
+                batch.add(next_token, sequence.pos, &[seq as i32], true)
+                    .map_err(|e| EngineError::Runtime(format!("Batch add failed in loop: {}", e)))?;
+                sequence.pos += 1;
+            }
+
+            if batch.n_tokens() == 0 {
+                break;
+            }
+            ctx.decode(&mut batch)
+                .map_err(|e| EngineError::Runtime(format!("Decode loop failed: {}", e)))?;
+        }
+        let generation = generation_start.elapsed();
+        let duration_ms = start_time.elapsed().as_millis() as u64;
+
+        Ok(sequences.into_iter().map(|mut sequence| {
+            let finish_reason = sequence.finish_reason.expect("every sequence finishes before the loop ends");
+            if !matches!(finish_reason, FinishReason::Stop { .. }) {
+                sequence.text.push_str(&sequence.utf8.finish());
+            }
+            let input_tokens = sequence.prompt.len() as u32;
+            let output_tokens = sequence.output.len() as u32;
+            InferenceResult {
+                text: sequence.text,
+                usage: Usage {
+                    input_tokens,
+                    output_tokens,
+                    total_tokens: input_tokens + output_tokens,
+                    cached_tokens: 0,
+                    cached_prefix_tokens: 0,
+                    memory_tokens: 0,
+                    duration_ms,
+                    prompt_eval_ms: prompt_eval.as_millis() as u64,
+                    generation_ms: generation.as_millis() as u64,
+                    tokens_per_second: tokens_per_second(output_tokens, generation),
+                    context_shifts: 0,
+                    prompt_tokens_dropped: 0,
+                },
+                finish_reason,
+                seed: Some(sequence.seed),
+                logprobs: options.logprobs.map(|_| Logprobs { content: sequence.logprobs }),
+            }
+        }).collect())
+    }
+}
+
+/// One prompt of a batch and the output generated for it so far.
+struct Sequence {
+    prompt: Vec<LlamaToken>,
+    sampler: Sampler,
+    grammar: Option<LlamaSampler>,
+    seed: u64,
+    output: Vec<LlamaToken>,
+    text: String,
+    utf8: Utf8Buffer,
+    logprobs: Vec<TokenLogprob>,
+    /// Position of the next token in the sequence.
+    pos: i32,
+    /// Where the sequence's logits are in the last decoded batch.
+    logits_at: i32,
+    finish_reason: Option<FinishReason>,
+}
+
+/// The candidates for the token following position `i` of the last decoded batch, without
+/// those `grammar` rules out.
+fn candidates(ctx: &LlamaContext, i: i32, grammar: Option<&LlamaSampler>) -> Vec<Candidate> {
+    let mut token_data = ctx.token_data_array_ith(i);
+    if let Some(grammar) = grammar {
+        token_data.apply_sampler(grammar);
+    }
+    token_data.data.iter()
+        .filter(|c| c.logit() > f32::NEG_INFINITY)
+        .map(|c| Candidate { id: c.id().0, logit: c.logit() })
+        .collect()
+}
+
+fn context_params(n_ctx: u32, n_batch: u32, threads: Threads, kv_cache_type: KvCacheType) -> LlamaContextParams {
+    let clamp = |n: usize| i32::try_from(n).unwrap_or(i32::MAX).max(1);
+    let cache_type = match kv_cache_type {
+        KvCacheType::F16 => params::KvCacheType::F16,
+        KvCacheType::Q8 => params::KvCacheType::Q8_0,
+        KvCacheType::Q4 => params::KvCacheType::Q4_0,
+    };
+    LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(n_ctx))
+        .with_n_batch(n_batch)
+        .with_n_threads(clamp(threads.n_threads))
+        .with_n_threads_batch(clamp(threads.n_threads_batch))
+        .with_type_k(cache_type)
+        .with_type_v(cache_type)
+}
+
+/// Scale `embedding` to unit length, so a dot product is the cosine similarity.
+fn normalize(embedding: &[f32]) -> Vec<f32> {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return embedding.to_vec();
+    }
+    embedding.iter().map(|x| x / norm).collect()
+}
+
+fn common_prefix_len(a: &[LlamaToken], b: &[LlamaToken]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
@@ -0,0 +1,14 @@
+//! Creating runtimes. llama.cpp initializes its backend once per process, so runtimes share it.
+
+use lie_runtime_llamacpp::LlamaCppRuntime;
+
+#[test]
+fn test_runtimes_can_be_created_repeatedly() {
+    let first = LlamaCppRuntime::new().unwrap();
+    let second = LlamaCppRuntime::new().unwrap();
+    drop(first);
+    drop(second);
+
+    // The backend was freed with the last runtime; a new one initializes it again
+    LlamaCppRuntime::new().unwrap();
+}
@@ -0,0 +1,48 @@
+//! Batched generation against serial generation. Needs a real model: set `CELA_TEST_MODEL`
+//! to run. With `--nocapture` it prints how long each took, as a rough benchmark:
+//!
+//! ```text
+//! CELA_TEST_MODEL=models/tiny.gguf cargo test --release -p lie-runtime-llamacpp --test batch -- --nocapture
+//! ```
+
+use lie_core::runtime::{InferenceOptions, KvCacheType, ModelLoadConfig, ModelRuntime};
+use lie_runtime_llamacpp::LlamaCppRuntime;
+use std::path::PathBuf;
+use std::time::Instant;
+
+#[tokio::test]
+async fn test_batch_matches_serial() {
+    let Some(model_path) = std::env::var_os("CELA_TEST_MODEL").map(PathBuf::from) else { return };
+
+    let mut runtime = LlamaCppRuntime::new().unwrap();
+    runtime.load(&ModelLoadConfig { model_path, context_size: 2048, gpu_layers: 0, batch_size: 512, n_threads: None, n_threads_batch: None, use_mmap: true, use_mlock: false, kv_cache_type: KvCacheType::F16 }).await.unwrap();
+
+    let prompts: Vec<String> = ["great", "awful", "fine", "terrible", "wonderful", "boring", "okay", "superb"]
+        .iter()
+        .map(|word| format!("Is the word \"{}\" positive or negative? Answer:", word))
+        .collect();
+    let options = InferenceOptions { max_tokens: Some(16), max_time_ms: None, reset_context: true, ..Default::default() };
+
+    let started = Instant::now();
+    let mut serial = Vec::new();
+    for prompt in &prompts {
+        serial.push(runtime.infer(prompt, options.clone()).await.unwrap());
+    }
+    let serial_time = started.elapsed();
+
+    let started = Instant::now();
+    let batched = runtime.infer_batch(&prompts, options).await.unwrap();
+    let batched_time = started.elapsed();
+
+    let tokens: u32 = batched.iter().map(|result| result.usage.output_tokens).sum();
+    println!("{} prompts, {} tokens: serial {:?}, batched {:?}", prompts.len(), tokens, serial_time, batched_time);
+
+    assert_eq!(batched.len(), prompts.len());
+    for (serial, batched) in serial.iter().zip(&batched) {
+        assert_eq!(batched.usage.input_tokens, serial.usage.input_tokens);
+        // Batches sum in a different order, so greedy outputs can drift apart eventually;
+        // they should at least start out the same
+        let first = |text: &str| text.split_whitespace().next().map(str::to_string);
+        assert_eq!(first(&batched.text), first(&serial.text), "{:?} vs {:?}", batched.text, serial.text);
+    }
+}
@@ -0,0 +1,82 @@
+//! KV cache reuse across calls. Needs a real model: set `CELA_TEST_MODEL` to run.
+
+use lie_core::runtime::{InferenceOptions, KvCacheType, ModelLoadConfig, ModelRuntime};
+use lie_runtime_llamacpp::LlamaCppRuntime;
+use std::path::PathBuf;
+
+#[tokio::test]
+async fn test_common_prefix_is_reused() {
+    let Some(model_path) = std::env::var_os("CELA_TEST_MODEL").map(PathBuf::from) else { return };
+
+    let mut runtime = LlamaCppRuntime::new().unwrap();
+    runtime.load(&ModelLoadConfig { model_path, context_size: 2048, gpu_layers: 0, batch_size: 512, n_threads: None, n_threads_batch: None, use_mmap: true, use_mlock: false, kv_cache_type: KvCacheType::F16 }).await.unwrap();
+
+    let options = InferenceOptions { max_tokens: Some(4), ..Default::default() };
+    let first = runtime.infer("The quick brown fox jumps over the lazy dog.", options.clone()).await.unwrap();
+    assert_eq!(first.usage.cached_tokens, 0);
+
+    let second = runtime.infer("The quick brown fox jumps over the lazy dog. Again!", options.clone()).await.unwrap();
+    assert!(second.usage.cached_tokens > 0);
+    assert!(second.usage.cached_tokens < second.usage.input_tokens);
+
+    let reset = InferenceOptions { reset_context: true, ..options };
+    let third = runtime.infer("The quick brown fox jumps over the lazy dog. Again!", reset).await.unwrap();
+    assert_eq!(third.usage.cached_tokens, 0);
+
+    // A fixed seed reproduces the sampled output, whatever is cached
+    let seeded = InferenceOptions { max_tokens: Some(16), temperature: Some(1.0), seed: Some(1234), ..Default::default() };
+    let a = runtime.infer("Once upon a time", seeded.clone()).await.unwrap();
+    let b = runtime.infer("Once upon a time", seeded).await.unwrap();
+    assert_eq!(a.text, b.text);
+    assert_eq!(a.seed, Some(1234));
+
+    let unseeded = runtime.infer("Once upon a time", InferenceOptions::default()).await.unwrap();
+    assert!(unseeded.seed.is_some());
+
+    let tokens = runtime.tokenize("Hello world", true).unwrap();
+    assert_eq!(runtime.count_tokens("Hello world").unwrap(), tokens.len());
+    assert_eq!(runtime.tokenize("Hello world", false).unwrap().len(), tokens.len() - 1);
+    assert_eq!(runtime.token_pieces(&tokens[1..]).unwrap().concat().trim_start(), "Hello world");
+
+    // Embeddings use their own context and are unit length
+    let texts = vec!["The quick brown fox".to_string(), "A slow green turtle".to_string()];
+    let embeddings = runtime.embed(&texts).await.unwrap();
+    assert_eq!(embeddings.len(), 2);
+    let norm: f32 = embeddings[0].iter().map(|x| x * x).sum::<f32>().sqrt();
+    assert!((norm - 1.0).abs() < 1e-3);
+    assert_ne!(embeddings[0], embeddings[1]);
+
+    // ...and leave the generation cache alone
+    let again = runtime.infer("Once upon a time", InferenceOptions { max_tokens: Some(1), ..Default::default() }).await.unwrap();
+    assert!(again.usage.cached_tokens > 0);
+}
+
+#[tokio::test]
+async fn test_shared_prefix_is_restored() {
+    let Some(model_path) = std::env::var_os("CELA_TEST_MODEL").map(PathBuf::from) else { return };
+
+    let mut runtime = LlamaCppRuntime::new().unwrap();
+    runtime.load(&ModelLoadConfig { model_path, context_size: 2048, gpu_layers: 0, batch_size: 512, n_threads: None, n_threads_batch: None, use_mmap: true, use_mlock: false, kv_cache_type: KvCacheType::F16 }).await.unwrap();
+
+    let system = "You are a helpful assistant. Answer briefly and precisely, in plain English, without \
+        lists or headings. If you don't know the answer, say so instead of guessing. ".repeat(2);
+    let options = InferenceOptions { max_tokens: Some(2), ..Default::default() };
+    let ask = |question: &str| format!("{}\nUser: {}\nAssistant:", system, question);
+
+    let first = runtime.infer(&ask("What is Rust?"), options.clone()).await.unwrap();
+    assert_eq!(first.usage.cached_tokens, 0);
+    let second = runtime.infer(&ask("What is a borrow checker?"), options.clone()).await.unwrap();
+    assert!(second.usage.input_tokens - second.usage.cached_tokens < second.usage.input_tokens / 4);
+
+    // Another prompt takes over the cache; the shared prefix comes back from the saved state
+    runtime.infer("Once upon a time", options.clone()).await.unwrap();
+    let third = runtime.infer(&ask("What is cargo?"), options.clone()).await.unwrap();
+    assert!(third.usage.cached_prefix_tokens > 0);
+    assert_eq!(third.usage.cached_tokens, third.usage.cached_prefix_tokens);
+    assert!(third.usage.input_tokens - third.usage.cached_tokens < third.usage.input_tokens / 4);
+
+    // Resetting the context forgets it too
+    runtime.infer("Once upon a time", options.clone()).await.unwrap();
+    let reset = runtime.infer(&ask("What is cargo?"), InferenceOptions { reset_context: true, ..options }).await.unwrap();
+    assert_eq!(reset.usage.cached_tokens, 0);
+}
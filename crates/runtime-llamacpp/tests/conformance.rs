@@ -0,0 +1,24 @@
+//! The shared `ModelRuntime` checks. Needs a real model: set `CELA_TEST_MODEL` to run.
+
+use lie_core::runtime::{conformance, KvCacheType, ModelLoadConfig, ModelRuntime};
+use lie_runtime_llamacpp::LlamaCppRuntime;
+use std::path::PathBuf;
+
+#[tokio::test]
+async fn test_conformance() {
+    let Some(model_path) = std::env::var_os("CELA_TEST_MODEL").map(PathBuf::from) else { return };
+
+    let mut runtime = LlamaCppRuntime::new().unwrap();
+    runtime.load(&ModelLoadConfig {
+        model_path,
+        context_size: 2048,
+        gpu_layers: 0,
+        batch_size: 512,
+        n_threads: None,
+        n_threads_batch: None,
+        use_mmap: true,
+        use_mlock: false,
+        kv_cache_type: KvCacheType::F16,
+    }).await.unwrap();
+    conformance::check_all(&mut runtime, "The capital of France is").await;
+}
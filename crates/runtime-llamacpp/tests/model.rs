@@ -0,0 +1,44 @@
+//! Tests that need a real GGUF model on disk.
+//! Set `CELA_TEST_MODEL` to the model path to run them; otherwise they are skipped.
+
+use lie_core::runtime::{InferenceOptions, KvCacheType, ModelLoadConfig, ModelRuntime};
+use lie_runtime_llamacpp::LlamaCppRuntime;
+use std::path::PathBuf;
+
+fn test_model_path() -> Option<PathBuf> {
+    std::env::var_os("CELA_TEST_MODEL").map(PathBuf::from)
+}
+
+/// Requires a model trained with at least a 4096 token context. The prompt is several
+/// times larger than the batch size, so it has to be decoded in chunks.
+#[tokio::test]
+async fn test_context_size_allows_long_prompts() {
+    let Some(model_path) = test_model_path() else { return };
+
+    let mut runtime = LlamaCppRuntime::new().unwrap();
+    let report = runtime.load(&ModelLoadConfig {
+        model_path: model_path.clone(),
+        context_size: 4096,
+        gpu_layers: 0,
+        batch_size: 512,
+        n_threads: None,
+        n_threads_batch: None,
+        use_mmap: true,
+        use_mlock: false,
+        kv_cache_type: KvCacheType::Q8,
+    }).await.unwrap();
+    assert_eq!(report.gpu_layers_offloaded, 0);
+
+    let info = runtime.model_info().expect("model info after load");
+    assert_eq!(info.path, model_path);
+    assert!(info.parameter_count > 0);
+    assert!(info.native_context_size >= 4096);
+    assert_eq!(info.context_size, 4096);
+    assert_eq!(info.kv_cache_type, KvCacheType::Q8);
+
+    let prompt = "hello ".repeat(3000);
+    let options = InferenceOptions { max_tokens: Some(1), ..Default::default() };
+    let result = runtime.infer(&prompt, options).await.unwrap();
+
+    assert!(result.usage.input_tokens > 2048);
+}
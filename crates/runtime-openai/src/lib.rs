@@ -0,0 +1,297 @@
+//! A runtime that forwards inference to an OpenAI-compatible HTTP API (OpenAI itself,
+//! llama.cpp's server, vLLM, Ollama, ...), so the engine, memory, server and CLI can front
+//! a remote model as well as a local one.
+//!
+//! The engine hands runtimes a finished prompt, chat template applied, so requests go to the
+//! legacy `/completions` endpoint, which takes the prompt as is.
+
+mod sse;
+
+use async_trait::async_trait;
+use lie_core::config::OpenAiConfig;
+use lie_core::error::EngineError;
+use lie_core::runtime::{FinishReason, InferenceOptions, InferenceResult, LoadReport, ModelInfo, ModelLoadConfig, ModelRuntime, TokenChunk, Usage};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sse::SseDecoder;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+pub struct OpenAiRuntime {
+    config: OpenAiConfig,
+    client: reqwest::Client,
+    /// The model requests name; `None` until `load`.
+    model: Option<String>,
+    model_info: Option<ModelInfo>,
+}
+
+impl OpenAiRuntime {
+    pub fn new(config: OpenAiConfig) -> Self {
+        Self { config, client: reqwest::Client::new(), model: None, model_info: None }
+    }
+
+    /// Start a `/completions` request and check that it was accepted.
+    async fn send(&self, prompt: &str, options: &InferenceOptions, stream: bool) -> Result<reqwest::Response, EngineError> {
+        let model = self.model.as_deref().ok_or(EngineError::ModelNotLoaded)?;
+        let url = format!("{}/completions", self.config.base_url.trim_end_matches('/'));
+        let mut request = self.client.post(&url).json(&request_body(model, prompt, options, stream));
+        if let Some(key) = &self.config.api_key {
+            request = request.bearer_auth(key);
+        }
+
+        let response = request.send().await.map_err(|e| upstream_error(&url, e))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(EngineError::Upstream {
+                status: Some(status.as_u16()),
+                message: format!("{} answered {}: {}", url, status, error_message(&body)),
+            });
+        }
+        Ok(response)
+    }
+
+    async fn complete(&self, prompt: &str, options: &InferenceOptions) -> Result<InferenceResult, EngineError> {
+        let started = Instant::now();
+        let response = self.send(prompt, options, false).await?;
+        let completion: Completion = response.json().await
+            .map_err(|e| EngineError::Runtime(format!("Failed to parse the completion: {}", e)))?;
+        let choice = completion.choices.into_iter().next()
+            .ok_or_else(|| EngineError::Runtime("The completion has no choices".to_string()))?;
+        Ok(result(choice.text, completion.usage, finish_reason(choice.finish_reason.as_deref()), started.elapsed()))
+    }
+
+    /// Stream a completion, sending each piece of text through `tx` as its event arrives.
+    async fn stream(&self, prompt: &str, options: &InferenceOptions, tx: &mpsc::UnboundedSender<TokenChunk>) -> Result<InferenceResult, EngineError> {
+        let started = Instant::now();
+        let mut response = self.send(prompt, options, true).await?;
+        let mut decoder = SseDecoder::default();
+        let mut text = String::new();
+        let mut usage = None;
+        let mut finish = None;
+
+        'events: loop {
+            let chunk = tokio::select! {
+                chunk = response.chunk() => chunk.map_err(|e| upstream_error("the stream", e))?,
+                _ = options.cancel.cancelled() => {
+                    finish = Some(FinishReason::Cancelled);
+                    break;
+                }
+            };
+            let Some(chunk) = chunk else { break };
+            for data in decoder.push(&chunk) {
+                if data == "[DONE]" {
+                    break 'events;
+                }
+                let event: Completion = serde_json::from_str(&data)
+                    .map_err(|e| EngineError::Runtime(format!("Failed to parse a stream event: {}", e)))?;
+                usage = event.usage.or(usage);
+                let Some(choice) = event.choices.into_iter().next() else { continue };
+                if !choice.text.is_empty() {
+                    text.push_str(&choice.text);
+                    if tx.send(TokenChunk::Token { text: choice.text }).is_err() {
+                        // Nobody is listening any more; dropping the response closes the connection
+                        finish = Some(FinishReason::Cancelled);
+                        break 'events;
+                    }
+                }
+                if let Some(reason) = choice.finish_reason {
+                    finish = Some(finish_reason(Some(&reason)));
+                }
+            }
+        }
+
+        let finish = finish.unwrap_or(FinishReason::Eos);
+        Ok(result(text, usage, finish, started.elapsed()))
+    }
+}
+
+#[async_trait]
+impl ModelRuntime for OpenAiRuntime {
+    /// Nothing is downloaded: this only settles the model name. `openai.model` wins over
+    /// `config.model_path`, which is otherwise taken as the name.
+    async fn load(&mut self, config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+        let model = self.config.model.clone()
+            .unwrap_or_else(|| config.model_path.to_string_lossy().into_owned());
+        if model.is_empty() {
+            return Err(EngineError::Config("No model name: set openai.model or model.default_path".to_string()));
+        }
+        let context_size = u32::try_from(config.context_size).unwrap_or(u32::MAX);
+        self.model_info = Some(ModelInfo {
+            name: model.clone(),
+            architecture: "openai".to_string(),
+            parameter_count: 0,
+            quantization: "unknown".to_string(),
+            native_context_size: context_size,
+            vocab_size: 0,
+            file_size: 0,
+            path: config.model_path.clone(),
+            context_size,
+            gpu_layers: 0,
+            n_threads: 0,
+            n_threads_batch: 0,
+            use_mmap: false,
+            use_mlock: false,
+            kv_cache_type: config.kv_cache_type,
+            loaded_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        });
+        self.model = Some(model);
+        Ok(LoadReport { gpu_layers_requested: config.gpu_layers, gpu_layers_offloaded: 0 })
+    }
+
+    async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+        let limit = options.max_time_ms;
+        let completion = self.complete(prompt, &options);
+        tokio::select! {
+            result = with_time_limit(limit, completion) => result,
+            _ = options.cancel.cancelled() => Ok(result(String::new(), None, FinishReason::Cancelled, Duration::ZERO)),
+        }
+    }
+
+    async fn infer_stream(&mut self, prompt: &str, options: InferenceOptions, tx: mpsc::UnboundedSender<TokenChunk>) -> Result<InferenceResult, EngineError> {
+        let result = self.stream(prompt, &options, &tx).await?;
+        let _ = tx.send(TokenChunk::done(&result));
+        Ok(result)
+    }
+
+    async fn unload(&mut self) -> Result<(), EngineError> {
+        self.model = None;
+        self.model_info = None;
+        Ok(())
+    }
+
+    fn model_info(&self) -> Option<ModelInfo> {
+        self.model_info.clone()
+    }
+}
+
+/// Run `request`, giving up with `EngineError::Timeout` after `limit_ms`.
+async fn with_time_limit<F>(limit_ms: Option<u64>, request: F) -> Result<InferenceResult, EngineError>
+where
+    F: std::future::Future<Output = Result<InferenceResult, EngineError>>,
+{
+    match limit_ms {
+        Some(ms) => tokio::time::timeout(Duration::from_millis(ms), request).await
+            .unwrap_or(Err(EngineError::Timeout(ms))),
+        None => request.await,
+    }
+}
+
+/// A completion response, or one event of a streamed one.
+#[derive(Deserialize)]
+struct Completion {
+    #[serde(default)]
+    choices: Vec<Choice>,
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    #[serde(default)]
+    text: String,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CompletionUsage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+}
+
+/// The `/completions` body for `options`. Options without an equivalent in the API, like
+/// grammars and `top_k`, are left out.
+fn request_body(model: &str, prompt: &str, options: &InferenceOptions, stream: bool) -> Value {
+    let mut body = json!({ "model": model, "prompt": prompt, "stream": stream });
+    let fields = [
+        ("max_tokens", options.max_tokens.map(Value::from)),
+        ("temperature", options.temperature.map(Value::from)),
+        ("top_p", options.top_p.map(Value::from)),
+        ("frequency_penalty", options.frequency_penalty.map(Value::from)),
+        ("presence_penalty", options.presence_penalty.map(Value::from)),
+        ("seed", options.seed.map(Value::from)),
+        ("stop", (!options.stop_sequences.is_empty()).then(|| Value::from(options.stop_sequences.clone()))),
+        // Without this, streams don't report usage
+        ("stream_options", stream.then(|| json!({ "include_usage": true }))),
+    ];
+    for (name, value) in fields {
+        if let Some(value) = value {
+            body[name] = value;
+        }
+    }
+    body
+}
+
+/// The API reports `stop` for both the end of the text and a stop sequence, without saying
+/// which sequence, so both come back as `Eos`.
+fn finish_reason(reason: Option<&str>) -> FinishReason {
+    match reason {
+        Some("length") => FinishReason::Length,
+        _ => FinishReason::Eos,
+    }
+}
+
+fn result(text: String, usage: Option<CompletionUsage>, finish_reason: FinishReason, elapsed: Duration) -> InferenceResult {
+    let (input_tokens, output_tokens) = usage.map_or((0, 0), |u| (u.prompt_tokens, u.completion_tokens));
+    let duration_ms = elapsed.as_millis() as u64;
+    InferenceResult {
+        text,
+        usage: Usage {
+            input_tokens,
+            output_tokens,
+            total_tokens: input_tokens.saturating_add(output_tokens),
+            duration_ms,
+            generation_ms: duration_ms,
+            tokens_per_second: if duration_ms == 0 { 0.0 } else { output_tokens as f64 * 1000.0 / duration_ms as f64 },
+            ..Usage::default()
+        },
+        finish_reason,
+        seed: None,
+        logprobs: None,
+    }
+}
+
+fn upstream_error(what: &str, e: reqwest::Error) -> EngineError {
+    EngineError::Upstream {
+        status: e.status().map(|s| s.as_u16()),
+        message: format!("Failed to reach {}: {}", what, e),
+    }
+}
+
+/// `error.message` of an OpenAI-style error body, or the body itself.
+fn error_message(body: &str) -> String {
+    serde_json::from_str::<Value>(body).ok()
+        .and_then(|v| v["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| body.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_body() {
+        let options = InferenceOptions {
+            max_tokens: Some(16),
+            temperature: Some(0.5),
+            top_k: Some(40),
+            stop_sequences: vec!["\n\n".to_string()],
+            ..Default::default()
+        };
+        let body = request_body("gpt-test", "Hi", &options, true);
+        assert_eq!(body, json!({
+            "model": "gpt-test",
+            "prompt": "Hi",
+            "stream": true,
+            "max_tokens": 16,
+            "temperature": 0.5,
+            "stop": ["\n\n"],
+            "stream_options": { "include_usage": true },
+        }));
+    }
+
+    #[test]
+    fn test_error_message() {
+        assert_eq!(error_message(r#"{"error": {"message": "Invalid API key", "type": "auth"}}"#), "Invalid API key");
+        assert_eq!(error_message("Bad Gateway\n"), "Bad Gateway");
+    }
+}
@@ -0,0 +1,38 @@
+//! Server-sent events, as far as OpenAI-style streams use them: `data:` lines only.
+
+/// Splits a byte stream into the `data` payloads of its events.
+#[derive(Default)]
+pub(crate) struct SseDecoder {
+    buffer: Vec<u8>,
+}
+
+impl SseDecoder {
+    /// Add `bytes` and return the payloads of the events they completed.
+    pub(crate) fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            // Comments, `event:`, `id:` and the blank lines between events carry nothing we use
+            if let Some(data) = line.strip_prefix("data:") {
+                events.push(data.strip_prefix(' ').unwrap_or(data).to_string());
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_split_across_chunks() {
+        let mut decoder = SseDecoder::default();
+        assert!(decoder.push(b"data: {\"a\"").is_empty());
+        assert_eq!(decoder.push(b": 1}\r\n\r\n: keep-alive\n\ndata:[DONE]\n\n"), vec!["{\"a\": 1}", "[DONE]"]);
+        assert_eq!(decoder.push("data: é\n".as_bytes()), vec!["é"]);
+    }
+}
@@ -0,0 +1,119 @@
+//! The runtime against a stand-in for an OpenAI-compatible server.
+
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use lie_core::config::OpenAiConfig;
+use lie_core::error::EngineError;
+use lie_core::runtime::{FinishReason, InferenceOptions, ModelLoadConfig, ModelRuntime, TokenChunk};
+use lie_runtime_openai::OpenAiRuntime;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+/// Answers like `/v1/completions`: the model `busy` gets a 503, anything else the prompt
+/// back in upper case, whole or as two events.
+async fn completions(headers: HeaderMap, Json(body): Json<Value>) -> Response {
+    if headers.get("authorization").and_then(|v| v.to_str().ok()) != Some("Bearer sk-test") {
+        return (StatusCode::UNAUTHORIZED, Json(json!({"error": {"message": "Invalid API key"}}))).into_response();
+    }
+    if body["model"] == "busy" {
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(json!({"error": {"message": "Overloaded"}}))).into_response();
+    }
+    let text = body["prompt"].as_str().unwrap().to_uppercase();
+    let usage = json!({"prompt_tokens": 3, "completion_tokens": 2, "total_tokens": 5});
+    if body["stream"] == true {
+        let (first, second) = text.split_at(text.len() / 2);
+        let events = [
+            json!({"choices": [{"text": first, "finish_reason": null}]}),
+            json!({"choices": [{"text": second, "finish_reason": "length"}]}),
+            json!({"choices": [], "usage": usage}),
+        ];
+        let mut stream: String = events.iter().map(|e| format!("data: {}\n\n", e)).collect();
+        stream.push_str("data: [DONE]\n\n");
+        return ([("content-type", "text/event-stream")], stream).into_response();
+    }
+    Json(json!({"choices": [{"text": text, "finish_reason": "stop"}], "usage": usage})).into_response()
+}
+
+async fn runtime(model: &str) -> OpenAiRuntime {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}/v1", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        axum::serve(listener, Router::new().route("/v1/completions", post(completions))).await.unwrap();
+    });
+
+    let mut runtime = OpenAiRuntime::new(OpenAiConfig {
+        base_url,
+        api_key: Some("sk-test".to_string()),
+        model: Some(model.to_string()),
+    });
+    runtime.load(&ModelLoadConfig {
+        model_path: "models/unused.gguf".into(),
+        context_size: 4096,
+        gpu_layers: 0,
+        batch_size: 512,
+        n_threads: None,
+        n_threads_batch: None,
+        use_mmap: true,
+        use_mlock: false,
+        kv_cache_type: Default::default(),
+    }).await.unwrap();
+    runtime
+}
+
+#[tokio::test]
+async fn test_completion() {
+    let mut runtime = runtime("gpt-test").await;
+    assert_eq!(runtime.model_info().unwrap().name, "gpt-test");
+
+    let result = runtime.infer("hello", InferenceOptions::default()).await.unwrap();
+    assert_eq!(result.text, "HELLO");
+    assert_eq!(result.finish_reason, FinishReason::Eos);
+    assert_eq!((result.usage.input_tokens, result.usage.output_tokens, result.usage.total_tokens), (3, 2, 5));
+}
+
+#[tokio::test]
+async fn test_streamed_completion() {
+    let mut runtime = runtime("gpt-test").await;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let result = runtime.infer_stream("hello", InferenceOptions::default(), tx).await.unwrap();
+    assert_eq!(result.text, "HELLO");
+    assert_eq!(result.finish_reason, FinishReason::Length);
+    assert_eq!(result.usage.total_tokens, 5);
+
+    let mut pieces = Vec::new();
+    while let Ok(chunk) = rx.try_recv() {
+        match chunk {
+            TokenChunk::Token { text } => pieces.push(text),
+            TokenChunk::Done { usage, .. } => assert_eq!(usage.output_tokens, 2),
+            TokenChunk::Error { message } => panic!("{}", message),
+        }
+    }
+    assert_eq!(pieces, vec!["HE", "LLO"]);
+}
+
+#[tokio::test]
+async fn test_errors_keep_the_status() {
+    let mut runtime = runtime("busy").await;
+    let err = runtime.infer("hello", InferenceOptions::default()).await.unwrap_err();
+    assert!(matches!(err, EngineError::Upstream { status: Some(503), .. }), "{:?}", err);
+    assert!(err.retryable());
+    assert!(err.to_string().contains("Overloaded"), "{}", err);
+
+    let mut runtime = OpenAiRuntime::new(OpenAiConfig { base_url: "http://127.0.0.1:1/v1".to_string(), ..OpenAiConfig::default() });
+    runtime.load(&ModelLoadConfig {
+        model_path: "gpt-test".into(),
+        context_size: 4096,
+        gpu_layers: 0,
+        batch_size: 512,
+        n_threads: None,
+        n_threads_batch: None,
+        use_mmap: true,
+        use_mlock: false,
+        kv_cache_type: Default::default(),
+    }).await.unwrap();
+    let err = runtime.infer("hello", InferenceOptions::default()).await.unwrap_err();
+    assert!(matches!(err, EngineError::Upstream { status: None, .. }), "{:?}", err);
+    assert!(err.retryable());
+}
@@ -0,0 +1,88 @@
+//! Serving connections with hyper directly, for what `axum::serve` can't take in axum 0.7:
+//! unix sockets and TLS.
+
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// How long a new connection may take to become ready for HTTP (e.g. the TLS handshake).
+/// Shutdown waits for connections in setup, so a stalled client must not hold it up forever.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A listener `serve` can take connections from.
+pub(crate) trait Accept {
+    type Stream: Send + 'static;
+
+    fn accept(&self) -> impl Future<Output = std::io::Result<Self::Stream>> + Send;
+}
+
+impl Accept for tokio::net::TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    async fn accept(&self) -> std::io::Result<Self::Stream> {
+        tokio::net::TcpListener::accept(self).await.map(|(stream, _)| stream)
+    }
+}
+
+#[cfg(unix)]
+impl Accept for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept(&self) -> std::io::Result<Self::Stream> {
+        tokio::net::UnixListener::accept(self).await.map(|(stream, _)| stream)
+    }
+}
+
+/// Serve `app` on the connections of `listener` until `shutdown` completes, then stop
+/// accepting and wait for the open connections to finish their requests. `handshake` turns
+/// each accepted stream into the one HTTP is spoken over; it runs in the connection's task.
+pub(crate) async fn serve<L, H, F, S>(listener: L, handshake: H, app: Router, shutdown: impl Future<Output = ()>) -> std::io::Result<()>
+where
+    L: Accept,
+    H: Fn(L::Stream) -> F + Clone + Send + 'static,
+    F: Future<Output = std::io::Result<S>> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        let stream = match accepted {
+            Ok(stream) => stream,
+            Err(e) => {
+                // Likely out of file descriptors; give connections time to close, as axum does
+                tracing::warn!("Failed to accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let (handshake, app, watcher) = (handshake.clone(), app.clone(), graceful.watcher());
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => return tracing::debug!("Connection setup failed: {}", e),
+                Err(_) => return tracing::debug!("Connection setup timed out"),
+            };
+            let service = TowerToHyperService::new(app);
+            // HTTP/1 only, as with `axum::serve`; the auto builder is the one whose upgradeable
+            // connections (for `/v1/ws`) can be shut down gracefully
+            let builder = auto::Builder::new(TokioExecutor::new()).http1_only();
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            if let Err(e) = watcher.watch(connection).await {
+                tracing::debug!("Connection ended with an error: {}", e);
+            }
+        });
+    }
+    drop(listener);
+    graceful.shutdown().await;
+    Ok(())
+}
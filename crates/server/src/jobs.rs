@@ -0,0 +1,152 @@
+//! Completions run in the background (`/v1/jobs`): submitting one returns an id right away,
+//! and the client polls for the output so far and fetches the response once it's finished.
+//! Jobs are kept in memory only, so they don't survive a restart.
+
+use lie_core::{Engine, EngineResponse, RequestContext, config::JobsConfig, runtime::{InferenceOptions, TokenChunk}};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    /// Waiting for the model, or still reading the prompt.
+    Queued,
+    /// Generating; `output` grows as it goes.
+    Running,
+    Done,
+    Cancelled,
+    Failed,
+}
+
+impl JobState {
+    pub fn is_finished(self) -> bool {
+        matches!(self, JobState::Done | JobState::Cancelled | JobState::Failed)
+    }
+}
+
+/// What `GET /v1/jobs/:id` reports.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub state: JobState,
+    /// Text generated so far; all of it once the job has finished.
+    pub output: String,
+    /// Unix timestamps (seconds).
+    pub created_at: u64,
+    pub finished_at: Option<u64>,
+}
+
+struct Job {
+    status: JobStatus,
+    /// The completion's response, or its error envelope, once finished.
+    response: Option<EngineResponse>,
+    finished: Option<Instant>,
+    cancel: CancellationToken,
+}
+
+pub(crate) struct JobStore {
+    config: JobsConfig,
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl JobStore {
+    pub(crate) fn new(config: JobsConfig) -> Self {
+        Self { config, jobs: Mutex::new(HashMap::new()) }
+    }
+
+    /// Start running `prompt` in the background, through the engine's queue like any other
+    /// request. `None` if `max_active` jobs are unfinished already.
+    pub(crate) fn submit(self: &Arc<Self>, engine: Arc<Engine>, prompt: String, mut options: InferenceOptions, context: RequestContext) -> Option<JobStatus> {
+        let id = lie_core::random_id();
+        let cancel = options.cancel.clone();
+        let status = JobStatus { id: id.clone(), state: JobState::Queued, output: String::new(), created_at: unix_now(), finished_at: None };
+
+        let mut jobs = self.lock();
+        if jobs.values().filter(|job| !job.status.state.is_finished()).count() >= self.config.max_active {
+            return None;
+        }
+        jobs.insert(id.clone(), Job { status: status.clone(), response: None, finished: None, cancel: cancel.clone() });
+        drop(jobs);
+
+        options.cancel = cancel;
+        let store = self.clone();
+        tokio::spawn(async move {
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let completion = engine.process_request_with_progress(&prompt, options, Some(context), tx);
+            let follow = async {
+                while let Some(chunk) = rx.recv().await {
+                    if let TokenChunk::Token { text } = chunk {
+                        store.update(&id, |job| {
+                            job.status.state = JobState::Running;
+                            job.status.output.push_str(&text);
+                        });
+                    }
+                }
+            };
+            let (result, ()) = tokio::join!(completion, follow);
+
+            let response = result.unwrap_or_else(|e| EngineResponse::from_error(&e));
+            tracing::info!("job {}: {}, {} tokens", id, response.status, response.usage.total_tokens);
+            store.update(&id, |job| {
+                job.status.state = match response.status.as_str() {
+                    "error" => JobState::Failed,
+                    "cancelled" => JobState::Cancelled,
+                    _ => JobState::Done,
+                };
+                job.status.output = response.output.text.clone();
+                job.status.finished_at = Some(unix_now());
+                job.finished = Some(Instant::now());
+                job.response = Some(response);
+            });
+        });
+        Some(status)
+    }
+
+    pub(crate) fn status(&self, id: &str) -> Option<JobStatus> {
+        self.lock().get(id).map(|job| job.status.clone())
+    }
+
+    /// The job's status, and its response once it has finished.
+    pub(crate) fn result(&self, id: &str) -> Option<(JobStatus, Option<EngineResponse>)> {
+        self.lock().get(id).map(|job| (job.status.clone(), job.response.clone()))
+    }
+
+    /// Cancel an unfinished job, which then finishes as `cancelled` with the output it has so
+    /// far, or forget a finished one. `false` if there is no such job.
+    pub(crate) fn cancel(&self, id: &str) -> bool {
+        let mut jobs = self.lock();
+        match jobs.get(id) {
+            Some(job) if job.status.state.is_finished() => {
+                jobs.remove(id);
+                true
+            }
+            Some(job) => {
+                job.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.lock().get_mut(id) {
+            f(job);
+        }
+    }
+
+    /// The jobs, without those finished longer than `retention_secs` ago.
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Job>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let retention = Duration::from_secs(self.config.retention_secs);
+        jobs.retain(|_, job| job.finished.is_none_or(|finished| finished.elapsed() < retention));
+        jobs
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
@@ -1,148 +1,3273 @@
+mod connections;
+mod jobs;
+mod tls;
+#[cfg(unix)]
+mod unix;
+mod ws;
+
 use axum::{
-    extract::{State, Json},
-    routing::{post, get},
+    async_trait,
+    body::Body,
+    extract::{rejection::JsonRejection, ws::WebSocketUpgrade, DefaultBodyLimit, Extension, FromRequest, Path, Request, State, Json},
+    http::{header, Method, StatusCode},
+    middleware::{self, Next},
+    response::{sse::{Event, Sse}, IntoResponse, Response},
+    routing::{delete, get, post},
     Router,
 };
-use lie_core::{Engine, EngineResponse, runtime::InferenceOptions, OutputContent, runtime::Usage};
-use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use lie_core::{Engine, build_info::BuildInfo, EngineResponse, LoadState, RequestContext, chat::ChatMessage, config::{ListenMode, ServerConfig}, error::{EngineError, ErrorCode}, gguf::ModelFile, memory::{validate_namespace, DEFAULT_NAMESPACE}, session::{Session, SessionSummary}, runtime::{InferenceOptions, KvCacheType, ModelInfo, ModelLoadConfig, OverflowStrategy, ResponseFormat, TokenChunk, MAX_LOGPROBS}};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::convert::Infallible;
+use std::future::{Future, IntoFuture};
+use std::sync::{Arc, OnceLock};
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use anyhow::Result;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+use anyhow::{anyhow, Context, Result};
+use arc_swap::ArcSwap;
+use http_body_util::Limited;
+use tokio::net::TcpListener;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use jobs::JobStore;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CompletionRequest {
     pub prompt: String,
     pub limits: Option<RequestLimits>,
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+    /// Memory namespace to inject from; the default namespace when unset.
+    #[serde(default)]
+    pub memory_namespace: Option<String>,
+    /// Classify the prompt's intent and report it in the response's `intent`.
+    #[serde(default)]
+    pub classify_intent: bool,
+    /// System prompt, in place of `model.system_prompt`.
+    #[serde(default)]
+    pub system: Option<String>,
+    /// Report the composed prompt and options in the response's `debug`. Needs
+    /// `server.allow_debug`.
+    #[serde(default)]
+    pub debug: bool,
+    /// Compose the prompt but don't run it. Needs `server.allow_debug`.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Send the output as server-sent events while it's generated (see `stream_completion`).
+    /// `/v1/completion` only.
+    #[serde(default)]
+    pub stream: bool,
+}
+
+impl CompletionRequest {
+    /// Validate the request as `/v1/completion` does and build its inference options. The
+    /// prompt length isn't capped: `server.max_prompt_chars` guards the HTTP API only.
+    pub fn to_options(&self) -> Result<InferenceOptions, String> {
+        validate_request(self, usize::MAX, true)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChatRequest {
+    pub messages: Vec<ChatMessage>,
+    pub limits: Option<RequestLimits>,
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+    #[serde(default)]
+    pub memory_namespace: Option<String>,
+    /// Classify the last user message's intent and report it in the response's `intent`.
+    #[serde(default)]
+    pub classify_intent: bool,
+    /// As on `CompletionRequest`.
+    #[serde(default)]
+    pub debug: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Body of `POST /v1/completions/batch`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchCompletionRequest {
+    pub requests: Vec<CompletionRequest>,
+}
+
+/// Reply of `POST /v1/completions/batch`: one response per request, in the same order. An
+/// item that failed has the error envelope in its place; the batch as a whole still succeeds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchCompletionResponse {
+    pub responses: Vec<EngineResponse>,
+    pub usage: BatchUsage,
+}
+
+/// Totals of a batch.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BatchUsage {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub total_tokens: u32,
+    /// Time the whole batch took, not the sum of its items.
+    pub duration_ms: u64,
+}
+
+/// Body of `POST /v1/sessions`; may be omitted.
+#[derive(Serialize, Deserialize, Default)]
+pub struct CreateSessionRequest {
+    #[serde(default)]
+    pub system: Option<String>,
+}
+
+/// Body of `GET /v1/sessions`.
+#[derive(Serialize, Deserialize)]
+pub struct SessionList {
+    pub sessions: Vec<SessionSummary>,
 }
 
+/// Body of `POST /v1/sessions/:id/messages`: the next user message of the session.
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionMessageRequest {
+    pub message: String,
+    pub limits: Option<RequestLimits>,
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct RequestLimits {
     pub max_tokens: Option<u32>,
     pub max_time_ms: Option<u64>,
     pub temperature: Option<f32>,
+    pub top_k: Option<u32>,
+    pub top_p: Option<f32>,
+    pub min_p: Option<f32>,
+    pub typical_p: Option<f32>,
+    #[serde(default)]
+    pub logit_bias: HashMap<String, f32>,
+    pub seed: Option<u64>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<u32>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    #[serde(default)]
+    pub penalize_prompt: bool,
+    pub context_shift: Option<bool>,
+    pub n_keep: Option<u32>,
+    #[serde(default)]
+    pub on_context_overflow: OverflowStrategy,
+    pub logprobs: Option<u32>,
+    pub n_threads: Option<u32>,
+    pub n_threads_batch: Option<u32>,
+    /// Generation ends before the first of these appears; it isn't part of the output.
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Generate even if the server has a cached response to the same request.
+    #[serde(default)]
+    pub no_cache: bool,
+}
+
+/// What `GET /v1/limits` reports: the largest requests this server accepts, so clients can
+/// split or trim their input up front.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ServerLimits {
+    pub max_body_bytes: usize,
+    pub max_prompt_chars: usize,
+    pub max_batch_size: usize,
+    pub max_tokens: u32,
+    pub max_time_ms: u64,
+    pub max_logprobs: u32,
+    pub max_stop_sequences: usize,
+    pub max_stop_sequence_chars: usize,
+    /// Whether requests may ask for `debug` and `dry_run` (`server.allow_debug`).
+    #[serde(default)]
+    pub allow_debug: bool,
+}
+
+impl From<&ServerConfig> for ServerLimits {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            max_body_bytes: config.max_body_bytes,
+            max_prompt_chars: config.max_prompt_chars,
+            max_batch_size: config.max_batch_size,
+            max_tokens: MAX_TOKENS,
+            max_time_ms: MAX_TIME_MS,
+            max_logprobs: MAX_LOGPROBS,
+            max_stop_sequences: MAX_STOP_SEQUENCES,
+            max_stop_sequence_chars: MAX_STOP_SEQUENCE_CHARS,
+            allow_debug: config.allow_debug,
+        }
+    }
+}
+
+/// The limits of a server, shared with whatever changes them while it runs (see
+/// `Server::limits`). Each request gets the ones current when it arrives.
+#[derive(Clone)]
+pub struct SharedLimits(Arc<ArcSwap<ServerLimits>>);
+
+impl SharedLimits {
+    fn new(config: &ServerConfig) -> Self {
+        Self(Arc::new(ArcSwap::from_pointee(ServerLimits::from(config))))
+    }
+
+    pub fn get(&self) -> ServerLimits {
+        **self.0.load()
+    }
+
+    /// Take up `max_body_bytes`, `max_prompt_chars` and `max_batch_size` from `config`.
+    pub fn reload(&self, config: &ServerConfig) {
+        let mut limits = self.get();
+        limits.max_body_bytes = config.max_body_bytes;
+        limits.max_prompt_chars = config.max_prompt_chars;
+        limits.max_batch_size = config.max_batch_size;
+        self.0.store(Arc::new(limits));
+    }
+}
+
+/// Bounds of `limits.max_tokens` and `limits.max_time_ms`.
+const MAX_TOKENS: u32 = 8192;
+const MAX_TIME_MS: u64 = 300_000;
+/// Bounds of `limits.stop`: how many sequences, and how long each may be.
+const MAX_STOP_SEQUENCES: usize = 8;
+const MAX_STOP_SEQUENCE_CHARS: usize = 64;
+
+#[derive(Serialize, Deserialize)]
+pub struct Fact {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SummaryRequest {
+    pub summary: String,
+}
+
+/// Path parameters of the memory routes. Those under `/v1/memory/namespaces/:ns` name a
+/// namespace; the others are for the default one.
+#[derive(Deserialize)]
+struct MemoryPath {
+    ns: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FactPath {
+    ns: Option<String>,
+    key: String,
+}
+
+fn namespace(ns: &Option<String>) -> &str {
+    ns.as_deref().unwrap_or(DEFAULT_NAMESPACE)
+}
+
+/// Body of successful memory responses. `status` mirrors `EngineResponse::status`;
+/// only the field relevant to the endpoint is present.
+#[derive(Serialize, Deserialize)]
+pub struct MemoryResponse {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fact: Option<Fact>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub facts: Option<Vec<Fact>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespaces: Option<Vec<String>>,
+}
+
+impl MemoryResponse {
+    fn success() -> Self {
+        Self { status: "success".to_string(), fact: None, facts: None, summary: None, namespaces: None }
+    }
+}
+
+/// Body of `/v1/models`, shaped like OpenAI's list-models response.
+/// `data` holds the loaded model, or is empty if none is loaded. `available` lists the
+/// model files in `model.models_dir`, which `POST /v1/admin/model` can switch to.
+#[derive(Serialize, Deserialize)]
+pub struct ModelList {
+    pub object: String,
+    pub data: Vec<ModelEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub available: Vec<ModelFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModelEntry {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub owned_by: String,
+    #[serde(flatten)]
+    pub info: ModelInfo,
+}
+
+/// Body of `POST /v1/admin/model`. Unset sizes keep those of the current model.
+#[derive(Serialize, Deserialize)]
+pub struct LoadModelRequest {
+    pub path: std::path::PathBuf,
+    pub context_size: Option<usize>,
+    pub gpu_layers: Option<usize>,
+    pub batch_size: Option<usize>,
+    pub n_threads: Option<usize>,
+    pub n_threads_batch: Option<usize>,
+    pub use_mmap: Option<bool>,
+    pub use_mlock: Option<bool>,
+    pub kv_cache_type: Option<KvCacheType>,
+    /// Cancel running requests instead of letting them finish before the switch.
+    #[serde(default)]
+    pub cancel_in_flight: bool,
+}
+
+/// Body of `/v1/admin/model`: load state, settings and metadata of the model.
+#[derive(Serialize, Deserialize)]
+pub struct ModelStatus {
+    #[serde(flatten)]
+    pub state: LoadState,
+    pub config: ModelLoadConfig,
+    pub info: Option<ModelInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenizeRequest {
+    pub text: String,
+    /// Prepend the beginning-of-sequence token, as prompts get. Defaults to true.
+    #[serde(default = "default_add_bos")]
+    pub add_bos: bool,
+}
+
+fn default_add_bos() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenizeResponse {
+    pub tokens: Vec<i32>,
+    pub count: usize,
+    /// The text of each token, for debugging tokenization.
+    pub pieces: Vec<String>,
+}
+
+/// `input` of an embeddings request: one text or a list of them.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Body of `/v1/embeddings`, shaped like OpenAI's embeddings request.
+/// `model` is accepted for compatibility and ignored; the loaded model is used.
+#[derive(Serialize, Deserialize)]
+pub struct EmbeddingRequest {
+    pub input: EmbeddingInput,
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EmbeddingList {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Embedding {
+    pub object: String,
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+impl From<ModelInfo> for ModelEntry {
+    fn from(info: ModelInfo) -> Self {
+        Self {
+            id: info.name.clone(),
+            object: "model".to_string(),
+            created: info.loaded_at,
+            owned_by: "local".to_string(),
+            info,
+        }
+    }
 }
 
 pub struct Server {
     engine: Arc<Engine>,
+    config: ServerConfig,
+    limits: SharedLimits,
+    build_info: Arc<BuildInfo>,
+    jobs: Arc<JobStore>,
+    listener: Option<Listener>,
+}
+
+enum Listener {
+    /// With TLS when an acceptor is set.
+    Tcp(TcpListener, Option<tokio_native_tls::TlsAcceptor>),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// Where a `Server` listens: a TCP address, or the path of a unix socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub fn tcp(&self) -> Option<SocketAddr> {
+        match self {
+            ListenAddr::Tcp(addr) => Some(*addr),
+            ListenAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
 }
 
 impl Server {
-    pub fn new(engine: Arc<Engine>) -> Self {
-        Self { engine }
+    pub fn new(engine: Arc<Engine>, config: ServerConfig) -> Self {
+        let jobs = Arc::new(JobStore::new(config.jobs.clone()));
+        let limits = SharedLimits::new(&config);
+        Self { engine, config, limits, build_info: Arc::new(BuildInfo::current()), jobs, listener: None }
     }
 
-    pub async fn run(&self) -> Result<()> {
-        let app = Router::new()
-            .route("/v1/health", get(health_check))
+    /// Report `build_info` from `/v1/version` and `/v1/health` instead of `BuildInfo::current()`,
+    /// which can't know the runtime's features and backend.
+    pub fn with_build_info(mut self, build_info: BuildInfo) -> Self {
+        self.build_info = Arc::new(build_info);
+        self
+    }
+
+    /// The request limits, for changing them while the server runs.
+    pub fn limits(&self) -> SharedLimits {
+        self.limits.clone()
+    }
+
+    pub fn router(&self) -> Router {
+        let api_keys = Arc::new(self.config.api_keys.clone());
+        let metrics = metrics_handle().clone();
+        let limits = self.limits.clone();
+
+        let router = Router::new()
+            .route("/metrics", get(move || render_metrics(metrics)))
             .route("/v1/completion", post(handle_completion))
-            .with_state(self.engine.clone());
+            .route("/v1/completions/batch", post(handle_completion_batch))
+            .route("/v1/chat", post(handle_chat))
+            .route("/v1/jobs", post(submit_job))
+            .route("/v1/jobs/:id", get(get_job).delete(cancel_job))
+            .route("/v1/jobs/:id/result", get(get_job_result))
+            .route("/v1/models", get(list_models))
+            .route("/v1/embeddings", post(create_embeddings))
+            .route("/v1/tokenize", post(tokenize))
+            .route("/v1/memory", delete(clear_memory))
+            .route("/v1/memory/facts", get(list_facts).post(set_fact))
+            .route("/v1/memory/facts/:key", get(get_fact).delete(delete_fact))
+            .route("/v1/memory/summary", get(get_summary).put(set_summary))
+            .route("/v1/memory/namespaces", get(list_namespaces))
+            .route("/v1/memory/namespaces/:ns", delete(clear_memory))
+            .route("/v1/memory/namespaces/:ns/facts", get(list_facts).post(set_fact))
+            .route("/v1/memory/namespaces/:ns/facts/:key", get(get_fact).delete(delete_fact))
+            .route("/v1/memory/namespaces/:ns/summary", get(get_summary).put(set_summary))
+            .route("/v1/sessions", get(list_sessions).post(create_session))
+            .route("/v1/sessions/:id", get(get_session).delete(delete_session))
+            .route("/v1/sessions/:id/messages", post(send_session_message))
+            .route("/v1/ws", get(open_websocket))
+            .route("/v1/admin/model", get(get_model).post(load_model).delete(unload_model))
+            .route("/v1/limits", get(move || async move { Json(limits.get()) }))
+            .route("/v1/version", get(get_version))
+            .route_layer(middleware::from_fn_with_state(api_keys, require_api_key))
+            // The page holds no data and asks for the key itself, so browsers can open it
+            .merge(playground_routes(self.config.playground))
+            // Health and readiness stay reachable without a key, for load balancers and probes
+            .route("/v1/health", get(health_check))
+            .route("/v1/ready", get(readiness_check))
+            .with_state(self.engine.clone())
+            .layer(Extension(self.build_info.clone()))
+            .layer(Extension(self.jobs.clone()))
+            // Bodies are limited by `apply_limits` instead, with the limit current at the time
+            .layer(DefaultBodyLimit::disable())
+            .layer(middleware::from_fn_with_state(self.limits.clone(), apply_limits))
+            .layer(middleware::from_fn(assign_request_id));
+        // Outermost, so preflight requests are answered before auth or body parsing
+        match cors_layer(&self.config) {
+            Some(cors) => router.layer(cors),
+            None => router,
+        }
+    }
 
-        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
-        println!("Server listening on {}", addr);
-        
-        let listener = tokio::net::TcpListener::bind(addr).await?;
-        axum::serve(listener, app).await?;
+    /// Bind the listener to the configured host/port or socket without serving yet.
+    /// `run` calls this itself if it hasn't been done already.
+    pub async fn bind(&mut self) -> Result<ListenAddr> {
+        match self.config.listen {
+            ListenMode::Tcp => self.bind_tcp().await.map(ListenAddr::Tcp),
+            ListenMode::Unix if self.config.tls.is_some() => {
+                Err(EngineError::Config("server.tls is for TCP; unix sockets are served without TLS".to_string()).into())
+            }
+            ListenMode::Unix => self.bind_unix(),
+        }
+    }
 
-        Ok(())
+    #[cfg(unix)]
+    fn bind_unix(&mut self) -> Result<ListenAddr> {
+        let listener = unix::bind(&self.config.socket_path)?;
+        self.listener = Some(Listener::Unix(listener));
+        Ok(ListenAddr::Unix(self.config.socket_path.clone()))
     }
-}
 
-async fn health_check() -> Json<serde_json::Value> {
-    Json(serde_json::json!({
-        "status": "ok",
-        "service": "lie-server",
-        "version": "1.0.0"
-    }))
-}
+    #[cfg(not(unix))]
+    fn bind_unix(&mut self) -> Result<ListenAddr> {
+        Err(anyhow!("server.listen = \"unix\" needs a unix platform; use \"tcp\" here"))
+    }
 
-fn validate_request(payload: &CompletionRequest) -> Result<InferenceOptions, String> {
-    if payload.prompt.trim().is_empty() {
-        return Err("Validation Error: Prompt cannot be empty".to_string());
+    async fn bind_tcp(&mut self) -> Result<SocketAddr> {
+        // Before binding, so a bad certificate fails startup without taking the port
+        let tls = self.config.tls.as_ref().map(tls::acceptor).transpose()?;
+        let host = self.config.host.as_str();
+        let port = self.config.port;
+
+        let addr = tokio::net::lookup_host((host, port)).await
+            .with_context(|| format!("Invalid server host '{}'", host))?
+            .next()
+            .ok_or_else(|| anyhow!("Server host '{}' did not resolve to an address", host))?;
+
+        let listener = TcpListener::bind(addr).await.map_err(|e| match e.kind() {
+            std::io::ErrorKind::AddrInUse => anyhow!("Port {} is already in use on {}", port, host),
+            _ => anyhow!("Failed to bind {}: {}", addr, e),
+        })?;
+
+        let local_addr = listener.local_addr()?;
+        if local_addr.ip().is_unspecified() && self.config.api_keys.is_empty() {
+            tracing::warn!(
+                "Server is bound to {} and reachable from other machines; there is no authentication",
+                local_addr
+            );
+        }
+
+        self.listener = Some(Listener::Tcp(listener, tls));
+        Ok(local_addr)
     }
 
-    let mut options = InferenceOptions::default();
-    if let Some(limits) = &payload.limits {
-        if let Some(mt) = limits.max_tokens {
-            if mt == 0 || mt > 8192 {
-                 return Err("Validation Error: max_tokens must be between 1 and 8192".to_string());
-            }
-            options.max_tokens = Some(mt);
+    /// The TCP address actually bound, once `bind` (or `run`) has been called.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self.listener.as_ref()? {
+            Listener::Tcp(listener, _) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
         }
-        
-        if let Some(mtm) = limits.max_time_ms {
-             if mtm > 300_000 {
-                 return Err("Validation Error: max_time_ms cannot exceed 300000".to_string());
-             }
-             options.max_time_ms = Some(mtm);
+    }
+
+    /// Serve until SIGINT or SIGTERM, then shut down gracefully (see `run_until`).
+    pub async fn run(&mut self) -> Result<()> {
+        self.run_until(shutdown_signal()).await
+    }
+
+    /// Serve until `shutdown` completes. Then stop accepting connections, give in-flight
+    /// requests `shutdown_grace_secs` to finish before cancelling them, and finally shut
+    /// the engine down, which unloads the model.
+    pub async fn run_until(&mut self, shutdown: impl Future<Output = ()> + Send + 'static) -> Result<()> {
+        let addr = self.bind_if_needed().await?;
+        let listener = self.listener.take().expect("listener was just bound");
+        let app = self.router();
+
+        println!("Server listening on {}", addr);
+        if let Some(tls) = &self.config.tls {
+            tracing::info!("Serving HTTPS with the certificate {}", tls.cert_path.display());
         }
 
-        if let Some(temp) = limits.temperature {
-            if temp < 0.0 || temp > 2.0 {
-                return Err("Validation Error: temperature must be between 0.0 and 2.0".to_string());
+        let (stopping_tx, stopping_rx) = tokio::sync::oneshot::channel();
+        let signal = async move {
+            shutdown.await;
+            tracing::info!("Shutting down; waiting for in-flight requests");
+            let _ = stopping_tx.send(());
+        };
+        let serve = async move {
+            match listener {
+                Listener::Tcp(listener, None) => axum::serve(listener, app).with_graceful_shutdown(signal).into_future().await,
+                Listener::Tcp(listener, Some(tls)) => {
+                    let handshake = move |stream| {
+                        let tls = tls.clone();
+                        async move { tls.accept(stream).await.map_err(std::io::Error::other) }
+                    };
+                    connections::serve(listener, handshake, app, signal).await
+                }
+                #[cfg(unix)]
+                Listener::Unix(listener) => connections::serve(listener, |stream| std::future::ready(Ok(stream)), app, signal).await,
             }
-            options.temperature = Some(temp);
+        };
+        tokio::pin!(serve);
+
+        let grace = Duration::from_secs(self.config.shutdown_grace_secs);
+        let grace_over = async {
+            if stopping_rx.await.is_ok() {
+                tokio::time::sleep(grace).await;
+            } else {
+                std::future::pending::<()>().await;
+            }
+        };
+
+        tokio::select! {
+            result = &mut serve => result?,
+            _ = grace_over => {
+                tracing::warn!("Shutdown grace period over; cancelling in-flight requests");
+                self.engine.cancel_all();
+                serve.await?;
+            }
+        }
+
+        if let ListenAddr::Unix(path) = &addr {
+            if let Err(e) = std::fs::remove_file(path) {
+                tracing::warn!("Failed to remove the socket {}: {}", path.display(), e);
+            }
+        }
+        self.engine.shutdown().await?;
+        Ok(())
+    }
+
+    /// Where the listener is bound, binding it first if `bind` hasn't been called.
+    async fn bind_if_needed(&mut self) -> Result<ListenAddr> {
+        match &self.listener {
+            None => self.bind().await,
+            Some(Listener::Tcp(listener, _)) => Ok(ListenAddr::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Some(Listener::Unix(_)) => Ok(ListenAddr::Unix(self.config.socket_path.clone())),
         }
     }
-    Ok(options)
 }
 
-async fn handle_completion(
-    State(engine): State<Arc<Engine>>,
-    Json(payload): Json<CompletionRequest>,
-) -> Json<EngineResponse> {
-    
-    // 1. Validation
-    let options = match validate_request(&payload) {
-        Ok(opts) => opts,
-        Err(e) => return Json(EngineResponse {
-            status: "error".to_string(),
-            intent: None,
-            output: OutputContent { text: "".to_string() },
-            usage: Usage::default(),
-            error: Some(e),
-        }),
+/// Resolves on the first SIGINT (Ctrl-C) or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
     };
 
-    // 2. Processing
-    match engine.process_request(&payload.prompt, options).await {
-        Ok(response) => Json(response),
-        Err(e) => {
-            Json(EngineResponse {
-                status: "error".to_string(),
-                intent: None,
-                output: OutputContent { text: "".to_string() },
-                usage: Usage::default(),
-                error: Some(format!("Runtime Error: {}", e)),
-            })
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// An error reply: the HTTP status plus the usual EngineResponse envelope as body.
+struct ApiError {
+    status: StatusCode,
+    response: EngineResponse,
+    /// Sent as `Retry-After` (seconds), for 429s.
+    retry_after: Option<u64>,
+}
 
-    #[test]
-    fn test_validation_empty_prompt() {
-        let req = CompletionRequest { prompt: "   ".to_string(), limits: None };
-        assert!(validate_request(&req).is_err());
+impl ApiError {
+    fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::from_response(EngineResponse::error(code, message))
     }
 
-    #[test]
-    fn test_validation_invalid_limits() {
-        let req = CompletionRequest { 
-            prompt: "Hi".to_string(), 
-            limits: Some(RequestLimits { max_tokens: Some(9000), max_time_ms: None, temperature: None }) 
+    /// Wrap an error envelope; the HTTP status follows its error code.
+    fn from_response(response: EngineResponse) -> Self {
+        let status = response.error_info.as_ref()
+            .map_or(StatusCode::INTERNAL_SERVER_ERROR, |info| status_for(info.code));
+        Self { status, response, retry_after: None }
+    }
+}
+
+impl From<EngineError> for ApiError {
+    fn from(e: EngineError) -> Self {
+        let retry_after = match e {
+            EngineError::QueueFull { retry_after_secs } => Some(retry_after_secs),
+            _ => None,
         };
-        assert!(validate_request(&req).is_err());
+        Self { retry_after, ..Self::from_response(EngineResponse::from_error(&e)) }
     }
+}
 
-    #[test]
-    fn test_validation_valid() {
-        let req = CompletionRequest { 
-            prompt: "Hi".to_string(), 
-            limits: Some(RequestLimits { max_tokens: Some(10), max_time_ms: None, temperature: Some(0.5) }) 
+/// `Json`, except that a body it can't parse is rejected with the usual error envelope and
+/// a 400, naming the field and position, instead of axum's plain-text 4xx.
+struct ApiJson<T>(T);
+
+#[async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for ApiJson<T> {
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(request, state).await {
+            Ok(Json(value)) => Ok(ApiJson(value)),
+            Err(rejection) => Err(ApiError::from(rejection)),
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        // Bad syntax and bad data alike are the client's mistake; other rejections, like a
+        // missing Content-Type, keep their own status
+        let status = match rejection {
+            JsonRejection::JsonDataError(_) | JsonRejection::JsonSyntaxError(_) => StatusCode::BAD_REQUEST,
+            _ => rejection.status(),
+        };
+        let message = match status {
+            StatusCode::PAYLOAD_TOO_LARGE => "Validation Error: the request body is larger than server.max_body_bytes (see /v1/limits)".to_string(),
+            _ => format!("Validation Error: {}", rejection.body_text()),
         };
-        assert!(validate_request(&req).is_ok());
+        Self { status, ..Self::new(ErrorCode::ValidationError, message) }
+    }
+}
+
+/// The HTTP status for each error code; the one place this mapping is made.
+fn status_for(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::ValidationError | ErrorCode::ContextOverflow => StatusCode::BAD_REQUEST,
+        ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+        ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ErrorCode::MemoryDisabled => StatusCode::CONFLICT,
+        ErrorCode::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::Unsupported => StatusCode::NOT_IMPLEMENTED,
+        ErrorCode::ModelNotLoaded => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        ErrorCode::ConfigError | ErrorCode::RuntimeError | ErrorCode::MemoryError => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, Json(self.response)).into_response();
+        if let Some(secs) = self.retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Hand the request the current limits, and cut its body off at `max_body_bytes`; reading
+/// past that fails the `Json` extractor with a 413.
+async fn apply_limits(State(limits): State<SharedLimits>, mut request: Request, next: Next) -> Response {
+    let limits = limits.get();
+    request.extensions_mut().insert(limits);
+    let request = request.map(|body| Body::new(Limited::new(body, limits.max_body_bytes)));
+    next.run(request).await
+}
+
+/// Give every request an id (the caller's `X-Request-Id`, if it sent a usable one) and
+/// run it in a span carrying that id. The id is echoed back in `X-Request-Id`.
+async fn assign_request_id(mut request: Request, next: Next) -> Response {
+    let context = request.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(RequestContext::with_id)
+        .unwrap_or_default();
+    let span = tracing::info_span!("request", id = %context.id, method = %request.method(), path = %request.uri().path());
+    let id = header::HeaderValue::from_str(&context.id).ok();
+    request.extensions_mut().insert(context);
+
+    let mut response = next.run(request).instrument(span).await;
+    if let Some(id) = id {
+        response.headers_mut().insert(REQUEST_ID_HEADER, id);
+    }
+    response
+}
+
+/// Reject requests without a valid `Authorization: Bearer <key>` header when API keys
+/// are configured.
+async fn require_api_key(State(api_keys): State<Arc<Vec<String>>>, request: Request, next: Next) -> Response {
+    if api_keys.is_empty() {
+        return next.run(request).await;
+    }
+
+    let presented = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    match presented {
+        Some(key) if is_valid_key(&api_keys, key) => next.run(request).await,
+        Some(_) => unauthorized("Invalid API key"),
+        None => unauthorized("Missing API key; send 'Authorization: Bearer <key>'"),
+    }
+}
+
+fn unauthorized(message: &str) -> Response {
+    let mut response = ApiError::new(ErrorCode::Unauthorized, message).into_response();
+    response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+    response
+}
+
+const PLAYGROUND_HTML: &str = include_str!("playground.html");
+
+/// `/` serving the playground when `server.playground` is on, else nothing.
+fn playground_routes(enabled: bool) -> Router<Arc<Engine>> {
+    if !enabled {
+        return Router::new();
+    }
+    Router::new().route("/", get(serve_playground))
+}
+
+/// The playground page. Browsers revalidate it on every visit and get a `304` while it
+/// hasn't changed, so a new build's page shows up right away.
+async fn serve_playground(headers: header::HeaderMap) -> Response {
+    static ETAG: OnceLock<String> = OnceLock::new();
+    let etag = ETAG.get_or_init(|| {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        std::hash::Hash::hash(PLAYGROUND_HTML, &mut hasher);
+        format!("\"{:016x}\"", std::hash::Hasher::finish(&hasher))
+    });
+    let cache_headers = [(header::ETAG, etag.clone()), (header::CACHE_CONTROL, "no-cache".to_string())];
+    if headers.get(header::IF_NONE_MATCH).is_some_and(|v| v.as_bytes() == etag.as_bytes()) {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    (cache_headers, axum::response::Html(PLAYGROUND_HTML)).into_response()
+}
+
+/// The CORS policy of `server.cors`, or `None` without allowed origins: then no CORS headers
+/// are sent, and browsers hold cross-origin requests to the same-origin policy.
+fn cors_layer(config: &ServerConfig) -> Option<CorsLayer> {
+    let cors = &config.cors;
+    if cors.allowed_origins.is_empty() {
+        return None;
+    }
+
+    let origins = if cors.allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(cors.allowed_origins.iter().filter_map(|origin| match header::HeaderValue::from_str(origin) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                tracing::warn!("Ignoring invalid CORS origin '{}'", origin);
+                None
+            }
+        }))
+    };
+    let mut headers = vec![header::CONTENT_TYPE];
+    if !config.api_keys.is_empty() {
+        headers.push(header::AUTHORIZATION);
+    }
+    for name in &cors.allowed_headers {
+        match header::HeaderName::from_bytes(name.as_bytes()) {
+            Ok(name) => headers.push(name),
+            Err(_) => tracing::warn!("Ignoring invalid CORS header '{}'", name),
+        }
+    }
+
+    let mut layer = CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers(headers)
+        .expose_headers([header::HeaderName::from_static(REQUEST_ID_HEADER)]);
+    if let Some(secs) = cors.max_age_secs {
+        layer = layer.max_age(Duration::from_secs(secs));
+    }
+    Some(layer)
+}
+
+/// Compare `presented` against every key without short-circuiting, so the time taken
+/// doesn't reveal how much of a key matched or which key it was.
+fn is_valid_key(api_keys: &[String], presented: &str) -> bool {
+    api_keys.iter().fold(false, |found, key| found | constant_time_eq(key.as_bytes(), presented.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The server is up as soon as it answers; `model` says whether it can serve inference yet.
+async fn get_version(Extension(build_info): Extension<Arc<BuildInfo>>) -> Json<BuildInfo> {
+    Json(BuildInfo::clone(&build_info))
+}
+
+async fn health_check(State(engine): State<Arc<Engine>>, Extension(build_info): Extension<Arc<BuildInfo>>) -> Json<serde_json::Value> {
+    let mut health = serde_json::json!({
+        "status": "ok",
+        "service": "lie-server",
+        "version": build_info.version
+    });
+    let model = match engine.load_state() {
+        LoadState::NotLoaded => "not_loaded",
+        LoadState::Loading { progress } => {
+            health["load_progress"] = serde_json::json!((progress * 100.0).round() as u32);
+            "loading"
+        }
+        LoadState::Ready => "ready",
+        LoadState::Idle => "idle",
+        LoadState::Failed { error } => {
+            health["load_error"] = serde_json::json!(error);
+            "failed"
+        }
+    };
+    health["model"] = serde_json::json!(model);
+    health["threads"] = serde_json::json!(engine.load_config().threads());
+    Json(health)
+}
+
+/// Readiness: `200` once the model is loaded, `503` before that or if loading failed.
+/// A model unloaded for being idle counts as ready, since the next request reloads it.
+async fn readiness_check(State(engine): State<Arc<Engine>>) -> (StatusCode, Json<serde_json::Value>) {
+    let state = engine.load_state();
+    let ready = matches!(state, LoadState::Ready | LoadState::Idle);
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let mut body = serde_json::to_value(&state).unwrap_or_default();
+    body["model_path"] = serde_json::json!(engine.model_path());
+    (status, Json(body))
+}
+
+fn validate_request(payload: &CompletionRequest, max_prompt_chars: usize, allow_debug: bool) -> Result<InferenceOptions, String> {
+    if payload.prompt.trim().is_empty() {
+        return Err("Validation Error: Prompt cannot be empty".to_string());
+    }
+    validate_prompt_chars("prompt", payload.prompt.chars().count(), max_prompt_chars)?;
+    let mut options = validate_limits(payload.limits.as_ref())?;
+    options.response_format = validate_response_format(payload.response_format.as_ref())?;
+    options.memory_namespace = validate_memory_namespace(payload.memory_namespace.as_ref())?;
+    options.classify_intent = payload.classify_intent;
+    options.system = payload.system.clone();
+    (options.debug, options.dry_run) = validate_debug(payload.debug, payload.dry_run, allow_debug)?;
+    Ok(options)
+}
+
+fn validate_chat_request(payload: &ChatRequest, max_prompt_chars: usize, allow_debug: bool) -> Result<InferenceOptions, String> {
+    if payload.messages.is_empty() {
+        return Err("Validation Error: messages cannot be empty".to_string());
+    }
+    let chars = payload.messages.iter().map(|m| m.content.chars().count()).sum();
+    validate_prompt_chars("messages", chars, max_prompt_chars)?;
+    let mut options = validate_limits(payload.limits.as_ref())?;
+    options.response_format = validate_response_format(payload.response_format.as_ref())?;
+    options.memory_namespace = validate_memory_namespace(payload.memory_namespace.as_ref())?;
+    options.classify_intent = payload.classify_intent;
+    (options.debug, options.dry_run) = validate_debug(payload.debug, payload.dry_run, allow_debug)?;
+    Ok(options)
+}
+
+/// `debug` and `dry_run` show the whole prompt, memory included, so they have to be allowed.
+fn validate_debug(debug: bool, dry_run: bool, allow_debug: bool) -> Result<(bool, bool), String> {
+    if (debug || dry_run) && !allow_debug {
+        return Err("Validation Error: debug and dry_run are turned off on this server (server.allow_debug)".to_string());
+    }
+    Ok((debug, dry_run))
+}
+
+fn validate_session_message(payload: &SessionMessageRequest, max_prompt_chars: usize) -> Result<InferenceOptions, String> {
+    if payload.message.trim().is_empty() {
+        return Err("Validation Error: message cannot be empty".to_string());
+    }
+    validate_prompt_chars("message", payload.message.chars().count(), max_prompt_chars)?;
+    let mut options = validate_limits(payload.limits.as_ref())?;
+    options.response_format = validate_response_format(payload.response_format.as_ref())?;
+    Ok(options)
+}
+
+/// Turn away prompts over `server.max_prompt_chars` before they are tokenized.
+fn validate_prompt_chars(what: &str, chars: usize, max_prompt_chars: usize) -> Result<(), String> {
+    if chars > max_prompt_chars {
+        return Err(format!("Validation Error: {} has {} characters, more than the {} allowed", what, chars, max_prompt_chars));
+    }
+    Ok(())
+}
+
+fn validate_memory_namespace(namespace: Option<&String>) -> Result<Option<String>, String> {
+    if let Some(namespace) = namespace {
+        validate_namespace(namespace).map_err(|e| e.to_string())?;
+    }
+    Ok(namespace.cloned())
+}
+
+/// Reject schemas that can't be turned into a grammar before they reach the engine.
+fn validate_response_format(format: Option<&ResponseFormat>) -> Result<Option<ResponseFormat>, String> {
+    if let Some(format) = format {
+        format.grammar().map_err(|e| format!("Validation Error: {}", e))?;
+    }
+    Ok(format.cloned())
+}
+
+fn validate_limits(limits: Option<&RequestLimits>) -> Result<InferenceOptions, String> {
+    let mut options = InferenceOptions::default();
+    if let Some(limits) = limits {
+        if let Some(mt) = limits.max_tokens {
+            if mt == 0 || mt > MAX_TOKENS {
+                 return Err(format!("Validation Error: max_tokens must be between 1 and {}", MAX_TOKENS));
+            }
+            options.max_tokens = Some(mt);
+        }
+        
+        if let Some(mtm) = limits.max_time_ms {
+             if mtm > MAX_TIME_MS {
+                 return Err(format!("Validation Error: max_time_ms cannot exceed {}", MAX_TIME_MS));
+             }
+             options.max_time_ms = Some(mtm);
+        }
+
+        if let Some(temp) = limits.temperature {
+            if !(0.0..=2.0).contains(&temp) {
+                return Err("Validation Error: temperature must be between 0.0 and 2.0".to_string());
+            }
+            options.temperature = Some(temp);
+        }
+
+        if let Some(top_k) = limits.top_k {
+            if top_k == 0 {
+                return Err("Validation Error: top_k must be at least 1".to_string());
+            }
+            options.top_k = Some(top_k);
+        }
+
+        if let Some(top_p) = limits.top_p {
+            if !(top_p > 0.0 && top_p <= 1.0) {
+                return Err("Validation Error: top_p must be greater than 0.0 and at most 1.0".to_string());
+            }
+            options.top_p = Some(top_p);
+        }
+
+        if let Some(min_p) = limits.min_p {
+            if !(0.0..=1.0).contains(&min_p) {
+                return Err("Validation Error: min_p must be between 0.0 and 1.0".to_string());
+            }
+            options.min_p = Some(min_p);
+        }
+
+        if let Some(typical_p) = limits.typical_p {
+            if !(typical_p > 0.0 && typical_p <= 1.0) {
+                return Err("Validation Error: typical_p must be greater than 0.0 and at most 1.0".to_string());
+            }
+            options.typical_p = Some(typical_p);
+        }
+
+        for (text, bias) in &limits.logit_bias {
+            if !(-100.0..=100.0).contains(bias) {
+                return Err(format!("Validation Error: logit_bias for '{}' must be between -100 and 100", text));
+            }
+        }
+        options.logit_bias = limits.logit_bias.clone();
+
+        options.seed = limits.seed;
+
+        if let Some(penalty) = limits.repeat_penalty {
+            if !(penalty > 0.0 && penalty <= 2.0) {
+                return Err("Validation Error: repeat_penalty must be greater than 0.0 and at most 2.0".to_string());
+            }
+            options.repeat_penalty = Some(penalty);
+        }
+
+        if let Some(last_n) = limits.repeat_last_n {
+            if last_n > 8192 {
+                return Err("Validation Error: repeat_last_n cannot exceed 8192".to_string());
+            }
+            options.repeat_last_n = Some(last_n);
+        }
+
+        if let Some(penalty) = limits.frequency_penalty {
+            if !(-2.0..=2.0).contains(&penalty) {
+                return Err("Validation Error: frequency_penalty must be between -2.0 and 2.0".to_string());
+            }
+            options.frequency_penalty = Some(penalty);
+        }
+
+        if let Some(penalty) = limits.presence_penalty {
+            if !(-2.0..=2.0).contains(&penalty) {
+                return Err("Validation Error: presence_penalty must be between -2.0 and 2.0".to_string());
+            }
+            options.presence_penalty = Some(penalty);
+        }
+
+        options.penalize_prompt = limits.penalize_prompt;
+        options.context_shift = limits.context_shift;
+        options.n_keep = limits.n_keep;
+        options.on_context_overflow = limits.on_context_overflow;
+        options.no_cache = limits.no_cache;
+
+        if let Some(logprobs) = limits.logprobs {
+            if logprobs > MAX_LOGPROBS {
+                return Err(format!("Validation Error: logprobs cannot exceed {}", MAX_LOGPROBS));
+            }
+            options.logprobs = Some(logprobs);
+        }
+
+        for (name, threads) in [("n_threads", limits.n_threads), ("n_threads_batch", limits.n_threads_batch)] {
+            if threads == Some(0) {
+                return Err(format!("Validation Error: {} must be at least 1", name));
+            }
+        }
+        options.n_threads = limits.n_threads;
+        options.n_threads_batch = limits.n_threads_batch;
+
+        if limits.stop.len() > MAX_STOP_SEQUENCES {
+            return Err(format!("Validation Error: stop cannot have more than {} sequences", MAX_STOP_SEQUENCES));
+        }
+        for sequence in &limits.stop {
+            if sequence.is_empty() || sequence.chars().count() > MAX_STOP_SEQUENCE_CHARS {
+                return Err(format!("Validation Error: stop sequences must be 1 to {} characters long", MAX_STOP_SEQUENCE_CHARS));
+            }
+        }
+        options.stop_sequences = limits.stop.clone();
+    }
+    Ok(options)
+}
+
+/// The Prometheus recorder's handle, installing the recorder on first use. If the
+/// application already installed a recorder of its own, `/metrics` stays empty.
+fn metrics_handle() -> &'static PrometheusHandle {
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+    HANDLE.get_or_init(|| {
+        let builder = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full(lie_core::metrics::INFERENCE_DURATION_SECONDS.to_string()),
+                &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0],
+            )
+            .expect("bucket list is not empty");
+        let recorder = builder.build_recorder();
+        let handle = recorder.handle();
+        match metrics::set_global_recorder(recorder) {
+            Ok(()) => lie_core::metrics::describe(),
+            Err(e) => tracing::warn!("Failed to install the metrics recorder: {}", e),
+        }
+        handle
+    })
+}
+
+async fn render_metrics(handle: PrometheusHandle) -> impl IntoResponse {
+    handle.run_upkeep();
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], handle.render())
+}
+
+/// Log the outcome of an inference request and turn it into the HTTP response, tagged
+/// with the request id either way.
+fn respond(id: &str, received_at: Instant, result: std::result::Result<EngineResponse, ApiError>) -> Response {
+    let elapsed_ms = received_at.elapsed().as_millis();
+    let mut error = match result {
+        Ok(response) if response.status != "error" => {
+            tracing::info!("request {}: {}, {} tokens, {} ms", id, response.status, response.usage.total_tokens, elapsed_ms);
+            return Json(response).into_response();
+        }
+        Ok(response) => ApiError::from_response(response),
+        Err(e) => e,
+    };
+    let message = error.response.error.as_deref().unwrap_or_default();
+    tracing::warn!("request {}: error ({}), {} ms", id, message, elapsed_ms);
+    error.response.request_id = Some(id.to_string());
+    error.into_response()
+}
+
+async fn handle_completion(
+    State(engine): State<Arc<Engine>>,
+    Extension(context): Extension<RequestContext>,
+    Extension(limits): Extension<ServerLimits>,
+    ApiJson(payload): ApiJson<CompletionRequest>,
+) -> Response {
+    let (id, received_at) = (context.id.clone(), context.received_at);
+    let result = match validate_request(&payload, limits.max_prompt_chars, limits.allow_debug) {
+        Ok(options) if payload.stream => return stream_completion(engine, payload.prompt, options, context),
+        Ok(options) => engine.process_request(&payload.prompt, options, Some(context)).await.map_err(ApiError::from),
+        Err(e) => Err(ApiError::new(ErrorCode::ValidationError, e)),
+    };
+    respond(&id, received_at, result)
+}
+
+/// Run a completion, sending a `token` event with each piece of text as it's generated and
+/// then a `done` event with the response, or `error` with the error response. Closing the
+/// connection cancels the request.
+fn stream_completion(engine: Arc<Engine>, prompt: String, options: InferenceOptions, context: RequestContext) -> Response {
+    let (events, mut receiver) = tokio::sync::mpsc::channel::<Event>(64);
+    let cancel = options.cancel.clone();
+    tokio::spawn(async move {
+        let (id, received_at) = (context.id.clone(), context.received_at);
+        let (tokens, mut chunks) = tokio::sync::mpsc::unbounded_channel();
+        let completion = engine.process_request_with_progress(&prompt, options, Some(context), tokens);
+        let forward = async {
+            while let Some(chunk) = chunks.recv().await {
+                if let TokenChunk::Token { text } = chunk {
+                    let event = Event::default().event("token").data(serde_json::json!({ "text": text }).to_string());
+                    if events.send(event).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        };
+        // Cancelled rather than dropped, so it leaves the runtime and the queue as usual
+        let cancel_on_disconnect = async {
+            events.closed().await;
+            tracing::info!("request {}: client disconnected, cancelling", id);
+            cancel.cancel();
+            std::future::pending::<()>().await
+        };
+        let result = tokio::select! {
+            (result, ()) = async { tokio::join!(completion, forward) } => result,
+            () = cancel_on_disconnect => unreachable!("never finishes"),
+        };
+
+        let (name, response) = match result {
+            Ok(response) if response.status != "error" => ("done", response),
+            Ok(response) => ("error", response),
+            Err(e) => ("error", EngineResponse::from_error(&e)),
+        };
+        tracing::info!("request {}: {}, {} tokens, {} ms (streamed)", id, response.status, response.usage.total_tokens, received_at.elapsed().as_millis());
+        let response = EngineResponse { request_id: Some(id), ..response };
+        let data = serde_json::to_string(&response).unwrap_or_default();
+        let _ = events.send(Event::default().event(name).data(data)).await;
+    });
+    let stream = futures_util::stream::poll_fn(move |cx| receiver.poll_recv(cx).map(|event| event.map(Ok::<_, Infallible>)));
+    Sse::new(stream).into_response()
+}
+
+/// Run the completions of a batch. When they all ask for the same options, they go to the
+/// runtime together (see `Engine::process_batch`); otherwise each runs on its own, at most
+/// `queue.max_concurrent` at a time so the batch never takes more than its share of the queue.
+async fn handle_completion_batch(
+    State(engine): State<Arc<Engine>>,
+    Extension(context): Extension<RequestContext>,
+    Extension(limits): Extension<ServerLimits>,
+    ApiJson(payload): ApiJson<BatchCompletionRequest>,
+) -> Result<Json<BatchCompletionResponse>, ApiError> {
+    let count = payload.requests.len();
+    if count == 0 || count > limits.max_batch_size {
+        return Err(ApiError::new(ErrorCode::ValidationError, format!(
+            "Validation Error: a batch needs 1 to {} requests, got {}", limits.max_batch_size, count
+        )));
+    }
+
+    let validated: Vec<_> = payload.requests.iter().map(|request| match request.stream {
+        true => Err("Validation Error: batch requests can't stream".to_string()),
+        false => validate_request(request, limits.max_prompt_chars, limits.allow_debug),
+    }).collect();
+    let shared = |request: &CompletionRequest| serde_json::json!([request.limits, request.response_format, request.memory_namespace, request.classify_intent, request.system, request.debug, request.dry_run]);
+    let uniform = payload.requests.iter().all(|request| shared(request) == shared(&payload.requests[0]));
+    let shared_options = match &validated[..] {
+        [Ok(options), ..] if uniform && validated.iter().all(Result::is_ok) => Some(options.clone()),
+        _ => None,
+    };
+    let item_ids = (0..count).map(|index| format!("{}-{}", context.id, index));
+
+    let mut responses = match shared_options {
+        Some(options) => complete_together(&engine, payload.requests, options, &context).await,
+        None => complete_each(&engine, payload.requests, validated, item_ids.clone().collect()).await?,
+    };
+    for (response, item_id) in responses.iter_mut().zip(item_ids) {
+        response.request_id = Some(item_id);
+    }
+
+    let mut usage = BatchUsage { duration_ms: context.received_at.elapsed().as_millis() as u64, ..BatchUsage::default() };
+    for response in &responses {
+        if response.status == "error" {
+            usage.failed += 1;
+        } else {
+            usage.succeeded += 1;
+        }
+        usage.input_tokens += response.usage.input_tokens;
+        usage.output_tokens += response.usage.output_tokens;
+        usage.total_tokens += response.usage.total_tokens;
+    }
+    tracing::info!("request {}: batch of {}, {} failed, {} ms", context.id, count, usage.failed, usage.duration_ms);
+    Ok(Json(BatchCompletionResponse { responses, usage }))
+}
+
+async fn complete_together(engine: &Engine, requests: Vec<CompletionRequest>, options: InferenceOptions, context: &RequestContext) -> Vec<EngineResponse> {
+    let prompts: Vec<String> = requests.into_iter().map(|request| request.prompt).collect();
+    match engine.process_batch(&prompts, options, Some(context.clone())).await {
+        Ok(results) => results.into_iter().map(|result| result.unwrap_or_else(|e| EngineResponse::from_error(&e))).collect(),
+        Err(e) => prompts.iter().map(|_| EngineResponse::from_error(&e)).collect(),
+    }
+}
+
+async fn complete_each(engine: &Arc<Engine>, requests: Vec<CompletionRequest>, validated: Vec<Result<InferenceOptions, String>>, item_ids: Vec<String>) -> Result<Vec<EngineResponse>, ApiError> {
+    let count = requests.len();
+    let slots = Arc::new(tokio::sync::Semaphore::new(engine.max_concurrent()));
+    // Dropping the set (the client went away) aborts the items still running
+    let mut items = tokio::task::JoinSet::new();
+    for (index, ((request, options), item_id)) in requests.into_iter().zip(validated).zip(item_ids).enumerate() {
+        let (engine, slots) = (engine.clone(), slots.clone());
+        items.spawn(async move {
+            let response = match options {
+                Ok(options) => {
+                    let _slot = slots.acquire().await.expect("the batch semaphore is never closed");
+                    engine.process_request(&request.prompt, options, Some(RequestContext::with_id(item_id))).await
+                        .unwrap_or_else(|e| EngineResponse::from_error(&e))
+                }
+                Err(e) => EngineResponse::error(ErrorCode::ValidationError, e),
+            };
+            (index, response)
+        });
+    }
+
+    let mut responses: Vec<Option<EngineResponse>> = (0..count).map(|_| None).collect();
+    while let Some(item) = items.join_next().await {
+        let (index, response) = item.map_err(|e| ApiError::new(ErrorCode::RuntimeError, format!("Batch item failed: {}", e)))?;
+        responses[index] = Some(response);
+    }
+    Ok(responses.into_iter().flatten().collect())
+}
+
+async fn handle_chat(
+    State(engine): State<Arc<Engine>>,
+    Extension(context): Extension<RequestContext>,
+    Extension(limits): Extension<ServerLimits>,
+    ApiJson(payload): ApiJson<ChatRequest>,
+) -> Response {
+    let (id, received_at) = (context.id.clone(), context.received_at);
+    let result = match validate_chat_request(&payload, limits.max_prompt_chars, limits.allow_debug) {
+        Ok(options) => engine.process_chat(&payload.messages, options, Some(context)).await.map_err(ApiError::from),
+        Err(e) => Err(ApiError::new(ErrorCode::ValidationError, e)),
+    };
+    respond(&id, received_at, result)
+}
+
+/// Start a completion in the background; the reply is its job, `queued`.
+async fn submit_job(
+    State(engine): State<Arc<Engine>>,
+    Extension(context): Extension<RequestContext>,
+    Extension(limits): Extension<ServerLimits>,
+    Extension(jobs): Extension<Arc<JobStore>>,
+    ApiJson(payload): ApiJson<CompletionRequest>,
+) -> Result<Response, ApiError> {
+    if payload.stream {
+        return Err(ApiError::new(ErrorCode::ValidationError, "Validation Error: jobs can't stream; poll the job for the output so far"));
+    }
+    let options = validate_request(&payload, limits.max_prompt_chars, limits.allow_debug)
+        .map_err(|e| ApiError::new(ErrorCode::ValidationError, e))?;
+    let status = jobs.submit(engine, payload.prompt, options, context)
+        .ok_or_else(|| ApiError::new(ErrorCode::QueueFull, "Too many jobs queued or running; try again once one has finished"))?;
+    let location = format!("/v1/jobs/{}", status.id);
+    Ok((StatusCode::ACCEPTED, [(header::LOCATION, location)], Json(status)).into_response())
+}
+
+async fn get_job(
+    Extension(jobs): Extension<Arc<JobStore>>,
+    Path(id): Path<String>,
+) -> Result<Json<jobs::JobStatus>, ApiError> {
+    jobs.status(&id).map(Json).ok_or_else(|| no_job(&id))
+}
+
+/// The job's response once it has finished, like `/v1/completion` would have replied;
+/// until then a 202 with its status.
+async fn get_job_result(
+    Extension(jobs): Extension<Arc<JobStore>>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    match jobs.result(&id) {
+        Some((_, Some(response))) if response.status != "error" => Ok(Json(response).into_response()),
+        Some((_, Some(response))) => Err(ApiError::from_response(response)),
+        Some((status, None)) => Ok((StatusCode::ACCEPTED, Json(status)).into_response()),
+        None => Err(no_job(&id)),
+    }
+}
+
+/// Cancel an unfinished job, or delete a finished one.
+async fn cancel_job(
+    Extension(jobs): Extension<Arc<JobStore>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if !jobs.cancel(&id) {
+        return Err(no_job(&id));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn no_job(id: &str) -> ApiError {
+    ApiError::new(ErrorCode::NotFound, format!("No job '{}'", id))
+}
+
+async fn create_session(
+    State(engine): State<Arc<Engine>>,
+    payload: Option<Json<CreateSessionRequest>>,
+) -> Result<Json<Session>, ApiError> {
+    let Json(payload) = payload.unwrap_or_default();
+    Ok(Json(engine.sessions.create(payload.system).await?))
+}
+
+async fn list_sessions(State(engine): State<Arc<Engine>>) -> Json<SessionList> {
+    Json(SessionList { sessions: engine.sessions.list().await })
+}
+
+async fn get_session(
+    State(engine): State<Arc<Engine>>,
+    Path(id): Path<String>,
+) -> Result<Json<Session>, ApiError> {
+    engine.sessions.get(&id).await
+        .map(Json)
+        .ok_or_else(|| ApiError::new(ErrorCode::NotFound, format!("No session '{}'", id)))
+}
+
+async fn delete_session(
+    State(engine): State<Arc<Engine>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if !engine.sessions.delete(&id).await? {
+        return Err(ApiError::new(ErrorCode::NotFound, format!("No session '{}'", id)));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn send_session_message(
+    State(engine): State<Arc<Engine>>,
+    Path(id): Path<String>,
+    Extension(context): Extension<RequestContext>,
+    Extension(limits): Extension<ServerLimits>,
+    ApiJson(payload): ApiJson<SessionMessageRequest>,
+) -> Response {
+    let (request_id, received_at) = (context.id.clone(), context.received_at);
+    let result = match validate_session_message(&payload, limits.max_prompt_chars) {
+        Ok(options) => engine.process_chat_in_session(&id, &payload.message, options, Some(context)).await.map_err(ApiError::from),
+        Err(e) => Err(ApiError::new(ErrorCode::ValidationError, e)),
+    };
+    respond(&request_id, received_at, result)
+}
+
+/// Chat over a WebSocket; see `ws`. Frames are limited to `server.max_body_bytes` like bodies.
+async fn open_websocket(
+    State(engine): State<Arc<Engine>>,
+    Extension(context): Extension<RequestContext>,
+    Extension(limits): Extension<ServerLimits>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.max_message_size(limits.max_body_bytes)
+        .on_upgrade(move |socket| ws::serve(engine, limits, context, socket))
+}
+
+async fn list_models(State(engine): State<Arc<Engine>>) -> Json<ModelList> {
+    // Reads the header of every file, so it's kept off the async executor
+    let scan = engine.clone();
+    let available = match tokio::task::spawn_blocking(move || scan.available_models()).await {
+        Ok(Ok(models)) => models,
+        Ok(Err(e)) => {
+            tracing::warn!("Failed to list model.models_dir: {}", e);
+            Vec::new()
+        }
+        Err(_) => Vec::new(),
+    };
+    Json(ModelList {
+        object: "list".to_string(),
+        data: engine.model_info().await.into_iter().map(ModelEntry::from).collect(),
+        available,
+    })
+}
+
+async fn model_status(engine: &Engine) -> ModelStatus {
+    ModelStatus {
+        state: engine.load_state(),
+        config: engine.load_config(),
+        info: engine.model_info().await,
+    }
+}
+
+async fn get_model(State(engine): State<Arc<Engine>>) -> Json<ModelStatus> {
+    Json(model_status(&engine).await)
+}
+
+async fn load_model(
+    State(engine): State<Arc<Engine>>,
+    ApiJson(payload): ApiJson<LoadModelRequest>,
+) -> Result<Json<ModelStatus>, ApiError> {
+    let current = engine.load_config();
+    let load_config = ModelLoadConfig {
+        model_path: payload.path,
+        context_size: payload.context_size.unwrap_or(current.context_size),
+        gpu_layers: payload.gpu_layers.unwrap_or(current.gpu_layers),
+        batch_size: payload.batch_size.unwrap_or(current.batch_size),
+        n_threads: payload.n_threads.or(current.n_threads),
+        n_threads_batch: payload.n_threads_batch.or(current.n_threads_batch),
+        use_mmap: payload.use_mmap.unwrap_or(current.use_mmap),
+        use_mlock: payload.use_mlock.unwrap_or(current.use_mlock),
+        kv_cache_type: payload.kv_cache_type.unwrap_or(current.kv_cache_type),
+    };
+    engine.reload_model(load_config, payload.cancel_in_flight).await?;
+    Ok(Json(model_status(&engine).await))
+}
+
+async fn unload_model(State(engine): State<Arc<Engine>>) -> Result<Json<ModelStatus>, ApiError> {
+    engine.unload_model().await?;
+    Ok(Json(model_status(&engine).await))
+}
+
+async fn create_embeddings(
+    State(engine): State<Arc<Engine>>,
+    ApiJson(payload): ApiJson<EmbeddingRequest>,
+) -> Result<Json<EmbeddingList>, ApiError> {
+    let texts = match payload.input {
+        EmbeddingInput::One(text) => vec![text],
+        EmbeddingInput::Many(texts) => texts,
+    };
+    if texts.is_empty() {
+        return Err(ApiError::new(ErrorCode::ValidationError, "Validation Error: input cannot be empty"));
+    }
+
+    let embeddings = engine.embed(&texts).await?;
+    let model = engine.model_info().await.map(|info| info.name).unwrap_or_default();
+
+    Ok(Json(EmbeddingList {
+        object: "list".to_string(),
+        data: embeddings.into_iter()
+            .enumerate()
+            .map(|(index, embedding)| Embedding { object: "embedding".to_string(), index, embedding })
+            .collect(),
+        model,
+    }))
+}
+
+async fn tokenize(
+    State(engine): State<Arc<Engine>>,
+    ApiJson(payload): ApiJson<TokenizeRequest>,
+) -> Result<Json<TokenizeResponse>, ApiError> {
+    let (tokens, pieces) = engine.tokenize(&payload.text, payload.add_bos).await?;
+    Ok(Json(TokenizeResponse { count: tokens.len(), tokens, pieces }))
+}
+
+async fn list_namespaces(State(engine): State<Arc<Engine>>) -> Json<MemoryResponse> {
+    let namespaces = engine.memory.namespaces().await;
+    Json(MemoryResponse { namespaces: Some(namespaces), ..MemoryResponse::success() })
+}
+
+async fn list_facts(
+    State(engine): State<Arc<Engine>>,
+    Path(path): Path<MemoryPath>,
+) -> Json<MemoryResponse> {
+    let facts = engine.memory.list_facts_ns(namespace(&path.ns)).await
+        .into_iter()
+        .map(|(key, value)| Fact { key, value })
+        .collect();
+    Json(MemoryResponse { facts: Some(facts), ..MemoryResponse::success() })
+}
+
+async fn set_fact(
+    State(engine): State<Arc<Engine>>,
+    Path(path): Path<MemoryPath>,
+    ApiJson(fact): ApiJson<Fact>,
+) -> Result<Json<MemoryResponse>, ApiError> {
+    if fact.key.trim().is_empty() {
+        return Err(ApiError::new(ErrorCode::ValidationError, "Validation Error: key cannot be empty"));
+    }
+    engine.memory.set_fact_ns(namespace(&path.ns), &fact.key, &fact.value).await?;
+    Ok(Json(MemoryResponse { fact: Some(fact), ..MemoryResponse::success() }))
+}
+
+async fn get_fact(
+    State(engine): State<Arc<Engine>>,
+    Path(FactPath { ns, key }): Path<FactPath>,
+) -> Result<Json<MemoryResponse>, ApiError> {
+    let value = engine.memory.get_fact_ns(namespace(&ns), &key).await
+        .ok_or_else(|| ApiError::new(ErrorCode::NotFound, format!("No fact named '{}'", key)))?;
+    Ok(Json(MemoryResponse { fact: Some(Fact { key, value }), ..MemoryResponse::success() }))
+}
+
+async fn delete_fact(
+    State(engine): State<Arc<Engine>>,
+    Path(FactPath { ns, key }): Path<FactPath>,
+) -> Result<Json<MemoryResponse>, ApiError> {
+    if !engine.memory.delete_fact_ns(namespace(&ns), &key).await? {
+        return Err(ApiError::new(ErrorCode::NotFound, format!("No fact named '{}'", key)));
+    }
+    Ok(Json(MemoryResponse::success()))
+}
+
+async fn get_summary(
+    State(engine): State<Arc<Engine>>,
+    Path(path): Path<MemoryPath>,
+) -> Json<MemoryResponse> {
+    let summary = engine.memory.get_summary_ns(namespace(&path.ns)).await;
+    Json(MemoryResponse { summary: Some(summary), ..MemoryResponse::success() })
+}
+
+async fn set_summary(
+    State(engine): State<Arc<Engine>>,
+    Path(path): Path<MemoryPath>,
+    ApiJson(payload): ApiJson<SummaryRequest>,
+) -> Result<Json<MemoryResponse>, ApiError> {
+    let namespace = namespace(&path.ns);
+    engine.memory.set_summary_ns(namespace, &payload.summary).await?;
+    let summary = engine.memory.get_summary_ns(namespace).await;
+    Ok(Json(MemoryResponse { summary: Some(summary), ..MemoryResponse::success() }))
+}
+
+async fn clear_memory(
+    State(engine): State<Arc<Engine>>,
+    Path(path): Path<MemoryPath>,
+) -> Result<Json<MemoryResponse>, ApiError> {
+    engine.memory.clear_ns(namespace(&path.ns)).await?;
+    Ok(Json(MemoryResponse::success()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use axum::body::Body;
+    use axum::http::Request;
+    use lie_core::config::{CorsConfig, EngineConfig, JobsConfig, TlsConfig};
+    use lie_core::runtime::{FinishReason, InferenceResult, LoadReport, ModelLoadConfig, ModelRuntime, TokenChunk, Usage};
+    use tower::ServiceExt;
+
+    /// Echoes the prompt back, or fails every call when `fail` is set.
+    #[derive(Default)]
+    struct MockRuntime {
+        fail: bool,
+        info: Option<ModelInfo>,
+        /// The options of every inference, in order.
+        received: Arc<std::sync::Mutex<Vec<InferenceOptions>>>,
+        /// The size of every `infer_batch` call, in order.
+        batches: Arc<std::sync::Mutex<Vec<usize>>>,
+    }
+
+    #[async_trait]
+    impl ModelRuntime for MockRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            self.received.lock().unwrap().push(options);
+            if self.fail {
+                return Err(EngineError::Runtime("Decode failed".to_string()));
+            }
+            Ok(InferenceResult {
+                text: prompt.to_string(),
+                usage: Usage::default(),
+                finish_reason: FinishReason::Eos,
+                seed: None,
+                logprobs: None,
+            })
+        }
+
+        async fn infer_batch(&mut self, prompts: &[String], options: InferenceOptions) -> Result<Vec<InferenceResult>, EngineError> {
+            self.batches.lock().unwrap().push(prompts.len());
+            let mut results = Vec::new();
+            for prompt in prompts {
+                results.push(self.infer(prompt, options.clone()).await?);
+            }
+            Ok(results)
+        }
+
+        async fn embed(&mut self, texts: &[String]) -> Result<Vec<Vec<f32>>, EngineError> {
+            if self.fail {
+                return Err(EngineError::Unsupported("no embeddings".to_string()));
+            }
+            Ok(texts.iter().map(|text| vec![text.len() as f32, 1.0]).collect())
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+
+        /// One token per character, with 1 as BOS.
+        fn tokenize(&self, text: &str, add_bos: bool) -> Result<Vec<i32>, EngineError> {
+            let bos = add_bos.then_some(1);
+            Ok(bos.into_iter().chain(text.chars().map(|c| c as i32)).collect())
+        }
+
+        fn token_pieces(&self, tokens: &[i32]) -> Result<Vec<String>, EngineError> {
+            Ok(tokens.iter()
+                .map(|&t| if t == 1 { "<s>".to_string() } else { char::from_u32(t as u32).unwrap().to_string() })
+                .collect())
+        }
+
+        fn model_info(&self) -> Option<ModelInfo> {
+            self.info.clone()
+        }
+    }
+
+    fn test_router(fail: bool) -> Router {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime { fail, ..Default::default() }));
+        Server::new(Arc::new(engine), EngineConfig::default().server).router()
+    }
+
+    fn keyed_router() -> Router {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+        let config = ServerConfig { api_keys: vec!["first-key".to_string(), "second-key".to_string()], ..ServerConfig::default() };
+        Server::new(Arc::new(engine), config).router()
+    }
+
+    async fn get_with_auth(router: &Router, uri: &str, authorization: Option<&str>) -> (StatusCode, serde_json::Value) {
+        let mut builder = Request::get(uri);
+        if let Some(authorization) = authorization {
+            builder = builder.header("authorization", authorization);
+        }
+        let response = router.clone().oneshot(builder.body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_api_key_missing() {
+        let (status, body) = get_with_auth(&keyed_router(), "/v1/models", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["status"], "error");
+        assert!(body["error"].as_str().unwrap().contains("Missing API key"));
+        assert_eq!(body["error_info"]["code"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn test_api_key_wrong() {
+        let router = keyed_router();
+        for authorization in ["Bearer not-a-key", "Bearer first-ke", "first-key", "Basic Zmlyc3Qta2V5"] {
+            let (status, body) = get_with_auth(&router, "/v1/models", Some(authorization)).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", authorization);
+            assert_eq!(body["status"], "error");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_api_key_valid() {
+        let router = keyed_router();
+        for key in ["first-key", "second-key"] {
+            let (status, _) = get_with_auth(&router, "/v1/models", Some(&format!("Bearer {}", key))).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_api_key_not_required_for_health_or_without_keys() {
+        let (status, _) = get_with_auth(&keyed_router(), "/v1/health", None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = get_with_auth(&test_router(false), "/v1/models", None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// Send a CORS preflight for `POST /v1/completion` from `origin`.
+    async fn preflight(router: &Router, origin: &str) -> axum::response::Response {
+        let request = Request::builder()
+            .method("OPTIONS")
+            .uri("/v1/completion")
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "authorization, content-type")
+            .body(Body::empty())
+            .unwrap();
+        router.clone().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+        let cors = CorsConfig { allowed_origins: vec!["http://ui.example".to_string()], max_age_secs: Some(600), ..CorsConfig::default() };
+        let config = ServerConfig { api_keys: vec!["key".to_string()], cors, ..ServerConfig::default() };
+        let router = Server::new(Arc::new(engine), config).router();
+
+        let response = preflight(&router, "http://ui.example").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers["access-control-allow-origin"], "http://ui.example");
+        assert!(headers["access-control-allow-methods"].to_str().unwrap().contains("POST"));
+        let allowed = headers["access-control-allow-headers"].to_str().unwrap();
+        assert!(allowed.contains("authorization") && allowed.contains("content-type"), "{}", allowed);
+        assert_eq!(headers["access-control-max-age"], "600");
+
+        let response = preflight(&router, "http://evil.example").await;
+        assert!(response.headers().get("access-control-allow-origin").is_none());
+
+        let request = Request::get("/v1/models")
+            .header("origin", "http://ui.example")
+            .header("authorization", "Bearer key")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["access-control-allow-origin"], "http://ui.example");
+        assert_eq!(response.headers()["access-control-expose-headers"], "x-request-id");
+
+        // Off by default
+        let response = preflight(&test_router(false), "http://ui.example").await;
+        assert!(response.headers().get("access-control-allow-origin").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_playground() {
+        let response = test_router(false).oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+        let config = ServerConfig { playground: true, api_keys: vec!["key".to_string()], ..ServerConfig::default() };
+        let router = Server::new(Arc::new(engine), config).router();
+
+        // Served without a key; the page's own requests carry it
+        let response = router.clone().oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/html"));
+        assert_eq!(response.headers()["cache-control"], "no-cache");
+        let etag = response.headers()["etag"].clone();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&bytes).contains("/v1/completion"));
+
+        let request = Request::get("/").header("if-none-match", etag).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let (status, _) = get_with_auth(&router, "/v1/memory/facts", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"x"));
+    }
+
+    async fn post_completion(router: Router, body: serde_json::Value) -> (StatusCode, EngineResponse) {
+        let request = Request::post("/v1/completion")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[test]
+    fn test_validation_empty_prompt() {
+        let req = CompletionRequest { prompt: "   ".to_string(), limits: None, response_format: None, memory_namespace: None, classify_intent: false, system: None, debug: false, dry_run: false, stream: false };
+        assert!(validate_request(&req, usize::MAX, false).is_err());
+    }
+
+    #[test]
+    fn test_validation_invalid_limits() {
+        let req = CompletionRequest { 
+            prompt: "Hi".to_string(), 
+            limits: Some(RequestLimits { max_tokens: Some(9000), ..Default::default() }),
+            response_format: None,
+            memory_namespace: None,
+            classify_intent: false,
+            system: None,
+            debug: false,
+            dry_run: false,
+            stream: false,
+        };
+        assert!(validate_request(&req, usize::MAX, false).is_err());
+    }
+
+    #[test]
+    fn test_validation_sampling_limits() {
+        let limits = |top_k, top_p| RequestLimits { top_k, top_p, ..Default::default() };
+
+        assert!(validate_limits(Some(&limits(Some(0), None))).is_err());
+        assert!(validate_limits(Some(&limits(None, Some(0.0)))).is_err());
+        assert!(validate_limits(Some(&limits(None, Some(1.5)))).is_err());
+        assert!(validate_limits(Some(&RequestLimits { min_p: Some(1.5), ..Default::default() })).is_err());
+        assert!(validate_limits(Some(&RequestLimits { typical_p: Some(0.0), ..Default::default() })).is_err());
+        assert!(validate_limits(Some(&RequestLimits { logprobs: Some(11), ..Default::default() })).is_err());
+        assert_eq!(validate_limits(Some(&RequestLimits { logprobs: Some(10), ..Default::default() })).unwrap().logprobs, Some(10));
+
+        // Several truncation samplers at once are fine
+        let combined = RequestLimits { top_k: Some(40), top_p: Some(0.9), min_p: Some(0.05), typical_p: Some(0.95), ..Default::default() };
+        let options = validate_limits(Some(&combined)).unwrap();
+        assert_eq!(options.min_p, Some(0.05));
+        assert_eq!(options.typical_p, Some(0.95));
+
+        let ban = RequestLimits { logit_bias: HashMap::from([("```".to_string(), -100.0)]), ..Default::default() };
+        assert_eq!(validate_limits(Some(&ban)).unwrap().logit_bias["```"], -100.0);
+        let too_big = RequestLimits { logit_bias: HashMap::from([("yes".to_string(), 150.0)]), ..Default::default() };
+        assert!(validate_limits(Some(&too_big)).is_err());
+
+        let options = validate_limits(Some(&limits(Some(40), Some(1.0)))).unwrap();
+        assert_eq!(options.top_k, Some(40));
+        assert_eq!(options.top_p, Some(1.0));
+    }
+
+    #[test]
+    fn test_validation_penalty_limits() {
+        let invalid = [
+            RequestLimits { repeat_penalty: Some(0.0), ..Default::default() },
+            RequestLimits { repeat_penalty: Some(2.5), ..Default::default() },
+            RequestLimits { repeat_last_n: Some(10_000), ..Default::default() },
+            RequestLimits { frequency_penalty: Some(-2.5), ..Default::default() },
+            RequestLimits { presence_penalty: Some(3.0), ..Default::default() },
+        ];
+        for limits in &invalid {
+            assert!(validate_limits(Some(limits)).is_err());
+        }
+
+        let limits = RequestLimits {
+            repeat_penalty: Some(1.1),
+            repeat_last_n: Some(128),
+            frequency_penalty: Some(0.5),
+            presence_penalty: Some(-0.5),
+            penalize_prompt: true,
+            ..Default::default()
+        };
+        let options = validate_limits(Some(&limits)).unwrap();
+        assert_eq!(options.repeat_penalty, Some(1.1));
+        assert_eq!(options.repeat_last_n, Some(128));
+        assert_eq!(options.frequency_penalty, Some(0.5));
+        assert_eq!(options.presence_penalty, Some(-0.5));
+        assert!(options.penalize_prompt);
+    }
+
+    #[test]
+    fn test_validation_stop_sequences() {
+        let too_many = RequestLimits { stop: (0..9).map(|i| i.to_string()).collect(), ..Default::default() };
+        assert!(validate_limits(Some(&too_many)).is_err());
+        for sequence in ["", &"x".repeat(65)] {
+            let limits = RequestLimits { stop: vec![sequence.to_string()], ..Default::default() };
+            assert!(validate_limits(Some(&limits)).is_err());
+        }
+
+        let limits = RequestLimits { stop: vec!["\n\n".to_string(), "é".repeat(64)], ..Default::default() };
+        assert_eq!(validate_limits(Some(&limits)).unwrap().stop_sequences, limits.stop);
+    }
+
+    #[tokio::test]
+    async fn test_limits_reach_the_runtime() {
+        let runtime = MockRuntime::default();
+        let received = runtime.received.clone();
+        let engine = Engine::new(EngineConfig::default(), Box::new(runtime));
+        let router = Server::new(Arc::new(engine), ServerConfig::default()).router();
+
+        let limits = serde_json::json!({
+            "max_tokens": 32,
+            "temperature": 0.7,
+            "top_k": 40,
+            "top_p": 0.9,
+            "seed": 42,
+            "repeat_penalty": 1.1,
+            "stop": ["\n\n", "</answer>"],
+        });
+        let (status, _) = send(&router, "POST", "/v1/completion", Some(serde_json::json!({"prompt": "Hi", "limits": limits}))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let options = received.lock().unwrap().pop().expect("an inference");
+        assert_eq!(options.max_tokens, Some(32));
+        assert_eq!((options.temperature, options.top_k, options.top_p), (Some(0.7), Some(40), Some(0.9)));
+        assert_eq!((options.seed, options.repeat_penalty), (Some(42), Some(1.1)));
+        assert_eq!(options.stop_sequences, vec!["\n\n", "</answer>"]);
+    }
+
+    #[test]
+    fn test_validation_valid() {
+        let req = CompletionRequest { 
+            prompt: "Hi".to_string(), 
+            limits: Some(RequestLimits { max_tokens: Some(10), temperature: Some(0.5), ..Default::default() }),
+            response_format: None,
+            memory_namespace: None,
+            classify_intent: false,
+            system: None,
+            debug: false,
+            dry_run: false,
+            stream: false,
+        };
+        assert!(validate_request(&req, usize::MAX, false).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_status_success() {
+        let (status, body) = post_completion(test_router(false), serde_json::json!({"prompt": "Hi"})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.output.text, "Hi");
+    }
+
+    #[tokio::test]
+    async fn test_system_prompt_goes_before_the_prompt() {
+        let (status, body) = post_completion(test_router(false), serde_json::json!({"prompt": "Hi", "system": "Be brief."})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.output.text, "Be brief.\n\nHi");
+    }
+
+    #[tokio::test]
+    async fn test_debug_needs_allow_debug() {
+        let body = serde_json::json!({"prompt": "Hi", "system": "Be brief.", "dry_run": true, "debug": true});
+        let (status, response) = send(&test_router(false), "POST", "/v1/completion", Some(body.clone())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(response["error"].as_str().unwrap().contains("server.allow_debug"), "{}", response);
+
+        let runtime = MockRuntime::default();
+        let received = runtime.received.clone();
+        let engine = Engine::new(EngineConfig::default(), Box::new(runtime));
+        let config = ServerConfig { allow_debug: true, ..ServerConfig::default() };
+        let router = Server::new(Arc::new(engine), config).router();
+        let (status, response) = send(&router, "POST", "/v1/completion", Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["status"], "dry_run");
+        assert_eq!(response["debug"]["prompt"], "Be brief.\n\nHi");
+        assert_eq!(response["debug"]["options"]["max_tokens"], 128);
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_finish_reason_is_snake_case() {
+        let (_, body) = send(&test_router(false), "POST", "/v1/completion", Some(serde_json::json!({"prompt": "Hi"}))).await;
+        assert_eq!(body["finish_reason"], serde_json::json!({"type": "eos"}));
+
+        let stop = FinishReason::Stop { sequence: "\n\n".to_string() };
+        assert_eq!(serde_json::to_value(stop).unwrap(), serde_json::json!({"type": "stop", "sequence": "\n\n"}));
+    }
+
+    #[tokio::test]
+    async fn test_status_empty_prompt() {
+        let (status, body) = post_completion(test_router(false), serde_json::json!({"prompt": "  "})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.status, "error");
+    }
+
+    #[tokio::test]
+    async fn test_status_max_tokens_out_of_range() {
+        let (status, _) = post_completion(
+            test_router(false),
+            serde_json::json!({"prompt": "Hi", "limits": {"max_tokens": 9000}}),
+        ).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_status_runtime_error() {
+        let (status, body) = post_completion(test_router(true), serde_json::json!({"prompt": "Hi"})).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.error.unwrap().contains("Decode failed"));
+        let info = body.error_info.unwrap();
+        assert_eq!(info.code, ErrorCode::RuntimeError);
+        assert!(!info.retryable);
+    }
+
+    /// POST `body` as is to `/v1/completion`.
+    async fn post_raw(body: &str, content_type: Option<&str>) -> (StatusCode, EngineResponse) {
+        let mut builder = Request::builder().method("POST").uri("/v1/completion");
+        if let Some(content_type) = content_type {
+            builder = builder.header("content-type", content_type);
+        }
+        let response = test_router(false).oneshot(builder.body(Body::from(body.to_string())).unwrap()).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).expect("an error envelope"))
+    }
+
+    #[tokio::test]
+    async fn test_malformed_bodies_get_the_error_envelope() {
+        let json = Some("application/json");
+        let cases = [
+            (r#"{"prompt": "Hi", "limits": {"max_token": 5}}"#, json, StatusCode::BAD_REQUEST, "unknown field `max_token`"),
+            (r#"{"prompt": "Hi", "temprature": 0.5}"#, json, StatusCode::BAD_REQUEST, "unknown field `temprature`"),
+            (r#"{"prompt": "Hi", "limits": {"max_tokens": "many"}}"#, json, StatusCode::BAD_REQUEST, "limits.max_tokens: invalid type"),
+            (r#"{"prompt": "Hi", "limits": {"#, json, StatusCode::BAD_REQUEST, "EOF while parsing an object at line 1 column 28"),
+            (r#"{"prompt": "Hi"}"#, None, StatusCode::UNSUPPORTED_MEDIA_TYPE, "Content-Type"),
+        ];
+        for (body, content_type, expected_status, expected_message) in cases {
+            let (status, response) = post_raw(body, content_type).await;
+            assert_eq!(status, expected_status, "{}", body);
+            let info = response.error_info.unwrap();
+            assert_eq!(info.code, ErrorCode::ValidationError);
+            assert!(info.message.starts_with("Validation Error: "), "{}", info.message);
+            assert!(info.message.contains(expected_message), "{}: {}", body, info.message);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_requests_are_rejected() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+        let config = ServerConfig { max_body_bytes: 1024, max_prompt_chars: 100, ..ServerConfig::default() };
+        let router = Server::new(Arc::new(engine), config).router();
+
+        let (status, limits) = send(&router, "GET", "/v1/limits", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((limits["max_body_bytes"].as_u64(), limits["max_prompt_chars"].as_u64()), (Some(1024), Some(100)));
+        assert_eq!(limits["max_tokens"], 8192);
+
+        let (status, body) = send(&router, "POST", "/v1/completion", Some(serde_json::json!({"prompt": "x".repeat(2000)}))).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error_info"]["code"], "validation_error");
+        assert!(body["error"].as_str().unwrap().contains("server.max_body_bytes"), "{}", body);
+
+        let (status, body) = send(&router, "POST", "/v1/completion", Some(serde_json::json!({"prompt": "é".repeat(101)}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "Validation Error: prompt has 101 characters, more than the 100 allowed");
+
+        let messages = serde_json::json!([{"role": "system", "content": "x".repeat(60)}, {"role": "user", "content": "x".repeat(60)}]);
+        let (status, _) = send(&router, "POST", "/v1/chat", Some(serde_json::json!({"messages": messages}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = send(&router, "POST", "/v1/completion", Some(serde_json::json!({"prompt": "é".repeat(100)}))).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_limits_reload() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+        let server = Server::new(Arc::new(engine), ServerConfig { max_prompt_chars: 100, ..ServerConfig::default() });
+        let router = server.router();
+        let prompt = serde_json::json!({"prompt": "x".repeat(150)});
+        let (status, _) = send(&router, "POST", "/v1/completion", Some(prompt.clone())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Only the max_* limits change
+        server.limits().reload(&ServerConfig { max_prompt_chars: 200, max_body_bytes: 1000, allow_debug: true, ..ServerConfig::default() });
+        let (_, limits) = send(&router, "GET", "/v1/limits", None).await;
+        assert_eq!((limits["max_prompt_chars"].as_u64(), limits["max_body_bytes"].as_u64()), (Some(200), Some(1000)));
+        assert_eq!(limits["allow_debug"], false);
+        let (status, _) = send(&router, "POST", "/v1/completion", Some(prompt)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&router, "POST", "/v1/completion", Some(serde_json::json!({"prompt": "x".repeat(1000)}))).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_completion_batch() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+        let config = ServerConfig { max_batch_size: 3, ..ServerConfig::default() };
+        let router = Server::new(Arc::new(engine), config).router();
+
+        let requests = serde_json::json!([{"prompt": "first"}, {"prompt": " "}, {"prompt": "third", "limits": {"max_tokens": 4}}]);
+        let (status, batch) = send(&router, "POST", "/v1/completions/batch", Some(serde_json::json!({"requests": requests}))).await;
+        assert_eq!(status, StatusCode::OK);
+        let responses = batch["responses"].as_array().unwrap();
+        assert_eq!(responses.len(), 3);
+        assert_eq!((responses[0]["status"].as_str(), responses[0]["output"]["text"].as_str()), (Some("success"), Some("first")));
+        assert_eq!(responses[1]["error_info"]["code"], "validation_error");
+        assert_eq!(responses[2]["output"]["text"], "third");
+        assert!(responses[2]["request_id"].as_str().unwrap().ends_with("-2"));
+        assert_eq!((batch["usage"]["succeeded"].as_u64(), batch["usage"]["failed"].as_u64()), (Some(2), Some(1)));
+
+        let (_, limits) = send(&router, "GET", "/v1/limits", None).await;
+        assert_eq!(limits["max_batch_size"], 3);
+        for requests in [serde_json::json!([]), serde_json::json!([{"prompt": "a"}, {"prompt": "b"}, {"prompt": "c"}, {"prompt": "d"}])] {
+            let (status, body) = send(&router, "POST", "/v1/completions/batch", Some(serde_json::json!({"requests": requests}))).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(body["error"].as_str().unwrap().contains("1 to 3 requests"), "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_completion_batch_with_shared_options_runs_together() {
+        let runtime = MockRuntime::default();
+        let batches = runtime.batches.clone();
+        let engine = Engine::new(EngineConfig::default(), Box::new(runtime));
+        let router = Server::new(Arc::new(engine), ServerConfig::default()).router();
+
+        let limits = serde_json::json!({"max_tokens": 2});
+        let requests = serde_json::json!([{"prompt": "a", "limits": limits}, {"prompt": "b", "limits": limits}, {"prompt": "c", "limits": limits}]);
+        let (status, batch) = send(&router, "POST", "/v1/completions/batch", Some(serde_json::json!({"requests": requests}))).await;
+        assert_eq!(status, StatusCode::OK);
+        let texts: Vec<&str> = batch["responses"].as_array().unwrap().iter().map(|r| r["output"]["text"].as_str().unwrap()).collect();
+        assert_eq!(texts, vec!["a", "b", "c"]);
+        assert!(batch["responses"][1]["request_id"].as_str().unwrap().ends_with("-1"));
+        assert_eq!(*batches.lock().unwrap(), vec![3]);
+
+        // Different options run one by one
+        let requests = serde_json::json!([{"prompt": "a"}, {"prompt": "b", "limits": limits}]);
+        send(&router, "POST", "/v1/completions/batch", Some(serde_json::json!({"requests": requests}))).await;
+        assert_eq!(*batches.lock().unwrap(), vec![3]);
+    }
+
+    #[tokio::test]
+    async fn test_completion_batch_reports_item_failures() {
+        let (status, batch) = send(&test_router(true), "POST", "/v1/completions/batch", Some(serde_json::json!({"requests": [{"prompt": "Hi"}]}))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(batch["responses"][0]["error_info"]["code"], "runtime_error");
+        assert_eq!(batch["usage"]["failed"], 1);
+    }
+
+    #[test]
+    fn test_error_codes_map_to_statuses() {
+        let cases = [
+            (EngineError::Validation("empty".to_string()), StatusCode::BAD_REQUEST),
+            (EngineError::ContextOverflow { tokens: 5000, context_size: 4096 }, StatusCode::BAD_REQUEST),
+            (EngineError::ModelNotLoaded, StatusCode::SERVICE_UNAVAILABLE),
+            (EngineError::Timeout(100), StatusCode::GATEWAY_TIMEOUT),
+            (EngineError::Memory("KV limit reached".to_string()), StatusCode::INTERNAL_SERVER_ERROR),
+            (EngineError::Runtime("Decode failed".to_string()), StatusCode::INTERNAL_SERVER_ERROR),
+        ];
+        for (error, status) in cases {
+            let code = error.code();
+            let api_error = ApiError::from(error);
+            assert_eq!(api_error.status, status, "{:?}", code);
+            assert_eq!(api_error.response.error_info.unwrap().code, code);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chat_endpoint() {
+        let router = test_router(false);
+        let (status, body) = send(&router, "POST", "/v1/chat", Some(serde_json::json!({
+            "messages": [{"role": "user", "content": "Hi"}]
+        }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["output"]["text"], "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n");
+
+        let (status, _) = send(&router, "POST", "/v1/chat", Some(serde_json::json!({"messages": []}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_completion_json_mode() {
+        let router = test_router(false);
+        let schema = r#"{"type": "object", "properties": {"ok": {"type": "boolean"}}, "required": ["ok"]}"#;
+
+        // The mock echoes the prompt, so the prompt plays the model's output
+        let (status, body) = send(&router, "POST", "/v1/completion", Some(serde_json::json!({
+            "prompt": "{\"ok\": true}",
+            "response_format": {"type": "json", "schema": schema}
+        }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["output"]["json"], serde_json::json!({"ok": true}));
+
+        let (_, body) = send(&router, "POST", "/v1/completion", Some(serde_json::json!({
+            "prompt": "Sure! {\"ok\": true}",
+            "response_format": {"type": "json"}
+        }))).await;
+        assert_eq!(body["status"], "error");
+        assert!(body["output"].get("json").is_none());
+
+        let (status, _) = send(&router, "POST", "/v1/completion", Some(serde_json::json!({
+            "prompt": "Hi",
+            "response_format": {"type": "json", "schema": "{\"type\": \"date\"}"}
+        }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_endpoint() {
+        let router = test_router(false);
+
+        let (status, body) = send(&router, "POST", "/v1/embeddings", Some(serde_json::json!({"input": "abc"}))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["object"], "list");
+        assert_eq!(body["data"][0], serde_json::json!({"object": "embedding", "index": 0, "embedding": [3.0, 1.0]}));
+
+        let (_, body) = send(&router, "POST", "/v1/embeddings", Some(serde_json::json!({
+            "input": ["a", "bb"],
+            "model": "ignored"
+        }))).await;
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[1]["index"], 1);
+        assert_eq!(data[1]["embedding"][0], 2.0);
+
+        let (status, _) = send(&router, "POST", "/v1/embeddings", Some(serde_json::json!({"input": []}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_tokenize_endpoint() {
+        let router = test_router(false);
+
+        let (status, body) = send(&router, "POST", "/v1/tokenize", Some(serde_json::json!({"text": "Hi"}))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({"tokens": [1, 72, 105], "count": 3, "pieces": ["<s>", "H", "i"]}));
+
+        let (_, body) = send(&router, "POST", "/v1/tokenize", Some(serde_json::json!({"text": "Hi", "add_bos": false}))).await;
+        assert_eq!(body["count"], 2);
+        assert_eq!(body["pieces"], serde_json::json!(["H", "i"]));
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_unsupported() {
+        let (status, body) = send(&test_router(true), "POST", "/v1/embeddings", Some(serde_json::json!({"input": "abc"}))).await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(body["status"], "error");
+    }
+
+    /// Takes `delay` per request on the blocking pool, like the llama.cpp worker does.
+    struct SlowRuntime {
+        delay: std::time::Duration,
+    }
+
+    #[async_trait]
+    impl ModelRuntime for SlowRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, _options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            let delay = self.delay;
+            tokio::task::spawn_blocking(move || std::thread::sleep(delay)).await.unwrap();
+            Ok(InferenceResult { text: prompt.to_string(), usage: Usage::default(), finish_reason: FinishReason::Eos, seed: None, logprobs: None })
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_answers_429() {
+        let mut config = EngineConfig::default();
+        config.queue.max_queue_depth = 1;
+        let runtime = SlowRuntime { delay: std::time::Duration::from_millis(300) };
+        let router = Server::new(Arc::new(Engine::new(config, Box::new(runtime))), ServerConfig::default()).router();
+
+        // One running, one waiting; everything after that is turned away
+        let mut accepted = Vec::new();
+        for _ in 0..2 {
+            accepted.push(tokio::spawn(post_completion(router.clone(), serde_json::json!({"prompt": "Hi"}))));
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+
+        let started = std::time::Instant::now();
+        for _ in 0..5 {
+            let request = Request::post("/v1/completion")
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"prompt": "Hi"}"#))
+                .unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            let retry_after: u64 = response.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+            assert!(retry_after >= 1);
+        }
+        assert!(started.elapsed() < std::time::Duration::from_millis(200));
+
+        let mut positions = Vec::new();
+        for request in accepted {
+            let (status, response) = request.await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            positions.push(response.queue.unwrap().position);
+        }
+        assert_eq!(positions, vec![0, 1]);
+    }
+
+    /// Ignores cancellation and never returns.
+    struct StuckRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for StuckRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, _prompt: &str, _options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            std::future::pending().await
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout_answers_504() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(StuckRuntime));
+        let router = Server::new(Arc::new(engine), ServerConfig::default()).router();
+
+        let (status, response) = post_completion(router, serde_json::json!({"prompt": "Hi", "limits": {"max_time_ms": 100}})).await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(response.error.unwrap().contains("timed out"));
+    }
+
+    /// A single-threaded executor makes any blocking in the request path stall the health check.
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_health_responds_during_inference() {
+        let runtime = SlowRuntime { delay: std::time::Duration::from_millis(500) };
+        let engine = Engine::new(EngineConfig::default(), Box::new(runtime));
+        let router = Server::new(Arc::new(engine), EngineConfig::default().server).router();
+
+        let completion = tokio::spawn(post_completion(router.clone(), serde_json::json!({"prompt": "Hi"})));
+        tokio::task::yield_now().await;
+
+        let started = std::time::Instant::now();
+        let (status, _) = send(&router, "GET", "/v1/health", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(started.elapsed() < std::time::Duration::from_millis(250));
+        assert!(!completion.is_finished());
+
+        let (status, _) = completion.await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// Records the request's cancellation token and never finishes on its own.
+    struct HangingRuntime {
+        seen: Arc<std::sync::Mutex<Option<tokio_util::sync::CancellationToken>>>,
+    }
+
+    #[async_trait]
+    impl ModelRuntime for HangingRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, _prompt: &str, options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            *self.seen.lock().unwrap() = Some(options.cancel.clone());
+            std::future::pending().await
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dropped_request_cancels_generation() {
+        let seen = Arc::new(std::sync::Mutex::new(None));
+        let engine = Engine::new(EngineConfig::default(), Box::new(HangingRuntime { seen: seen.clone() }));
+        let router = Server::new(Arc::new(engine), EngineConfig::default().server).router();
+
+        // The client gives up: the handler future is dropped mid-generation
+        let request = post_completion(router, serde_json::json!({"prompt": "Hi"}));
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(50), request).await.is_err());
+
+        let token = seen.lock().unwrap().clone().expect("runtime was called");
+        assert!(token.is_cancelled());
+    }
+
+    /// Sends one token, then waits for the request to be cancelled.
+    struct StreamingRuntime;
+
+    #[async_trait]
+    impl ModelRuntime for StreamingRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, _prompt: &str, _options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            unreachable!("jobs stream")
+        }
+
+        async fn infer_stream(&mut self, _prompt: &str, options: InferenceOptions, tx: tokio::sync::mpsc::UnboundedSender<TokenChunk>) -> Result<InferenceResult, EngineError> {
+            let _ = tx.send(TokenChunk::Token { text: "Once upon".to_string() });
+            options.cancel.cancelled().await;
+            Ok(InferenceResult { text: "Once upon".to_string(), usage: Usage::default(), finish_reason: FinishReason::Cancelled, seed: None, logprobs: None })
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    /// Poll the job until it is in `state`.
+    async fn wait_for_job(router: &Router, id: &str, state: &str) -> serde_json::Value {
+        for _ in 0..100 {
+            let (status, job) = send(router, "GET", &format!("/v1/jobs/{}", id), None).await;
+            assert_eq!(status, StatusCode::OK);
+            if job["state"] == state {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("job {} never got {}", id, state);
+    }
+
+    #[tokio::test]
+    async fn test_jobs() {
+        let router = test_router(false);
+
+        let request = Request::post("/v1/jobs")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::json!({"prompt": "Hello"}).to_string()))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let location = response.headers()["location"].to_str().unwrap().to_string();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let job: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let id = job["id"].as_str().unwrap();
+        assert_eq!(location, format!("/v1/jobs/{}", id));
+        assert_eq!(job["state"], "queued");
+
+        let job = wait_for_job(&router, id, "done").await;
+        assert_eq!(job["output"], "Hello");
+        assert!(job["finished_at"].is_u64());
+        let (status, result) = send(&router, "GET", &format!("/v1/jobs/{}/result", id), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((result["status"].as_str(), result["output"]["text"].as_str()), (Some("success"), Some("Hello")));
+
+        // Deleting a finished job forgets it
+        let request = Request::delete(&location).body(Body::empty()).unwrap();
+        assert_eq!(router.clone().oneshot(request).await.unwrap().status(), StatusCode::NO_CONTENT);
+        let (status, body) = send(&router, "GET", &location, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error_info"]["code"], "not_found");
+
+        let (status, _) = send(&router, "POST", "/v1/jobs", Some(serde_json::json!({"prompt": ""}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_job_failure() {
+        let router = test_router(true);
+        let (_, job) = send(&router, "POST", "/v1/jobs", Some(serde_json::json!({"prompt": "Hello"}))).await;
+        let id = job["id"].as_str().unwrap();
+
+        wait_for_job(&router, id, "failed").await;
+        let (status, result) = send(&router, "GET", &format!("/v1/jobs/{}/result", id), None).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(result["error_info"]["code"], "runtime_error");
+    }
+
+    #[tokio::test]
+    async fn test_job_partial_output_and_cancel() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(StreamingRuntime));
+        let config = ServerConfig { jobs: JobsConfig { max_active: 1, ..JobsConfig::default() }, ..ServerConfig::default() };
+        let router = Server::new(Arc::new(engine), config).router();
+
+        let (_, job) = send(&router, "POST", "/v1/jobs", Some(serde_json::json!({"prompt": "Tell a story"}))).await;
+        let id = job["id"].as_str().unwrap();
+        let job = wait_for_job(&router, id, "running").await;
+        assert_eq!(job["output"], "Once upon");
+        let (status, result) = send(&router, "GET", &format!("/v1/jobs/{}/result", id), None).await;
+        assert_eq!((status, result["state"].as_str()), (StatusCode::ACCEPTED, Some("running")));
+
+        // Only one job may be active
+        let (status, body) = send(&router, "POST", "/v1/jobs", Some(serde_json::json!({"prompt": "Another"}))).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["error_info"]["code"], "queue_full");
+
+        let request = Request::delete(format!("/v1/jobs/{}", id)).body(Body::empty()).unwrap();
+        assert_eq!(router.clone().oneshot(request).await.unwrap().status(), StatusCode::NO_CONTENT);
+        wait_for_job(&router, id, "cancelled").await;
+        let (status, result) = send(&router, "GET", &format!("/v1/jobs/{}/result", id), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((result["status"].as_str(), result["output"]["text"].as_str()), (Some("cancelled"), Some("Once upon")));
+    }
+
+    type TestSocket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+    /// Serve `engine` on a port of its own and open a WebSocket to `/v1/ws`, with the request
+    /// id `ws-test`.
+    async fn open_test_socket(engine: Arc<Engine>) -> TestSocket {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let mut server = Server::new(engine, ServerConfig { port: 0, ..ServerConfig::default() });
+        let addr = server.bind().await.unwrap().tcp().unwrap();
+        tokio::spawn(async move { server.run().await });
+        let mut request = format!("ws://{}/v1/ws", addr).into_client_request().unwrap();
+        request.headers_mut().insert("x-request-id", "ws-test".parse().unwrap());
+        let (socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        socket
+    }
+
+    async fn send_frame(socket: &mut TestSocket, frame: serde_json::Value) {
+        use futures_util::SinkExt;
+        socket.send(tokio_tungstenite::tungstenite::Message::Text(frame.to_string())).await.unwrap();
+    }
+
+    async fn next_frame(socket: &mut TestSocket) -> serde_json::Value {
+        use futures_util::StreamExt;
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next()).await.expect("a frame").unwrap().unwrap();
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_websocket_chat() {
+        let engine = Arc::new(Engine::new(EngineConfig::default(), Box::new(MockRuntime::default())));
+        let session = engine.sessions.create(None).await.unwrap();
+        let mut socket = open_test_socket(engine.clone()).await;
+
+        send_frame(&mut socket, serde_json::json!({"type": "chat", "session_id": session.id, "content": "Hello there", "limits": {"max_tokens": 16}})).await;
+        let mut text = String::new();
+        let done = loop {
+            let frame = next_frame(&mut socket).await;
+            match frame["type"].as_str().unwrap() {
+                "token" => text.push_str(frame["text"].as_str().unwrap()),
+                _ => break frame,
+            }
+        };
+        assert_eq!(done["type"], "done", "{}", done);
+        assert_eq!(done["status"], "success");
+        assert_eq!(done["output"]["text"], text.as_str());
+        // Each reply's id is the connection's, numbered
+        assert_eq!(done["request_id"], "ws-test-0");
+
+        // The history is kept on the server
+        let history = engine.sessions.get(&session.id).await.unwrap().messages;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].content, text);
+
+        // Bad frames are answered with an error, and the connection stays usable
+        send_frame(&mut socket, serde_json::json!({"type": "shout"})).await;
+        let error = next_frame(&mut socket).await;
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["error_info"]["code"], "validation_error");
+        send_frame(&mut socket, serde_json::json!({"type": "chat", "session_id": "missing", "content": "Hi"})).await;
+        let error = next_frame(&mut socket).await;
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["error_info"]["code"], "not_found");
+        assert_eq!(error["request_id"], "ws-test-1");
+    }
+
+    #[tokio::test]
+    async fn test_websocket_cancel_and_one_reply_at_a_time() {
+        let engine = Arc::new(Engine::new(EngineConfig::default(), Box::new(StreamingRuntime)));
+        let session = engine.sessions.create(None).await.unwrap();
+        let mut socket = open_test_socket(engine.clone()).await;
+        let chat = serde_json::json!({"type": "chat", "session_id": session.id, "content": "Tell a story"});
+
+        send_frame(&mut socket, chat.clone()).await;
+        assert_eq!(next_frame(&mut socket).await, serde_json::json!({"type": "token", "text": "Once upon"}));
+        send_frame(&mut socket, chat.clone()).await;
+        let busy = next_frame(&mut socket).await;
+        assert_eq!(busy["type"], "error");
+        assert!(busy["error"].as_str().unwrap().contains("still being generated"), "{}", busy);
+
+        send_frame(&mut socket, serde_json::json!({"type": "cancel"})).await;
+        let done = next_frame(&mut socket).await;
+        assert_eq!(done["type"], "done");
+        assert_eq!(done["status"], "cancelled");
+
+        // Closing the connection cancels the reply, which lets go of the runtime
+        send_frame(&mut socket, chat.clone()).await;
+        assert_eq!(next_frame(&mut socket).await["type"], "token");
+        drop(socket);
+        let mut another = open_test_socket(engine).await;
+        send_frame(&mut another, chat).await;
+        assert_eq!(next_frame(&mut another).await["type"], "token");
+    }
+
+    #[tokio::test]
+    async fn test_streamed_completion() {
+        use http_body_util::BodyExt;
+
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+        let router = Server::new(Arc::new(engine), ServerConfig::default()).router();
+        let request = Request::post("/v1/completion").header("content-type", "application/json")
+            .body(Body::from(serde_json::json!({"prompt": "Hello there", "stream": true}).to_string())).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let body = String::from_utf8(response.into_body().collect().await.unwrap().to_bytes().to_vec()).unwrap();
+        assert!(body.starts_with("event: token\ndata: {\"text\":"), "{}", body);
+        let done = body.split("event: done\ndata: ").nth(1).expect("a done event");
+        let done: EngineResponse = serde_json::from_str(done.trim()).unwrap();
+        assert_eq!(done.status, "success");
+        assert!(done.request_id.is_some());
+
+        // Rejected up front like any other request, and not for jobs or batches
+        let (status, _) = send(&router, "POST", "/v1/completion", Some(serde_json::json!({"prompt": " ", "stream": true}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(&router, "POST", "/v1/jobs", Some(serde_json::json!({"prompt": "Hi", "stream": true}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_closing_a_stream_cancels_it() {
+        use http_body_util::BodyExt;
+
+        let engine = Engine::new(EngineConfig::default(), Box::new(StreamingRuntime));
+        let router = Server::new(Arc::new(engine), ServerConfig::default()).router();
+        let request = Request::post("/v1/completion").header("content-type", "application/json")
+            .body(Body::from(serde_json::json!({"prompt": "Tell a story", "stream": true}).to_string())).unwrap();
+        let mut body = router.clone().oneshot(request).await.unwrap().into_body();
+        let frame = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(frame, "event: token\ndata: {\"text\":\"Once upon\"}\n\n");
+        drop(body);
+
+        // The runtime only gets to the job once the stream has let go of it
+        let (_, job) = send(&router, "POST", "/v1/jobs", Some(serde_json::json!({"prompt": "Another"}))).await;
+        wait_for_job(&router, job["id"].as_str().unwrap(), "running").await;
+    }
+
+    #[tokio::test]
+    async fn test_models_lists_loaded_model() {
+        let info = ModelInfo {
+            name: "tiny-chat".to_string(),
+            architecture: "llama".to_string(),
+            parameter_count: 1_000_000,
+            quantization: "Q4_K_M".to_string(),
+            native_context_size: 4096,
+            vocab_size: 32000,
+            file_size: 1024,
+            path: "models/tiny-chat.gguf".into(),
+            context_size: 2048,
+            gpu_layers: 10,
+            n_threads: 4,
+            n_threads_batch: 4,
+            use_mmap: true,
+            use_mlock: false,
+            kv_cache_type: KvCacheType::F16,
+            loaded_at: 1_700_000_000,
+        };
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime { info: Some(info), ..Default::default() }));
+        let router = Server::new(Arc::new(engine), EngineConfig::default().server).router();
+
+        let (status, body) = send(&router, "GET", "/v1/models", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["object"], "list");
+        let model = &body["data"][0];
+        assert_eq!(model["id"], "tiny-chat");
+        assert_eq!(model["object"], "model");
+        assert_eq!(model["created"], 1_700_000_000);
+        assert_eq!(model["path"], "models/tiny-chat.gguf");
+        assert_eq!(model["context_size"], 2048);
+        assert_eq!(model["gpu_layers"], 10);
+    }
+
+    #[tokio::test]
+    async fn test_models_empty_when_not_loaded() {
+        let (status, body) = send(&test_router(false), "GET", "/v1/models", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_models_lists_the_models_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        // Just a header: no metadata and no tensors
+        let mut header = b"GGUF".to_vec();
+        header.extend(3u32.to_le_bytes());
+        header.extend([0u8; 16]);
+        std::fs::write(dir.path().join("tiny.gguf"), &header).unwrap();
+        std::fs::write(dir.path().join("broken.gguf"), b"not a model").unwrap();
+
+        let mut config = EngineConfig::default();
+        config.model.models_dir = dir.path().to_path_buf();
+        let router = Server::new(Arc::new(Engine::new(config.clone(), Box::new(MockRuntime::default()))), config.server).router();
+        let (status, body) = send(&router, "GET", "/v1/models", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let available = body["available"].as_array().unwrap();
+        assert_eq!(available.len(), 1, "{}", body);
+        assert_eq!(available[0]["name"], "tiny");
+        assert_eq!(available[0]["size"], 24);
+    }
+
+    fn memory_router(dir: &tempfile::TempDir) -> Router {
+        let mut config = EngineConfig::default();
+        config.memory.enabled = true;
+        config.memory.persistence_path = dir.path().join("memory.json");
+        let engine = Engine::new(config.clone(), Box::new(MockRuntime::default()));
+        Server::new(Arc::new(engine), config.server).router()
+    }
+
+    async fn send(router: &Router, method: &str, uri: &str, body: Option<serde_json::Value>) -> (StatusCode, serde_json::Value) {
+        let builder = Request::builder().method(method).uri(uri);
+        let request = match body {
+            Some(body) => builder.header("content-type", "application/json").body(Body::from(body.to_string())),
+            None => builder.body(Body::empty()),
+        }.unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_memory_facts_endpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = memory_router(&dir);
+
+        let (status, _) = send(&router, "POST", "/v1/memory/facts", Some(serde_json::json!({"key": "name", "value": "Divyansh"}))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = send(&router, "GET", "/v1/memory/facts/name", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["fact"]["value"], "Divyansh");
+
+        let (status, body) = send(&router, "GET", "/v1/memory/facts", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["facts"], serde_json::json!([{"key": "name", "value": "Divyansh"}]));
+
+        let (status, _) = send(&router, "DELETE", "/v1/memory/facts/name", None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = send(&router, "GET", "/v1/memory/facts/name", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], "error");
+
+        let (status, _) = send(&router, "DELETE", "/v1/memory/facts/name", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_memory_writes_conflict_when_disabled() {
+        let router = test_router(false);
+        let (status, body) = send(&router, "POST", "/v1/memory/facts", Some(serde_json::json!({"key": "name", "value": "Divyansh"}))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error_info"]["code"], "memory_disabled");
+
+        let (status, _) = send(&router, "PUT", "/v1/memory/summary", Some(serde_json::json!({"summary": "Likes Rust"}))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        // Reads still work, and find nothing
+        let (status, body) = send(&router, "GET", "/v1/memory/facts", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["facts"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_memory_namespace_endpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = memory_router(&dir);
+
+        send(&router, "POST", "/v1/memory/facts", Some(serde_json::json!({"key": "user", "value": "Alice"}))).await;
+        let (status, _) = send(&router, "POST", "/v1/memory/namespaces/bob/facts", Some(serde_json::json!({"key": "user", "value": "Bob"}))).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, body) = send(&router, "GET", "/v1/memory/namespaces/bob/facts/user", None).await;
+        assert_eq!(body["fact"]["value"], "Bob");
+        let (_, body) = send(&router, "GET", "/v1/memory/facts/user", None).await;
+        assert_eq!(body["fact"]["value"], "Alice");
+        let (_, body) = send(&router, "GET", "/v1/memory/namespaces", None).await;
+        assert_eq!(body["namespaces"], serde_json::json!(["bob", "default"]));
+
+        let (status, _) = send(&router, "PUT", "/v1/memory/namespaces/bad%20name/summary", Some(serde_json::json!({"summary": "x"}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Completions pick the namespace to inject from
+        let (_, body) = send(&router, "POST", "/v1/completion", Some(serde_json::json!({"prompt": "Who am I?", "memory_namespace": "bob"}))).await;
+        assert!(body["output"]["text"].as_str().unwrap().contains("user=Bob"));
+        let (status, _) = send(&router, "POST", "/v1/completion", Some(serde_json::json!({"prompt": "Hi", "memory_namespace": "a/b"}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = send(&router, "DELETE", "/v1/memory/namespaces/bob", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&router, "GET", "/v1/memory/namespaces/bob/facts/user", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_memory_summary_and_clear_endpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = memory_router(&dir);
+
+        let (status, body) = send(&router, "PUT", "/v1/memory/summary", Some(serde_json::json!({"summary": "Likes Rust"}))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["summary"], "Likes Rust");
+        send(&router, "POST", "/v1/memory/facts", Some(serde_json::json!({"key": "a", "value": "b"}))).await;
+
+        let (status, _) = send(&router, "DELETE", "/v1/memory", None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, body) = send(&router, "GET", "/v1/memory/summary", None).await;
+        assert_eq!(body["summary"], "");
+        let (_, body) = send(&router, "GET", "/v1/memory/facts", None).await;
+        assert_eq!(body["facts"], serde_json::json!([]));
+    }
+
+    fn test_server(host: &str, port: u16) -> Server {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+        Server::new(Arc::new(engine), ServerConfig { host: host.to_string(), port, ..ServerConfig::default() })
+    }
+
+    #[tokio::test]
+    async fn test_bind_reports_local_addr() {
+        let mut server = test_server("127.0.0.1", 0);
+        assert!(server.local_addr().is_none());
+
+        let addr = server.bind().await.unwrap().tcp().unwrap();
+        assert_ne!(addr.port(), 0);
+        assert_eq!(server.local_addr(), Some(addr));
+    }
+
+    #[tokio::test]
+    async fn test_bind_port_in_use() {
+        let mut first = test_server("127.0.0.1", 0);
+        let addr = first.bind().await.unwrap().tcp().unwrap();
+
+        let mut second = test_server("127.0.0.1", addr.port());
+        let err = second.bind().await.unwrap_err();
+        assert!(err.to_string().contains("already in use"));
+    }
+
+    /// Sleeps `delay` per request and records whether it was unloaded.
+    struct UnloadTrackingRuntime {
+        delay: Duration,
+        unloaded: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    #[async_trait]
+    impl ModelRuntime for UnloadTrackingRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, prompt: &str, _options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            tokio::time::sleep(self.delay).await;
+            Ok(InferenceResult { text: prompt.to_string(), usage: Usage::default(), finish_reason: FinishReason::Eos, seed: None, logprobs: None })
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            self.unloaded.store(true, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// A minimal HTTP/1.1 client, so the test goes through a real connection.
+    async fn raw_post(addr: SocketAddr, path: &str, body: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path, body.len(), body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown_finishes_requests_and_unloads() {
+        let unloaded = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let runtime = UnloadTrackingRuntime { delay: Duration::from_millis(200), unloaded: unloaded.clone() };
+        let engine = Engine::new(EngineConfig::default(), Box::new(runtime));
+        let mut server = Server::new(Arc::new(engine), ServerConfig { port: 0, ..ServerConfig::default() });
+        let addr = server.bind().await.unwrap().tcp().unwrap();
+
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(async move {
+            server.run_until(async { let _ = stop_rx.await; }).await
+        });
+
+        let request = tokio::spawn(raw_post(addr, "/v1/completion", r#"{"prompt": "Hi"}"#));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop_tx.send(()).unwrap();
+
+        // The in-flight request still completes, then the model is unloaded
+        let response = request.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        serving.await.unwrap().unwrap();
+        assert!(unloaded.load(std::sync::atomic::Ordering::SeqCst));
+
+        // New connections are refused
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("lie.sock");
+        // Left behind by a server that didn't shut down cleanly
+        drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+        let config = ServerConfig { listen: ListenMode::Unix, socket_path: socket_path.clone(), ..ServerConfig::default() };
+        let mut server = Server::new(Arc::new(engine), config.clone());
+        assert_eq!(server.bind().await.unwrap(), ListenAddr::Unix(socket_path.clone()));
+        assert!(server.local_addr().is_none());
+
+        // A second server doesn't take over a socket in use
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+        let err = Server::new(Arc::new(engine), config).bind().await.unwrap_err();
+        assert!(err.to_string().contains("already in use"), "{}", err);
+
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(async move {
+            server.run_until(async { let _ = stop_rx.await; }).await
+        });
+
+        let mut stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        stream.write_all(b"GET /v1/health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        stop_tx.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+        assert!(!socket_path.exists());
+    }
+
+    /// A self-signed certificate for `localhost`, made for these tests only.
+    fn test_tls_config() -> TlsConfig {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        TlsConfig { cert_path: dir.join("cert.pem"), key_path: dir.join("key.pem") }
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+        let config = ServerConfig { port: 0, tls: Some(test_tls_config()), ..ServerConfig::default() };
+        let mut server = Server::new(Arc::new(engine), config);
+        let addr = server.bind().await.unwrap().tcp().unwrap();
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(async move {
+            server.run_until(async { let _ = stop_rx.await; }).await
+        });
+
+        let cert = native_tls::Certificate::from_pem(&std::fs::read(test_tls_config().cert_path).unwrap()).unwrap();
+        let connector = native_tls::TlsConnector::builder().add_root_certificate(cert).build().unwrap();
+        let connector = tokio_native_tls::TlsConnector::from(connector);
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector.connect("localhost", stream).await.unwrap();
+        stream.write_all(b"GET /v1/health HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = vec![0; 4096];
+        let n = stream.read(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response[..n]);
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        stop_tx.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_config_errors() {
+        let missing = TlsConfig { cert_path: "no/such/cert.pem".into(), ..test_tls_config() };
+        // The key where the certificate should be
+        let swapped = TlsConfig { cert_path: test_tls_config().key_path, key_path: test_tls_config().cert_path };
+        for (tls, expected) in [(missing, "Failed to read the TLS certificate no/such/cert.pem"), (swapped, "Failed to load the TLS certificate")] {
+            let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+            let mut server = Server::new(Arc::new(engine), ServerConfig { port: 0, tls: Some(tls), ..ServerConfig::default() });
+            let err = server.bind().await.unwrap_err();
+            assert!(matches!(err.downcast_ref(), Some(EngineError::Config(_))), "{}", err);
+            assert!(err.to_string().contains(expected), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_invalid_host() {
+        let mut server = test_server("not a host", 0);
+        let err = server.bind().await.unwrap_err();
+        assert!(err.to_string().contains("Invalid server host"));
+    }
+
+    #[tokio::test]
+    async fn test_threads_reach_the_load_config() {
+        let mut config = EngineConfig::default();
+        config.model.n_threads = Some(3);
+        let engine = Arc::new(Engine::new(config, Box::new(SlowRuntime { delay: std::time::Duration::ZERO })));
+        let router = Server::new(engine.clone(), ServerConfig::default()).router();
+
+        let (_, health) = send(&router, "GET", "/v1/health", None).await;
+        assert_eq!(health["threads"], serde_json::json!({"n_threads": 3, "n_threads_batch": 3}));
+
+        let body = serde_json::json!({"path": "models/other.gguf", "n_threads_batch": 8});
+        let (status, _) = send(&router, "POST", "/v1/admin/model", Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+        let load_config = engine.load_config();
+        assert_eq!((load_config.n_threads, load_config.n_threads_batch), (Some(3), Some(8)));
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let engine = Engine::new(EngineConfig::default(), Box::new(MockRuntime::default()));
+        let build_info = BuildInfo { features: vec!["cuda".to_string()], backend: Some("mock".to_string()), ..BuildInfo::current() };
+        let router = Server::new(Arc::new(engine), ServerConfig::default()).with_build_info(build_info).router();
+
+        let (status, version) = send(&router, "GET", "/v1/version", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!((version["features"].clone(), version["backend"].clone()), (serde_json::json!(["cuda"]), serde_json::json!("mock")));
+        assert!(version["build_date"].is_string());
+
+        let (_, health) = send(&router, "GET", "/v1/health", None).await;
+        assert_eq!(health["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_model_state() {
+        let engine = Arc::new(Engine::new(EngineConfig::default(), Box::new(SlowRuntime { delay: std::time::Duration::ZERO })));
+        let router = Server::new(engine.clone(), ServerConfig::default()).router();
+
+        let (status, health) = send(&router, "GET", "/v1/health", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(health["model"], "not_loaded");
+        assert!(health["threads"]["n_threads"].as_u64().unwrap() >= 1);
+
+        engine.init().await.unwrap();
+        let (_, health) = send(&router, "GET", "/v1/health", None).await;
+        assert_eq!(health["model"], "ready");
+        assert!(health.get("load_progress").is_none());
+
+        // An idle-unloaded model still counts as ready; the next request reloads it
+        assert!(engine.unload_if_idle(std::time::Duration::ZERO).await.unwrap());
+        let (_, health) = send(&router, "GET", "/v1/health", None).await;
+        assert_eq!(health["model"], "idle");
+        let (status, _) = send(&router, "GET", "/v1/ready", None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// Loads only once `release` is notified.
+    struct GatedLoadRuntime {
+        release: Arc<tokio::sync::Notify>,
+    }
+
+    #[async_trait]
+    impl ModelRuntime for GatedLoadRuntime {
+        async fn load(&mut self, _config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            self.release.notified().await;
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, _prompt: &str, _options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            Ok(InferenceResult { text: "Hi".to_string(), usage: Usage::default(), finish_reason: FinishReason::Eos, seed: None, logprobs: None })
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ready_only_after_load() {
+        let release = Arc::new(tokio::sync::Notify::new());
+        let engine = Arc::new(Engine::new(EngineConfig::default(), Box::new(GatedLoadRuntime { release: release.clone() })));
+        let router = Server::new(engine.clone(), ServerConfig::default()).router();
+
+        let (status, ready) = send(&router, "GET", "/v1/ready", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(ready["state"], "not_loaded");
+        assert_eq!(ready["model_path"], "models/default.gguf");
+
+        let init = {
+            let engine = engine.clone();
+            tokio::spawn(async move { engine.init().await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        // Liveness stays ok while readiness and inference report the load
+        let (status, _) = send(&router, "GET", "/v1/health", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, ready) = send(&router, "GET", "/v1/ready", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(ready["state"], "loading");
+        let (status, response) = post_completion(router.clone(), serde_json::json!({"prompt": "Hi"})).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.error_info.unwrap().code, ErrorCode::ModelNotLoaded);
+
+        release.notify_one();
+        init.await.unwrap().unwrap();
+        let (status, ready) = send(&router, "GET", "/v1/ready", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ready["state"], "ready");
+    }
+
+    /// Fails to load paths containing "bad".
+    #[derive(Default)]
+    struct PickyLoadRuntime {
+        loaded: bool,
+    }
+
+    #[async_trait]
+    impl ModelRuntime for PickyLoadRuntime {
+        async fn load(&mut self, config: &ModelLoadConfig) -> Result<LoadReport, EngineError> {
+            if config.model_path.to_string_lossy().contains("bad") {
+                return Err(EngineError::Runtime("Failed to load model".to_string()));
+            }
+            self.loaded = true;
+            Ok(LoadReport::default())
+        }
+
+        async fn infer(&mut self, _prompt: &str, _options: InferenceOptions) -> Result<InferenceResult, EngineError> {
+            if !self.loaded {
+                return Err(EngineError::ModelNotLoaded);
+            }
+            Ok(InferenceResult { text: "Hi".to_string(), usage: Usage::default(), finish_reason: FinishReason::Eos, seed: None, logprobs: None })
+        }
+
+        async fn unload(&mut self) -> Result<(), EngineError> {
+            self.loaded = false;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_model_swap() {
+        let engine = Arc::new(Engine::new(EngineConfig::default(), Box::new(PickyLoadRuntime::default())));
+        engine.init().await.unwrap();
+        let router = Server::new(engine, ServerConfig::default()).router();
+
+        let (status, model) = send(&router, "POST", "/v1/admin/model", Some(serde_json::json!({"path": "other.gguf", "context_size": 1024}))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(model["state"], "ready");
+        assert_eq!(model["config"]["model_path"], "other.gguf");
+        assert_eq!(model["config"]["context_size"], 1024);
+        assert_eq!(model["config"]["batch_size"], 512);
+
+        // The old model stays loaded when the new one fails
+        let (status, _) = send(&router, "POST", "/v1/admin/model", Some(serde_json::json!({"path": "bad.gguf"}))).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let (_, model) = send(&router, "GET", "/v1/admin/model", None).await;
+        assert_eq!((model["state"].as_str(), model["config"]["model_path"].as_str()), (Some("ready"), Some("other.gguf")));
+
+        let (status, model) = send(&router, "DELETE", "/v1/admin/model", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(model["state"], "not_loaded");
+        let (status, _) = post_completion(router.clone(), serde_json::json!({"prompt": "Hi"})).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_admin_model_requires_api_key() {
+        let (status, _) = get_with_auth(&keyed_router(), "/v1/admin/model", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = get_with_auth(&keyed_router(), "/v1/admin/model", Some("Bearer first-key")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let router = test_router(false);
+        let (status, _) = post_completion(router.clone(), serde_json::json!({"prompt": "Hi"})).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let response = router.oneshot(Request::get("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(text.contains("lie_requests_total{status=\"success\"}"), "{}", text);
+        assert!(text.contains("lie_inference_duration_seconds_bucket"), "{}", text);
+        assert!(text.contains("# TYPE lie_output_tokens_total counter"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_request_id_header() {
+        let router = test_router(false);
+        let request = |id: Option<&str>| {
+            let mut builder = Request::post("/v1/completion").header("content-type", "application/json");
+            if let Some(id) = id {
+                builder = builder.header("x-request-id", id);
+            }
+            builder.body(Body::from(serde_json::json!({"prompt": "Hi"}).to_string())).unwrap()
+        };
+
+        // An incoming id is kept and returned in the header and the body
+        let response = router.clone().oneshot(request(Some("trace-42"))).await.unwrap();
+        assert_eq!(response.headers()["x-request-id"], "trace-42");
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: EngineResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body.request_id.as_deref(), Some("trace-42"));
+
+        // Otherwise one is generated, and errors carry it too
+        let (status, response) = post_completion(test_router(true), serde_json::json!({"prompt": "Hi"})).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.request_id.unwrap().len(), 16);
+        let response = router.oneshot(request(None)).await.unwrap();
+        assert!(response.headers().contains_key("x-request-id"));
+    }
+
+    #[tokio::test]
+    async fn test_session_endpoints() {
+        let router = test_router(false);
+        let (status, session) = send(&router, "POST", "/v1/sessions", Some(serde_json::json!({"system": "Be brief."}))).await;
+        assert_eq!(status, StatusCode::OK);
+        let id = session["id"].as_str().unwrap().to_string();
+
+        let uri = format!("/v1/sessions/{}/messages", id);
+        let (status, response) = send(&router, "POST", &uri, Some(serde_json::json!({"message": "Hello"}))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["status"], "success");
+        let (_, list) = send(&router, "GET", "/v1/sessions", None).await;
+        assert_eq!(list["sessions"][0]["id"], id.as_str());
+        assert_eq!(list["sessions"][0]["messages"], 2);
+        let (status, _) = send(&router, "POST", &uri, Some(serde_json::json!({"message": " "}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (_, transcript) = send(&router, "GET", &format!("/v1/sessions/{}", id), None).await;
+        assert_eq!(transcript["system"], "Be brief.");
+        let roles: Vec<&str> = transcript["messages"].as_array().unwrap().iter().map(|m| m["role"].as_str().unwrap()).collect();
+        assert_eq!(roles, vec!["user", "assistant"]);
+
+        let response = router.clone()
+            .oneshot(Request::delete(format!("/v1/sessions/{}", id)).body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let (status, response) = send(&router, "POST", &uri, Some(serde_json::json!({"message": "Hello"}))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(response["error_info"]["code"], "not_found");
     }
 }
@@ -0,0 +1,21 @@
+//! Listening on a unix domain socket (`server.listen = "unix"`); `connections::serve` serves it.
+
+use anyhow::{anyhow, Result};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use tokio::net::UnixListener;
+
+/// Bind `path`, replacing a socket file left behind by a server that is no longer running.
+pub(crate) fn bind(path: &Path) -> Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{} exists and is not a socket", path.display()));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow!("{} is already in use by a running server", path.display()));
+        }
+        std::fs::remove_file(path)
+            .map_err(|e| anyhow!("Failed to remove the stale socket {}: {}", path.display(), e))?;
+    }
+    UnixListener::bind(path).map_err(|e| anyhow!("Failed to bind {}: {}", path.display(), e))
+}
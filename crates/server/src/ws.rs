@@ -0,0 +1,151 @@
+//! Chat over a WebSocket (`/v1/ws`), for clients that keep a connection open: each `chat`
+//! frame sends a message in a session, whose history the server keeps, and the reply comes
+//! back as `token` frames followed by a `done` or `error` frame with the response. A
+//! connection runs one reply at a time; a `cancel` frame, or closing the connection, stops it.
+
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::{SinkExt, StreamExt};
+use lie_core::{Engine, EngineResponse, RequestContext, error::ErrorCode, runtime::{InferenceOptions, TokenChunk}};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use crate::{validate_session_message, RequestLimits, ServerLimits, SessionMessageRequest};
+
+/// A frame from the client.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ClientFrame {
+    /// Send `content` in the session `session_id`, as `POST /v1/sessions/:id/messages` does.
+    Chat {
+        session_id: String,
+        content: String,
+        #[serde(default)]
+        limits: Option<Box<RequestLimits>>,
+    },
+    /// Stop the reply being generated; it ends with a `done` frame of status `cancelled`.
+    Cancel,
+}
+
+/// A frame from the server. `done` and `error` carry the response, as the SSE events do.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Token { text: String },
+    Done(EngineResponse),
+    Error(EngineResponse),
+}
+
+/// The reply a connection is generating.
+struct Generation {
+    cancel: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl Generation {
+    fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+}
+
+/// Ids for the replies of a connection: that of the upgrade request followed by the reply's
+/// number, `<id>-0`, `<id>-1` and so on, as the items of a batch are numbered.
+struct ReplyIds {
+    connection: String,
+    sent: usize,
+}
+
+impl ReplyIds {
+    fn next(&mut self) -> String {
+        let id = format!("{}-{}", self.connection, self.sent);
+        self.sent += 1;
+        id
+    }
+}
+
+/// Serve the frames of one connection until the client closes it, then cancel the reply
+/// still being generated, if any. `context` is that of the request that opened it.
+pub(crate) async fn serve(engine: Arc<Engine>, limits: ServerLimits, context: RequestContext, socket: WebSocket) {
+    let mut reply_ids = ReplyIds { connection: context.id, sent: 0 };
+    let (mut sink, mut stream) = socket.split();
+    let (frames, mut outgoing) = mpsc::unbounded_channel::<ServerFrame>();
+    let mut generation: Option<Generation> = None;
+    loop {
+        tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    if let Err(e) = receive(&engine, &limits, &text, &frames, &mut generation, &mut reply_ids) {
+                        let _ = frames.send(ServerFrame::Error(EngineResponse::error(ErrorCode::ValidationError, e)));
+                    }
+                }
+                Some(Ok(Message::Binary(_))) => {
+                    let response = EngineResponse::error(ErrorCode::ValidationError, "Validation Error: frames must be JSON text");
+                    let _ = frames.send(ServerFrame::Error(response));
+                }
+                // Pings are answered by axum
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            },
+            Some(frame) = outgoing.recv() => {
+                let text = serde_json::to_string(&frame).unwrap_or_default();
+                if sink.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    // Cancelled rather than aborted, so it leaves the runtime and the queue as usual
+    if let Some(generation) = generation.filter(Generation::is_running) {
+        tracing::info!("WebSocket closed, cancelling the reply being generated");
+        generation.cancel.cancel();
+    }
+}
+
+/// Act on a frame from the client, or say what's wrong with it.
+fn receive(engine: &Arc<Engine>, limits: &ServerLimits, text: &str, frames: &mpsc::UnboundedSender<ServerFrame>, generation: &mut Option<Generation>, reply_ids: &mut ReplyIds) -> Result<(), String> {
+    let frame: ClientFrame = serde_json::from_str(text).map_err(|e| format!("Validation Error: {}", e))?;
+    match frame {
+        ClientFrame::Cancel => {
+            if let Some(generation) = generation {
+                generation.cancel.cancel();
+            }
+            Ok(())
+        }
+        ClientFrame::Chat { session_id, content, limits: request_limits } => {
+            if generation.as_ref().is_some_and(Generation::is_running) {
+                return Err("Validation Error: a reply is still being generated; wait for it or cancel it first".to_string());
+            }
+            let payload = SessionMessageRequest { message: content, limits: request_limits.map(|limits| *limits), response_format: None };
+            let options = validate_session_message(&payload, limits.max_prompt_chars)?;
+            let cancel = options.cancel.clone();
+            let context = RequestContext::with_id(reply_ids.next());
+            let task = tokio::spawn(reply(engine.clone(), context, session_id, payload.message, options, frames.clone()));
+            *generation = Some(Generation { cancel, task });
+            Ok(())
+        }
+    }
+}
+
+/// Generate the reply to `message`, sending `token` frames as it goes and then the response.
+async fn reply(engine: Arc<Engine>, context: RequestContext, session_id: String, message: String, options: InferenceOptions, frames: mpsc::UnboundedSender<ServerFrame>) {
+    let (id, received_at) = (context.id.clone(), context.received_at);
+    let (tokens, mut chunks) = mpsc::unbounded_channel();
+    let chat = engine.process_chat_in_session_with_progress(&session_id, &message, options, Some(context), tokens);
+    let forward = async {
+        while let Some(chunk) = chunks.recv().await {
+            if let TokenChunk::Token { text } = chunk {
+                let _ = frames.send(ServerFrame::Token { text });
+            }
+        }
+    };
+    let (result, ()) = tokio::join!(chat, forward);
+
+    let (done, response) = match result {
+        Ok(response) => (response.status != "error", response),
+        Err(e) => (false, EngineResponse::from_error(&e)),
+    };
+    tracing::info!("request {}: {}, {} tokens, {} ms (WebSocket)", id, response.status, response.usage.total_tokens, received_at.elapsed().as_millis());
+    let response = EngineResponse { request_id: Some(id), ..response };
+    let _ = frames.send(if done { ServerFrame::Done(response) } else { ServerFrame::Error(response) });
+}